@@ -0,0 +1,517 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use crate::simbox::{BoxShape, SimBox};
+use crate::{to_f64, Matrix, Real, Rvec};
+
+pub const CPT_MAGIC: &[u8; 8] = b"DYNAMCPT";
+// Version 1: header, box, atoms, integrator state, RNG states, neighbor list
+// settings and a checksum of everything before it.
+// Version 2: reals as f64, whatever the precision of the build.
+pub const CPT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NeighborListSettings {
+    // Steps between list updates.
+    pub nstlist: i32,
+    pub rlist: Real,
+    pub cutoff: Real,
+}
+
+// Everything needed to continue a run exactly where it stopped. Integrators,
+// thermostats and barostats store their extended variables under a name of
+// their choosing in `state` (values round-trip exactly through f64), and their
+// random number generators in `rngs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub step: i32,
+    pub time: Real,
+    pub simbox: Option<SimBox>,
+    pub positions: Vec<Rvec>,
+    pub velocities: Vec<Rvec>,
+    pub forces: Vec<Rvec>,
+    pub state: Vec<(String, Vec<f64>)>,
+    pub rngs: Vec<(String, [u64; 4])>,
+    pub neighbor_list: Option<NeighborListSettings>,
+}
+
+// Integrator state that is not part of the positions, velocities and forces.
+pub trait Checkpointable {
+    fn save_state(&self, cpt: &mut Checkpoint);
+    fn restore_state(&mut self, cpt: &Checkpoint);
+}
+
+impl Checkpoint {
+    pub fn new(positions: Vec<Rvec>, velocities: Vec<Rvec>, forces: Vec<Rvec>) -> Checkpoint {
+        if velocities.len() != positions.len() || forces.len() != positions.len() {
+            panic!("Positions, velocities and forces must have the same number of atoms");
+        }
+        Checkpoint {
+            step: 0,
+            time: 0.0,
+            simbox: None,
+            positions,
+            velocities,
+            forces,
+            state: Vec::new(),
+            rngs: Vec::new(),
+            neighbor_list: None,
+        }
+    }
+
+    pub fn capture<I: Checkpointable + ?Sized>(
+        integrator: &I,
+        simbox: Option<&SimBox>,
+        positions: &[Rvec],
+        velocities: &[Rvec],
+        forces: &[Rvec],
+    ) -> Checkpoint {
+        let mut cpt = Checkpoint::new(positions.to_vec(), velocities.to_vec(), forces.to_vec());
+        cpt.simbox = simbox.copied();
+        integrator.save_state(&mut cpt);
+        cpt
+    }
+
+    // Puts the system and the integrator back in the checkpointed state.
+    pub fn restore<I: Checkpointable + ?Sized>(
+        &self,
+        integrator: &mut I,
+        positions: &mut [Rvec],
+        velocities: &mut [Rvec],
+        forces: &mut [Rvec],
+    ) {
+        if positions.len() != self.positions.len() {
+            panic!(
+                "Checkpoint has {} atoms but the system has {}",
+                self.positions.len(),
+                positions.len()
+            );
+        }
+        positions.copy_from_slice(&self.positions);
+        velocities.copy_from_slice(&self.velocities);
+        forces.copy_from_slice(&self.forces);
+        integrator.restore_state(self);
+    }
+
+    pub fn set_state(&mut self, name: &str, values: Vec<f64>) {
+        match self.state.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = values,
+            None => self.state.push((name.to_string(), values)),
+        }
+    }
+
+    pub fn get_state(&self, name: &str) -> &[f64] {
+        self.state
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+            .unwrap_or_else(|| panic!("Checkpoint has no state for {}", name))
+    }
+
+    pub fn set_rng(&mut self, name: &str, state: [u64; 4]) {
+        match self.rngs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, s)) => *s = state,
+            None => self.rngs.push((name.to_string(), state)),
+        }
+    }
+
+    pub fn get_rng(&self, name: &str) -> [u64; 4] {
+        self.rngs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, s)| *s)
+            .unwrap_or_else(|| panic!("Checkpoint has no random state for {}", name))
+    }
+
+    // Writes to a temporary file that replaces `filename` once complete, so
+    // that a crash while writing never leaves a broken checkpoint behind.
+    pub fn write(&self, filename: &str) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(CPT_MAGIC);
+        put_u32(&mut buf, CPT_VERSION);
+        put_i32(&mut buf, self.step);
+        put_real(&mut buf, self.time);
+
+        put_simbox(&mut buf, self.simbox.as_ref());
+
+        put_u32(&mut buf, self.positions.len() as u32);
+        for v in [&self.positions, &self.velocities, &self.forces] {
+            v.iter().flatten().for_each(|&x| put_real(&mut buf, x));
+        }
+
+        put_u32(&mut buf, self.state.len() as u32);
+        for (name, values) in &self.state {
+            put_string(&mut buf, name);
+            put_u32(&mut buf, values.len() as u32);
+            values.iter().for_each(|&x| put_f64(&mut buf, x));
+        }
+        put_u32(&mut buf, self.rngs.len() as u32);
+        for (name, state) in &self.rngs {
+            put_string(&mut buf, name);
+            state.iter().for_each(|&x| put_u64(&mut buf, x));
+        }
+        match &self.neighbor_list {
+            Some(nl) => {
+                put_u32(&mut buf, 1);
+                put_i32(&mut buf, nl.nstlist);
+                put_real(&mut buf, nl.rlist);
+                put_real(&mut buf, nl.cutoff);
+            }
+            None => put_u32(&mut buf, 0),
+        }
+        let sum = checksum(&buf);
+        put_u64(&mut buf, sum);
+
+        let tmp = format!("{}.tmp", filename);
+        let mut file =
+            BufWriter::new(File::create(&tmp).unwrap_or_else(|e| panic!("{}: {}", tmp, e)));
+        file.write_all(&buf).unwrap();
+        file.into_inner().unwrap().sync_all().unwrap();
+        std::fs::rename(&tmp, filename).unwrap();
+    }
+
+    pub fn read(filename: &str) -> Checkpoint {
+        let mut data = Vec::new();
+        File::open(filename)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        if data.len() < 20 || &data[..8] != CPT_MAGIC {
+            panic!("{}: not a checkpoint file", filename);
+        }
+        let (body, sum) = data.split_at(data.len() - 8);
+        if checksum(body) != u64::from_le_bytes(sum.try_into().unwrap()) {
+            panic!("{}: checkpoint is corrupted or truncated", filename);
+        }
+
+        let mut r = Cursor::new(body, 8);
+        let version = r.u32();
+        if version != CPT_VERSION {
+            panic!(
+                "{}: unsupported checkpoint version {} (this build reads {})",
+                filename, version, CPT_VERSION
+            );
+        }
+        let step = r.i32();
+        let time = r.real();
+
+        let simbox = r.simbox();
+
+        let natoms = r.u32() as usize;
+        let mut vectors = || {
+            (0..natoms)
+                .map(|_| [r.real(), r.real(), r.real()])
+                .collect::<Vec<Rvec>>()
+        };
+        let mut cpt = Checkpoint::new(vectors(), vectors(), vectors());
+        cpt.step = step;
+        cpt.time = time;
+        cpt.simbox = simbox;
+
+        for _ in 0..r.u32() {
+            let name = r.string();
+            let values = (0..r.u32()).map(|_| r.f64()).collect();
+            cpt.state.push((name, values));
+        }
+        for _ in 0..r.u32() {
+            let name = r.string();
+            let state = [r.u64(), r.u64(), r.u64(), r.u64()];
+            cpt.rngs.push((name, state));
+        }
+        if r.u32() != 0 {
+            cpt.neighbor_list = Some(NeighborListSettings {
+                nstlist: r.i32(),
+                rlist: r.real(),
+                cutoff: r.real(),
+            });
+        }
+        cpt
+    }
+}
+
+// Writes a checkpoint every `interval` steps. The previous checkpoint is kept
+// as `<name>_prev.<ext>` in case the newest one turns out to be unusable.
+pub struct CheckpointWriter {
+    pub filename: String,
+    pub interval: i32,
+}
+
+impl CheckpointWriter {
+    pub fn new(filename: &str, interval: i32) -> CheckpointWriter {
+        CheckpointWriter {
+            filename: filename.to_string(),
+            interval,
+        }
+    }
+
+    // Calls `capture` and writes its checkpoint if `step` is due; returns
+    // whether it was.
+    pub fn write<F: FnOnce() -> Checkpoint>(&self, step: i32, capture: F) -> bool {
+        if self.interval <= 0 || step % self.interval != 0 {
+            return false;
+        }
+        let path = std::path::Path::new(&self.filename);
+        if path.exists() {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            let prev = match path.extension().and_then(|e| e.to_str()) {
+                Some(ext) => path.with_file_name(format!("{}_prev.{}", stem, ext)),
+                None => path.with_file_name(format!("{}_prev", stem)),
+            };
+            std::fs::rename(path, prev).unwrap();
+        }
+        capture().write(&self.filename);
+        true
+    }
+}
+
+// 64 bit FNV-1a.
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+pub(crate) fn put_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+pub(crate) fn put_i32(buf: &mut Vec<u8>, x: i32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, x: u64) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+pub(crate) fn put_f64(buf: &mut Vec<u8>, x: f64) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+// Reals are stored as f64, which holds both precisions exactly.
+pub(crate) fn put_real(buf: &mut Vec<u8>, x: Real) {
+    put_f64(buf, to_f64(x));
+}
+
+pub(crate) fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// The box shape, offset by one so that 0 means no box, and the box vectors.
+pub(crate) fn put_simbox(buf: &mut Vec<u8>, simbox: Option<&SimBox>) {
+    match simbox {
+        Some(simbox) => {
+            put_u32(buf, 1 + simbox.shape as u32);
+            simbox
+                .vectors
+                .iter()
+                .flatten()
+                .for_each(|&x| put_real(buf, x));
+        }
+        None => put_u32(buf, 0),
+    }
+}
+
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    pub(crate) fn new(data: &[u8], pos: usize) -> Cursor<'_> {
+        Cursor { data, pos }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        if self.pos + N > self.data.len() {
+            panic!("Unexpected end of data");
+        }
+        let bytes = self.data[self.pos..self.pos + N].try_into().unwrap();
+        self.pos += N;
+        bytes
+    }
+
+    pub(crate) fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn i32(&mut self) -> i32 {
+        i32::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn f64(&mut self) -> f64 {
+        f64::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn real(&mut self) -> Real {
+        self.f64() as Real
+    }
+
+    pub(crate) fn string(&mut self) -> String {
+        let n = self.u32() as usize;
+        if self.pos + n > self.data.len() {
+            panic!("Unexpected end of data");
+        }
+        let s = String::from_utf8_lossy(&self.data[self.pos..self.pos + n]).into_owned();
+        self.pos += n;
+        s
+    }
+
+    pub(crate) fn simbox(&mut self) -> Option<SimBox> {
+        let shape = match self.u32() {
+            0 => return None,
+            1 => BoxShape::Rectangular,
+            2 => BoxShape::Triclinic,
+            3 => BoxShape::TruncatedOctahedron,
+            4 => BoxShape::RhombicDodecahedron,
+            shape => panic!("Invalid box shape {}", shape - 1),
+        };
+        let mut vectors: Matrix = [[0.0; 3]; 3];
+        vectors.iter_mut().flatten().for_each(|x| *x = self.real());
+        Some(SimBox::new(vectors, shape))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffield::{ForceGroup, Forces};
+    use crate::integrator::brownian::BrownianDynamics;
+    use crate::integrator::respa::{Respa, RespaSplitting};
+    use crate::integrator::rigid::RigidVerlet;
+    use crate::integrator::verlet::VelocityVerlet;
+    use crate::topology::Topology;
+
+    #[test]
+    fn it_restarts_exactly() {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("tri".to_string(), 1, 3);
+        for name in ["c1", "c2", "c3"] {
+            top.add_atom(0, "c", name, 1, "TRI", 0.0);
+        }
+        top.add_bonded_interaction(0, "bond_harm 1 2 0.15 250000.0");
+        top.add_bonded_interaction(0, "bond_harm 2 3 0.15 250000.0");
+        top.add_bonded_interaction(0, "angle_harm 1 2 3 109.5 500.0");
+        let ffield = Forces::new(&top);
+        let masses = vec![12.0; 3];
+        let coords = vec![[0.0, 0.0, 0.0], [0.16, 0.01, 0.0], [0.2, 0.15, 0.02]];
+        let filename = &crate::temp_path("restart.cpt");
+        let writer = CheckpointWriter::new(filename, 10);
+
+        // Velocity Verlet: 20 steps in one go, or 10 + restart + 10.
+        let run = |vv: &mut VelocityVerlet, x: &mut [Rvec], f: &mut [Rvec], v: &mut [Rvec]| {
+            for _ in 0..10 {
+                vv.step(&ffield, x, f, v, &masses);
+                writer.write(vv.step, || Checkpoint::capture(&*vv, None, x, v, f));
+            }
+        };
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], vec![[0.0; 3]; 3]);
+        let mut vv = VelocityVerlet::new(0.0005, 3);
+        run(&mut vv, &mut x, &mut f, &mut v);
+        run(&mut vv, &mut x, &mut f, &mut v);
+        let reference = (x, v);
+
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], vec![[0.0; 3]; 3]);
+        let mut vv = VelocityVerlet::new(0.0005, 3);
+        run(&mut vv, &mut x, &mut f, &mut v);
+        let mut vv = VelocityVerlet::new(0.0005, 3);
+        let (mut x, mut f, mut v) = (vec![[0.0; 3]; 3], vec![[0.0; 3]; 3], vec![[0.0; 3]; 3]);
+        Checkpoint::read(filename).restore(&mut vv, &mut x, &mut v, &mut f);
+        assert_eq!(vv.step, 10);
+        run(&mut vv, &mut x, &mut f, &mut v);
+        assert_eq!((x, v), reference);
+
+        // A rigid body keeps its principal frame across the restart.
+        let groups = vec![vec![0, 1, 2]];
+        let v0 = vec![[0.3, -0.2, 0.1], [-0.1, 0.4, 0.0], [0.0, -0.1, -0.3]];
+        let run = |rv: &mut RigidVerlet, x: &mut [Rvec], f: &mut [Rvec], v: &mut [Rvec]| {
+            for _ in 0..10 {
+                rv.step(&ffield, x, f, v, &masses);
+                writer.write(rv.step, || Checkpoint::capture(&*rv, None, x, v, f));
+            }
+        };
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], v0.clone());
+        let mut rv = RigidVerlet::new(0.0005, groups.clone(), &x, &v, &masses);
+        run(&mut rv, &mut x, &mut f, &mut v);
+        run(&mut rv, &mut x, &mut f, &mut v);
+        let reference = (x, v);
+
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], v0.clone());
+        let mut rv = RigidVerlet::new(0.0005, groups.clone(), &x, &v, &masses);
+        run(&mut rv, &mut x, &mut f, &mut v);
+        let cpt = Checkpoint::read(filename);
+        let mut rv = RigidVerlet::new(0.0005, groups, &cpt.positions, &cpt.velocities, &masses);
+        cpt.restore(&mut rv, &mut x, &mut v, &mut f);
+        run(&mut rv, &mut x, &mut f, &mut v);
+        assert_eq!((x, v), reference);
+
+        // RESPA stores the forces of its slower levels.
+        let levels = [
+            (vec![ForceGroup::Bonds], 1),
+            (vec![ForceGroup::Angles, ForceGroup::Torsions], 2),
+            (vec![ForceGroup::Pairs, ForceGroup::Nonbonded], 4),
+        ];
+        let respa = || Respa::new(0.0002, 3, &levels, RespaSplitting::Mollified);
+        let run = |r: &mut Respa, x: &mut [Rvec], f: &mut [Rvec], v: &mut [Rvec]| {
+            for _ in 0..10 {
+                r.step(&ffield, x, v, &masses);
+                writer.write(r.step, || Checkpoint::capture(&*r, None, x, v, f));
+            }
+        };
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], v0.clone());
+        let mut r = respa();
+        run(&mut r, &mut x, &mut f, &mut v);
+        run(&mut r, &mut x, &mut f, &mut v);
+        let reference = (x, v);
+
+        let (mut x, mut f, mut v) = (coords.clone(), vec![[0.0; 3]; 3], v0.clone());
+        let mut r = respa();
+        run(&mut r, &mut x, &mut f, &mut v);
+        let mut r = respa();
+        let (mut x, mut f, mut v) = (vec![[0.0; 3]; 3], vec![[0.0; 3]; 3], vec![[0.0; 3]; 3]);
+        Checkpoint::read(filename).restore(&mut r, &mut x, &mut v, &mut f);
+        assert_eq!(r.step, 10);
+        run(&mut r, &mut x, &mut f, &mut v);
+        assert_eq!((x, v), reference);
+
+        // Brownian dynamics carries its random number generator along.
+        let mut bd = BrownianDynamics::new(0.001, 300.0, vec![0.01; 3], 42);
+        let mut x = coords.clone();
+        let mut f = vec![[0.0; 3]; 3];
+        for _ in 0..5 {
+            bd.step(&ffield, &mut x, &mut f);
+        }
+        let v = vec![[0.0; 3]; 3];
+        let mut cpt = Checkpoint::capture(&bd, None, &x, &v, &f);
+        cpt.neighbor_list = Some(NeighborListSettings {
+            nstlist: 10,
+            rlist: 1.1,
+            cutoff: 1.0,
+        });
+        cpt.write(filename);
+        let restored = Checkpoint::read(filename);
+        assert_eq!(restored, cpt);
+        let mut bd2 = BrownianDynamics::new(0.001, 300.0, vec![0.01; 3], 0);
+        let (mut x2, mut f2) = (x.clone(), f.clone());
+        restored.restore(&mut bd2, &mut x2, &mut v.clone(), &mut f2);
+        for _ in 0..5 {
+            bd.step(&ffield, &mut x, &mut f);
+            bd2.step(&ffield, &mut x2, &mut f2);
+        }
+        assert_eq!(x, x2);
+
+        // Truncated files are detected.
+        let data = std::fs::read(filename).unwrap();
+        std::fs::write(filename, &data[..data.len() - 4]).unwrap();
+        assert!(std::panic::catch_unwind(|| Checkpoint::read(filename)).is_err());
+        std::fs::remove_file(filename).unwrap();
+        let prev = filename.replace(".cpt", "_prev.cpt");
+        assert!(std::path::Path::new(&prev).exists());
+        std::fs::remove_file(prev).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use crate::linalg::*;
+use crate::{Real, Rvec, DIM};
+
+// Holonomic constraints acting on a set of coordinates.
+// `apply` restores the constraints on `positions` after an unconstrained move
+// from `reference` (which must satisfy them), while `project` removes the
+// constrained components from a per-atom vector field (velocities, forces or
+// search directions) evaluated at `positions`.
+pub trait Constraints {
+    fn apply(&self, reference: &[Rvec], positions: &mut [Rvec]);
+    fn project(&self, positions: &[Rvec], vectors: &mut [Rvec]);
+}
+
+// Atoms that are not allowed to move at all.
+pub struct FrozenAtoms {
+    pub atoms: Vec<usize>,
+}
+
+impl FrozenAtoms {
+    pub fn new(atoms: Vec<usize>) -> FrozenAtoms {
+        FrozenAtoms { atoms }
+    }
+}
+
+impl Constraints for FrozenAtoms {
+    fn apply(&self, reference: &[Rvec], positions: &mut [Rvec]) {
+        self.atoms.iter().for_each(|&i| positions[i] = reference[i]);
+    }
+
+    fn project(&self, _positions: &[Rvec], vectors: &mut [Rvec]) {
+        self.atoms.iter().for_each(|&i| vectors[i] = [0.0; DIM]);
+    }
+}
+
+// Fixed bond lengths, solved iteratively with SHAKE for positions and
+// RATTLE for vector fields.
+pub struct Shake {
+    pub bonds: Vec<([usize; 2], Real)>,
+    inv_masses: Vec<Real>,
+    pub tol: Real,
+    pub max_iter: usize,
+}
+
+impl Shake {
+    pub fn new(bonds: Vec<([usize; 2], Real)>, masses: &[Real], tol: Real) -> Shake {
+        Shake {
+            bonds,
+            inv_masses: masses.iter().map(|m| m.recip()).collect(),
+            tol,
+            max_iter: 1000,
+        }
+    }
+}
+
+impl Constraints for Shake {
+    fn apply(&self, reference: &[Rvec], positions: &mut [Rvec]) {
+        for _ in 0..self.max_iter {
+            let mut done = true;
+            for &([i, j], d) in &self.bonds {
+                let rij = displace_vec(&positions[i], &positions[j]);
+                let diff = d * d - norm2(&rij);
+                if diff.abs() <= 2.0 * self.tol * d * d {
+                    continue;
+                }
+                done = false;
+                let rij_ref = displace_vec(&reference[i], &reference[j]);
+                let (wi, wj) = (self.inv_masses[i], self.inv_masses[j]);
+                let g = diff / (2.0 * (wi + wj) * dot(&rij, &rij_ref));
+                for d in 0..DIM {
+                    positions[i][d] -= g * wi * rij_ref[d];
+                    positions[j][d] += g * wj * rij_ref[d];
+                }
+            }
+            if done {
+                return;
+            }
+        }
+        panic!("SHAKE did not converge in {} iterations", self.max_iter);
+    }
+
+    fn project(&self, positions: &[Rvec], vectors: &mut [Rvec]) {
+        for _ in 0..self.max_iter {
+            let mut done = true;
+            for &([i, j], d) in &self.bonds {
+                let rij = displace_vec(&positions[i], &positions[j]);
+                let vij = displace_vec(&vectors[i], &vectors[j]);
+                let rv = dot(&rij, &vij);
+                if rv.abs() <= self.tol * d * norm2(&vij).sqrt().max(Real::MIN_POSITIVE) {
+                    continue;
+                }
+                done = false;
+                let (wi, wj) = (self.inv_masses[i], self.inv_masses[j]);
+                let k = rv / (norm2(&rij) * (wi + wj));
+                for d in 0..DIM {
+                    vectors[i][d] += k * wi * rij[d];
+                    vectors[j][d] -= k * wj * rij[d];
+                }
+            }
+            if done {
+                return;
+            }
+        }
+        panic!("RATTLE did not converge in {} iterations", self.max_iter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shakes() {
+        let shake = Shake::new(vec![([0, 1], 0.1), ([1, 2], 0.1)], &[1.0, 12.0, 1.0], 1e-6);
+        let reference = [[0.0, 0.0, 0.0], [0.1, 0.0, 0.0], [0.1, 0.1, 0.0]];
+        let mut positions = [[-0.01, 0.002, 0.0], [0.1, 0.01, 0.0], [0.12, 0.13, 0.01]];
+        shake.apply(&reference, &mut positions);
+        for ([i, j], d) in &shake.bonds {
+            let r = norm2(&displace_vec(&positions[*i], &positions[*j])).sqrt();
+            assert!((r - d).abs() < 1e-5);
+        }
+
+        let mut velocities = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        shake.project(&positions, &mut velocities);
+        for ([i, j], _) in &shake.bonds {
+            let rij = displace_vec(&positions[*i], &positions[*j]);
+            let vij = displace_vec(&velocities[*i], &velocities[*j]);
+            assert!(dot(&rij, &vij).abs() < 1e-5);
+        }
+    }
+}
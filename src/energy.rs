@@ -0,0 +1,558 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use crate::ffield::{Forces, NGROUPS};
+use crate::integrator::velocities::kinetic_energy;
+use crate::simbox::SimBox;
+use crate::trajectory::writer::open_truncated;
+use crate::{to_f64, Matrix, Real, Rvec, BOLTZ, DIM};
+
+// kJ/(mol nm^3) to bar.
+pub const PRESFAC: Real = 16.605_39;
+// amu/nm^3 to kg/m^3.
+pub const AMU_DENSITY: Real = 1.660_539;
+
+pub const ENERGY_MAGIC: &[u8; 8] = b"DYNAMENE";
+// Version 2: time and values as f64, whatever the precision of the build.
+pub const ENERGY_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observable {
+    Bonds,
+    Angles,
+    Torsions,
+    Pairs,
+    Nonbonded,
+    Potential,
+    Kinetic,
+    Total,
+    Conserved,
+    Temperature,
+    Pressure,
+    Volume,
+    Density,
+}
+
+impl Observable {
+    pub const ALL: [Observable; 13] = [
+        Observable::Bonds,
+        Observable::Angles,
+        Observable::Torsions,
+        Observable::Pairs,
+        Observable::Nonbonded,
+        Observable::Potential,
+        Observable::Kinetic,
+        Observable::Total,
+        Observable::Conserved,
+        Observable::Temperature,
+        Observable::Pressure,
+        Observable::Volume,
+        Observable::Density,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Observable::Bonds => "bonds",
+            Observable::Angles => "angles",
+            Observable::Torsions => "torsions",
+            Observable::Pairs => "pairs",
+            Observable::Nonbonded => "nonbonded",
+            Observable::Potential => "potential",
+            Observable::Kinetic => "kinetic",
+            Observable::Total => "total",
+            Observable::Conserved => "conserved",
+            Observable::Temperature => "temperature",
+            Observable::Pressure => "pressure",
+            Observable::Volume => "volume",
+            Observable::Density => "density",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Observable::Temperature => "K",
+            Observable::Pressure => "bar",
+            Observable::Volume => "nm^3",
+            Observable::Density => "kg/m^3",
+            _ => "kJ/mol",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Observable {
+        Observable::ALL
+            .into_iter()
+            .find(|o| o.name() == name.to_lowercase())
+            .unwrap_or_else(|| panic!("Unknown energy term: {}", name))
+    }
+}
+
+// Instantaneous energies and thermodynamic state of the system.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Energies {
+    // Potential energy of each force group, in the order of `ForceGroup::ALL`.
+    pub terms: [Real; NGROUPS],
+    pub kinetic: Real,
+    // Energy exchanged with thermostats and barostats, added to the total
+    // energy to give the conserved quantity of the run.
+    pub reservoir: Real,
+    pub temperature: Real,
+    // Virial -1/2 sum r (x) f, in kJ/mol.
+    pub virial: Matrix,
+    // Pressure in bar and volume in nm^3, zero without a box.
+    pub pressure: Real,
+    pub volume: Real,
+    pub mass: Real,
+}
+
+impl Energies {
+    // Evaluates the force field group by group.
+    pub fn compute(
+        ffield: &Forces,
+        positions: &[Rvec],
+        velocities: &[Rvec],
+        masses: &[Real],
+        ndof: usize,
+        simbox: Option<&SimBox>,
+    ) -> Energies {
+        let mut forces = vec![[0.0; DIM]; positions.len()];
+        let terms = ffield.calc_terms(positions, &mut forces);
+        Energies::new(terms, positions, &forces, velocities, masses, ndof, simbox)
+    }
+
+    // From the energy terms and the forces of an evaluation that already
+    // took place, e.g. the last step of an integrator. The virial is the
+    // single sum over atoms, which is exact as long as interactions are not
+    // computed across periodic boundaries. The nonbonded group is, so with a
+    // box the pressure misses the contribution of its periodic shifts.
+    pub fn new(
+        terms: [Real; NGROUPS],
+        positions: &[Rvec],
+        forces: &[Rvec],
+        velocities: &[Rvec],
+        masses: &[Real],
+        ndof: usize,
+        simbox: Option<&SimBox>,
+    ) -> Energies {
+        let mut energies = Energies {
+            terms,
+            ..Default::default()
+        };
+        energies.virial = virial(positions, forces);
+        energies.kinetic = kinetic_energy(velocities, masses);
+        energies.temperature = if ndof > 0 {
+            2.0 * energies.kinetic / (ndof as Real * BOLTZ)
+        } else {
+            0.0
+        };
+        energies.mass = masses.iter().sum();
+        if let Some(simbox) = simbox {
+            let xi = (0..DIM).map(|d| energies.virial[d][d]).sum::<Real>();
+            energies.volume = simbox.volume();
+            energies.pressure = 2.0 * (energies.kinetic - xi) / (3.0 * energies.volume) * PRESFAC;
+        }
+        energies
+    }
+
+    pub fn potential(&self) -> Real {
+        self.terms.iter().sum()
+    }
+
+    pub fn get(&self, observable: Observable) -> Real {
+        match observable {
+            Observable::Bonds => self.terms[0],
+            Observable::Angles => self.terms[1],
+            Observable::Torsions => self.terms[2],
+            Observable::Pairs => self.terms[3],
+            Observable::Nonbonded => self.terms[4],
+            Observable::Potential => self.potential(),
+            Observable::Kinetic => self.kinetic,
+            Observable::Total => self.potential() + self.kinetic,
+            Observable::Conserved => self.potential() + self.kinetic + self.reservoir,
+            Observable::Temperature => self.temperature,
+            Observable::Pressure => self.pressure,
+            Observable::Volume => self.volume,
+            Observable::Density if self.volume > 0.0 => self.mass / self.volume * AMU_DENSITY,
+            Observable::Density => 0.0,
+        }
+    }
+}
+
+// Summed in double precision: the terms of large systems cancel out to a
+// much smaller total.
+pub fn virial(positions: &[Rvec], forces: &[Rvec]) -> Matrix {
+    let mut xi = [[0.0f64; DIM]; DIM];
+    for (r, f) in positions.iter().zip(forces.iter()) {
+        for a in 0..DIM {
+            for b in 0..DIM {
+                xi[a][b] -= 0.5 * to_f64(r[a]) * to_f64(f[b]);
+            }
+        }
+    }
+    xi.map(|row| row.map(|x| x as Real))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnergyFormat {
+    Csv,
+    Binary,
+}
+
+impl EnergyFormat {
+    // CSV for .csv files, the binary format otherwise.
+    pub fn from_filename(filename: &str) -> EnergyFormat {
+        if filename.to_lowercase().ends_with(".csv") {
+            EnergyFormat::Csv
+        } else {
+            EnergyFormat::Binary
+        }
+    }
+}
+
+// Writes the selected observables every `interval` steps, either as CSV with
+// a header row or in binary: a header with the column names followed by
+// step (i32), time and values (f64) for each frame, all little endian.
+pub struct EnergyWriter {
+    file: BufWriter<File>,
+    pub format: EnergyFormat,
+    pub columns: Vec<Observable>,
+    pub interval: i32,
+}
+
+impl EnergyWriter {
+    pub fn new(filename: &str, columns: Vec<Observable>, interval: i32) -> EnergyWriter {
+        let file = File::create(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let mut writer = EnergyWriter {
+            file: BufWriter::new(file),
+            format: EnergyFormat::from_filename(filename),
+            columns,
+            interval,
+        };
+        writer.write_header();
+        writer
+    }
+
+    fn write_header(&mut self) {
+        let header = header(self.format, &self.columns);
+        self.file.write_all(&header).unwrap();
+    }
+
+    // Continues `filename` after its last row at or before `step`, as on a
+    // restart from a checkpoint at `step`: the later rows are dropped. The
+    // file must have the same columns.
+    pub fn append(
+        filename: &str,
+        columns: Vec<Observable>,
+        interval: i32,
+        step: i32,
+    ) -> EnergyWriter {
+        let Ok(data) = std::fs::read(filename) else {
+            return Self::new(filename, columns, interval);
+        };
+        let format = EnergyFormat::from_filename(filename);
+        let header = header(format, &columns);
+        if !data.starts_with(&header) {
+            panic!("{}: the columns differ from those of this run", filename);
+        }
+        // Whole rows only, in case the previous run stopped while writing.
+        let row_size = 4 + 8 * (1 + columns.len());
+        let rows: Box<dyn Iterator<Item = &[u8]>> = match format {
+            EnergyFormat::Csv => Box::new(data[header.len()..].split_inclusive(|&b| b == b'\n')),
+            EnergyFormat::Binary => Box::new(data[header.len()..].chunks(row_size)),
+        };
+        let mut len = header.len();
+        for row in rows {
+            let row_step = match format {
+                EnergyFormat::Csv if row.ends_with(b"\n") => String::from_utf8_lossy(row)
+                    .split(',')
+                    .next()
+                    .and_then(|s| s.parse::<i32>().ok()),
+                EnergyFormat::Binary if row.len() == row_size => {
+                    Some(i32::from_le_bytes(row[..4].try_into().unwrap()))
+                }
+                _ => None,
+            };
+            match row_step {
+                Some(s) if s <= step => len += row.len(),
+                _ => break,
+            }
+        }
+        EnergyWriter {
+            file: BufWriter::new(open_truncated(filename, len as u64)),
+            format,
+            columns,
+            interval,
+        }
+    }
+
+    pub fn write(&mut self, step: i32, time: Real, energies: &Energies) {
+        match self.format {
+            EnergyFormat::Csv => {
+                let mut line = format!("{},{}", step, time);
+                for &c in &self.columns {
+                    line.push(',');
+                    line.push_str(&energies.get(c).to_string());
+                }
+                writeln!(self.file, "{}", line).unwrap();
+            }
+            EnergyFormat::Binary => {
+                let mut buf = step.to_le_bytes().to_vec();
+                buf.extend_from_slice(&to_f64(time).to_le_bytes());
+                for &c in &self.columns {
+                    buf.extend_from_slice(&to_f64(energies.get(c)).to_le_bytes());
+                }
+                self.file.write_all(&buf).unwrap();
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+}
+
+// The CSV header row, or the binary header.
+fn header(format: EnergyFormat, columns: &[Observable]) -> Vec<u8> {
+    match format {
+        EnergyFormat::Csv => {
+            let mut line = "step,time".to_string();
+            for c in columns {
+                line.push(',');
+                line.push_str(c.name());
+            }
+            line.push('\n');
+            line.into_bytes()
+        }
+        EnergyFormat::Binary => {
+            let mut buf = ENERGY_MAGIC.to_vec();
+            buf.extend_from_slice(&ENERGY_VERSION.to_le_bytes());
+            buf.extend_from_slice(&(columns.len() as u32).to_le_bytes());
+            for c in columns {
+                buf.push(c.name().len() as u8);
+                buf.extend_from_slice(c.name().as_bytes());
+            }
+            buf
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyFrame {
+    pub step: i32,
+    pub time: Real,
+    pub values: Vec<Real>,
+}
+
+// Contents of an energy file written by `EnergyWriter`, in either format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyFile {
+    pub columns: Vec<Observable>,
+    pub frames: Vec<EnergyFrame>,
+}
+
+impl EnergyFile {
+    pub fn read(filename: &str) -> EnergyFile {
+        let mut data = Vec::new();
+        File::open(filename)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        if data.starts_with(ENERGY_MAGIC) {
+            Self::parse_binary(&data)
+        } else {
+            Self::parse_csv(BufReader::new(data.as_slice()))
+        }
+    }
+
+    pub fn parse_csv<R: BufRead>(reader: R) -> EnergyFile {
+        let mut lines = reader.lines().map_while(Result::ok);
+        let header = lines.next().expect("Empty energy file");
+        let names = header.trim().split(',').collect::<Vec<&str>>();
+        if names.len() < 2 || names[0] != "step" || names[1] != "time" {
+            panic!("Energy file header must start with step,time: {}", header);
+        }
+        let columns = names[2..]
+            .iter()
+            .map(|n| Observable::from_name(n))
+            .collect::<Vec<Observable>>();
+        let frames = lines
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let fields = line.trim().split(',').collect::<Vec<&str>>();
+                if fields.len() != columns.len() + 2 {
+                    panic!("Wrong number of columns in energy file line: {}", line);
+                }
+                let value = |s: &str| {
+                    s.parse::<Real>()
+                        .unwrap_or_else(|_| panic!("Invalid number in energy file: {}", s))
+                };
+                EnergyFrame {
+                    step: fields[0]
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid step in energy file: {}", fields[0])),
+                    time: value(fields[1]),
+                    values: fields[2..].iter().map(|s| value(s)).collect(),
+                }
+            })
+            .collect();
+        EnergyFile { columns, frames }
+    }
+
+    pub fn parse_binary(data: &[u8]) -> EnergyFile {
+        let mut pos = ENERGY_MAGIC.len();
+        let mut take = |n: usize| {
+            if pos + n > data.len() {
+                panic!("Unexpected end of energy file");
+            }
+            pos += n;
+            &data[pos - n..pos]
+        };
+        let word = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+        let version = word(take(4));
+        if version != ENERGY_VERSION {
+            panic!("Unsupported energy file version {}", version);
+        }
+        let ncols = word(take(4)) as usize;
+        let columns = (0..ncols)
+            .map(|_| {
+                let len = take(1)[0] as usize;
+                Observable::from_name(&String::from_utf8_lossy(take(len)))
+            })
+            .collect::<Vec<Observable>>();
+        let header = pos;
+        let frame_size = 4 + 8 * (1 + ncols);
+        if !(data.len() - header).is_multiple_of(frame_size) {
+            panic!("Truncated energy file");
+        }
+        let frames = data[header..]
+            .chunks(frame_size)
+            .map(|chunk| {
+                let f = |i: usize| {
+                    let at = 4 + 8 * i;
+                    f64::from_le_bytes(chunk[at..at + 8].try_into().unwrap()) as Real
+                };
+                EnergyFrame {
+                    step: i32::from_le_bytes(chunk[..4].try_into().unwrap()),
+                    time: f(0),
+                    values: (0..ncols).map(|i| f(1 + i)).collect(),
+                }
+            })
+            .collect();
+        EnergyFile { columns, frames }
+    }
+
+    // Time series of one observable.
+    pub fn column(&self, observable: Observable) -> Vec<Real> {
+        let i = self
+            .columns
+            .iter()
+            .position(|&c| c == observable)
+            .unwrap_or_else(|| panic!("Energy file has no {} column", observable.name()));
+        self.frames.iter().map(|f| f.values[i]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::verlet::VelocityVerlet;
+    use crate::topology::Topology;
+
+    #[test]
+    fn it_writes_and_reads_energies() {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("tri".to_string(), 1, 3);
+        for name in ["c1", "c2", "c3"] {
+            top.add_atom(0, "c", name, 1, "TRI", 0.0);
+        }
+        top.add_bonded_interaction(0, "bond_harm 1 2 0.15 250000.0");
+        top.add_bonded_interaction(0, "bond_harm 2 3 0.15 250000.0");
+        top.add_bonded_interaction(0, "angle_harm 1 2 3 109.5 500.0");
+        let ffield = Forces::new(&top);
+        let masses = vec![12.0; 3];
+        let coords = vec![[0.0, 0.0, 0.0], [0.16, 0.01, 0.0], [0.2, 0.15, 0.02]];
+        let velocities = vec![[0.1, 0.0, 0.0], [0.0, -0.2, 0.0], [0.0, 0.0, 0.3]];
+        let simbox = SimBox::rectangular(2.0, 2.0, 2.0);
+        let e = Energies::compute(&ffield, &coords, &velocities, &masses, 6, Some(&simbox));
+
+        let mut forces = vec![[0.0; DIM]; 3];
+        assert!((e.potential() - ffield.calc(&coords, &mut forces)).abs() < 1e-6);
+        assert!((e.kinetic - 0.84).abs() < 1e-5);
+        assert!((e.get(Observable::Temperature) - 2.0 * 0.84 / (6.0 * BOLTZ)).abs() < 1e-3);
+        assert_eq!(e.get(Observable::Volume), 8.0);
+        assert!((e.get(Observable::Density) - 36.0 / 8.0 * AMU_DENSITY).abs() < 1e-5);
+        // Internal forces only: the virial does not depend on the origin.
+        let shifted = coords
+            .iter()
+            .map(|r| [r[0] + 1.0, r[1], r[2]])
+            .collect::<Vec<_>>();
+        let e2 = Energies::compute(&ffield, &shifted, &velocities, &masses, 6, Some(&simbox));
+        assert!((e2.pressure - e.pressure).abs() < 1e-2 * e.pressure.abs().max(1.0));
+
+        // The terms and forces of an integrator step give the same energies.
+        let mut vv = VelocityVerlet::new(0.0005, 3);
+        let (mut x, mut v) = (coords.clone(), velocities.clone());
+        vv.step(&ffield, &mut x, &mut forces, &mut v, &masses);
+        let from_step = Energies::new(vv.terms, &x, &forces, &v, &masses, 6, Some(&simbox));
+        let computed = Energies::compute(&ffield, &x, &v, &masses, 6, Some(&simbox));
+        assert_eq!(from_step, computed);
+
+        let columns = vec![
+            Observable::Potential,
+            Observable::Total,
+            Observable::Pressure,
+        ];
+        for ext in ["csv", "ene"] {
+            let filename = &crate::temp_path(&format!("energies.{}", ext));
+            let mut writer = EnergyWriter::new(filename, columns.clone(), 2);
+            for step in (0..5).step_by(2) {
+                writer.write(step, 0.002 * step as Real, &e);
+            }
+            writer.flush();
+            let file = EnergyFile::read(filename);
+            assert_eq!(file.columns, columns);
+            assert_eq!(file.frames.len(), 3);
+            assert_eq!(file.frames[2].step, 4);
+            assert_eq!(file.column(Observable::Total)[1], e.get(Observable::Total));
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_continues_energy_files_after_a_checkpoint() {
+        let columns = vec![Observable::Potential, Observable::Kinetic];
+        let mut e = Energies::new([0.0; NGROUPS], &[], &[], &[], &[], 0, None);
+        for ext in ["csv", "ene"] {
+            let filename = &crate::temp_path(&format!("restart.{}", ext));
+            // The first run wrote up to step 8 and stopped in the middle of a
+            // row, with a checkpoint at step 4.
+            let mut writer = EnergyWriter::new(filename, columns.clone(), 2);
+            for step in (0..=8).step_by(2) {
+                e.kinetic = step as Real;
+                writer.write(step, 0.002 * step as Real, &e);
+            }
+            writer.file.write_all(b"10,0").unwrap();
+            writer.flush();
+
+            let mut writer = EnergyWriter::append(filename, columns.clone(), 2, 4);
+            for step in (6..=10).step_by(2) {
+                e.kinetic = 100.0 + step as Real;
+                writer.write(step, 0.002 * step as Real, &e);
+            }
+            writer.flush();
+            let file = EnergyFile::read(filename);
+            let steps = file.frames.iter().map(|f| f.step).collect::<Vec<i32>>();
+            assert_eq!(steps, [0, 2, 4, 6, 8, 10], "{}", ext);
+            assert_eq!(
+                file.column(Observable::Kinetic),
+                [0.0, 2.0, 4.0, 106.0, 108.0, 110.0]
+            );
+
+            let other = vec![Observable::Potential];
+            assert!(
+                std::panic::catch_unwind(|| EnergyWriter::append(filename, other, 2, 4)).is_err()
+            );
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+}
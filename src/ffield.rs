@@ -1,310 +1,692 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
 use crate::linalg::DEG2RAD;
-use std::ops::Index;
+use crate::simbox::SimBox;
 
 // use crate::topology::atom::Atom;
 use crate::{
-    topology::{atom::Atom, molecule::Molecule, Topology},
-    Rvec, DIM,
+    to_f64,
+    topology::{molecule::Molecule, Topology},
+    Real, Rvec, DIM,
 };
-// use rayon::prelude::*;
+use accumulate::{BlockBuffer, FixedForces, ForceAccumulator, Offset};
+use interactions::InteractionList;
+use nonbonded::{ClusterPairList, Nonbonded, SlotForces, SlotPositions};
+
+pub mod accumulate;
+pub mod finite_diff;
+pub mod functions;
+pub mod interactions;
+pub mod nonbonded;
+
+// The interactions of the topology are stored by kind, in the order of
+// their parameters below. Interactions of other kinds can be added to the
+// `custom_*` lists of their force group; custom pairs are not scaled by
+// ljscale. The nonbonded interactions within a cutoff are off until set
+// with `set_nonbonded`.
+#[derive(Clone)]
+pub struct Forces {
+    // k, r0
+    pub bonds: InteractionList<2, 2>,
+    // k, t0
+    pub angles: InteractionList<3, 2>,
+    // k, n, p0
+    pub dihedrals: InteractionList<4, 3>,
+    // k, p0
+    pub impropers: InteractionList<4, 2>,
+    // sigma, epsilon
+    pub pairs: InteractionList<2, 2>,
+    pub custom_bonds: Vec<Arc<dyn TwoAtomInteraction + Send + Sync>>,
+    pub custom_angles: Vec<Arc<dyn ThreeAtomInteraction + Send + Sync>>,
+    pub custom_torsions: Vec<Arc<dyn FourAtomInteraction + Send + Sync>>,
+    pub custom_pairs: Vec<Arc<dyn TwoAtomInteraction + Send + Sync>>,
+    comb_rule: functions::CombRule,
+    #[allow(dead_code)]
+    qqscale: Real,
+    ljscale: Real,
+    // Sums the forces in fixed point, for results that do not depend on the
+    // order of the interactions.
+    pub fixed_point: bool,
+    // Threads of the parallel evaluation, serial without.
+    pool: Option<Arc<ThreadPool>>,
+    buffers: Buffers,
+    nonbonded: Option<NonbondedGroup>,
+}
 
-mod functions;
+// The nonbonded interactions, the box and the list cutoff of their cluster
+// pair list, which `calc_group` rebuilds once it is outdated.
+#[derive(Clone)]
+struct NonbondedGroup {
+    nb: Arc<Nonbonded>,
+    simbox: Option<SimBox>,
+    rlist: Real,
+    list: PairList,
+}
 
-pub struct Forces {
-    pub bonds: Vec<Box<dyn TwoAtomInteraction + Send + Sync>>,
-    pub angles: Vec<Box<dyn ThreeAtomInteraction + Send + Sync>>,
-    pub torsions: Vec<Box<dyn FourAtomInteraction + Send + Sync>>,
-    pub pairs: Vec<Box<dyn TwoAtomInteraction + Send + Sync>>,
-    comb_rule: fn(f32, f32, f32, f32) -> (f32, f32),
-    qqscale: f32,
-    ljscale: f32,
+// The last cluster pair list, shared by the calls that find it up to date.
+// Clones build their own.
+#[derive(Default)]
+struct PairList(Mutex<Option<Arc<ClusterPairList>>>);
+
+impl Clone for PairList {
+    fn clone(&self) -> Self {
+        PairList::default()
+    }
+}
+
+// Forces of the blocks and their fixed-point sum, kept between calls of
+// `calc_group`. Each call takes a set out of the pool and puts it back when
+// done, so that concurrent calls do not wait for each other. Clones start
+// with their own.
+#[derive(Default)]
+struct Buffers(Mutex<Vec<BlockBuffers>>);
+
+#[derive(Default)]
+struct BlockBuffers {
+    float: Vec<Vec<Rvec>>,
+    fixed: Vec<FixedForces>,
+    slots: Vec<SlotForces>,
+    sum: FixedForces,
+}
+
+impl Clone for Buffers {
+    fn clone(&self) -> Self {
+        Buffers::default()
+    }
+}
+
+impl Buffers {
+    fn take(&self) -> BlockBuffers {
+        self.0.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    fn put(&self, buffers: BlockBuffers) {
+        self.0.lock().unwrap().push(buffers);
+    }
 }
 
+// Interactions per block of the parallel evaluation. The blocks do not
+// depend on the number of threads, and neither do the forces.
+pub const BLOCK: usize = 256;
+
+// The cluster pairs of the nonbonded interactions are split into at most
+// this many blocks of at least BLOCK pairs, whatever the number of threads.
+// Each block sums into forces over all the atoms, so more blocks would cost
+// more to add up than they save.
+const NONBONDED_BLOCKS: usize = 64;
+
 impl Forces {
     #[allow(clippy::unnecessary_operation)]
     pub fn new(top: &Topology) -> Forces {
         let mut ff = Forces {
-            bonds: Vec::new(),
-            angles: Vec::new(),
-            torsions: Vec::new(),
-            pairs: Vec::new(),
-            comb_rule: match top.defaults.comb_rule.as_str() {
-                "geom" => functions::comb_rule_geom,
-                "LB" => functions::comb_rule_LB,
-                wtf => panic!("Unknown combination rule: {}", wtf),
-            },
+            bonds: InteractionList::new(),
+            angles: InteractionList::new(),
+            dihedrals: InteractionList::new(),
+            impropers: InteractionList::new(),
+            pairs: InteractionList::new(),
+            custom_bonds: Vec::new(),
+            custom_angles: Vec::new(),
+            custom_torsions: Vec::new(),
+            custom_pairs: Vec::new(),
+            comb_rule: functions::comb_rule(&top.defaults.comb_rule),
             qqscale: top.defaults.qqscale.unwrap_or(1.0),
             ljscale: top.defaults.ljscale.unwrap_or(1.0),
+            fixed_point: false,
+            pool: None,
+            buffers: Buffers::default(),
+            nonbonded: None,
         };
         ff.build(top);
         ff
     }
 
+    // The interactions of a molecule type are parsed once, for its first
+    // copy, and repeated for the others.
     fn build(&mut self, top: &Topology) {
         let mut off = 0;
-        for mol in &top.molecules {
+        for mol in top.molecules() {
+            if mol.nmols == 0 {
+                continue;
+            }
             let natoms = mol.atoms.len();
-            for _ in 0..mol.nmols {
-                for interaction in &mol.bonded_interactions {
-                    self.parse_interaction(mol, interaction, off);
-                }
-                off += natoms;
+            let starts = (
+                self.bonds.len(),
+                self.angles.len(),
+                self.dihedrals.len(),
+                self.impropers.len(),
+                self.pairs.len(),
+            );
+            for interaction in &mol.bonded_interactions {
+                self.parse_interaction(mol, interaction, off);
             }
+            let copies = mol.nmols - 1;
+            self.bonds.repeat(starts.0, copies, natoms);
+            self.angles.repeat(starts.1, copies, natoms);
+            self.dihedrals.repeat(starts.2, copies, natoms);
+            self.impropers.repeat(starts.3, copies, natoms);
+            self.pairs.repeat(starts.4, copies, natoms);
+            off += natoms * mol.nmols;
         }
     }
 
     fn parse_interaction(&mut self, mol: &Molecule, interaction: &str, offset: usize) {
-        let fields = interaction.split_whitespace().collect::<Vec<&str>>();
-        let funct = fields[0];
-        let params = &fields[1..];
+        let (funct, local) = check_interaction(interaction, mol.atoms.len())
+            .unwrap_or_else(|e| panic!("Molecule {}: {}: {}", mol.name, interaction, e));
+        let params = &interaction.split_whitespace().collect::<Vec<&str>>()[local.len() + 1..];
+        let param = |i: usize| params[i].parse::<Real>().unwrap();
+        let atoms = local.iter().map(|i| i + offset).collect::<Vec<usize>>();
+        let [i, j] = [atoms[0], atoms[1]];
         match funct {
             "bond_harm" => {
-                let mut atoms: [usize; 2] = [0, 0];
-                params[..2]
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| atoms[i] = x.parse::<usize>().unwrap() + offset - 1);
-                let r0 = params[2].parse::<f32>().unwrap();
-                let k = params[3].parse::<f32>().unwrap();
-                self.bonds.push(BondHarmonic::new(k, r0, atoms));
+                let (r0, k) = (param(0), param(1));
+                self.bonds.push([i, j], [k, r0]);
             }
 
             "angle_harm" => {
-                let mut atoms: [usize; 3] = [0, 0, 0];
-                params[..3]
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| atoms[i] = x.parse::<usize>().unwrap() + offset - 1);
-                let t0 = params[3].parse::<f32>().unwrap() * DEG2RAD;
-                let k = params[4].parse::<f32>().unwrap();
-                self.angles.push(AngleHarmonic::new(k, t0, atoms));
+                let t0 = param(0) * DEG2RAD;
+                let k = param(1);
+                self.angles.push([i, j, atoms[2]], [k, t0]);
             }
 
             "pdih" => {
-                let mut atoms: [usize; 4] = [0, 0, 0, 0];
-                params[..4]
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| atoms[i] = x.parse::<usize>().unwrap() + offset - 1);
-                let p0 = params[4].parse::<f32>().unwrap() * DEG2RAD;
-                let k = params[5].parse::<f32>().unwrap();
-                let n = params[6].parse::<f32>().unwrap();
-                self.torsions.push(DihedralPeriodic::new(k, n, p0, atoms));
+                let p0 = param(0) * DEG2RAD;
+                let (k, n) = (param(1), param(2));
+                self.dihedrals.push([i, j, atoms[2], atoms[3]], [k, n, p0]);
             }
 
             "idih_harm" => {
-                let mut atoms: [usize; 4] = [0, 0, 0, 0];
-                params[..4]
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| atoms[i] = x.parse::<usize>().unwrap() + offset - 1);
-                let p0 = params[4].parse::<f32>().unwrap() * DEG2RAD;
-                let k = params[5].parse::<f32>().unwrap();
-                self.torsions
-                    .push(ImproperDihedralHarmonic::new(k, p0, atoms));
+                let p0 = param(0) * DEG2RAD;
+                let k = param(1);
+                self.impropers.push([i, j, atoms[2], atoms[3]], [k, p0]);
             }
             "lj_pair" => {
-                let mut atoms: [usize; 2] = [0, 0];
-                params[..2]
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| atoms[i] = x.parse::<usize>().unwrap() + offset - 1);
-                let v = params.get(2);
-                let w = params.get(3);
-
-                if let (Some(v), Some(w)) = (v, w) {
-                    self.pairs.push(BondedLJPair::new(
-                        v.parse().unwrap(),
-                        w.parse().unwrap(),
-                        atoms,
-                    ));
+                let (v, w) = if params.len() == 2 {
+                    (param(0), param(1))
                 } else {
-                    let [mut ai, mut aj] = atoms;
-                    ai -= offset;
-                    aj -= offset;
-                    let (vcomb, wcomb) = (self.comb_rule)(
-                        mol.atoms[ai].v,
-                        mol.atoms[ai].w,
-                        mol.atoms[aj].v,
-                        mol.atoms[aj].w,
-                    );
-                    self.pairs.push(BondedLJPair::new(vcomb, wcomb, atoms));
-                }
+                    let [ai, aj] = [&mol.atoms[local[0]], &mol.atoms[local[1]]];
+                    (self.comb_rule)(ai.v, ai.w, aj.v, aj.w)
+                };
+                self.pairs.push([i, j], [v, w]);
             }
-            wtf => panic!("Unknown interaction: {}", wtf),
+            _ => unreachable!(),
         }
     }
 
-    pub fn calc(&self, positions: &[Rvec], forces: &mut [Rvec]) -> f32 {
-        let mut tot_u = 0.0;
+    // Adds the nonbonded interactions of `nb` as the Nonbonded group, listed
+    // out to `rlist` in `simbox` (without periodic images with None).
+    pub fn set_nonbonded(&mut self, nb: Nonbonded, simbox: Option<SimBox>, rlist: Real) {
+        self.nonbonded = Some(NonbondedGroup {
+            nb: Arc::new(nb),
+            simbox,
+            rlist,
+            list: PairList::default(),
+        });
+    }
 
-        self.bonds.iter().for_each(|bond| {
-            let [i, j] = bond.atoms();
-            let (u, f) = bond.calc(&positions[i], &positions[j]);
+    // Number of interactions in a group, none for the Nonbonded group whose
+    // pairs depend on the positions.
+    pub fn count(&self, group: ForceGroup) -> usize {
+        self.lists(group).iter().sum()
+    }
 
-            tot_u += u;
-            for d in 0..DIM {
-                forces[i][d] += f[0][d];
-                forces[j][d] += f[1][d];
-            }
+    // Lengths of the lists of a group, in the order its interactions are
+    // numbered.
+    fn lists(&self, group: ForceGroup) -> [usize; 3] {
+        match group {
+            ForceGroup::Bonds => [self.bonds.len(), self.custom_bonds.len(), 0],
+            ForceGroup::Angles => [self.angles.len(), self.custom_angles.len(), 0],
+            ForceGroup::Torsions => [
+                self.dihedrals.len(),
+                self.impropers.len(),
+                self.custom_torsions.len(),
+            ],
+            ForceGroup::Pairs => [self.pairs.len(), self.custom_pairs.len(), 0],
+            ForceGroup::Nonbonded => [0; 3],
+        }
+    }
+
+    pub fn calc(&self, positions: &[Rvec], forces: &mut [Rvec]) -> Real {
+        self.calc_terms(positions, forces).iter().sum()
+    }
+
+    // Energies of all the groups, in the order of `ForceGroup::ALL`.
+    pub fn calc_terms(&self, positions: &[Rvec], forces: &mut [Rvec]) -> [Real; NGROUPS] {
+        ForceGroup::ALL.map(|group| self.calc_group(group, positions, forces))
+    }
+
+    // Evaluates the blocks of interactions on `nthreads` threads (all the
+    // cores with 0), or in turn in the calling thread with 1. The forces do
+    // not depend on it.
+    pub fn set_threads(&mut self, nthreads: usize) {
+        self.pool = (nthreads != 1).then(|| {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(nthreads)
+                .build()
+                .unwrap_or_else(|e| panic!("Cannot start {} threads: {}", nthreads, e));
+            Arc::new(pool)
         });
+    }
 
-        self.angles.iter().for_each(|angle| {
-            let [i, j, k] = angle.atoms();
-            let (u, f) = angle.calc(&positions[i], &positions[j], &positions[k]);
+    pub fn nthreads(&self) -> usize {
+        self.pool
+            .as_ref()
+            .map_or(1, |pool| pool.current_num_threads())
+    }
 
-            tot_u += u;
-            for d in 0..DIM {
-                forces[i][d] += f[0][d];
-                forces[j][d] += f[1][d];
-                forces[k][d] += f[2][d];
+    // Accumulates the forces of a single group of interactions and returns
+    // their potential energy, summed in double precision. The blocks are
+    // added up in order, whichever thread evaluated them.
+    pub fn calc_group(&self, group: ForceGroup, positions: &[Rvec], forces: &mut [Rvec]) -> Real {
+        if group == ForceGroup::Nonbonded {
+            return self.calc_nonbonded(positions, forces);
+        }
+        let mut buffers = self.buffers.take();
+        let BlockBuffers {
+            float, fixed, sum, ..
+        } = &mut buffers;
+        let mut u = 0.0;
+        if self.fixed_point {
+            let blocks = self.blocks(group, positions, fixed);
+            sum.reset(forces.len());
+            for ((ub, start), block) in blocks.into_iter().zip(fixed.iter()) {
+                u += ub;
+                sum.merge(start, block);
             }
-        });
-        self.torsions.iter().for_each(|torsion| {
-            let [i, j, k, l] = torsion.atoms();
-            let (u, f) = torsion.calc(&positions[i], &positions[j], &positions[k], &positions[l]);
-
-            tot_u += u;
-            for d in 0..DIM {
-                forces[i][d] += f[0][d];
-                forces[j][d] += f[1][d];
-                forces[k][d] += f[2][d];
-                forces[l][d] += f[3][d];
+            sum.add_to(forces);
+        } else {
+            let blocks = self.blocks(group, positions, float);
+            for ((ub, start), block) in blocks.into_iter().zip(float.iter()) {
+                u += ub;
+                for (f, g) in forces[start..].iter_mut().zip(block.iter()) {
+                    for d in 0..DIM {
+                        f[d] += g[d];
+                    }
+                }
             }
-        });
-        self.pairs.iter().for_each(|pair| {
-            let [i, j] = pair.atoms();
-            let (u, f) = pair.calc(&positions[i], &positions[j]);
-
-            tot_u += u * self.ljscale;
-            for d in 0..DIM {
-                forces[i][d] += f[0][d] * self.ljscale;
-                forces[j][d] += f[1][d] * self.ljscale;
+        }
+        self.buffers.put(buffers);
+        u as Real
+    }
+
+    // The nonbonded interactions, with the list rebuilt first when the atoms
+    // have moved too far since it was built, evaluated by blocks of cluster
+    // pairs like the other groups. Zero when they are off.
+    fn calc_nonbonded(&self, positions: &[Rvec], forces: &mut [Rvec]) -> Real {
+        let Some(group) = &self.nonbonded else {
+            return 0.0;
+        };
+        let list = {
+            let mut list = group.list.0.lock().unwrap();
+            if list.as_ref().is_none_or(|l| l.is_outdated(positions)) {
+                let simbox = group.simbox.as_ref();
+                *list = Some(Arc::new(ClusterPairList::new(
+                    &group.nb,
+                    positions,
+                    simbox,
+                    group.rlist,
+                )));
             }
+            list.clone().unwrap()
+        };
+        let x = SlotPositions::new(&list, positions);
+        let n = list.pairs.len();
+        let size = n.div_ceil(NONBONDED_BLOCKS).max(BLOCK);
+        let blocks = (0..n)
+            .step_by(size)
+            .map(|start| start..(start + size).min(n))
+            .collect::<Vec<Range<usize>>>();
+        let mut buffers = self.buffers.take();
+        let BlockBuffers { slots, sum, .. } = &mut buffers;
+        slots.resize_with(blocks.len(), SlotForces::default);
+        let energies = self.run_blocks(blocks, slots, |(pairs, f)| {
+            f.reset(list.slots.len());
+            let (ulj, uc) = group.nb.calc_pairs(&list, pairs, &x, f);
+            ulj + uc
         });
-        tot_u
+        if self.fixed_point {
+            sum.reset(forces.len());
+            slots.iter().for_each(|f| f.add_to(&list, sum));
+            sum.add_to(forces);
+        } else {
+            slots.iter().for_each(|f| f.add_to(&list, forces));
+        }
+        self.buffers.put(buffers);
+        energies.iter().sum::<f64>() as Real
     }
-}
 
-pub trait TwoAtomInteraction {
-    fn calc(&self, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]);
-    fn atoms(&self) -> [usize; 2];
-}
-
-pub trait ThreeAtomInteraction {
-    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]);
-    fn atoms(&self) -> [usize; 3];
-}
-
-pub trait FourAtomInteraction {
-    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (f32, [Rvec; 4]);
-    fn atoms(&self) -> [usize; 4];
-}
-
-pub struct BondHarmonic {
-    k: f32,
-    r0: f32,
-    atoms: [usize; 2],
-}
+    // Evaluates the blocks of a group, in parallel with a thread pool, each
+    // into its own buffer over the atoms it touches. Returns the energy and
+    // first atom of every block.
+    fn blocks<A: BlockBuffer>(
+        &self,
+        group: ForceGroup,
+        positions: &[Rvec],
+        buffers: &mut Vec<A>,
+    ) -> Vec<(f64, usize)> {
+        let n = self.count(group);
+        let blocks = (0..n)
+            .step_by(BLOCK)
+            .map(|start| start..(start + BLOCK).min(n))
+            .collect::<Vec<Range<usize>>>();
+        buffers.resize_with(blocks.len(), A::default);
+        self.run_blocks(blocks, buffers, |(interactions, buffer)| {
+            let atoms = self.atom_range(group, interactions.clone());
+            buffer.reset(atoms.len());
+            let mut block = Offset::new(atoms.start, buffer);
+            let u = self.accumulate(group, interactions, positions, &mut block);
+            (u, atoms.start)
+        })
+    }
 
-impl BondHarmonic {
-    pub fn new(k: f32, r0: f32, atoms: [usize; 2]) -> Box<BondHarmonic> {
-        Box::new(BondHarmonic { k, r0, atoms })
+    // Runs `run` on every block with its buffer, on the threads of the pool
+    // when there is one, and returns the results in the order of the blocks.
+    fn run_blocks<B, T, F>(&self, blocks: Vec<Range<usize>>, buffers: &mut [B], run: F) -> Vec<T>
+    where
+        B: Send,
+        T: Send,
+        F: Fn((Range<usize>, &mut B)) -> T + Sync + Send,
+    {
+        match &self.pool {
+            Some(pool) => pool.install(|| {
+                blocks
+                    .into_par_iter()
+                    .zip(buffers.par_iter_mut())
+                    .map(run)
+                    .collect()
+            }),
+            None => blocks
+                .into_iter()
+                .zip(buffers.iter_mut())
+                .map(run)
+                .collect(),
+        }
     }
-}
 
-impl TwoAtomInteraction for BondHarmonic {
-    fn calc(&self, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
-        functions::bond_harm(self.k, self.r0, ri, rj)
+    // Smallest range of atoms holding the atoms of some interactions.
+    fn atom_range(&self, group: ForceGroup, interactions: Range<usize>) -> Range<usize> {
+        let (mut lo, mut hi) = (usize::MAX, 0);
+        let mut bound = |atoms: &[usize]| {
+            for &i in atoms {
+                (lo, hi) = (lo.min(i), hi.max(i + 1));
+            }
+        };
+        for (list, r) in split(interactions, self.lists(group)) {
+            match (group, list) {
+                (ForceGroup::Bonds, 0) => self.bonds.atoms[r].iter().for_each(|a| bound(a)),
+                (ForceGroup::Bonds, _) => {
+                    self.custom_bonds[r].iter().for_each(|x| bound(&x.atoms()))
+                }
+                (ForceGroup::Angles, 0) => self.angles.atoms[r].iter().for_each(|a| bound(a)),
+                (ForceGroup::Angles, _) => {
+                    self.custom_angles[r].iter().for_each(|x| bound(&x.atoms()))
+                }
+                (ForceGroup::Torsions, 0) => self.dihedrals.atoms[r].iter().for_each(|a| bound(a)),
+                (ForceGroup::Torsions, 1) => self.impropers.atoms[r].iter().for_each(|a| bound(a)),
+                (ForceGroup::Torsions, _) => self.custom_torsions[r]
+                    .iter()
+                    .for_each(|x| bound(&x.atoms())),
+                (ForceGroup::Pairs, 0) => self.pairs.atoms[r].iter().for_each(|a| bound(a)),
+                (ForceGroup::Pairs, _) => {
+                    self.custom_pairs[r].iter().for_each(|x| bound(&x.atoms()))
+                }
+                (ForceGroup::Nonbonded, _) => unreachable!(),
+            }
+        }
+        lo.min(hi)..hi
     }
-    fn atoms(&self) -> [usize; 2] {
-        self.atoms
+
+    // Accumulates the forces of a range of interactions of a group and
+    // returns their potential energy.
+    pub fn accumulate<A>(
+        &self,
+        group: ForceGroup,
+        interactions: Range<usize>,
+        positions: &[Rvec],
+        forces: &mut A,
+    ) -> f64
+    where
+        A: ForceAccumulator + ?Sized,
+    {
+        let mut tot_u = 0.0;
+        for (list, r) in split(interactions, self.lists(group)) {
+            tot_u += match (group, list) {
+                (ForceGroup::Bonds, 0) => self.bonds.accumulate(
+                    r,
+                    positions,
+                    |p, r| functions::bond_harm(p[0], p[1], &r[0], &r[1]),
+                    1.0,
+                    forces,
+                ),
+                (ForceGroup::Bonds, _) => accumulate_two(&self.custom_bonds[r], positions, forces),
+                (ForceGroup::Angles, 0) => self.angles.accumulate(
+                    r,
+                    positions,
+                    |p, r| functions::angle_harm(p[0], p[1], &r[0], &r[1], &r[2]),
+                    1.0,
+                    forces,
+                ),
+                (ForceGroup::Angles, _) => {
+                    accumulate_three(&self.custom_angles[r], positions, forces)
+                }
+                (ForceGroup::Torsions, 0) => self.dihedrals.accumulate(
+                    r,
+                    positions,
+                    |p, r| functions::pdih(p[0], p[1], p[2], &r[0], &r[1], &r[2], &r[3]),
+                    1.0,
+                    forces,
+                ),
+                (ForceGroup::Torsions, 1) => self.impropers.accumulate(
+                    r,
+                    positions,
+                    |p, r| functions::idih_harm(p[0], p[1], &r[0], &r[1], &r[2], &r[3]),
+                    1.0,
+                    forces,
+                ),
+                (ForceGroup::Torsions, _) => {
+                    accumulate_four(&self.custom_torsions[r], positions, forces)
+                }
+                (ForceGroup::Pairs, 0) => self.pairs.accumulate(
+                    r,
+                    positions,
+                    |p, r| functions::lj_sigma_epsilon(p[0], p[1], &r[0], &r[1]),
+                    self.ljscale,
+                    forces,
+                ),
+                (ForceGroup::Pairs, _) => accumulate_two(&self.custom_pairs[r], positions, forces),
+                (ForceGroup::Nonbonded, _) => unreachable!(),
+            };
+        }
+        tot_u
     }
 }
 
-pub struct BondedLJPair {
-    v: f32,
-    w: f32,
-    atoms: [usize; 2],
+// Splits a range of the interactions of a group into ranges of its lists of
+// lengths `lists`, as (list, range within the list).
+fn split(
+    interactions: Range<usize>,
+    lists: [usize; 3],
+) -> impl Iterator<Item = (usize, Range<usize>)> {
+    let mut start = 0;
+    lists.into_iter().enumerate().filter_map(move |(list, n)| {
+        let lo = interactions.start.max(start) - start;
+        let hi = interactions.end.min(start + n).saturating_sub(start);
+        start += n;
+        (lo < hi).then_some((list, lo..hi))
+    })
 }
 
-impl BondedLJPair {
-    pub fn new(v: f32, w: f32, atoms: [usize; 2]) -> Box<BondedLJPair> {
-        Box::new(BondedLJPair { v, w, atoms })
+fn accumulate_two<A>(
+    interactions: &[Arc<dyn TwoAtomInteraction + Send + Sync>],
+    positions: &[Rvec],
+    forces: &mut A,
+) -> f64
+where
+    A: ForceAccumulator + ?Sized,
+{
+    let mut tot_u = 0.0;
+    for interaction in interactions {
+        let [i, j] = interaction.atoms();
+        let (u, f) = interaction.calc(&positions[i], &positions[j]);
+
+        tot_u += to_f64(u);
+        forces.add(i, &f[0], 1.0);
+        forces.add(j, &f[1], 1.0);
     }
+    tot_u
 }
 
-impl TwoAtomInteraction for BondedLJPair {
-    fn calc(&self, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
-        functions::lj(self.v, self.w, ri, rj)
-    }
-    fn atoms(&self) -> [usize; 2] {
-        self.atoms
+fn accumulate_three<A>(
+    interactions: &[Arc<dyn ThreeAtomInteraction + Send + Sync>],
+    positions: &[Rvec],
+    forces: &mut A,
+) -> f64
+where
+    A: ForceAccumulator + ?Sized,
+{
+    let mut tot_u = 0.0;
+    for interaction in interactions {
+        let [i, j, k] = interaction.atoms();
+        let (u, f) = interaction.calc(&positions[i], &positions[j], &positions[k]);
+
+        tot_u += to_f64(u);
+        forces.add(i, &f[0], 1.0);
+        forces.add(j, &f[1], 1.0);
+        forces.add(k, &f[2], 1.0);
     }
+    tot_u
 }
 
-pub struct AngleHarmonic {
-    k: f32,
-    t0: f32,
-    atoms: [usize; 3],
-}
-
-impl AngleHarmonic {
-    pub fn new(k: f32, t0: f32, atoms: [usize; 3]) -> Box<AngleHarmonic> {
-        Box::new(AngleHarmonic { k, t0, atoms })
+fn accumulate_four<A>(
+    interactions: &[Arc<dyn FourAtomInteraction + Send + Sync>],
+    positions: &[Rvec],
+    forces: &mut A,
+) -> f64
+where
+    A: ForceAccumulator + ?Sized,
+{
+    let mut tot_u = 0.0;
+    for interaction in interactions {
+        let [i, j, k, l] = interaction.atoms();
+        let (u, f) = interaction.calc(&positions[i], &positions[j], &positions[k], &positions[l]);
+
+        tot_u += to_f64(u);
+        forces.add(i, &f[0], 1.0);
+        forces.add(j, &f[1], 1.0);
+        forces.add(k, &f[2], 1.0);
+        forces.add(l, &f[3], 1.0);
     }
+    tot_u
 }
 
-impl ThreeAtomInteraction for AngleHarmonic {
-    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]) {
-        functions::angle_harm(self.k, self.t0, ri, rj, rk)
-    }
-    fn atoms(&self) -> [usize; 3] {
-        self.atoms
-    }
+// Interactions that are evaluated together, e.g. by multiple time step integrators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ForceGroup {
+    Bonds,
+    Angles,
+    Torsions,
+    Pairs,
+    Nonbonded,
 }
 
-pub struct DihedralPeriodic {
-    k: f32,
-    n: f32,
-    p0: f32,
-    atoms: [usize; 4],
-}
+pub const NGROUPS: usize = 5;
 
-impl DihedralPeriodic {
-    pub fn new(k: f32, n: f32, p0: f32, atoms: [usize; 4]) -> Box<DihedralPeriodic> {
-        Box::new(DihedralPeriodic { k, n, p0, atoms })
+impl ForceGroup {
+    pub const ALL: [ForceGroup; NGROUPS] = [
+        ForceGroup::Bonds,
+        ForceGroup::Angles,
+        ForceGroup::Torsions,
+        ForceGroup::Pairs,
+        ForceGroup::Nonbonded,
+    ];
+
+    // Position in `ForceGroup::ALL`.
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForceGroup::Bonds => "bonds",
+            ForceGroup::Angles => "angles",
+            ForceGroup::Torsions => "torsions",
+            ForceGroup::Pairs => "pairs",
+            ForceGroup::Nonbonded => "nonbonded",
+        }
     }
 }
 
-impl FourAtomInteraction for DihedralPeriodic {
-    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (f32, [Rvec; 4]) {
-        functions::pdih(self.k, self.n, self.p0, ri, rj, rk, rl)
+// Bonded interactions: name, number of atoms and the numbers of parameters
+// they can take (LJ pairs take their parameters from the atom types when
+// they are not given).
+pub const INTERACTIONS: [(&str, usize, &[usize]); 5] = [
+    ("bond_harm", 2, &[2]),
+    ("angle_harm", 3, &[2]),
+    ("pdih", 4, &[3]),
+    ("idih_harm", 4, &[2]),
+    ("lj_pair", 2, &[0, 2]),
+];
+
+// Checks an interaction line of a molecule of `natoms` atoms and returns its
+// name and its atoms, as 0-based indices within the molecule.
+pub fn check_interaction(interaction: &str, natoms: usize) -> Result<(&str, Vec<usize>), String> {
+    let fields = interaction.split_whitespace().collect::<Vec<&str>>();
+    let Some(&funct) = fields.first() else {
+        return Err("empty interaction".to_string());
+    };
+    let Some(&(_, n, nparams)) = INTERACTIONS.iter().find(|(name, _, _)| *name == funct) else {
+        return Err(format!("unknown interaction {}", funct));
+    };
+    if fields.len() <= n || !nparams.contains(&(fields.len() - 1 - n)) {
+        let nparams = nparams
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<String>>();
+        return Err(format!(
+            "{} takes {} atoms and {} parameters",
+            funct,
+            n,
+            nparams.join(" or ")
+        ));
     }
-    fn atoms(&self) -> [usize; 4] {
-        self.atoms
+    let mut atoms = Vec::new();
+    for field in &fields[1..=n] {
+        match field.parse::<usize>() {
+            Ok(i) if i >= 1 && i <= natoms => atoms.push(i - 1),
+            Ok(i) => {
+                return Err(format!(
+                    "atom {} is out of range, the molecule has {} atoms",
+                    i, natoms
+                ))
+            }
+            Err(_) => return Err(format!("invalid atom number {}", field)),
+        }
+    }
+    if let Some(field) = fields[n + 1..].iter().find(|x| x.parse::<Real>().is_err()) {
+        return Err(format!("invalid parameter {}", field));
     }
+    if (1..n).any(|i| atoms[..i].contains(&atoms[i])) {
+        return Err("repeated atom".to_string());
+    }
+    Ok((funct, atoms))
 }
 
-pub struct ImproperDihedralHarmonic {
-    k: f32,
-    p0: f32,
-    atoms: [usize; 4],
+pub trait TwoAtomInteraction {
+    fn calc(&self, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]);
+    fn atoms(&self) -> [usize; 2];
 }
 
-impl ImproperDihedralHarmonic {
-    pub fn new(k: f32, p0: f32, atoms: [usize; 4]) -> Box<ImproperDihedralHarmonic> {
-        Box::new(ImproperDihedralHarmonic { k, p0, atoms })
-    }
+pub trait ThreeAtomInteraction {
+    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (Real, [Rvec; 3]);
+    fn atoms(&self) -> [usize; 3];
 }
 
-impl FourAtomInteraction for ImproperDihedralHarmonic {
-    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (f32, [Rvec; 4]) {
-        functions::idih_harm(self.k, self.p0, ri, rj, rk, rl)
-    }
-    fn atoms(&self) -> [usize; 4] {
-        self.atoms
-    }
+pub trait FourAtomInteraction {
+    fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (Real, [Rvec; 4]);
+    fn atoms(&self) -> [usize; 4];
 }
 
 #[cfg(test)]
@@ -336,9 +718,164 @@ mod tests {
         top.add_bonded_interaction(1, "bond_harm 2 3 0 0");
 
         let ff = Forces::new(&top);
-        let [a1, a2] = ff.bonds[ff.bonds.len() - 1].atoms();
+        let [a1, a2] = ff.bonds.atoms[ff.bonds.len() - 1];
         // 3 + 3*10 atoms = 33; (2, 3) is (32, 33), indexed at (31, 32)
         assert_eq!(a1, 31);
         assert_eq!(a2, 32);
     }
+
+    fn diala() -> (Topology, Vec<Rvec>) {
+        let top = Topology::read("tests/diala.top");
+        let frame = crate::trajectory::reader::read_frame("tests/diala.crd");
+        (top, frame.positions)
+    }
+
+    fn evaluate(ff: &Forces, positions: &[Rvec]) -> (Vec<Real>, Vec<Rvec>) {
+        let mut forces = vec![[0.0; DIM]; positions.len()];
+        let u = ForceGroup::ALL
+            .iter()
+            .map(|&group| ff.calc_group(group, positions, &mut forces))
+            .collect();
+        (u, forces)
+    }
+
+    #[test]
+    fn it_does_not_depend_on_the_thread_count() {
+        let (top, positions) = diala();
+        let mut ff = Forces::new(&top);
+        for fixed_point in [false, true] {
+            ff.fixed_point = fixed_point;
+            ff.set_threads(1);
+            let serial = evaluate(&ff, &positions);
+            for nthreads in [2, 3, 4, 7] {
+                ff.set_threads(nthreads);
+                assert_eq!(ff.nthreads(), nthreads);
+                assert_eq!(evaluate(&ff, &positions), serial);
+            }
+        }
+    }
+
+    // The peptide in 300 waters.
+    fn solvated() -> (Topology, Vec<Rvec>, Option<SimBox>) {
+        let (mut top, mut positions) = diala();
+        top.set_nmols(1, 300);
+        positions.truncate(top.hierarchy().natoms());
+        let simbox = crate::trajectory::reader::read_frame("tests/diala.crd").simbox;
+        (top, positions, simbox)
+    }
+
+    #[test]
+    fn it_evaluates_the_nonbonded_group() {
+        let (top, positions, simbox) = solvated();
+        let mut ff = Forces::new(&top);
+        let mut forces = vec![[0.0; DIM]; positions.len()];
+        assert_eq!(
+            ff.calc_group(ForceGroup::Nonbonded, &positions, &mut forces),
+            0.0
+        );
+
+        ff.set_nonbonded(Nonbonded::new(&top, 0.9, 0.0), simbox, 1.0);
+        let nb = Nonbonded::new(&top, 0.9, 0.0);
+        // The second evaluation needs a new list: x and z swapped.
+        let swapped = positions.iter().map(|r| [r[2], r[1], r[0]]).collect();
+        for positions in [positions, swapped] {
+            let mut expected = vec![[0.0; DIM]; positions.len()];
+            let (ulj, uc) = nb.reference(&positions, simbox.as_ref(), &mut expected);
+            let mut forces = vec![[0.0; DIM]; positions.len()];
+            let u = ff.calc_group(ForceGroup::Nonbonded, &positions, &mut forces);
+            assert!((u - (ulj + uc)).abs() <= 1e-4 * (ulj + uc).abs());
+            for (f, g) in forces.iter().flatten().zip(expected.iter().flatten()) {
+                assert!((f - g).abs() <= 1e-2 * g.abs().max(1.0));
+            }
+        }
+    }
+
+    // Times the evaluation of diala with its nonbonded interactions on 1, 2
+    // and 4 threads, run with `cargo test --release -- --ignored --nocapture`.
+    // The speedups depend on the machine; on a single core there are none.
+    #[test]
+    #[ignore]
+    fn it_times_the_threads() {
+        let (top, positions) = diala();
+        let simbox = crate::trajectory::reader::read_frame("tests/diala.crd").simbox;
+        let mut ff = Forces::new(&top);
+        ff.set_nonbonded(Nonbonded::new(&top, 0.9, 0.0), simbox, 1.0);
+        let mut forces = vec![[0.0; DIM]; positions.len()];
+        let mut times = Vec::new();
+        for nthreads in [1, 2, 4] {
+            ff.set_threads(nthreads);
+            ff.calc(&positions, &mut forces);
+            let start = std::time::Instant::now();
+            for _ in 0..200 {
+                ff.calc(&positions, &mut forces);
+            }
+            let time = start.elapsed().as_secs_f64() / 200.0;
+            times.push(time);
+            println!(
+                "{} threads: {:.3} ms per evaluation, speedup {:.2}",
+                nthreads,
+                1e3 * time,
+                times[0] / time
+            );
+        }
+    }
+
+    #[test]
+    fn it_splits_the_nonbonded_pairs_regardless_of_the_threads() {
+        let (top, positions, simbox) = solvated();
+        let mut ff = Forces::new(&top);
+        ff.set_nonbonded(Nonbonded::new(&top, 0.9, 0.0), simbox, 1.0);
+        let nb = Nonbonded::new(&top, 0.9, 0.0);
+        let list = ClusterPairList::new(&nb, &positions, simbox.as_ref(), 1.0);
+        assert!(list.pairs.len() > 4 * BLOCK);
+        for fixed_point in [false, true] {
+            ff.fixed_point = fixed_point;
+            ff.set_threads(1);
+            let serial = evaluate(&ff, &positions);
+            for nthreads in [2, 3, 4, 7] {
+                ff.set_threads(nthreads);
+                assert_eq!(evaluate(&ff, &positions), serial);
+            }
+        }
+    }
+
+    struct Bond {
+        k: Real,
+        r0: Real,
+        atoms: [usize; 2],
+    }
+
+    impl TwoAtomInteraction for Bond {
+        fn calc(&self, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
+            functions::bond_harm(self.k, self.r0, ri, rj)
+        }
+        fn atoms(&self) -> [usize; 2] {
+            self.atoms
+        }
+    }
+
+    #[test]
+    fn it_takes_custom_interactions() {
+        let (top, positions) = diala();
+        let ff = Forces::new(&top);
+        let mut custom = ff.clone();
+        let half = custom.bonds.len() / 2;
+        for (atoms, [k, r0]) in custom
+            .bonds
+            .atoms
+            .drain(half..)
+            .zip(custom.bonds.params.drain(half..))
+        {
+            custom.custom_bonds.push(Arc::new(Bond { k, r0, atoms }));
+        }
+        assert_eq!(custom.count(ForceGroup::Bonds), ff.count(ForceGroup::Bonds));
+        // The block across the two lists sums its energy in two parts.
+        let (u, f) = evaluate(&custom, &positions);
+        let (u0, f0) = evaluate(&ff, &positions);
+        assert!((u[0] - u0[0]).abs() <= 1e-6 * u0[0].abs());
+        assert_eq!(u[1..], u0[1..]);
+        assert_eq!(f, f0);
+        custom.set_threads(2);
+        assert_eq!(evaluate(&custom, &positions), (u, f));
+    }
 }
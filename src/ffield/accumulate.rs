@@ -0,0 +1,195 @@
+use crate::{to_f64, Real, Rvec, DIM};
+
+// Resolution of the fixed-point forces: 2^-32 kJ/mol/nm, leaving room for
+// sums up to 2^31 kJ/mol/nm per component.
+pub const FIXED_SCALE: f64 = 4294967296.0;
+
+// Destination of the forces of the interactions.
+pub trait ForceAccumulator {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real);
+}
+
+impl ForceAccumulator for [Rvec] {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real) {
+        for d in 0..DIM {
+            self[i][d] += f[d] * scale;
+        }
+    }
+}
+
+impl ForceAccumulator for Vec<Rvec> {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real) {
+        self.as_mut_slice().add(i, f, scale);
+    }
+}
+
+impl<A: ForceAccumulator + ?Sized> ForceAccumulator for &mut A {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real) {
+        (**self).add(i, f, scale);
+    }
+}
+
+// Buffer of the forces of a block of interactions, reused between
+// evaluations.
+pub trait BlockBuffer: ForceAccumulator + Default + Send {
+    // Zeroes the forces of `natoms` atoms.
+    fn reset(&mut self, natoms: usize);
+}
+
+impl BlockBuffer for Vec<Rvec> {
+    fn reset(&mut self, natoms: usize) {
+        self.clear();
+        self.resize(natoms, [0.0; DIM]);
+    }
+}
+
+// Forces on the atoms from `start` on, as summed by a block of interactions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Offset<A> {
+    pub start: usize,
+    pub forces: A,
+}
+
+impl<A> Offset<A> {
+    pub fn new(start: usize, forces: A) -> Offset<A> {
+        Offset { start, forces }
+    }
+}
+
+impl<A: ForceAccumulator> ForceAccumulator for Offset<A> {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real) {
+        self.forces.add(i - self.start, f, scale);
+    }
+}
+
+// Forces summed as 64 bit integers. Integer addition is associative, so the
+// result does not depend on the order of the contributions, and splitting
+// them between threads gives the same forces whatever the number of threads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FixedForces {
+    pub forces: Vec<[i64; DIM]>,
+}
+
+impl FixedForces {
+    pub fn new(natoms: usize) -> FixedForces {
+        FixedForces {
+            forces: vec![[0; DIM]; natoms],
+        }
+    }
+
+    // Adds the sums of `other`, which start at atom `start`.
+    pub fn merge(&mut self, start: usize, other: &FixedForces) {
+        for (f, g) in self.forces[start..].iter_mut().zip(other.forces.iter()) {
+            for d in 0..DIM {
+                f[d] = checked_sum(f[d], g[d]);
+            }
+        }
+    }
+
+    // Adds the sums to floating point forces.
+    pub fn add_to(&self, forces: &mut [Rvec]) {
+        for (f, g) in forces.iter_mut().zip(self.forces.iter()) {
+            for d in 0..DIM {
+                f[d] += (g[d] as f64 / FIXED_SCALE) as Real;
+            }
+        }
+    }
+}
+
+impl BlockBuffer for FixedForces {
+    fn reset(&mut self, natoms: usize) {
+        self.forces.clear();
+        self.forces.resize(natoms, [0; DIM]);
+    }
+}
+
+impl ForceAccumulator for FixedForces {
+    fn add(&mut self, i: usize, f: &Rvec, scale: Real) {
+        for (sum, &x) in self.forces[i].iter_mut().zip(f.iter()) {
+            let x = to_f64(x) * to_f64(scale) * FIXED_SCALE;
+            // `as` would saturate, and turn NaN into 0.
+            if x.is_nan() || x.abs() >= i64::MAX as f64 {
+                panic!("Fixed-point force overflow: {} kJ/mol/nm", x / FIXED_SCALE);
+            }
+            *sum = checked_sum(*sum, x.round() as i64);
+        }
+    }
+}
+
+fn checked_sum(a: i64, b: i64) -> i64 {
+    a.checked_add(b).unwrap_or_else(|| {
+        panic!(
+            "Fixed-point force overflow: the sum exceeds {} kJ/mol/nm",
+            i64::MAX as f64 / FIXED_SCALE
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffield::Forces;
+    use crate::random::Rng;
+    use crate::topology::Topology;
+    use crate::trajectory::reader::read_frame;
+
+    #[test]
+    fn it_sums_in_any_order() {
+        let mut rng = Rng::new(3);
+        let contributions = (0..1000)
+            .map(|_| {
+                let f = [0; DIM].map(|_| (1000.0 * rng.normal()) as Real);
+                (rng.next_u64() as usize % 4, f)
+            })
+            .collect::<Vec<(usize, Rvec)>>();
+        let sum = |order: &mut dyn Iterator<Item = &(usize, Rvec)>| {
+            let mut fixed = FixedForces::new(4);
+            order.for_each(|(i, f)| fixed.add(*i, f, 1.0));
+            fixed
+        };
+        let forward = sum(&mut contributions.iter());
+        assert_eq!(sum(&mut contributions.iter().rev()), forward);
+
+        // Split in two halves summed separately.
+        let (a, b) = contributions.split_at(377);
+        let mut split = sum(&mut b.iter());
+        split.merge(0, &sum(&mut a.iter()));
+        assert_eq!(split, forward);
+
+        let mut forces = vec![[0.0; DIM]; 4];
+        forward.add_to(&mut forces);
+        let mut exact = [[0.0f64; DIM]; 4];
+        for (i, f) in &contributions {
+            for d in 0..DIM {
+                exact[*i][d] += to_f64(f[d]);
+            }
+        }
+        for (f, e) in forces.iter().flatten().zip(exact.iter().flatten()) {
+            assert!((to_f64(*f) - e).abs() < 1e-3 * e.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Fixed-point force overflow")]
+    fn it_detects_overflow() {
+        let mut fixed = FixedForces::new(1);
+        for _ in 0..3 {
+            fixed.add(0, &[1e9, 0.0, 0.0], 1.0);
+        }
+    }
+
+    #[test]
+    fn it_matches_floating_point_forces() {
+        let top = Topology::read("tests/diala.top");
+        let frame = read_frame("tests/diala.crd");
+        let mut ffield = Forces::new(&top);
+        let n = frame.natoms();
+        let (mut float, mut fixed) = (vec![[0.0; DIM]; n], vec![[0.0; DIM]; n]);
+        let u = ffield.calc(&frame.positions, &mut float);
+        ffield.fixed_point = true;
+        assert_eq!(ffield.calc(&frame.positions, &mut fixed), u);
+        for (a, b) in float.iter().flatten().zip(fixed.iter().flatten()) {
+            assert!((a - b).abs() <= 1e-4 * a.abs().max(1.0));
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use super::{Forces, FourAtomInteraction, ThreeAtomInteraction, TwoAtomInteraction};
+use crate::random::Rng;
+use crate::{to_f64, Real, Rvec, DIM};
+
+// Displacement of the central differences, in nm.
+pub const STEP: Real = 1e-3;
+
+// Largest difference between the forces returned by `calc` and the central
+// finite differences of its energy, relative to the largest force (or to
+// 1 kJ/mol/nm when the forces are smaller than that). Infinite when the
+// energy or the forces are not finite.
+pub fn force_error<F>(calc: F, positions: &[Rvec], step: Real) -> Real
+where
+    F: Fn(&[Rvec]) -> (Real, Vec<Rvec>),
+{
+    let (u, forces) = calc(positions);
+    if !u.is_finite() || forces.iter().flatten().any(|f| !f.is_finite()) {
+        return Real::INFINITY;
+    }
+    let mut numeric = vec![[0.0; DIM]; positions.len()];
+    let mut r = positions.to_vec();
+    for i in 0..positions.len() {
+        for d in 0..DIM {
+            let x = positions[i][d];
+            let (xp, xm) = (x + step, x - step);
+            r[i][d] = xp;
+            let up = calc(&r).0;
+            r[i][d] = xm;
+            let um = calc(&r).0;
+            r[i][d] = x;
+            // The step actually taken, after rounding.
+            numeric[i][d] = -(to_f64(up) - to_f64(um)) / (to_f64(xp) - to_f64(xm));
+        }
+    }
+    let scale = numeric
+        .iter()
+        .flatten()
+        .fold(1.0f64, |max, f: &f64| max.max(f.abs()));
+    forces
+        .iter()
+        .flatten()
+        .zip(numeric.iter().flatten())
+        .map(|(&f, &n)| ((to_f64(f) - n).abs() / scale) as Real)
+        .fold(0.0, Real::max)
+}
+
+pub fn two_atom_error(interaction: &dyn TwoAtomInteraction, r: &[Rvec; 2], step: Real) -> Real {
+    let calc = |r: &[Rvec]| {
+        let (u, f) = interaction.calc(&r[0], &r[1]);
+        (u, f.to_vec())
+    };
+    force_error(calc, r, step)
+}
+
+pub fn three_atom_error(interaction: &dyn ThreeAtomInteraction, r: &[Rvec; 3], step: Real) -> Real {
+    let calc = |r: &[Rvec]| {
+        let (u, f) = interaction.calc(&r[0], &r[1], &r[2]);
+        (u, f.to_vec())
+    };
+    force_error(calc, r, step)
+}
+
+pub fn four_atom_error(interaction: &dyn FourAtomInteraction, r: &[Rvec; 4], step: Real) -> Real {
+    let calc = |r: &[Rvec]| {
+        let (u, f) = interaction.calc(&r[0], &r[1], &r[2], &r[3]);
+        (u, f.to_vec())
+    };
+    force_error(calc, r, step)
+}
+
+pub fn forces_error(forces: &Forces, positions: &[Rvec], step: Real) -> Real {
+    let calc = |r: &[Rvec]| {
+        let mut f = vec![[0.0; DIM]; r.len()];
+        let u = forces.calc(r, &mut f);
+        (u, f)
+    };
+    force_error(calc, positions, step)
+}
+
+// A random chain of `n` atoms with bonds of length `bond` and random bond
+// and dihedral angles, as the interactions of a molecule would see.
+pub fn random_chain(rng: &mut Rng, n: usize, bond: Real) -> Vec<Rvec> {
+    let mut chain = vec![[0; DIM].map(|_| rng.uniform() as Real)];
+    while chain.len() < n {
+        let dir = [0; DIM].map(|_| rng.normal() as Real);
+        let norm = dir.iter().map(|x| x * x).sum::<Real>().sqrt();
+        let last = chain[chain.len() - 1];
+        let next = [0, 1, 2].map(|d| last[d] + bond * dir[d] / norm);
+        // Bond angles between 50 and 140 degrees: the degenerate geometries
+        // are tested on purpose.
+        let clear = chain.len() < 2 || {
+            let r = chain[chain.len() - 2];
+            let d2 = (0..DIM).map(|d| (r[d] - next[d]).powi(2)).sum::<Real>();
+            d2 > (0.85 * bond).powi(2) && d2 < (1.88 * bond).powi(2)
+        };
+        if clear {
+            chain.push(next);
+        }
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffield::functions;
+    use crate::ffield::*;
+    use crate::topology::Topology;
+    use crate::trajectory::reader::read_frame;
+
+    const TOL: Real = 1e-2;
+
+    // Interactions made of a function of the functions module.
+    type PairFn = fn(&Rvec, &Rvec) -> (Real, [Rvec; 2]);
+    type AngleFn = fn(&Rvec, &Rvec, &Rvec) -> (Real, [Rvec; 3]);
+    type TorsionFn = fn(&Rvec, &Rvec, &Rvec, &Rvec) -> (Real, [Rvec; 4]);
+
+    struct Pair(PairFn);
+
+    impl TwoAtomInteraction for Pair {
+        fn calc(&self, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
+            (self.0)(ri, rj)
+        }
+        fn atoms(&self) -> [usize; 2] {
+            [0, 1]
+        }
+    }
+
+    struct Angle(AngleFn);
+
+    impl ThreeAtomInteraction for Angle {
+        fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (Real, [Rvec; 3]) {
+            (self.0)(ri, rj, rk)
+        }
+        fn atoms(&self) -> [usize; 3] {
+            [0, 1, 2]
+        }
+    }
+
+    struct Torsion(TorsionFn);
+
+    impl FourAtomInteraction for Torsion {
+        fn calc(&self, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (Real, [Rvec; 4]) {
+            (self.0)(ri, rj, rk, rl)
+        }
+        fn atoms(&self) -> [usize; 4] {
+            [0, 1, 2, 3]
+        }
+    }
+
+    fn angle() -> Angle {
+        Angle(|ri, rj, rk| functions::angle_harm(500.0, 1.9, ri, rj, rk))
+    }
+
+    fn pdih() -> Torsion {
+        Torsion(|ri, rj, rk, rl| functions::pdih(4.6, 3.0, 0.3, ri, rj, rk, rl))
+    }
+
+    fn idih() -> Torsion {
+        Torsion(|ri, rj, rk, rl| functions::idih_harm(40.0, 0.2, ri, rj, rk, rl))
+    }
+
+    fn rb() -> Torsion {
+        Torsion(|ri, rj, rk, rl| {
+            functions::rbdih(9.28, 12.16, -13.12, -3.06, 26.24, -31.5, ri, rj, rk, rl)
+        })
+    }
+
+    #[test]
+    fn it_matches_finite_differences() {
+        let mut rng = Rng::new(7);
+        let bond = Pair(|ri, rj| functions::bond_harm(250000.0, 0.15, ri, rj));
+        let pair = Pair(|ri, rj| functions::lj_sigma_epsilon(0.34, 0.45, ri, rj));
+        let c6c12 = Pair(|ri, rj| functions::lj(4.0e-6, 2.5e-3, ri, rj));
+        let buckingham = Pair(|ri, rj| functions::buckingham(2.0e5, 35.0, 0.003, ri, rj));
+        let coulomb = Pair(|ri, rj| functions::coulomb(0.5, -0.8, ri, rj));
+        let ewald = Pair(|ri, rj| functions::ewald_real(0.5, -0.8, 3.5, ri, rj));
+        let excluded = Pair(|ri, rj| functions::ewald_excluded(0.5, -0.8, 3.5, ri, rj));
+        let (angle, pdih, idih) = (angle(), pdih(), idih());
+        let rb = rb();
+        for _ in 0..100 {
+            let r = random_chain(&mut rng, 4, 0.15);
+            let (two, three, four) = ([r[0], r[2]], [r[0], r[1], r[2]], [r[0], r[1], r[2], r[3]]);
+            let twos: [&dyn TwoAtomInteraction; 7] = [
+                &bond,
+                &pair,
+                &c6c12,
+                &buckingham,
+                &coulomb,
+                &ewald,
+                &excluded,
+            ];
+            for interaction in twos {
+                assert!(two_atom_error(interaction, &two, STEP) < TOL);
+            }
+            assert!(three_atom_error(&angle, &three, STEP) < TOL);
+            for interaction in [&pdih as &dyn FourAtomInteraction, &rb] {
+                assert!(four_atom_error(interaction, &four, STEP) < TOL);
+            }
+            // The improper has a cusp opposite to its minimum.
+            let (phi, _) = functions::dphidr(&r[0], &r[1], &r[2], &r[3]);
+            if (phi - 0.2).cos() > -0.99 {
+                assert!(four_atom_error(&idih, &four, STEP) < TOL);
+            }
+        }
+    }
+
+    #[test]
+    fn it_takes_lj_in_both_parameterisations() {
+        let (sigma, epsilon): (Real, Real) = (0.34, 0.45);
+        let (c6, c12) = (
+            4.0 * epsilon * sigma.powi(6),
+            4.0 * epsilon * sigma.powi(12),
+        );
+        let (ri, rj) = ([0.1, 0.2, 0.3], [0.4, 0.1, 0.2]);
+        let (u, f) = functions::lj(c12, c6, &ri, &rj);
+        let (v, g) = functions::lj_sigma_epsilon(sigma, epsilon, &ri, &rj);
+        assert!((u - v).abs() <= 1e-5 * v.abs());
+        for (a, b) in f.iter().flatten().zip(g.iter().flatten()) {
+            assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn it_handles_degenerate_geometries() {
+        let (angle, pdih, idih) = (angle(), pdih(), idih());
+        let rb = rb();
+        let torsions = [&pdih as &dyn FourAtomInteraction, &idih, &rb];
+
+        // Exactly collinear: no gradient, and no change of the energy to
+        // first order.
+        let line = [[0.0, 0.0, 0.0], [0.15, 0.0, 0.0], [0.3, 0.0, 0.0]];
+        assert!(three_atom_error(&angle, &line, STEP) < TOL);
+        // Close to collinear, with a smaller step to stay on one side.
+        let near = [[0.0, 0.0, 0.0], [0.15, 0.0, 0.0], [0.3, 0.005, 0.0]];
+        assert!(three_atom_error(&angle, &near, 1e-4) < TOL);
+
+        // Planar cis and trans dihedrals, and almost planar ones.
+        for y in [0.15, -0.15] {
+            for z in [0.0, 1e-3] {
+                let r = [
+                    [0.0, 0.15, 0.0],
+                    [0.0, 0.0, 0.0],
+                    [0.15, 0.0, 0.0],
+                    [0.15, y, z],
+                ];
+                for torsion in torsions {
+                    assert!(four_atom_error(torsion, &r, 1e-4) < TOL);
+                }
+            }
+        }
+        let (trans, _) = functions::dphidr(
+            &[0.0, 0.15, 0.0],
+            &[0.0, 0.0, 0.0],
+            &[0.15, 0.0, 0.0],
+            &[0.15, -0.15, 0.0],
+        );
+        assert!((trans.abs() - crate::consts::PI).abs() < 1e-6);
+
+        // Three atoms in a line: the dihedral is undefined, the forces must
+        // still be finite.
+        let r = [
+            [0.0, 0.0, 0.0],
+            [0.15, 0.0, 0.0],
+            [0.3, 0.0, 0.0],
+            [0.3, 0.15, 0.0],
+        ];
+        for torsion in torsions {
+            let (u, f) = torsion.calc(&r[0], &r[1], &r[2], &r[3]);
+            assert!(u.is_finite() && f.iter().flatten().all(|x| x.is_finite()));
+        }
+    }
+
+    #[test]
+    fn it_matches_finite_differences_of_the_force_field() {
+        let mut top = Topology::read("tests/diala.top");
+        top.set_nmols(1, 2);
+        let frame = read_frame("tests/diala.crd");
+        let forces = Forces::new(&top);
+        assert!(forces_error(&forces, &frame.positions[..28], STEP) < TOL);
+    }
+}
@@ -1,25 +1,23 @@
-use std::f32::consts::PI;
-
-use rustfft::num_traits::float::FloatCore;
+use crate::consts::PI;
 
 use crate::linalg::*;
 
-use crate::{Rvec, DIM};
+use crate::{Real, Rvec, DIM};
 
 #[inline]
-fn harmonic(k: f32, x0: f32, x: f32) -> (f32, f32) {
+fn harmonic(k: Real, x0: Real, x: Real) -> (Real, Real) {
     let dx = x - x0;
     let kdx = k * dx;
     (0.5 * kdx * dx, -kdx)
 }
 
 #[inline]
-fn periodic(k: f32, n: f32, x0: f32, x: f32) -> (f32, f32) {
+fn periodic(k: Real, n: Real, x0: Real, x: Real) -> (Real, Real) {
     let dx = x * n - x0;
     (k * (1.0 + dx.cos()), k * n * dx.sin())
 }
 
-pub fn bond_harm(k: f32, r0: f32, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
+pub fn bond_harm(k: Real, r0: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
     let rij = displace_vec(ri, rj);
     let norm2_rij = norm2(&rij);
     let norm_rij = norm2_rij.sqrt();
@@ -37,19 +35,15 @@ pub fn bond_harm(k: f32, r0: f32, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
     (u, fvec)
 }
 
-pub fn angle_harm(k: f32, t0: f32, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]) {
+pub fn angle_harm(k: Real, t0: Real, ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (Real, [Rvec; 3]) {
     let (t, mut fvec) = dthetadr(ri, rj, rk);
     let (u, f) = harmonic(k, t0, t);
-    for i in 0..DIM {
-        fvec[0][i] *= f;
-        fvec[1][i] *= f;
-        fvec[2][i] *= f;
-    }
+    fvec.iter_mut().flatten().for_each(|x| *x *= f);
     (u, fvec)
 }
 
 #[inline]
-pub fn dthetadr(ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]) {
+pub fn dthetadr(ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (Real, [Rvec; 3]) {
     let rji = displace_vec(rj, ri);
     let rjk = displace_vec(rj, rk);
 
@@ -59,12 +53,17 @@ pub fn dthetadr(ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]) {
     let norm_rji = norm2_rji.sqrt();
     let norm_rjk = norm2_rjk.sqrt();
 
-    let cos_t = dot(&rji, &rjk) * (norm_rji * norm_rjk).recip();
+    let cos_t = (dot(&rji, &rjk) * (norm_rji * norm_rjk).recip()).clamp(-1.0, 1.0);
     let t = cos_t.acos();
 
-    let f = (1.0 - cos_t.powi(2)).sqrt().recip();
-
     let mut fvec = [[0.0; DIM]; 3];
+    // The angle has no gradient when the atoms are collinear.
+    let sin2_t = 1.0 - cos_t.powi(2);
+    if sin2_t <= 0.0 {
+        return (t, fvec);
+    }
+    let f = sin2_t.sqrt().recip();
+
     for i in 0..DIM {
         let uji = rji[i] / norm_rji;
         let ujk = rjk[i] / norm_rjk;
@@ -80,84 +79,87 @@ pub fn dthetadr(ri: &Rvec, rj: &Rvec, rk: &Rvec) -> (f32, [Rvec; 3]) {
     (t, fvec)
 }
 
+// Dihedral angle between the planes ijk and jkl, in (-pi, pi] and with the
+// sign convention of GROMACS, and its gradient.
 #[inline]
-pub fn dphidr(ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (f32, [Rvec; 4]) {
-    let rij = displace_vec(ri, rj);
-    let rjk = displace_vec(rj, rk);
-    let rkl = displace_vec(rk, rl);
+pub fn dphidr(ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (Real, [Rvec; 4]) {
+    let rij = displace_vec(rj, ri);
+    let rkj = displace_vec(rj, rk);
+    let rkl = displace_vec(rl, rk);
 
-    let nijk = cross(&rij, &rjk);
-    let njkl = cross(&rjk, &rkl);
+    let m = cross(&rij, &rkj);
+    let n = cross(&rkj, &rkl);
 
-    let s = dot(&nijk, &rkl).signum();
-
-    let norm2_nijk = norm2(&nijk);
-    let norm2_njkl = norm2(&njkl);
-    let norm2_rjk = norm2(&rjk);
-
-    let cos_phi = dot(&nijk, &njkl) * (norm2_nijk * norm2_njkl).sqrt().recip();
-    let phi = s * cos_phi.acos();
+    let norm2_m = norm2(&m);
+    let norm2_n = norm2(&n);
+    let norm2_rkj = norm2(&rkj);
+    let norm_rkj = norm2_rkj.sqrt();
 
     let mut fvec = [[0.0; DIM]; 4];
+    // The angle is not defined when three consecutive atoms are collinear.
+    if norm2_m <= 0.0 || norm2_n <= 0.0 {
+        return (0.0, fvec);
+    }
+    // atan2 keeps the precision near 0 and pi, where acos loses it.
+    let phi = (dot(&cross(&m, &n), &rkj) / norm_rkj).atan2(dot(&m, &n));
 
+    let p = dot(&rij, &rkj) / norm2_rkj;
+    let q = dot(&rkl, &rkj) / norm2_rkj;
     for i in 0..DIM {
-        let dri = rjk[i] / norm2_nijk * nijk[i];
-        let drl = -rjk[i] / norm2_njkl * njkl[i];
-        let a = rij[i] * rjk[i] / norm2_rjk;
-        let b = rkl[i] * rjk[i] / norm2_rjk;
+        let dri = norm_rkj / norm2_m * m[i];
+        let drl = -norm_rkj / norm2_n * n[i];
         fvec[0][i] = dri;
-        fvec[1][i] = (a - 1f32) * dri - b * drl;
-        fvec[2][i] = (b - 1f32) * drl - a * dri;
+        fvec[1][i] = (p - 1.0) * dri - q * drl;
+        fvec[2][i] = (q - 1.0) * drl - p * dri;
         fvec[3][i] = drl;
     }
     (phi, fvec)
 }
 
 pub fn pdih(
-    k: f32,
-    n: f32,
-    p0: f32,
+    k: Real,
+    n: Real,
+    p0: Real,
     ri: &Rvec,
     rj: &Rvec,
     rk: &Rvec,
     rl: &Rvec,
-) -> (f32, [Rvec; 4]) {
+) -> (Real, [Rvec; 4]) {
     let (phi, mut fvec) = dphidr(ri, rj, rk, rl);
     let (u, f) = periodic(k, n, p0, phi);
-    // fvec.flatten_mut().iter_mut().for_each(|x| *x *= f);
-    for i in 0..DIM {
-        fvec[0][i] *= f;
-        fvec[1][i] *= f;
-        fvec[2][i] *= f;
-        fvec[3][i] *= f;
-    }
+    fvec.iter_mut().flatten().for_each(|x| *x *= f);
     (u, fvec)
 }
 
-pub fn idih_harm(k: f32, p0: f32, ri: &Rvec, rj: &Rvec, rk: &Rvec, rl: &Rvec) -> (f32, [Rvec; 4]) {
+pub fn idih_harm(
+    k: Real,
+    p0: Real,
+    ri: &Rvec,
+    rj: &Rvec,
+    rk: &Rvec,
+    rl: &Rvec,
+) -> (Real, [Rvec; 4]) {
     let (phi, mut fvec) = dphidr(ri, rj, rk, rl);
-    let (u, f) = harmonic(k, p0, phi);
-    for i in 0..DIM {
-        fvec[0][i] *= f;
-        fvec[1][i] *= f;
-        fvec[2][i] *= f;
-        fvec[3][i] *= f;
-    }
+    // The deviation from p0 the short way around the circle.
+    let dphi = (phi - p0 + PI).rem_euclid(2.0 * PI) - PI;
+    let (u, f) = harmonic(k, 0.0, dphi);
+    fvec.iter_mut().flatten().for_each(|x| *x *= f);
     (u, fvec)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn rbdih(
-    c0: f32,
-    c1: f32,
-    c2: f32,
-    c3: f32,
-    c4: f32,
-    c5: f32,
+    c0: Real,
+    c1: Real,
+    c2: Real,
+    c3: Real,
+    c4: Real,
+    c5: Real,
     ri: &Rvec,
     rj: &Rvec,
     rk: &Rvec,
     rl: &Rvec,
-) -> (f32, [Rvec; 4]) {
+) -> (Real, [Rvec; 4]) {
     let (phi, mut fvec) = dphidr(ri, rj, rk, rl);
     let cos_psi = (phi - PI).cos();
     let cos_psi_2 = cos_psi.powi(2);
@@ -165,79 +167,153 @@ pub fn rbdih(
     let cos_psi_4 = cos_psi_2.powi(2);
     let cos_psi_5 = cos_psi_4 * cos_psi;
     let u = c0 + c1 * cos_psi + c2 * cos_psi_2 + c3 * cos_psi_3 + c4 * cos_psi_4 + c5 * cos_psi_5;
-    let f = phi.sin()
+    // -dU/dphi = sin(psi) dU/dcos(psi)
+    let f = (phi - PI).sin()
         * (c1
             + 2.0 * c2 * cos_psi
             + 3.0 * c3 * cos_psi_2
             + 4.0 * c4 * cos_psi_3
             + 5.0 * c5 * cos_psi_4);
-    for i in 0..DIM {
-        fvec[0][i] *= f;
-        fvec[1][i] *= f;
-        fvec[2][i] *= f;
-        fvec[3][i] *= f;
-    }
+    fvec.iter_mut().flatten().for_each(|x| *x *= f);
     (u, fvec)
 }
 
-pub fn lj(c12: f32, c6: f32, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
+// Lennard-Jones potential c12 / r^12 - c6 / r^6.
+pub fn lj(c12: Real, c6: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
     let rij = displace_vec(ri, rj);
     let norm2_rij = norm2(&rij);
-    let norm_rij = norm2_rij.sqrt();
-    let norm6_rij = norm2_rij.powi(3);
-    let norm12_rij = norm6_rij.powi(2);
-    let u = c12 * norm12_rij.recip() - c6 * norm6_rij.recip();
-    let f = (-12.0 * c12 / (norm_rij * norm12_rij) + 6.0 * c6 / (norm_rij * norm6_rij)) / norm_rij;
-    let mut fvec = [[0.0; DIM]; 2];
-    for (i, r) in rij.iter().enumerate() {
-        let dri = f * -r;
-        fvec[0][i] = dri;
-        fvec[1][i] = -dri;
-    }
-    (u, fvec)
+    let r6 = norm2_rij.powi(3).recip();
+    let r12 = r6 * r6;
+    let u = c12 * r12 - c6 * r6;
+    // dU/dr / r
+    let f = (-12.0 * c12 * r12 + 6.0 * c6 * r6) / norm2_rij;
+    pair_forces(f, &rij, u)
 }
 
-pub fn buckingham(a: f32, b: f32, c: f32, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
+// Lennard-Jones potential 4 epsilon ((sigma/r)^12 - (sigma/r)^6), with the
+// sigma (nm) and epsilon (kJ/mol) of the topology.
+pub fn lj_sigma_epsilon(sigma: Real, epsilon: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
+    let rij = displace_vec(ri, rj);
+    let norm2_rij = norm2(&rij);
+    let sr6 = (sigma * sigma / norm2_rij).powi(3);
+    let sr12 = sr6 * sr6;
+    let u = 4.0 * epsilon * (sr12 - sr6);
+    // dU/dr / r
+    let f = -24.0 * epsilon * (2.0 * sr12 - sr6) / norm2_rij;
+    pair_forces(f, &rij, u)
+}
+
+// Buckingham potential a exp(-b r) - c / r^6.
+pub fn buckingham(a: Real, b: Real, c: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
     let rij = displace_vec(ri, rj);
     let norm2_rij = norm2(&rij);
     let norm_rij = norm2_rij.sqrt();
     let norm6_rij = norm2_rij.powi(3);
-    let exp = (-b * norm2_rij).exp();
+    let exp = (-b * norm_rij).exp();
     let u = a * exp - c / norm6_rij;
-    let f = (a * b * exp - 6.0 * c / (norm6_rij * norm_rij)) / norm_rij;
-    let mut fvec = [[0.0; DIM]; 2];
-    for (i, r) in rij.iter().enumerate() {
-        let dri = f * -r;
-        fvec[0][i] = dri;
-        fvec[1][i] = -dri;
-    }
-    (u, fvec)
+    let f = (-a * b * exp + 6.0 * c / (norm6_rij * norm_rij)) / norm_rij;
+    pair_forces(f, &rij, u)
 }
 
-pub fn coulomb(qi: f32, qj: f32, ri: &Rvec, rj: &Rvec) -> (f32, [Rvec; 2]) {
+// Electric conversion factor 1/(4 pi epsilon0), in kJ mol^-1 nm e^-2.
+pub const ONE_4PI_EPS0: Real = 138.935_49;
+
+pub fn coulomb(qi: Real, qj: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
     let rij = displace_vec(ri, rj);
     let norm2_rij = norm2(&rij);
     let norm_rij = norm2_rij.sqrt();
-    let qiqj = 138.935_49 * qi * qj;
+    let qiqj = ONE_4PI_EPS0 * qi * qj;
     let u = qiqj / norm_rij;
-    let f = -u / norm2_rij / norm_rij;
+    let f = -u / norm2_rij;
+    pair_forces(f, &rij, u)
+}
+
+// Real space term of the Ewald sum, the Coulomb interaction screened by
+// erfc(beta r).
+pub fn ewald_real(qi: Real, qj: Real, beta: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
+    let rij = displace_vec(ri, rj);
+    let norm2_rij = norm2(&rij);
+    let norm_rij = norm2_rij.sqrt();
+    let qiqj = ONE_4PI_EPS0 * qi * qj;
+    let u = qiqj * erfc(beta * norm_rij) / norm_rij;
+    let gauss = 2.0 * beta / PI.sqrt() * (-beta * beta * norm2_rij).exp();
+    let f = -(u + qiqj * gauss) / norm2_rij;
+    pair_forces(f, &rij, u)
+}
+
+// Correction of the Ewald sum for an excluded pair, whose interaction
+// erf(beta r) / r the reciprocal space part includes.
+pub fn ewald_excluded(qi: Real, qj: Real, beta: Real, ri: &Rvec, rj: &Rvec) -> (Real, [Rvec; 2]) {
+    let rij = displace_vec(ri, rj);
+    let norm2_rij = norm2(&rij);
+    let norm_rij = norm2_rij.sqrt();
+    let qiqj = ONE_4PI_EPS0 * qi * qj;
+    let u = -qiqj * (1.0 - erfc(beta * norm_rij)) / norm_rij;
+    let gauss = 2.0 * beta / PI.sqrt() * (-beta * beta * norm2_rij).exp();
+    let f = -(u + qiqj * gauss) / norm2_rij;
+    pair_forces(f, &rij, u)
+}
+
+// Coefficients of the erfc approximation of Numerical Recipes,
+// erfc(z) = t exp(-z^2 + sum c_k t^k) with t = 1 / (1 + z/2), with a
+// relative error below 1.2e-7 for z >= 0.
+#[allow(clippy::excessive_precision)]
+pub const ERFC: [Real; 10] = [
+    -1.265_512_2,
+    1.000_023_7,
+    0.374_091_96,
+    0.096_784_18,
+    -0.186_288_06,
+    0.278_868_07,
+    -1.135_203_98,
+    1.488_515_87,
+    -0.822_152_23,
+    0.170_872_77,
+];
+
+pub fn erfc(z: Real) -> Real {
+    let t = 1.0 / (1.0 + 0.5 * z.abs());
+    let poly = ERFC.iter().rev().fold(0.0, |p, c| p * t + c);
+    let y = t * (-z * z + poly).exp();
+    if z >= 0.0 {
+        y
+    } else {
+        2.0 - y
+    }
+}
+
+// Forces of a central potential from f = dU/dr / r and rij = rj - ri.
+#[inline]
+fn pair_forces(f: Real, rij: &Rvec, u: Real) -> (Real, [Rvec; 2]) {
     let mut fvec = [[0.0; DIM]; 2];
     for (i, r) in rij.iter().enumerate() {
-        let dri = f * -r;
+        let dri = f * r;
         fvec[0][i] = dri;
         fvec[1][i] = -dri;
     }
     (u, fvec)
 }
 
-pub fn comb_rule_geom(vi: f32, wi: f32, vj: f32, wj: f32) -> (f32, f32) {
+pub fn comb_rule_geom(vi: Real, wi: Real, vj: Real, wj: Real) -> (Real, Real) {
     let v = (vi * vj).sqrt();
     let w = (wi * wj).sqrt();
     (v, w)
 }
 
-pub fn comb_rule_LB(vi: f32, wi: f32, vj: f32, wj: f32) -> (f32, f32) {
+#[allow(non_snake_case)]
+pub fn comb_rule_LB(vi: Real, wi: Real, vj: Real, wj: Real) -> (Real, Real) {
     let v = 0.5 * (vi + vj);
     let w = (wi * wj).sqrt();
     (v, w)
 }
+
+pub type CombRule = fn(Real, Real, Real, Real) -> (Real, Real);
+
+// Combination rule by its topology name.
+pub fn comb_rule(name: &str) -> CombRule {
+    match name {
+        "geom" => comb_rule_geom,
+        "LB" => comb_rule_LB,
+        wtf => panic!("Unknown combination rule: {}", wtf),
+    }
+}
@@ -0,0 +1,99 @@
+use std::ops::Range;
+
+use super::accumulate::ForceAccumulator;
+use crate::{to_f64, Real, Rvec};
+
+// Interactions of a single kind, `N` atoms and `P` parameters each, in two
+// contiguous arrays: the atoms and the parameters of interaction `k` are
+// `atoms[k]` and `params[k]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InteractionList<const N: usize, const P: usize> {
+    pub atoms: Vec<[usize; N]>,
+    pub params: Vec<[Real; P]>,
+}
+
+impl<const N: usize, const P: usize> InteractionList<N, P> {
+    pub fn new() -> InteractionList<N, P> {
+        InteractionList {
+            atoms: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, atoms: [usize; N], params: [Real; P]) {
+        self.atoms.push(atoms);
+        self.params.push(params);
+    }
+
+    // Appends `copies` copies of the interactions from `start` on, the atoms
+    // of each shifted by `stride` from those of the previous one.
+    pub fn repeat(&mut self, start: usize, copies: usize, stride: usize) {
+        let end = self.len();
+        for copy in 1..=copies {
+            for i in start..end {
+                let atoms = self.atoms[i].map(|a| a + copy * stride);
+                self.push(atoms, self.params[i]);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.atoms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty()
+    }
+
+    // Accumulates the forces of a range of the interactions, scaled by
+    // `scale`, and returns their scaled energy. `kernel` gives the energy
+    // and the forces of one interaction from its parameters and positions.
+    pub fn accumulate<A, F>(
+        &self,
+        interactions: Range<usize>,
+        positions: &[Rvec],
+        kernel: F,
+        scale: Real,
+        forces: &mut A,
+    ) -> f64
+    where
+        A: ForceAccumulator + ?Sized,
+        F: Fn(&[Real; P], &[Rvec; N]) -> (Real, [Rvec; N]),
+    {
+        let mut tot_u = 0.0;
+        let atoms = &self.atoms[interactions.clone()];
+        for (atoms, params) in atoms.iter().zip(&self.params[interactions]) {
+            let (u, f) = kernel(params, &atoms.map(|i| positions[i]));
+            tot_u += to_f64(u * scale);
+            for (&i, f) in atoms.iter().zip(f.iter()) {
+                forces.add(i, f, scale);
+            }
+        }
+        tot_u
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffield::functions;
+    use crate::DIM;
+
+    #[test]
+    fn it_accumulates_a_range() {
+        let positions = [[0.0, 0.0, 0.0], [0.12, 0.0, 0.0], [0.12, 0.1, 0.0]];
+        let mut bonds = InteractionList::new();
+        bonds.push([0, 1], [1000.0, 0.1]);
+        bonds.push([1, 2], [2000.0, 0.15]);
+        assert_eq!(bonds.len(), 2);
+
+        let kernel = |p: &[Real; 2], r: &[Rvec; 2]| functions::bond_harm(p[0], p[1], &r[0], &r[1]);
+        let mut forces = vec![[0.0; DIM]; 3];
+        let u = bonds.accumulate(1..2, &positions, kernel, 0.5, &mut forces);
+        let (u1, f1) = functions::bond_harm(2000.0, 0.15, &positions[1], &positions[2]);
+        assert_eq!(u, to_f64(0.5 * u1));
+        assert_eq!(forces[0], [0.0; DIM]);
+        assert_eq!(forces[1], f1[0].map(|x| 0.5 * x));
+        assert_eq!(forces[2], f1[1].map(|x| 0.5 * x));
+    }
+}
@@ -0,0 +1,856 @@
+// Lennard-Jones and real space Ewald Coulomb interactions between all the
+// atom pairs within a cutoff, evaluated on clusters of atoms: atoms are
+// grouped by CLUSTER nearby atoms, and the kernels compute whole
+// cluster pairs with vector instructions, masking out the excluded pairs,
+// the padding and the pairs beyond the cutoff. With Ewald, the excluded
+// pairs within the cutoff take off the erf(beta r) / r interaction that the
+// reciprocal space part gives them.
+//
+// `Forces` evaluates them as the Nonbonded group once set with
+// `Forces::set_nonbonded`. There is no reciprocal space part yet, so
+// `dynamo run` only takes plain cut-off Coulomb (beta 0) and rejects PME
+// and reaction-field rather than ignoring them.
+
+use crate::consts::PI;
+use crate::ffield::accumulate::ForceAccumulator;
+use crate::ffield::functions::{self, ERFC, ONE_4PI_EPS0};
+use crate::linalg::{norm2, rvadd, rvsub};
+use crate::simbox::SimBox;
+use crate::topology::Topology;
+use crate::{to_f64, Real, Rvec, DIM};
+use simd::{Lanes, Simd, MAX_LANES};
+use std::ops::Range;
+
+mod simd;
+
+pub const CLUSTER: usize = 4;
+
+// Slot of the padding of the clusters.
+const EMPTY: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // Plain arrays, on any machine and in double precision.
+    Scalar,
+    Sse,
+    Avx2,
+}
+
+impl Backend {
+    pub fn available(&self) -> bool {
+        match self {
+            Backend::Scalar => true,
+            Backend::Sse => cfg!(all(target_arch = "x86_64", not(feature = "double"))),
+            Backend::Avx2 => has_avx2(),
+        }
+    }
+
+    // The widest backend of the machine.
+    pub fn detect() -> Backend {
+        [Backend::Avx2, Backend::Sse, Backend::Scalar]
+            .into_iter()
+            .find(|b| b.available())
+            .unwrap()
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+}
+
+#[cfg(not(all(target_arch = "x86_64", not(feature = "double"))))]
+fn has_avx2() -> bool {
+    false
+}
+
+// Ewald splitting parameter beta for which erfc(beta rc) is `rtol`, as
+// GROMACS sets it from ewald-rtol.
+pub fn ewald_coefficient(rc: Real, rtol: Real) -> Real {
+    let mut hi: Real = 5.0;
+    while functions::erfc(hi * rc) > rtol {
+        hi *= 2.0;
+    }
+    let mut lo = 0.0;
+    for _ in 0..60 {
+        let beta = 0.5 * (lo + hi);
+        if functions::erfc(beta * rc) > rtol {
+            lo = beta;
+        } else {
+            hi = beta;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+pub struct Nonbonded {
+    // Cutoff in nm, and Ewald splitting parameter in 1/nm (plain Coulomb
+    // with 0).
+    pub rc: Real,
+    pub beta: Real,
+    pub backend: Backend,
+    pub charges: Vec<Real>,
+    pub sigma: Vec<Real>,
+    pub epsilon: Vec<Real>,
+    // Excluded atoms of every atom, sorted.
+    pub exclusions: Vec<Vec<usize>>,
+    comb_rule: functions::CombRule,
+    geometric: bool,
+}
+
+impl Nonbonded {
+    pub fn new(top: &Topology, rc: Real, beta: Real) -> Nonbonded {
+        let h = top.hierarchy();
+        let local = top
+            .molecules()
+            .iter()
+            .map(|mol| mol.exclusions())
+            .collect::<Vec<Vec<Vec<usize>>>>();
+        let mut exclusions = vec![Vec::new(); h.natoms()];
+        for mol in &h.molecules {
+            let start = mol.atoms.start;
+            for (i, excluded) in local[mol.moltype].iter().enumerate() {
+                exclusions[start + i] = excluded.iter().map(|j| start + j).collect();
+            }
+        }
+        Nonbonded {
+            rc,
+            beta,
+            backend: Backend::detect(),
+            charges: top.atoms().map(|a| a.charge).collect(),
+            sigma: top.atoms().map(|a| a.v).collect(),
+            epsilon: top.atoms().map(|a| a.w).collect(),
+            exclusions,
+            comb_rule: functions::comb_rule(&top.defaults.comb_rule),
+            geometric: top.defaults.comb_rule == "geom",
+        }
+    }
+
+    fn excluded(&self, i: usize, j: usize) -> bool {
+        self.exclusions[i].binary_search(&j).is_ok()
+    }
+
+    // Potential energies of the LJ and Coulomb interactions of the pairs of
+    // the list within the cutoff, whose forces are added to `forces`.
+    pub fn calc(
+        &self,
+        list: &ClusterPairList,
+        positions: &[Rvec],
+        forces: &mut [Rvec],
+    ) -> (Real, Real) {
+        let x = SlotPositions::new(list, positions);
+        let mut f = SlotForces::default();
+        f.reset(list.slots.len());
+        let (ulj, uc) = self.calc_pairs(list, 0..list.pairs.len(), &x, &mut f);
+        f.add_to(list, forces);
+        (ulj as Real, uc as Real)
+    }
+
+    // The same for a range of the cluster pairs of the list, whose forces
+    // are added to `f` by slot: a block of the parallel evaluation.
+    pub fn calc_pairs(
+        &self,
+        list: &ClusterPairList,
+        pairs: Range<usize>,
+        x: &SlotPositions,
+        f: &mut SlotForces,
+    ) -> (f64, f64) {
+        let (pairs, x, f) = (&list.pairs[pairs], &x.0, &mut f.0);
+        match self.backend {
+            Backend::Scalar => unsafe { kernel::<Lanes>(self, list, pairs, x, f) },
+            #[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+            Backend::Sse => unsafe { kernel::<simd::Sse>(self, list, pairs, x, f) },
+            #[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+            Backend::Avx2 if has_avx2() => unsafe { kernel_avx2(self, list, pairs, x, f) },
+            #[allow(unreachable_patterns)]
+            backend => panic!("The {:?} nonbonded kernels are not available", backend),
+        }
+    }
+
+    // The same interactions pair by pair with the functions of the force
+    // field, in O(N^2): the reference of the kernels.
+    pub fn reference(
+        &self,
+        positions: &[Rvec],
+        simbox: Option<&SimBox>,
+        forces: &mut [Rvec],
+    ) -> (Real, Real) {
+        let (mut ulj, mut uc) = (0.0, 0.0);
+        let rc2 = self.rc * self.rc;
+        for i in 0..positions.len() {
+            for j in i + 1..positions.len() {
+                let ri = positions[i];
+                let dr = match simbox {
+                    Some(simbox) => simbox.displace(&ri, &positions[j]),
+                    None => rvsub(&positions[j], &ri),
+                };
+                if norm2(&dr) >= rc2 {
+                    continue;
+                }
+                let rj = rvadd(&ri, &dr);
+                let (qi, qj) = (self.charges[i], self.charges[j]);
+                if self.excluded(i, j) {
+                    if self.beta > 0.0 {
+                        let (v, g) = functions::ewald_excluded(qi, qj, self.beta, &ri, &rj);
+                        uc += to_f64(v);
+                        for d in 0..DIM {
+                            forces[i][d] += g[0][d];
+                            forces[j][d] += g[1][d];
+                        }
+                    }
+                    continue;
+                }
+                let (v, w) = (self.comb_rule)(
+                    self.sigma[i],
+                    self.epsilon[i],
+                    self.sigma[j],
+                    self.epsilon[j],
+                );
+                let (u, f) = functions::lj_sigma_epsilon(v, w, &ri, &rj);
+                let (v, g) = if self.beta > 0.0 {
+                    functions::ewald_real(qi, qj, self.beta, &ri, &rj)
+                } else {
+                    functions::coulomb(qi, qj, &ri, &rj)
+                };
+                ulj += to_f64(u);
+                uc += to_f64(v);
+                for d in 0..DIM {
+                    forces[i][d] += f[0][d] + g[0][d];
+                    forces[j][d] += f[1][d] + g[1][d];
+                }
+            }
+        }
+        (ulj as Real, uc as Real)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterPair {
+    pub ci: usize,
+    pub cj: usize,
+    // Periodic shift of the atoms of cj.
+    pub shift: Rvec,
+    // Bit CLUSTER * a + b is set when atom a of ci interacts with atom b
+    // of cj, or in `excl` when the pair is excluded (with Ewald only).
+    pub mask: u32,
+    pub excl: u32,
+}
+
+// The clusters and the cluster pairs within `rlist`. Each cluster is kept
+// whole across periodic boundaries by shifts of its atoms, so the list holds
+// while no atom moves by more than half the buffer rlist - rc, see
+// `is_outdated`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterPairList {
+    // Atom of every slot of the clusters, EMPTY for the padding.
+    pub slots: Vec<usize>,
+    slot_shifts: Vec<Rvec>,
+    pub pairs: Vec<ClusterPair>,
+    // Parameters by slot: charge, sigma (its square root with the
+    // geometric rule) and the square root of epsilon.
+    q: Vec<Real>,
+    sigma: Vec<Real>,
+    sqrt_eps: Vec<Real>,
+    // Positions the list was built for, and rlist - rc.
+    reference: Vec<Rvec>,
+    buffer: Real,
+}
+
+impl ClusterPairList {
+    pub fn new(
+        nb: &Nonbonded,
+        positions: &[Rvec],
+        simbox: Option<&SimBox>,
+        rlist: Real,
+    ) -> ClusterPairList {
+        if rlist < nb.rc {
+            panic!("rlist {} is shorter than the cutoff {}", rlist, nb.rc);
+        }
+        // Each cluster pair takes a single periodic shift, so no pair may see
+        // two images within rlist.
+        if let Some(simbox) = simbox {
+            if rlist > simbox.max_cutoff() {
+                panic!(
+                    "rlist {} is longer than the box allows ({})",
+                    rlist,
+                    simbox.max_cutoff()
+                );
+            }
+        }
+        let mut grid = Grid::new(positions, simbox);
+        let slots = std::mem::take(&mut grid.slots);
+        let nclusters = slots.len() / CLUSTER;
+        let min_image = |r: &Rvec| simbox.map_or(*r, |simbox| simbox.min_image(r));
+
+        let mut slot_shifts = vec![[0.0; DIM]; slots.len()];
+        let mut centers = Vec::with_capacity(nclusters);
+        let mut extents = Vec::with_capacity(nclusters);
+        for c in 0..nclusters {
+            let first = positions[slots[c * CLUSTER]];
+            let (mut lo, mut hi) = (first, first);
+            for s in c * CLUSTER..(c + 1) * CLUSTER {
+                if slots[s] == EMPTY {
+                    continue;
+                }
+                let dr = rvsub(&positions[slots[s]], &first);
+                slot_shifts[s] = rvsub(&min_image(&dr), &dr);
+                let r = rvadd(&positions[slots[s]], &slot_shifts[s]);
+                for d in 0..DIM {
+                    lo[d] = lo[d].min(r[d]);
+                    hi[d] = hi[d].max(r[d]);
+                }
+            }
+            centers.push([0, 1, 2].map(|d| 0.5 * (lo[d] + hi[d])));
+            extents.push([0, 1, 2].map(|d| 0.5 * (hi[d] - lo[d])));
+        }
+
+        // Only the clusters of the nearby columns of the grid can be within
+        // rlist; the pairs are then checked on the bounding boxes.
+        let reach = [0, 1, 2].map(|d| rlist / grid.spacing[d]);
+        let mut pairs = Vec::new();
+        for (col, clusters) in grid.columns.iter().enumerate() {
+            let columns = grid.columns_near(col, &reach);
+            for ci in clusters.clone() {
+                let near = columns
+                    .iter()
+                    .flat_map(|&col| grid.clusters_near(col, grid.z[ci], reach[2]))
+                    .flatten()
+                    .filter(|&cj| cj >= ci);
+                for cj in near {
+                    let dc = rvsub(&centers[cj], &centers[ci]);
+                    let shift = rvsub(&min_image(&dc), &dc);
+                    let gap = [0, 1, 2].map(|d| {
+                        let gap = (dc[d] + shift[d]).abs() - extents[ci][d] - extents[cj][d];
+                        gap.max(0.0)
+                    });
+                    if norm2(&gap) >= rlist * rlist {
+                        continue;
+                    }
+                    let (mut mask, mut excl) = (0, 0);
+                    for a in 0..CLUSTER {
+                        for b in 0..CLUSTER {
+                            let (i, j) = (slots[ci * CLUSTER + a], slots[cj * CLUSTER + b]);
+                            let counted = ci != cj || b > a;
+                            if i == EMPTY || j == EMPTY || !counted {
+                                continue;
+                            }
+                            if !nb.excluded(i, j) {
+                                mask |= 1 << (CLUSTER * a + b);
+                            } else if nb.beta > 0.0 {
+                                excl |= 1 << (CLUSTER * a + b);
+                            }
+                        }
+                    }
+                    if mask != 0 || excl != 0 {
+                        pairs.push(ClusterPair {
+                            ci,
+                            cj,
+                            shift,
+                            mask,
+                            excl,
+                        });
+                    }
+                }
+            }
+        }
+
+        let param = |values: &[Real], f: fn(Real) -> Real| {
+            slots
+                .iter()
+                .map(|&i| if i == EMPTY { 0.0 } else { f(values[i]) })
+                .collect::<Vec<Real>>()
+        };
+        let sigma = if nb.geometric {
+            param(&nb.sigma, Real::sqrt)
+        } else {
+            param(&nb.sigma, |x| x)
+        };
+        ClusterPairList {
+            q: param(&nb.charges, |x| x),
+            sigma,
+            sqrt_eps: param(&nb.epsilon, Real::sqrt),
+            slots,
+            slot_shifts,
+            pairs,
+            reference: positions.to_vec(),
+            buffer: rlist - nb.rc,
+        }
+    }
+
+    // Whether the list must be rebuilt for `positions`: once an atom has
+    // moved by half the buffer, two atoms from beyond rlist may have come
+    // within the cutoff.
+    pub fn is_outdated(&self, positions: &[Rvec]) -> bool {
+        let limit2 = 0.25 * self.buffer * self.buffer;
+        positions
+            .iter()
+            .zip(&self.reference)
+            .any(|(r, r0)| norm2(&rvsub(r, r0)) > limit2)
+    }
+}
+
+// The atoms grouped into compact clusters, so that a single periodic shift
+// is right for all the atom pairs of a cluster pair: the atoms are binned
+// into columns along z, sorted by z within their column, and every column
+// is cut into clusters, padded with EMPTY slots.
+struct Grid {
+    slots: Vec<usize>,
+    ncols: [usize; 2],
+    // Clusters of every column, and the fractional z of the first and last
+    // atoms of every cluster.
+    columns: Vec<Range<usize>>,
+    z: Vec<[Real; 2]>,
+    // Distances between the planes of unit fractional coordinates: atoms
+    // within r of each other are at most r / spacing[d] apart in
+    // fractional coordinate d.
+    spacing: Rvec,
+    periodic: bool,
+}
+
+impl Grid {
+    fn new(positions: &[Rvec], simbox: Option<&SimBox>) -> Grid {
+        let natoms = positions.len();
+        let (fractional, lengths, spacing): (Vec<Rvec>, Rvec, Rvec) = match simbox {
+            Some(simbox) => (
+                positions
+                    .iter()
+                    .map(|r| simbox.to_fractional(&simbox.wrap(r)))
+                    .collect(),
+                simbox.lengths(),
+                simbox.reciprocal().map(|v| 1.0 / norm2(&v).sqrt()),
+            ),
+            None => {
+                let (mut lo, mut hi) = ([Real::MAX; DIM], [Real::MIN; DIM]);
+                for r in positions {
+                    for d in 0..DIM {
+                        lo[d] = lo[d].min(r[d]);
+                        hi[d] = hi[d].max(r[d]);
+                    }
+                }
+                let lengths = [0, 1, 2].map(|d| (hi[d] - lo[d]).max(1e-3));
+                let fractional = positions
+                    .iter()
+                    .map(|r| [0, 1, 2].map(|d| (r[d] - lo[d]) / lengths[d]))
+                    .collect();
+                (fractional, lengths, lengths)
+            }
+        };
+
+        // Columns about as wide as a cluster is tall.
+        let volume = lengths[0] * lengths[1] * lengths[2];
+        let width = (CLUSTER as Real * volume / natoms.max(1) as Real).cbrt();
+        let ncols = [0, 1].map(|d| ((lengths[d] / width).round() as usize).max(1));
+        let column = |s: &Rvec| {
+            let c = [0, 1].map(|d| ((s[d] * ncols[d] as Real) as usize).min(ncols[d] - 1));
+            c[0] * ncols[1] + c[1]
+        };
+        let mut order = (0..natoms).collect::<Vec<usize>>();
+        order.sort_by(|&i, &j| {
+            let (si, sj) = (&fractional[i], &fractional[j]);
+            column(si)
+                .cmp(&column(sj))
+                .then(si[2].total_cmp(&sj[2]))
+                .then(i.cmp(&j))
+        });
+
+        let mut slots = Vec::with_capacity(natoms + CLUSTER * ncols[0] * ncols[1]);
+        let mut columns = vec![0..0; ncols[0] * ncols[1]];
+        let mut z = Vec::new();
+        for (k, &i) in order.iter().enumerate() {
+            let col = column(&fractional[i]);
+            if slots.len() % CLUSTER == 0 {
+                if columns[col].is_empty() {
+                    columns[col] = z.len()..z.len();
+                }
+                columns[col].end += 1;
+                z.push([fractional[i][2]; 2]);
+            }
+            z.last_mut().unwrap()[1] = fractional[i][2];
+            slots.push(i);
+            if k + 1 == natoms || column(&fractional[order[k + 1]]) != col {
+                slots.resize(slots.len().next_multiple_of(CLUSTER), EMPTY);
+            }
+        }
+        Grid {
+            slots,
+            ncols,
+            columns,
+            z,
+            spacing,
+            periodic: simbox.is_some(),
+        }
+    }
+
+    // Columns holding the atoms within `reach` (in fractional coordinates)
+    // of the atoms of column `col`.
+    fn columns_near(&self, col: usize, reach: &Rvec) -> Vec<usize> {
+        let near = |d: usize, c: usize| {
+            let n = self.ncols[d];
+            let k = ((reach[d] * n as Real).ceil() as usize).min(n);
+            if !self.periodic {
+                (c.saturating_sub(k)..(c + k + 1).min(n)).collect::<Vec<usize>>()
+            } else if 2 * k + 1 >= n {
+                (0..n).collect()
+            } else {
+                (0..=2 * k).map(|o| (c + n + o - k) % n).collect()
+            }
+        };
+        let (xs, ys) = (near(0, col / self.ncols[1]), near(1, col % self.ncols[1]));
+        xs.iter()
+            .flat_map(|x| ys.iter().map(move |y| x * self.ncols[1] + y))
+            .collect()
+    }
+
+    // Clusters of column `col` with atoms within `reach` of the fractional
+    // z range `z`, as ranges of cluster indices.
+    fn clusters_near(&self, col: usize, z: [Real; 2], reach: Real) -> Vec<Range<usize>> {
+        let clusters = self.columns[col].clone();
+        let (lo, hi) = (z[0] - reach, z[1] + reach);
+        if self.periodic && hi - lo >= 1.0 {
+            return vec![clusters];
+        }
+        let shifts: &[Real] = if self.periodic {
+            &[-1.0, 0.0, 1.0]
+        } else {
+            &[0.0]
+        };
+        // The first and the last z of the clusters of a column both grow.
+        let zs = &self.z[clusters.clone()];
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for shift in shifts {
+            let start = zs.partition_point(|z| z[1] < lo + shift);
+            let end = zs.partition_point(|z| z[0] <= hi + shift).max(start);
+            let range = clusters.start + start..clusters.start + end;
+            match ranges.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => ranges.push(range),
+            }
+        }
+        ranges
+    }
+}
+
+// Positions by slot, one array per dimension, shifted to keep the clusters
+// whole.
+pub struct SlotPositions([Vec<Real>; DIM]);
+
+impl SlotPositions {
+    pub fn new(list: &ClusterPairList, positions: &[Rvec]) -> SlotPositions {
+        let coordinate = |d: usize| {
+            list.slots
+                .iter()
+                .zip(&list.slot_shifts)
+                .map(|(&i, shift)| {
+                    if i == EMPTY {
+                        0.0
+                    } else {
+                        positions[i][d] + shift[d]
+                    }
+                })
+                .collect::<Vec<Real>>()
+        };
+        SlotPositions([0, 1, 2].map(coordinate))
+    }
+}
+
+// Forces by slot, one array per dimension.
+#[derive(Default)]
+pub struct SlotForces([Vec<Real>; DIM]);
+
+impl SlotForces {
+    // Zeroes the forces of `nslots` slots.
+    pub fn reset(&mut self, nslots: usize) {
+        for f in &mut self.0 {
+            f.clear();
+            f.resize(nslots, 0.0);
+        }
+    }
+
+    // Adds the forces to those of the atoms of the slots of `list`.
+    pub fn add_to<A>(&self, list: &ClusterPairList, forces: &mut A)
+    where
+        A: ForceAccumulator + ?Sized,
+    {
+        for (s, &i) in list.slots.iter().enumerate() {
+            if i != EMPTY {
+                forces.add(i, &[0, 1, 2].map(|d| self.0[d][s]), 1.0);
+            }
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn kernel_avx2(
+    nb: &Nonbonded,
+    list: &ClusterPairList,
+    pairs: &[ClusterPair],
+    x: &[Vec<Real>; DIM],
+    f: &mut [Vec<Real>; DIM],
+) -> (f64, f64) {
+    kernel::<simd::Avx2>(nb, list, pairs, x, f)
+}
+
+// The lanes of a vector are the pairs of LANES / CLUSTER atoms of ci from
+// atom `a` on with every atom of cj.
+#[inline(always)]
+unsafe fn gather<S: Simd>(values: &[Real], index: impl Fn(usize) -> usize, shift: Real) -> S {
+    let mut lanes = [0.0; MAX_LANES];
+    for (l, x) in lanes[..S::LANES].iter_mut().enumerate() {
+        *x = values[index(l)] + shift;
+    }
+    S::load(&lanes)
+}
+
+#[inline(always)]
+unsafe fn erfc<S: Simd>(z: S) -> S {
+    let one = S::splat(1.0);
+    let t = one.div(one.add(S::splat(0.5).mul(z)));
+    let mut poly = S::splat(0.0);
+    for &c in ERFC.iter().rev() {
+        poly = poly.mul(t).add(S::splat(c));
+    }
+    t.mul(poly.sub(z.mul(z)).exp())
+}
+
+#[inline(always)]
+unsafe fn kernel<S: Simd>(
+    nb: &Nonbonded,
+    list: &ClusterPairList,
+    pairs: &[ClusterPair],
+    x: &[Vec<Real>; DIM],
+    f: &mut [Vec<Real>; DIM],
+) -> (f64, f64) {
+    let (zero, one) = (S::splat(0.0), S::splat(1.0));
+    let rc2 = S::splat(nb.rc * nb.rc);
+    let beta = S::splat(nb.beta);
+    let gauss = S::splat(2.0 * nb.beta / PI.sqrt());
+    let epsfac = S::splat(ONE_4PI_EPS0);
+    let (four, six, half) = (S::splat(4.0), S::splat(6.0), S::splat(0.5));
+    let iatoms = S::LANES / CLUSTER;
+    let mut lanes = [[0.0; MAX_LANES]; DIM + 2];
+    let (mut ulj, mut uc) = (0.0, 0.0);
+
+    for pair in pairs {
+        let (i0, j0) = (pair.ci * CLUSTER, pair.cj * CLUSTER);
+        let jslot = |l: usize| j0 + l % CLUSTER;
+        let xj = [0, 1, 2].map(|d| gather::<S>(&x[d], jslot, pair.shift[d]));
+        let qj = gather::<S>(&list.q, jslot, 0.0);
+        let sj = gather::<S>(&list.sigma, jslot, 0.0);
+        let ej = gather::<S>(&list.sqrt_eps, jslot, 0.0);
+        for a in (0..CLUSTER).step_by(iatoms) {
+            let islot = |l: usize| i0 + a + l / CLUSTER;
+            let xi = [0, 1, 2].map(|d| gather::<S>(&x[d], islot, 0.0));
+            let dx = [0, 1, 2].map(|d| xi[d].sub(xj[d]));
+            let r2 = dx[0].mul(dx[0]).add(dx[1].mul(dx[1])).add(dx[2].mul(dx[2]));
+            let within = r2.lt(rc2);
+            let m = S::and(S::mask(pair.mask >> (CLUSTER * a)), within);
+            let excl = S::and(S::mask(pair.excl >> (CLUSTER * a)), within);
+            // Both kinds of pairs for the Coulomb part.
+            let mc = S::and(S::mask((pair.mask | pair.excl) >> (CLUSTER * a)), within);
+            let r2 = S::select(mc, r2, one);
+            let rinv2 = one.div(r2);
+            let rinv = rinv2.sqrt();
+
+            // Lennard-Jones.
+            let si = gather::<S>(&list.sigma, islot, 0.0);
+            let sigma = if nb.geometric {
+                si.mul(sj)
+            } else {
+                half.mul(si.add(sj))
+            };
+            let eps4 = four.mul(gather::<S>(&list.sqrt_eps, islot, 0.0).mul(ej));
+            let sr2 = sigma.mul(sigma).mul(rinv2);
+            let sr6 = sr2.mul(sr2).mul(sr2);
+            let sr12 = sr6.mul(sr6);
+            let vlj = eps4.mul(sr12.sub(sr6));
+            // -dU/dr / r
+            let flj = six.mul(eps4).mul(sr12.add(sr12).sub(sr6)).mul(rinv2);
+
+            // Real space Ewald, erfc(beta r) - 1 for the excluded pairs.
+            let qq = epsfac.mul(gather::<S>(&list.q, islot, 0.0).mul(qj));
+            let br = beta.mul(r2.mul(rinv));
+            let screened = erfc(br).sub(S::select(excl, one, zero));
+            let vc = qq.mul(screened).mul(rinv);
+            let screen = gauss.mul(zero.sub(br.mul(br)).exp());
+            let fc = vc.add(qq.mul(screen)).mul(rinv2);
+
+            let flj = S::select(m, flj, zero);
+            let fscal = S::select(mc, flj.add(fc), zero);
+            S::select(m, vlj, zero).store(&mut lanes[DIM]);
+            S::select(mc, vc, zero).store(&mut lanes[DIM + 1]);
+            for d in 0..DIM {
+                fscal.mul(dx[d]).store(&mut lanes[d]);
+            }
+            let (g, u) = lanes.split_at(DIM);
+            for l in 0..S::LANES {
+                ulj += to_f64(u[0][l]);
+                uc += to_f64(u[1][l]);
+                for (fd, g) in f.iter_mut().zip(g) {
+                    fd[islot(l)] += g[l];
+                    fd[jslot(l)] -= g[l];
+                }
+            }
+        }
+    }
+    (ulj, uc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+    use crate::trajectory::reader::read_frame;
+
+    fn solvated(nwaters: usize) -> (Topology, Vec<Rvec>, Option<SimBox>) {
+        let mut top = Topology::read("tests/diala.top");
+        top.set_nmols(1, nwaters);
+        let mut frame = read_frame("tests/diala.crd");
+        frame.positions.truncate(top.hierarchy().natoms());
+        (top, frame.positions, frame.simbox)
+    }
+
+    #[test]
+    fn it_corrects_the_excluded_pairs() {
+        // A single water, whose atoms all exclude each other.
+        let mut top = Topology::read("tests/diala.top");
+        top.set_nmols(0, 0);
+        top.set_nmols(1, 1);
+        let frame = read_frame("tests/diala.crd");
+        let positions = &frame.positions[22..25];
+        let rc = 0.9;
+        let beta = ewald_coefficient(rc, 1e-5);
+        let mut expected = 0.0;
+        for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+            let r = norm2(&rvsub(&positions[j], &positions[i])).sqrt();
+            let qq = ONE_4PI_EPS0 * top.atom(i).charge * top.atom(j).charge;
+            expected -= qq * (1.0 - functions::erfc(beta * r)) / r;
+        }
+
+        for beta in [0.0, beta] {
+            let nb = Nonbonded::new(&top, rc, beta);
+            let mut forces = vec![[0.0; DIM]; 3];
+            let (ulj, uc) = nb.reference(positions, None, &mut forces);
+            assert_eq!(ulj, 0.0);
+            if beta == 0.0 {
+                assert_eq!((uc, forces), (0.0, vec![[0.0; DIM]; 3]));
+                continue;
+            }
+            assert!((uc - expected).abs() <= 1e-5 * expected.abs());
+            let list = ClusterPairList::new(&nb, positions, None, rc);
+            let mut kernel = vec![[0.0; DIM]; 3];
+            let (vlj, vc) = nb.calc(&list, positions, &mut kernel);
+            assert_eq!(vlj, 0.0);
+            assert!((vc - expected).abs() <= 1e-4 * expected.abs());
+            for (f, g) in kernel.iter().flatten().zip(forces.iter().flatten()) {
+                assert!((f - g).abs() <= 1e-3 * g.abs().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn it_finds_the_pairs_within_rlist() {
+        let (top, positions, simbox) = solvated(300);
+        let nb = Nonbonded::new(&top, 0.9, 0.0);
+        let rlist = 1.0;
+        let lengths = simbox.as_ref().unwrap().lengths();
+        let triclinic = SimBox::from_lengths_angles(lengths, [80.0, 70.0, 65.0]);
+        for simbox in [simbox.as_ref(), Some(&triclinic), None] {
+            let list = ClusterPairList::new(&nb, &positions, simbox, rlist);
+            let mut listed = std::collections::HashSet::new();
+            for pair in &list.pairs {
+                for bit in (0..CLUSTER * CLUSTER).filter(|b| pair.mask >> b & 1 == 1) {
+                    let i = list.slots[pair.ci * CLUSTER + bit / CLUSTER];
+                    let j = list.slots[pair.cj * CLUSTER + bit % CLUSTER];
+                    listed.insert((i.min(j), i.max(j)));
+                }
+            }
+            for i in 0..positions.len() {
+                for j in i + 1..positions.len() {
+                    let dr = match simbox {
+                        Some(simbox) => simbox.displace(&positions[i], &positions[j]),
+                        None => rvsub(&positions[j], &positions[i]),
+                    };
+                    if norm2(&dr) < rlist * rlist && !nb.excluded(i, j) {
+                        assert!(listed.contains(&(i, j)), "{} {} {:?}", i, j, simbox);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_refuses_rlist_beyond_the_box() {
+        let (top, positions, _) = solvated(300);
+        let nb = Nonbonded::new(&top, 0.9, 0.0);
+        let small = SimBox::rectangular(1.8, 2.5, 2.5);
+        let list = || ClusterPairList::new(&nb, &positions, Some(&small), 0.95);
+        assert!(std::panic::catch_unwind(list).is_err());
+        ClusterPairList::new(&nb, &positions, Some(&small), 0.9);
+    }
+
+    #[test]
+    fn it_rebuilds_the_list_when_atoms_move() {
+        let (top, positions, simbox) = solvated(300);
+        let simbox = simbox.as_ref();
+        let nb = Nonbonded::new(&top, 0.9, 0.0);
+        let list = ClusterPairList::new(&nb, &positions, simbox, 1.0);
+        assert!(!list.is_outdated(&positions));
+
+        // Moved by less than half the buffer: the list still holds.
+        let mut rng = Rng::new(5);
+        let moved = positions
+            .iter()
+            .map(|r| {
+                let dr = [0; DIM].map(|_| rng.normal() as Real);
+                let scale = 0.049 / norm2(&dr).sqrt();
+                [0, 1, 2].map(|d| r[d] + scale * dr[d])
+            })
+            .collect::<Vec<Rvec>>();
+        assert!(!list.is_outdated(&moved));
+        let mut expected = vec![[0.0; DIM]; moved.len()];
+        let (ulj, uc) = nb.reference(&moved, simbox, &mut expected);
+        let mut forces = vec![[0.0; DIM]; moved.len()];
+        let (vlj, vc) = nb.calc(&list, &moved, &mut forces);
+        assert!((vlj - ulj).abs() <= 1e-4 * ulj.abs());
+        assert!((vc - uc).abs() <= 1e-4 * uc.abs());
+
+        let mut moved = positions.clone();
+        moved[7][0] += 0.051;
+        assert!(list.is_outdated(&moved));
+    }
+
+    #[test]
+    fn it_matches_the_pair_functions() {
+        let (top, positions, simbox) = solvated(300);
+        let (positions, simbox) = (&positions[..], simbox.as_ref());
+
+        let rc = 0.9;
+        let beta = ewald_coefficient(rc, 1e-5);
+        assert!((functions::erfc(beta * rc) / 1e-5 - 1.0).abs() < 1e-3);
+
+        for beta in [0.0, beta] {
+            let mut nb = Nonbonded::new(&top, rc, beta);
+            let mut expected = vec![[0.0; DIM]; positions.len()];
+            let (ulj, uc) = nb.reference(positions, simbox, &mut expected);
+            let fmax = expected
+                .iter()
+                .flatten()
+                .fold(0.0 as Real, |m, f| m.max(f.abs()));
+            let list = ClusterPairList::new(&nb, positions, simbox, rc + 0.1);
+            for backend in [Backend::Scalar, Backend::Sse, Backend::Avx2] {
+                if !backend.available() {
+                    continue;
+                }
+                nb.backend = backend;
+                let mut forces = vec![[0.0; DIM]; positions.len()];
+                let (vlj, vc) = nb.calc(&list, positions, &mut forces);
+                assert!((vlj - ulj).abs() <= 1e-4 * ulj.abs(), "{:?}", backend);
+                assert!((vc - uc).abs() <= 1e-4 * uc.abs(), "{:?}", backend);
+                for (f, g) in forces.iter().flatten().zip(expected.iter().flatten()) {
+                    assert!((f - g).abs() <= 1e-4 * fmax, "{:?}", backend);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// The polynomial exp and its helpers serve the x86 backends, which are
+// single precision only.
+#![cfg_attr(feature = "double", allow(dead_code))]
+
+use crate::Real;
+
+// Most lanes of the vectors of any backend.
+pub const MAX_LANES: usize = 8;
+
+// A vector of `LANES` reals for the nonbonded kernels. The methods are
+// unsafe because the instructions of a backend may be missing on the
+// machine: see `Backend::available`.
+pub trait Simd: Copy {
+    const LANES: usize;
+    type Mask: Copy;
+
+    unsafe fn splat(x: Real) -> Self;
+    // The first `LANES` values of `x`.
+    unsafe fn load(x: &[Real]) -> Self;
+    unsafe fn store(self, x: &mut [Real]);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn sub(self, other: Self) -> Self;
+    unsafe fn mul(self, other: Self) -> Self;
+    unsafe fn div(self, other: Self) -> Self;
+    unsafe fn sqrt(self) -> Self;
+    unsafe fn max(self, other: Self) -> Self;
+    // Nearest integer.
+    unsafe fn round(self) -> Self;
+    // 2^self for integers from -126 to 127.
+    unsafe fn pow2(self) -> Self;
+    unsafe fn exp(self) -> Self;
+    unsafe fn lt(self, other: Self) -> Self::Mask;
+    // The lanes whose bit is set in `bits`.
+    unsafe fn mask(bits: u32) -> Self::Mask;
+    unsafe fn and(a: Self::Mask, b: Self::Mask) -> Self::Mask;
+    // `a` in the lanes of `mask`, `b` elsewhere.
+    unsafe fn select(mask: Self::Mask, a: Self, b: Self) -> Self;
+}
+
+// exp(x) for x from -87 to 88, to single precision: x = n ln2 + r with
+// |r| <= ln2/2, and a Taylor polynomial of exp(r).
+#[inline(always)]
+pub unsafe fn exp_poly<S: Simd>(x: S) -> S {
+    const LN2_HI: Real = 0.693_359_4;
+    const LN2_LO: Real = -2.121_944_4e-4;
+    let x = x.max(S::splat(-87.0));
+    let n = x.mul(S::splat(crate::consts::LOG2_E)).round();
+    let r = x.sub(n.mul(S::splat(LN2_HI))).sub(n.mul(S::splat(LN2_LO)));
+    let mut p = S::splat(1.0 / 5040.0);
+    for c in [
+        1.0 / 720.0,
+        1.0 / 120.0,
+        1.0 / 24.0,
+        1.0 / 6.0,
+        0.5,
+        1.0,
+        1.0,
+    ] {
+        p = p.mul(r).add(S::splat(c));
+    }
+    p.mul(n.pow2())
+}
+
+// Four lanes of plain reals, for any machine and precision.
+#[derive(Debug, Clone, Copy)]
+pub struct Lanes([Real; 4]);
+
+impl Lanes {
+    #[inline(always)]
+    fn zip(self, other: Lanes, f: impl Fn(Real, Real) -> Real) -> Lanes {
+        Lanes([0, 1, 2, 3].map(|l| f(self.0[l], other.0[l])))
+    }
+}
+
+impl Simd for Lanes {
+    const LANES: usize = 4;
+    type Mask = [bool; 4];
+
+    #[inline(always)]
+    unsafe fn splat(x: Real) -> Self {
+        Lanes([x; 4])
+    }
+    #[inline(always)]
+    unsafe fn load(x: &[Real]) -> Self {
+        Lanes([x[0], x[1], x[2], x[3]])
+    }
+    #[inline(always)]
+    unsafe fn store(self, x: &mut [Real]) {
+        x[..4].copy_from_slice(&self.0);
+    }
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self {
+        self.zip(other, |a, b| a + b)
+    }
+    #[inline(always)]
+    unsafe fn sub(self, other: Self) -> Self {
+        self.zip(other, |a, b| a - b)
+    }
+    #[inline(always)]
+    unsafe fn mul(self, other: Self) -> Self {
+        self.zip(other, |a, b| a * b)
+    }
+    #[inline(always)]
+    unsafe fn div(self, other: Self) -> Self {
+        self.zip(other, |a, b| a / b)
+    }
+    #[inline(always)]
+    unsafe fn sqrt(self) -> Self {
+        Lanes(self.0.map(Real::sqrt))
+    }
+    #[inline(always)]
+    unsafe fn max(self, other: Self) -> Self {
+        self.zip(other, Real::max)
+    }
+    #[inline(always)]
+    unsafe fn round(self) -> Self {
+        Lanes(self.0.map(Real::round))
+    }
+    #[inline(always)]
+    unsafe fn pow2(self) -> Self {
+        Lanes(self.0.map(Real::exp2))
+    }
+    #[inline(always)]
+    unsafe fn exp(self) -> Self {
+        Lanes(self.0.map(Real::exp))
+    }
+    #[inline(always)]
+    unsafe fn lt(self, other: Self) -> Self::Mask {
+        [0, 1, 2, 3].map(|l| self.0[l] < other.0[l])
+    }
+    #[inline(always)]
+    unsafe fn mask(bits: u32) -> Self::Mask {
+        [0, 1, 2, 3].map(|l| bits & (1 << l) != 0)
+    }
+    #[inline(always)]
+    unsafe fn and(a: Self::Mask, b: Self::Mask) -> Self::Mask {
+        [0, 1, 2, 3].map(|l| a[l] && b[l])
+    }
+    #[inline(always)]
+    unsafe fn select(mask: Self::Mask, a: Self, b: Self) -> Self {
+        Lanes([0, 1, 2, 3].map(|l| if mask[l] { a.0[l] } else { b.0[l] }))
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+pub use x86::{Avx2, Sse};
+
+#[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+mod x86 {
+    use super::{exp_poly, Simd};
+    use std::arch::x86_64::*;
+
+    // SSE2, which every x86_64 machine has.
+    #[derive(Clone, Copy)]
+    pub struct Sse(__m128);
+
+    impl Simd for Sse {
+        const LANES: usize = 4;
+        type Mask = __m128;
+
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            Sse(_mm_set1_ps(x))
+        }
+        #[inline(always)]
+        unsafe fn load(x: &[f32]) -> Self {
+            debug_assert!(x.len() >= 4);
+            Sse(_mm_loadu_ps(x.as_ptr()))
+        }
+        #[inline(always)]
+        unsafe fn store(self, x: &mut [f32]) {
+            debug_assert!(x.len() >= 4);
+            _mm_storeu_ps(x.as_mut_ptr(), self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, other: Self) -> Self {
+            Sse(_mm_add_ps(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, other: Self) -> Self {
+            Sse(_mm_sub_ps(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, other: Self) -> Self {
+            Sse(_mm_mul_ps(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, other: Self) -> Self {
+            Sse(_mm_div_ps(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn sqrt(self) -> Self {
+            Sse(_mm_sqrt_ps(self.0))
+        }
+        #[inline(always)]
+        unsafe fn max(self, other: Self) -> Self {
+            Sse(_mm_max_ps(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn round(self) -> Self {
+            Sse(_mm_cvtepi32_ps(_mm_cvtps_epi32(self.0)))
+        }
+        #[inline(always)]
+        unsafe fn pow2(self) -> Self {
+            let n = _mm_add_epi32(_mm_cvtps_epi32(self.0), _mm_set1_epi32(127));
+            Sse(_mm_castsi128_ps(_mm_slli_epi32::<23>(n)))
+        }
+        #[inline(always)]
+        unsafe fn exp(self) -> Self {
+            exp_poly(self)
+        }
+        #[inline(always)]
+        unsafe fn lt(self, other: Self) -> Self::Mask {
+            _mm_cmplt_ps(self.0, other.0)
+        }
+        #[inline(always)]
+        unsafe fn mask(bits: u32) -> Self::Mask {
+            let lanes = _mm_setr_epi32(1, 2, 4, 8);
+            let set = _mm_and_si128(_mm_set1_epi32(bits as i32), lanes);
+            _mm_castsi128_ps(_mm_cmpeq_epi32(set, lanes))
+        }
+        #[inline(always)]
+        unsafe fn and(a: Self::Mask, b: Self::Mask) -> Self::Mask {
+            _mm_and_ps(a, b)
+        }
+        #[inline(always)]
+        unsafe fn select(mask: Self::Mask, a: Self, b: Self) -> Self {
+            Sse(_mm_or_ps(_mm_and_ps(mask, a.0), _mm_andnot_ps(mask, b.0)))
+        }
+    }
+
+    // AVX2 and FMA, detected at run time. The kernels are compiled for them
+    // by calling these methods from functions with the same features.
+    #[derive(Clone, Copy)]
+    pub struct Avx2(__m256);
+
+    impl Simd for Avx2 {
+        const LANES: usize = 8;
+        type Mask = __m256;
+
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn splat(x: f32) -> Self {
+            Avx2(_mm256_set1_ps(x))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn load(x: &[f32]) -> Self {
+            debug_assert!(x.len() >= 8);
+            Avx2(_mm256_loadu_ps(x.as_ptr()))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn store(self, x: &mut [f32]) {
+            debug_assert!(x.len() >= 8);
+            _mm256_storeu_ps(x.as_mut_ptr(), self.0)
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn add(self, other: Self) -> Self {
+            Avx2(_mm256_add_ps(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn sub(self, other: Self) -> Self {
+            Avx2(_mm256_sub_ps(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn mul(self, other: Self) -> Self {
+            Avx2(_mm256_mul_ps(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn div(self, other: Self) -> Self {
+            Avx2(_mm256_div_ps(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn sqrt(self) -> Self {
+            Avx2(_mm256_sqrt_ps(self.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn max(self, other: Self) -> Self {
+            Avx2(_mm256_max_ps(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn round(self) -> Self {
+            Avx2(_mm256_round_ps::<
+                { _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC },
+            >(self.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn pow2(self) -> Self {
+            let n = _mm256_add_epi32(_mm256_cvtps_epi32(self.0), _mm256_set1_epi32(127));
+            Avx2(_mm256_castsi256_ps(_mm256_slli_epi32::<23>(n)))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn exp(self) -> Self {
+            exp_poly(self)
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn lt(self, other: Self) -> Self::Mask {
+            _mm256_cmp_ps::<_CMP_LT_OQ>(self.0, other.0)
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn mask(bits: u32) -> Self::Mask {
+            let lanes = _mm256_setr_epi32(1, 2, 4, 8, 16, 32, 64, 128);
+            let set = _mm256_and_si256(_mm256_set1_epi32(bits as i32), lanes);
+            _mm256_castsi256_ps(_mm256_cmpeq_epi32(set, lanes))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn and(a: Self::Mask, b: Self::Mask) -> Self::Mask {
+            _mm256_and_ps(a, b)
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn select(mask: Self::Mask, a: Self, b: Self) -> Self {
+            Avx2(_mm256_blendv_ps(b.0, a.0, mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn check<S: Simd>() {
+        let x = (0..MAX_LANES)
+            .map(|l| -0.3 - 10.0 * l as Real)
+            .collect::<Vec<Real>>();
+        let mut out = [0.0; MAX_LANES];
+        S::load(&x).exp().store(&mut out);
+        for (y, x) in out.iter().zip(&x).take(S::LANES) {
+            assert!((y - x.exp()).abs() <= 2e-6 * x.exp());
+        }
+        exp_poly(S::load(&x)).store(&mut out);
+        for (y, x) in out.iter().zip(&x).take(S::LANES) {
+            assert!((y - x.exp()).abs() <= 2e-6 * x.exp());
+        }
+        let mask = S::and(S::mask(0b0110), S::load(&x).lt(S::splat(-5.0)));
+        S::select(mask, S::splat(1.0), S::splat(0.0)).store(&mut out);
+        assert_eq!(out[..4], [0.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn it_computes_in_every_backend() {
+        unsafe { check::<Lanes>() };
+        #[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+        {
+            unsafe { check::<Sse>() };
+            if crate::ffield::nonbonded::Backend::Avx2.available() {
+                unsafe { check_avx2() };
+            }
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(feature = "double")))]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn check_avx2() {
+        check::<Avx2>()
+    }
+}
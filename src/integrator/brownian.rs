@@ -0,0 +1,437 @@
+use std::f64::consts::PI;
+
+use crate::checkpoint::{Checkpoint, Checkpointable};
+use crate::ffield::{Forces, NGROUPS};
+use crate::linalg::*;
+use crate::random::Rng;
+use crate::{to_f64, Real, Rvec, BOLTZ, DIM};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpyNoise {
+    // Exact factorization of the full 3N x 3N diffusion tensor, O(N^3).
+    Cholesky,
+    // Lanczos approximation of D^1/2 * xi (Ando et al., JCP 137, 064106, 2012),
+    // iterated until the relative change falls below `tol`.
+    Krylov { max_iter: usize, tol: f64 },
+}
+
+// Hydrodynamic interactions through the Rotne-Prager-Yamakawa tensor.
+// Radii are in nm and the solvent viscosity in amu/(nm ps), which is
+// 6.022e5 times its value in Pa s (water at 300 K is about 512).
+pub struct Rpy {
+    pub radii: Vec<Real>,
+    pub viscosity: Real,
+    pub noise: RpyNoise,
+}
+
+impl Rpy {
+    pub fn new(radii: Vec<Real>, viscosity: Real, noise: RpyNoise) -> Rpy {
+        Rpy {
+            radii,
+            viscosity,
+            noise,
+        }
+    }
+
+    // Full diffusion tensor, row-major with 3N x 3N entries, in nm^2/ps.
+    pub fn diffusion_tensor(&self, coords: &[Rvec], temp: Real) -> Vec<f64> {
+        let n = DIM * coords.len();
+        let kt = to_f64(BOLTZ * temp);
+        let eta = to_f64(self.viscosity);
+        let mut tensor = vec![0.0; n * n];
+        for i in 0..coords.len() {
+            let ai = to_f64(self.radii[i]);
+            let dii = kt / (6.0 * PI * eta * ai);
+            for d in 0..DIM {
+                tensor[(DIM * i + d) * n + DIM * i + d] = dii;
+            }
+            for j in 0..i {
+                let aj = to_f64(self.radii[j]);
+                let rij = displace_vec(&coords[j], &coords[i]).map(to_f64);
+                let r = (rij[0] * rij[0] + rij[1] * rij[1] + rij[2] * rij[2]).sqrt();
+                // Generalized to unequal and overlapping beads by Zuk et al.,
+                // J. Fluid Mech. 741, R5 (2014), which keeps the tensor
+                // positive definite.
+                let (c_id, c_rr) = if r > ai + aj {
+                    let s = (ai * ai + aj * aj) / (r * r);
+                    let pre = kt / (8.0 * PI * eta * r);
+                    (pre * (1.0 + s / 3.0), pre * (1.0 - s))
+                } else if r > (ai - aj).abs() {
+                    let pre = kt / (6.0 * PI * eta * ai * aj * 32.0 * r.powi(3));
+                    let d2 = (ai - aj).powi(2);
+                    (
+                        pre * (16.0 * r.powi(3) * (ai + aj) - (d2 + 3.0 * r * r).powi(2)),
+                        pre * 3.0 * (d2 - r * r).powi(2),
+                    )
+                } else {
+                    // One bead inside the other moves with the larger one.
+                    (kt / (6.0 * PI * eta * ai.max(aj)), 0.0)
+                };
+                for a in 0..DIM {
+                    for b in 0..DIM {
+                        let mut dab = if r > 0.0 {
+                            c_rr * rij[a] * rij[b] / (r * r)
+                        } else {
+                            0.0
+                        };
+                        if a == b {
+                            dab += c_id;
+                        }
+                        tensor[(DIM * i + a) * n + DIM * j + b] = dab;
+                        tensor[(DIM * j + b) * n + DIM * i + a] = dab;
+                    }
+                }
+            }
+        }
+        tensor
+    }
+}
+
+// Overdamped Langevin (Brownian) dynamics, Ermak-McCammon scheme:
+//
+//     x(t + dt) = x(t) + D F dt / kT + sqrt(2 dt) B xi,    B B^T = D
+//
+// Without hydrodynamics the diffusion tensor is diagonal, with per-atom
+// coefficients in nm^2/ps. The RPY tensor is divergence free, so no drift
+// correction is needed when hydrodynamic interactions are enabled.
+pub struct BrownianDynamics {
+    pub dt: Real,
+    pub time: Real,
+    pub step: i32,
+    pub temp: Real,
+    pub diffusion: Vec<Real>,
+    pub hydrodynamics: Option<Rpy>,
+    pub rng: Rng,
+    // Potential energy of each force group at the end of the last step.
+    pub terms: [Real; NGROUPS],
+}
+
+impl BrownianDynamics {
+    pub fn new(dt: Real, temp: Real, diffusion: Vec<Real>, seed: u64) -> BrownianDynamics {
+        BrownianDynamics {
+            dt,
+            time: 0.0,
+            step: 0,
+            temp,
+            diffusion,
+            hydrodynamics: None,
+            rng: Rng::new(seed),
+            terms: [0.0; NGROUPS],
+        }
+    }
+
+    // Diffusion coefficients from per-atom friction coefficients in 1/ps,
+    // i.e. D = kT / (m gamma).
+    pub fn from_friction(
+        dt: Real,
+        temp: Real,
+        gamma: &[Real],
+        masses: &[Real],
+        seed: u64,
+    ) -> BrownianDynamics {
+        let diffusion = gamma
+            .iter()
+            .zip(masses.iter())
+            .map(|(g, m)| BOLTZ * temp / (g * m))
+            .collect();
+        Self::new(dt, temp, diffusion, seed)
+    }
+
+    // Moves the system by one step with the forces of the current
+    // configuration, as left by the previous step, and returns the potential
+    // energy of the new configuration, whose forces are left in `forces`.
+    pub fn step(&mut self, ffield: &Forces, coords: &mut [Rvec], forces: &mut [Rvec]) -> Real {
+        self.time += self.dt;
+        self.step += 1;
+
+        let kt = BOLTZ * self.temp;
+        let dt = self.dt;
+
+        match &self.hydrodynamics {
+            None => coords
+                .iter_mut()
+                .zip(forces.iter())
+                .zip(self.diffusion.iter())
+                .for_each(|((crd, frc), diff)| {
+                    let sd = (2.0 * diff * dt).sqrt();
+                    for d in 0..DIM {
+                        crd[d] += diff * frc[d] * dt / kt + sd * self.rng.normal() as Real;
+                    }
+                }),
+            Some(rpy) => {
+                let n = DIM * coords.len();
+                let tensor = rpy.diffusion_tensor(coords, self.temp);
+                let xi = (0..n).map(|_| self.rng.normal()).collect::<Vec<f64>>();
+                let noise = match rpy.noise {
+                    RpyNoise::Cholesky => {
+                        let l = cholesky(&tensor, n);
+                        lower_mat_vec(&l, &xi, n)
+                    }
+                    RpyNoise::Krylov { max_iter, tol } => {
+                        lanczos_sqrt(&tensor, &xi, n, max_iter, tol)
+                    }
+                };
+                let flat = forces
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .map(to_f64)
+                    .collect::<Vec<f64>>();
+                let drift = mat_vec_n(&tensor, &flat, n);
+                let sd = (2.0 * to_f64(dt)).sqrt();
+                coords.iter_mut().flatten().enumerate().for_each(|(k, x)| {
+                    *x += (drift[k] * to_f64(dt) / to_f64(kt) + sd * noise[k]) as Real;
+                });
+            }
+        }
+        forces.fill([0.0; DIM]);
+        self.terms = ffield.calc_terms(coords, forces);
+        self.terms.iter().sum()
+    }
+}
+
+impl Checkpointable for BrownianDynamics {
+    fn save_state(&self, cpt: &mut Checkpoint) {
+        cpt.step = self.step;
+        cpt.time = self.time;
+        cpt.set_rng("brownian", self.rng.state());
+    }
+
+    fn restore_state(&mut self, cpt: &Checkpoint) {
+        self.step = cpt.step;
+        self.time = cpt.time;
+        self.rng = Rng::from_state(cpt.get_rng("brownian"));
+    }
+}
+
+fn mat_vec_n(a: &[f64], x: &[f64], n: usize) -> Vec<f64> {
+    a.chunks(n)
+        .map(|row| row.iter().zip(x.iter()).map(|(a, x)| a * x).sum())
+        .collect()
+}
+
+fn lower_mat_vec(l: &[f64], x: &[f64], n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|i| (0..=i).map(|j| l[i * n + j] * x[j]).sum())
+        .collect()
+}
+
+// Lower triangular factor of a symmetric positive definite matrix.
+fn cholesky(a: &[f64], n: usize) -> Vec<f64> {
+    let mut l = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..=i {
+            let s: f64 = (0..j).map(|k| l[i * n + k] * l[j * n + k]).sum();
+            if i == j {
+                let d = a[i * n + i] - s;
+                if d <= 0.0 {
+                    panic!("Diffusion tensor is not positive definite");
+                }
+                l[i * n + i] = d.sqrt();
+            } else {
+                l[i * n + j] = (a[i * n + j] - s) / l[j * n + j];
+            }
+        }
+    }
+    l
+}
+
+// Approximates A^1/2 x in the Krylov subspace span{x, Ax, A^2x, ...}.
+fn lanczos_sqrt(a: &[f64], x: &[f64], n: usize, max_iter: usize, tol: f64) -> Vec<f64> {
+    let xnorm = x.iter().map(|v| v * v).sum::<f64>().sqrt();
+    let mut basis = vec![x.iter().map(|v| v / xnorm).collect::<Vec<f64>>()];
+    let (mut alpha, mut beta) = (Vec::new(), Vec::new());
+    let mut prev: Option<Vec<f64>> = None;
+
+    for m in 0..max_iter.min(n) {
+        let mut w = mat_vec_n(a, &basis[m], n);
+        let am = w
+            .iter()
+            .zip(basis[m].iter())
+            .map(|(a, b)| a * b)
+            .sum::<f64>();
+        alpha.push(am);
+        // Full reorthogonalization keeps the basis well conditioned.
+        for v in &basis {
+            let c = w.iter().zip(v.iter()).map(|(a, b)| a * b).sum::<f64>();
+            w.iter_mut().zip(v.iter()).for_each(|(w, v)| *w -= c * v);
+        }
+
+        let y = tridiag_sqrt_e1(&alpha, &beta, xnorm);
+        let mut approx = vec![0.0; n];
+        for (v, c) in basis.iter().zip(y.iter()) {
+            approx
+                .iter_mut()
+                .zip(v.iter())
+                .for_each(|(a, v)| *a += c * v);
+        }
+        let bm = w.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if let Some(p) = &prev {
+            let diff = approx
+                .iter()
+                .zip(p.iter())
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f64>();
+            let norm = approx.iter().map(|a| a * a).sum::<f64>();
+            if diff.sqrt() <= tol * norm.sqrt() {
+                return approx;
+            }
+        }
+        if bm <= 1e-12 * xnorm {
+            return approx;
+        }
+        beta.push(bm);
+        basis.push(w.iter().map(|v| v / bm).collect());
+        prev = Some(approx);
+    }
+    prev.unwrap_or_else(|| vec![0.0; n])
+}
+
+// Returns |x| T^1/2 e1 for the symmetric tridiagonal T(alpha, beta).
+fn tridiag_sqrt_e1(alpha: &[f64], beta: &[f64], xnorm: f64) -> Vec<f64> {
+    let m = alpha.len();
+    let mut t = vec![0.0; m * m];
+    for i in 0..m {
+        t[i * m + i] = alpha[i];
+        if i + 1 < m {
+            t[i * m + i + 1] = beta[i];
+            t[(i + 1) * m + i] = beta[i];
+        }
+    }
+    let (evals, evecs) = jacobi_eigen(&mut t, m);
+    (0..m)
+        .map(|i| {
+            (0..m)
+                .map(|k| evecs[i * m + k] * evals[k].max(0.0).sqrt() * evecs[k])
+                .sum::<f64>()
+                * xnorm
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::Topology;
+
+    fn free_particles(n: usize) -> Forces {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("p".to_string(), 0, 1.0, 0.0, 0.0);
+        top.add_molecule("P".to_string(), n, 0);
+        top.add_atom(0, "p", "P", 1, "P", 0.0);
+        Forces::new(&top)
+    }
+
+    #[test]
+    fn it_diffuses() {
+        let n = 2000;
+        let ffield = free_particles(n);
+        let mut bd = BrownianDynamics::new(0.01, 300.0, vec![0.5; n], 42);
+        let mut coords = vec![[0.0; DIM]; n];
+        let mut forces = vec![[0.0; DIM]; n];
+        for _ in 0..100 {
+            bd.step(&ffield, &mut coords, &mut forces);
+        }
+        let msd = coords.iter().map(norm2).sum::<Real>() / n as Real;
+        let expected = 6.0 * 0.5 * bd.time;
+        assert!((msd - expected).abs() < 0.05 * expected);
+    }
+
+    // Pairs of atoms bound by harmonic springs of rest length 0, far from
+    // each other.
+    fn springs(n: usize, k: Real) -> (Forces, Vec<Rvec>) {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("p".to_string(), 0, 1.0, 0.0, 0.0);
+        top.add_molecule("P".to_string(), n, 0);
+        top.add_atom(0, "p", "P1", 1, "P", 0.0);
+        top.add_atom(0, "p", "P2", 1, "P", 0.0);
+        top.add_bonded_interaction(0, &format!("bond_harm 1 2 0.0 {}", k));
+        let coords = (0..2 * n)
+            .map(|i| [100.0 * (i / 2) as Real + 0.2 * (i % 2) as Real, 0.0, 0.0])
+            .collect();
+        (Forces::new(&top), coords)
+    }
+
+    #[test]
+    fn it_samples_a_harmonic_well() {
+        // <x^2> = kT / k along each axis for the separation of the pair.
+        let (n, k, temp) = (4, 100.0, 300.0);
+        let expected = 3.0 * BOLTZ * temp / k;
+        let hydrodynamics = [
+            None,
+            Some(Rpy::new([0.1, 0.2].repeat(n), 5.0, RpyNoise::Cholesky)),
+        ];
+        for rpy in hydrodynamics {
+            let (ffield, mut coords) = springs(n, k);
+            let mut bd = BrownianDynamics::new(0.002, temp, vec![0.2; 2 * n], 11);
+            bd.hydrodynamics = rpy;
+            let mut forces = vec![[0.0; DIM]; 2 * n];
+            ffield.calc(&coords, &mut forces);
+            let (mut sum, mut count) = (0.0, 0);
+            for step in 0..20000 {
+                bd.step(&ffield, &mut coords, &mut forces);
+                if step >= 1000 {
+                    for pair in coords.chunks(2) {
+                        sum += to_f64(norm2(&displace_vec(&pair[0], &pair[1])));
+                        count += 1;
+                    }
+                }
+            }
+            let r2 = sum / count as f64;
+            assert!(
+                (r2 - to_f64(expected)).abs() < 0.06 * to_f64(expected),
+                "{}",
+                r2
+            );
+        }
+    }
+
+    #[test]
+    fn it_keeps_rpy_positive_definite_for_unequal_overlapping_beads() {
+        let rpy = Rpy::new(vec![0.05, 0.4], 512.0, RpyNoise::Cholesky);
+        let n = 2 * DIM;
+        let tensor = |r: Real| rpy.diffusion_tensor(&[[0.0; DIM], [r, 0.0, 0.0]], 300.0);
+        for i in 1..=100 {
+            cholesky(&tensor(0.005 * i as Real), n);
+        }
+        // Continuous where the beads start and stop overlapping.
+        for r in [0.35, 0.45] {
+            let (a, b) = (tensor(r - 1e-4), tensor(r + 1e-4));
+            for (x, y) in a.iter().zip(&b) {
+                assert!((x - y).abs() < 1e-3 * a[0], "{} {} at {}", x, y, r);
+            }
+        }
+    }
+
+    #[test]
+    fn it_factorizes_rpy() {
+        let mut rng = Rng::new(3);
+        let coords = (0..20)
+            .map(|_| [0; DIM].map(|_| 2.0 * rng.uniform() as Real))
+            .collect::<Vec<Rvec>>();
+        let rpy = Rpy::new(vec![0.15; 20], 512.0, RpyNoise::Cholesky);
+        let n = DIM * coords.len();
+        let tensor = rpy.diffusion_tensor(&coords, 300.0);
+        let xi = (0..n).map(|_| rng.normal()).collect::<Vec<f64>>();
+
+        // The Cholesky factor reproduces the tensor.
+        let l = cholesky(&tensor, n);
+        for i in 0..n {
+            for j in 0..n {
+                let llt = (0..n).map(|k| l[i * n + k] * l[j * n + k]).sum::<f64>();
+                assert!((llt - tensor[i * n + j]).abs() < 1e-10 * tensor[i * n + i]);
+            }
+        }
+        // |D^1/2 xi|^2 = xi.D.xi for the symmetric square root of D.
+        let expected = xi
+            .iter()
+            .zip(mat_vec_n(&tensor, &xi, n).iter())
+            .map(|(a, b)| a * b)
+            .sum::<f64>();
+        let krylov = lanczos_sqrt(&tensor, &xi, n, 60, 1e-8);
+        let norm = krylov.iter().map(|x| x * x).sum::<f64>();
+        assert!((norm - expected).abs() < 1e-6 * expected);
+    }
+}
@@ -0,0 +1,461 @@
+use super::velocities::ComMotionRemoval;
+use crate::checkpoint::{Checkpoint, Checkpointable};
+use crate::ffield::{ForceGroup, Forces, NGROUPS};
+use crate::{to_f64, Real, Rvec, DIM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RespaSplitting {
+    // Slow forces are applied as impulses at the boundaries of their time step.
+    Impulse,
+    // Slow forces are evaluated at mollified positions, averaged along a
+    // short trajectory that starts at rest from the current positions and
+    // only feels the faster forces. This filters out the fast motions that
+    // otherwise resonate with the outer time step. It is the long-averaging
+    // flavour of MOLLY (Izaguirre et al., JCP 110, 9853, 1999) without the
+    // Jacobian correction, so it is time reversible but not symplectic.
+    Mollified,
+}
+
+struct RespaLevel {
+    groups: Vec<ForceGroup>,
+    period: usize,
+    // Energy of each group, at the last evaluation of the level.
+    energies: Vec<Real>,
+    forces: Vec<Rvec>,
+    average: Vec<Rvec>,
+}
+
+// Reversible multiple time step integrator (r-RESPA, Tuckerman et al.,
+// JCP 97, 1990, 1992). Every force group is assigned to a level with its own
+// period, in units of the inner time step `dt`. Periods must be nested, e.g.
+// bonds every step, angles and torsions every 2, and pairs and nonbonded
+// interactions every 4 steps:
+//
+//     Respa::new(dt, n, &[
+//         (vec![ForceGroup::Bonds], 1),
+//         (vec![ForceGroup::Angles, ForceGroup::Torsions], 2),
+//         (vec![ForceGroup::Pairs, ForceGroup::Nonbonded], 4),
+//     ], RespaSplitting::Impulse);
+//
+// A call to `step` advances the system by one outer time step, i.e.
+// `dt` times the longest period.
+pub struct Respa {
+    pub dt: Real,
+    pub time: Real,
+    pub step: i32,
+    pub splitting: RespaSplitting,
+    pub comm: Option<ComMotionRemoval>,
+    // Whether `step` reports the energy of the actual positions with the
+    // Mollified splitting, at the cost of evaluating the slow levels again.
+    // Without, the slow levels report the energy of their mollified
+    // positions, which is enough for the steps without energy output.
+    pub report_energy: bool,
+    levels: Vec<RespaLevel>,
+    initialized: bool,
+    aux_coords: Vec<Rvec>,
+    aux_velocities: Vec<Rvec>,
+    aux_forces: Vec<Rvec>,
+}
+
+impl Respa {
+    pub fn new(
+        dt: Real,
+        n: usize,
+        levels: &[(Vec<ForceGroup>, usize)],
+        splitting: RespaSplitting,
+    ) -> Respa {
+        let mut levels = levels
+            .iter()
+            .map(|(groups, period)| RespaLevel {
+                groups: groups.clone(),
+                period: *period,
+                energies: vec![0.0; groups.len()],
+                forces: vec![[0.0; DIM]; n],
+                average: vec![[0.0; DIM]; n],
+            })
+            .collect::<Vec<RespaLevel>>();
+        levels.sort_by_key(|level| level.period);
+
+        if levels.is_empty() || levels[0].period == 0 {
+            panic!("RESPA requires at least one level with a positive period");
+        }
+        for pair in levels.windows(2) {
+            if pair[1].period == pair[0].period || pair[1].period % pair[0].period != 0 {
+                panic!(
+                    "RESPA periods must be distinct multiples of each other: {} and {}",
+                    pair[0].period, pair[1].period
+                );
+            }
+        }
+        for group in ForceGroup::ALL {
+            let count = levels
+                .iter()
+                .flat_map(|level| level.groups.iter())
+                .filter(|&&g| g == group)
+                .count();
+            if count != 1 {
+                panic!(
+                    "Force group {:?} must be assigned to exactly one RESPA level",
+                    group
+                );
+            }
+        }
+
+        Respa {
+            dt,
+            time: 0.0,
+            step: 0,
+            splitting,
+            comm: None,
+            report_energy: true,
+            levels,
+            initialized: false,
+            aux_coords: vec![[0.0; DIM]; n],
+            aux_velocities: vec![[0.0; DIM]; n],
+            aux_forces: vec![[0.0; DIM]; n],
+        }
+    }
+
+    // Length of one outer time step.
+    pub fn outer_dt(&self) -> Real {
+        self.dt * self.levels.last().unwrap().period as Real
+    }
+
+    // Accumulates the forces of all levels as last evaluated.
+    pub fn forces(&self, forces: &mut [Rvec]) {
+        for level in &self.levels {
+            forces
+                .iter_mut()
+                .zip(level.forces.iter())
+                .for_each(|(f, lf)| {
+                    for d in 0..DIM {
+                        f[d] += lf[d];
+                    }
+                });
+        }
+    }
+
+    pub fn step(
+        &mut self,
+        ffield: &Forces,
+        coords: &mut [Rvec],
+        velocities: &mut [Rvec],
+        masses: &[Real],
+    ) -> Real {
+        if !self.initialized {
+            for k in 0..self.levels.len() {
+                self.update_forces(k, ffield, coords, masses);
+            }
+            self.initialized = true;
+        }
+        self.time += self.outer_dt();
+        self.step += 1;
+
+        self.advance(self.levels.len() - 1, ffield, coords, velocities, masses);
+        if let Some(comm) = &self.comm {
+            comm.apply(self.step, coords, velocities, masses);
+        }
+        if self.splitting == RespaSplitting::Mollified && self.report_energy {
+            for level in &mut self.levels[1..] {
+                self.aux_forces.fill([0.0; DIM]);
+                level.energies = level
+                    .groups
+                    .iter()
+                    .map(|&group| ffield.calc_group(group, coords, &mut self.aux_forces))
+                    .collect();
+            }
+        }
+        self.levels.iter().flat_map(|level| &level.energies).sum()
+    }
+
+    // Potential energy of each force group, in the order of `ForceGroup::ALL`.
+    pub fn terms(&self) -> [Real; NGROUPS] {
+        let mut terms = [0.0; NGROUPS];
+        for level in &self.levels {
+            for (group, u) in level.groups.iter().zip(&level.energies) {
+                terms[group.index()] = *u;
+            }
+        }
+        terms
+    }
+
+    fn advance(
+        &mut self,
+        k: usize,
+        ffield: &Forces,
+        coords: &mut [Rvec],
+        velocities: &mut [Rvec],
+        masses: &[Real],
+    ) {
+        let dt = self.dt * self.levels[k].period as Real;
+        self.kick(k, dt, velocities, masses);
+
+        if k == 0 {
+            coords
+                .iter_mut()
+                .zip(velocities.iter())
+                .for_each(|(crd, vel)| {
+                    for d in 0..DIM {
+                        crd[d] += vel[d] * dt;
+                    }
+                });
+        } else {
+            let n = self.levels[k].period / self.levels[k - 1].period;
+            for _ in 0..n {
+                self.advance(k - 1, ffield, coords, velocities, masses);
+            }
+        }
+
+        self.update_forces(k, ffield, coords, masses);
+        self.kick(k, dt, velocities, masses);
+    }
+
+    #[inline]
+    fn kick(&self, k: usize, dt: Real, velocities: &mut [Rvec], masses: &[Real]) {
+        Self::half_kick(velocities, &self.levels[k].forces, masses, dt);
+    }
+
+    fn update_forces(&mut self, k: usize, ffield: &Forces, coords: &[Rvec], masses: &[Real]) {
+        let mollify = self.splitting == RespaSplitting::Mollified && k > 0;
+        if mollify {
+            self.mollify(k, ffield, coords, masses);
+        }
+        let level = &mut self.levels[k];
+        let positions = if mollify { &level.average } else { coords };
+        level.forces.fill([0.0; DIM]);
+        level.energies = level
+            .groups
+            .iter()
+            .map(|&group| ffield.calc_group(group, positions, &mut level.forces))
+            .collect();
+    }
+
+    // Averages the positions along a trajectory of one period of level `k`
+    // that starts at rest from `coords` and is driven by the faster levels.
+    fn mollify(&mut self, k: usize, ffield: &Forces, coords: &[Rvec], masses: &[Real]) {
+        let n = self.levels[k].period / self.levels[0].period;
+        let dt = self.dt * self.levels[0].period as Real;
+
+        self.aux_coords.copy_from_slice(coords);
+        self.aux_velocities.fill([0.0; DIM]);
+        self.fast_forces(k, ffield);
+        let mut average = coords.to_vec();
+        for _ in 0..n {
+            Self::half_kick(&mut self.aux_velocities, &self.aux_forces, masses, dt);
+            self.aux_coords
+                .iter_mut()
+                .zip(self.aux_velocities.iter())
+                .for_each(|(crd, vel)| {
+                    for d in 0..DIM {
+                        crd[d] += vel[d] * dt;
+                    }
+                });
+            self.fast_forces(k, ffield);
+            Self::half_kick(&mut self.aux_velocities, &self.aux_forces, masses, dt);
+            average
+                .iter_mut()
+                .zip(self.aux_coords.iter())
+                .for_each(|(a, c)| {
+                    for d in 0..DIM {
+                        a[d] += c[d];
+                    }
+                });
+        }
+        let norm = ((n + 1) as Real).recip();
+        average.iter_mut().flatten().for_each(|a| *a *= norm);
+        self.levels[k].average = average;
+    }
+
+    fn fast_forces(&mut self, k: usize, ffield: &Forces) {
+        self.aux_forces.fill([0.0; DIM]);
+        for level in &self.levels[..k] {
+            for &group in &level.groups {
+                ffield.calc_group(group, &self.aux_coords, &mut self.aux_forces);
+            }
+        }
+    }
+
+    #[inline]
+    fn half_kick(velocities: &mut [Rvec], forces: &[Rvec], masses: &[Real], dt: Real) {
+        velocities
+            .iter_mut()
+            .zip(forces.iter())
+            .zip(masses.iter())
+            .for_each(|((vel, frc), mass)| {
+                for d in 0..DIM {
+                    vel[d] += 0.5 * frc[d] / mass * dt;
+                }
+            });
+    }
+}
+
+// The forces of the slower levels were evaluated at earlier steps and cannot
+// be recomputed from the current positions, so they are stored with their
+// energies.
+impl Checkpointable for Respa {
+    fn save_state(&self, cpt: &mut Checkpoint) {
+        cpt.step = self.step;
+        cpt.time = self.time;
+        let mut values = vec![if self.initialized { 1.0 } else { 0.0 }];
+        for level in &self.levels {
+            values.extend(level.energies.iter().copied().map(to_f64));
+            values.extend(level.forces.iter().flatten().copied().map(to_f64));
+        }
+        cpt.set_state("respa", values);
+    }
+
+    fn restore_state(&mut self, cpt: &Checkpoint) {
+        let values = cpt.get_state("respa");
+        let n: usize = self
+            .levels
+            .iter()
+            .map(|l| l.groups.len() + DIM * l.forces.len())
+            .sum();
+        if values.len() != 1 + n {
+            panic!("Checkpoint does not match the RESPA levels of the integrator");
+        }
+        self.step = cpt.step;
+        self.time = cpt.time;
+        self.initialized = values[0] != 0.0;
+        let mut values = values[1..].iter().map(|&x| x as Real);
+        for level in self.levels.iter_mut() {
+            level
+                .energies
+                .iter_mut()
+                .for_each(|x| *x = values.next().unwrap());
+            level
+                .forces
+                .iter_mut()
+                .flatten()
+                .for_each(|x| *x = values.next().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::verlet::VelocityVerlet;
+    use crate::topology::Topology;
+
+    fn setup() -> (Forces, Vec<Rvec>, Vec<Real>) {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("tri".to_string(), 2, 3);
+        top.add_atom(0, "c", "c1", 1, "TRI", 0.0);
+        top.add_atom(0, "c", "c2", 1, "TRI", 0.0);
+        top.add_atom(0, "c", "c3", 1, "TRI", 0.0);
+        top.add_bonded_interaction(0, "bond_harm 1 2 0.15 250000.0");
+        top.add_bonded_interaction(0, "bond_harm 2 3 0.15 250000.0");
+        top.add_bonded_interaction(0, "angle_harm 1 2 3 109.5 500.0");
+        top.add_bonded_interaction(0, "lj_pair 1 3 0.2 0.5");
+        let coords = vec![
+            [0.0, 0.0, 0.0],
+            [0.16, 0.01, 0.0],
+            [0.2, 0.15, 0.02],
+            [1.0, 0.0, 0.0],
+            [1.14, 0.0, 0.01],
+            [1.22, 0.13, 0.0],
+        ];
+        (Forces::new(&top), coords, vec![12.0; 6])
+    }
+
+    fn total_energy(u: Real, velocities: &[Rvec], masses: &[Real]) -> Real {
+        let ekin: Real = velocities
+            .iter()
+            .zip(masses.iter())
+            .map(|(v, m)| 0.5 * m * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]))
+            .sum();
+        u + ekin
+    }
+
+    #[test]
+    fn it_matches_verlet_with_one_level() {
+        let (ffield, coords, masses) = setup();
+        let groups = ForceGroup::ALL.to_vec();
+        let mut respa = Respa::new(0.0005, 6, &[(groups, 1)], RespaSplitting::Impulse);
+        let mut vv = VelocityVerlet::new(0.0005, 6);
+
+        let (mut x1, mut v1) = (coords.clone(), vec![[0.0; DIM]; 6]);
+        let (mut x2, mut v2) = (coords.clone(), vec![[0.0; DIM]; 6]);
+        let mut f2 = vec![[0.0; DIM]; 6];
+        ffield.calc(&x2, &mut f2);
+        for _ in 0..100 {
+            let u1 = respa.step(&ffield, &mut x1, &mut v1, &masses);
+            let u2 = vv.step(&ffield, &mut x2, &mut f2, &mut v2, &masses);
+            assert!((u1 - u2).abs() <= 1e-3 * u2.abs().max(1.0));
+        }
+        for (a, b) in x1.iter().zip(x2.iter()) {
+            for d in 0..DIM {
+                assert!((a[d] - b[d]).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn it_conserves_energy() {
+        for splitting in [RespaSplitting::Impulse, RespaSplitting::Mollified] {
+            let (ffield, mut coords, masses) = setup();
+            let levels = [
+                (vec![ForceGroup::Bonds], 1),
+                (vec![ForceGroup::Angles, ForceGroup::Torsions], 2),
+                (vec![ForceGroup::Pairs, ForceGroup::Nonbonded], 4),
+            ];
+            let mut respa = Respa::new(0.0002, 6, &levels, splitting);
+            let mut velocities = vec![[0.0; DIM]; 6];
+            let u0 = respa.step(&ffield, &mut coords, &mut velocities, &masses);
+            let e0 = total_energy(u0, &velocities, &masses);
+            for _ in 0..500 {
+                let u = respa.step(&ffield, &mut coords, &mut velocities, &masses);
+                let e = total_energy(u, &velocities, &masses);
+                assert!(
+                    (e - e0).abs() < 0.001 * e0.abs(),
+                    "{:?}: {} vs {}",
+                    splitting,
+                    e,
+                    e0
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_reports_the_unmollified_energy() {
+        let (ffield, mut coords, masses) = setup();
+        assert!(ffield.count(ForceGroup::Pairs) > 0);
+        let levels = [
+            (vec![ForceGroup::Bonds], 1),
+            (vec![ForceGroup::Angles, ForceGroup::Torsions], 2),
+            (vec![ForceGroup::Pairs, ForceGroup::Nonbonded], 4),
+        ];
+        let mut respa = Respa::new(0.0002, 6, &levels, RespaSplitting::Mollified);
+        let mut velocities = vec![[0.0; DIM]; 6];
+        let mut forces = vec![[0.0; DIM]; 6];
+        for _ in 0..20 {
+            let u = respa.step(&ffield, &mut coords, &mut velocities, &masses);
+            let expected = ffield.calc(&coords, &mut forces);
+            assert!(
+                (u - expected).abs() <= 1e-5 * expected.abs(),
+                "{} vs {}",
+                u,
+                expected
+            );
+        }
+        // The slow forces are those of the mollified positions.
+        let mut slow = vec![[0.0; DIM]; 6];
+        ffield.calc_group(ForceGroup::Pairs, &coords, &mut slow);
+        assert_ne!(respa.levels[2].forces, slow);
+
+        // Without the report, the same trajectory and the mollified energy.
+        let (_, mut quiet_coords, _) = setup();
+        let mut quiet = Respa::new(0.0002, 6, &levels, RespaSplitting::Mollified);
+        quiet.report_energy = false;
+        let mut quiet_velocities = vec![[0.0; DIM]; 6];
+        for _ in 0..20 {
+            quiet.step(&ffield, &mut quiet_coords, &mut quiet_velocities, &masses);
+        }
+        assert_eq!((quiet_coords, quiet_velocities), (coords, velocities));
+        assert_ne!(quiet.terms(), respa.terms());
+    }
+}
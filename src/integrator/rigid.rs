@@ -0,0 +1,563 @@
+use super::velocities::{scale_to_temperature, ComMotionRemoval};
+use crate::checkpoint::{Checkpoint, Checkpointable};
+use crate::ffield::{Forces, NGROUPS};
+use crate::linalg::*;
+use crate::{to_f64, Matrix, Real, Rvec, DIM};
+
+// Unit quaternion (q0, q1, q2, q3), with q0 the scalar part.
+pub type Quat = [Real; 4];
+
+// Rotation matrix from the space frame to the body frame.
+pub fn rotation_matrix(q: &Quat) -> Matrix {
+    let [q0, q1, q2, q3] = *q;
+    [
+        [
+            q0 * q0 + q1 * q1 - q2 * q2 - q3 * q3,
+            2.0 * (q1 * q2 + q0 * q3),
+            2.0 * (q1 * q3 - q0 * q2),
+        ],
+        [
+            2.0 * (q1 * q2 - q0 * q3),
+            q0 * q0 - q1 * q1 + q2 * q2 - q3 * q3,
+            2.0 * (q2 * q3 + q0 * q1),
+        ],
+        [
+            2.0 * (q1 * q3 + q0 * q2),
+            2.0 * (q2 * q3 - q0 * q1),
+            q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3,
+        ],
+    ]
+}
+
+// Inverse of `rotation_matrix` for a proper rotation (Shepperd's method).
+pub fn quat_from_matrix(a: &Matrix) -> Quat {
+    let trace = a[0][0] + a[1][1] + a[2][2];
+    let q = if trace >= a[0][0] && trace >= a[1][1] && trace >= a[2][2] {
+        let q0 = 0.5 * (1.0 + trace).sqrt();
+        let f = 0.25 / q0;
+        [
+            q0,
+            (a[1][2] - a[2][1]) * f,
+            (a[2][0] - a[0][2]) * f,
+            (a[0][1] - a[1][0]) * f,
+        ]
+    } else if a[0][0] >= a[1][1] && a[0][0] >= a[2][2] {
+        let q1 = 0.5 * (1.0 + a[0][0] - a[1][1] - a[2][2]).sqrt();
+        let f = 0.25 / q1;
+        [
+            (a[1][2] - a[2][1]) * f,
+            q1,
+            (a[0][1] + a[1][0]) * f,
+            (a[0][2] + a[2][0]) * f,
+        ]
+    } else if a[1][1] >= a[2][2] {
+        let q2 = 0.5 * (1.0 - a[0][0] + a[1][1] - a[2][2]).sqrt();
+        let f = 0.25 / q2;
+        [
+            (a[2][0] - a[0][2]) * f,
+            (a[0][1] + a[1][0]) * f,
+            q2,
+            (a[1][2] + a[2][1]) * f,
+        ]
+    } else {
+        let q3 = 0.5 * (1.0 - a[0][0] - a[1][1] + a[2][2]).sqrt();
+        let f = 0.25 / q3;
+        [
+            (a[0][1] - a[1][0]) * f,
+            (a[0][2] + a[2][0]) * f,
+            (a[1][2] + a[2][1]) * f,
+            q3,
+        ]
+    };
+    normalize(&q)
+}
+
+#[inline]
+fn normalize(q: &Quat) -> Quat {
+    let norm = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    q.map(|x| x / norm)
+}
+
+#[inline]
+fn qdot(a: &Quat, b: &Quat) -> Real {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
+}
+
+// Permutation operators P_k of the NO_SQUISH splitting; S(q) has columns
+// q, P_1 q, P_2 q, P_3 q.
+#[inline]
+fn permute(k: usize, q: &Quat) -> Quat {
+    match k {
+        1 => [-q[1], q[0], q[3], -q[2]],
+        2 => [-q[2], -q[3], q[0], q[1]],
+        3 => [-q[3], q[2], -q[1], q[0]],
+        _ => unreachable!(),
+    }
+}
+
+// S(q) (0, v)
+#[inline]
+fn s_mul(q: &Quat, v: &Rvec) -> Quat {
+    let mut out = [0.0; 4];
+    for k in 1..=DIM {
+        let pk = permute(k, q);
+        for i in 0..4 {
+            out[i] += pk[i] * v[k - 1];
+        }
+    }
+    out
+}
+
+// A set of atoms moving as a rigid body. The orientation is stored as a
+// quaternion with its conjugate momentum p = 2 S(q) (0, I w), as required by
+// the NO_SQUISH integrator (Miller et al., JCP 116, 8649, 2002).
+pub struct RigidBody {
+    pub atoms: Vec<usize>,
+    pub mass: Real,
+    pub inertia: Rvec,
+    pub com: Rvec,
+    pub vcom: Rvec,
+    pub q: Quat,
+    pub p: Quat,
+    body_coords: Vec<Rvec>,
+}
+
+impl RigidBody {
+    // Builds the body from the current atomic positions, which define its
+    // shape, and velocities, which set its linear and angular momentum.
+    pub fn new(
+        atoms: Vec<usize>,
+        coords: &[Rvec],
+        velocities: &[Rvec],
+        masses: &[Real],
+    ) -> RigidBody {
+        let mass = atoms.iter().map(|&i| masses[i]).sum::<Real>();
+        let com = super::velocities::center_of_mass(&atoms, coords, masses);
+
+        let mut tensor = [0.0f64; DIM * DIM];
+        for &i in &atoms {
+            let r = rvsub(&coords[i], &com);
+            let r2 = norm2(&r);
+            for a in 0..DIM {
+                for b in 0..DIM {
+                    let delta = if a == b { r2 } else { 0.0 };
+                    tensor[a * DIM + b] += (masses[i] * (delta - r[a] * r[b])) as f64;
+                }
+            }
+        }
+        let (evals, evecs) = jacobi_eigen(&mut tensor, DIM);
+        // Rows of the rotation matrix are the principal axes.
+        let mut a: Matrix = [[0.0; DIM]; DIM];
+        for k in 0..DIM {
+            for d in 0..DIM {
+                a[k][d] = evecs[d * DIM + k] as Real;
+            }
+        }
+        if det(&a) < 0.0 {
+            a[2] = rmul(&a[2], -1.0);
+        }
+        let q = quat_from_matrix(&a);
+        let a = rotation_matrix(&q);
+        let body_coords = atoms
+            .iter()
+            .map(|&i| mat_vec(&a, &rvsub(&coords[i], &com)))
+            .collect();
+
+        let mut body = RigidBody {
+            atoms,
+            mass,
+            inertia: [evals[0] as Real, evals[1] as Real, evals[2] as Real],
+            com,
+            vcom: [0.0; DIM],
+            q,
+            p: [0.0; 4],
+            body_coords,
+        };
+        body.sync_momenta(coords, velocities, masses);
+        body
+    }
+
+    // Rotational degrees of freedom are 2 for linear bodies.
+    pub fn ndof(&self) -> usize {
+        match self.atoms.len() {
+            1 => DIM,
+            _ if self.is_linear() => DIM + 2,
+            _ => 2 * DIM,
+        }
+    }
+
+    fn is_linear(&self) -> bool {
+        let imax = self.inertia.iter().cloned().fold(0.0, Real::max);
+        self.inertia.iter().any(|&i| i <= 1e-6 * imax)
+    }
+
+    #[inline]
+    fn inv_inertia(&self, k: usize) -> Real {
+        let imax = self.inertia.iter().cloned().fold(0.0, Real::max);
+        if self.inertia[k] > 1e-6 * imax {
+            self.inertia[k].recip()
+        } else {
+            0.0
+        }
+    }
+
+    // Angular velocity in the body frame.
+    pub fn omega(&self) -> Rvec {
+        let mut w = [0.0; DIM];
+        for (k, wk) in w.iter_mut().enumerate() {
+            *wk = 0.5 * qdot(&permute(k + 1, &self.q), &self.p) * self.inv_inertia(k);
+        }
+        w
+    }
+
+    // Sets the center of mass velocity and angular momentum from the atomic
+    // velocities, e.g. after they were modified externally.
+    pub fn sync_momenta(&mut self, coords: &[Rvec], velocities: &[Rvec], masses: &[Real]) {
+        self.vcom = super::velocities::center_of_mass(&self.atoms, velocities, masses);
+        let mut angmom = [0.0; DIM];
+        for &i in &self.atoms {
+            let r = rvsub(&coords[i], &self.com);
+            let v = rvsub(&velocities[i], &self.vcom);
+            angmom = rvadd(&angmom, &rmul(&cross(&r, &v), masses[i]));
+        }
+        let mut lbody = mat_vec(&rotation_matrix(&self.q), &angmom);
+        for (k, l) in lbody.iter_mut().enumerate() {
+            if self.inv_inertia(k) == 0.0 {
+                *l = 0.0;
+            }
+        }
+        self.p = s_mul(&self.q, &lbody).map(|x| 2.0 * x);
+    }
+
+    // Applies half a step of the atomic forces as a force on the center of
+    // mass and a torque.
+    fn kick(&mut self, dt: Real, coords: &[Rvec], forces: &[Rvec]) {
+        let mut ftot = [0.0; DIM];
+        let mut torque = [0.0; DIM];
+        for &i in &self.atoms {
+            ftot = rvadd(&ftot, &forces[i]);
+            torque = rvadd(&torque, &cross(&rvsub(&coords[i], &self.com), &forces[i]));
+        }
+        self.vcom = rvadd(&self.vcom, &rmul(&ftot, 0.5 * dt / self.mass));
+        let tbody = mat_vec(&rotation_matrix(&self.q), &torque);
+        let dp = s_mul(&self.q, &tbody);
+        self.p
+            .iter_mut()
+            .zip(dp.iter())
+            .for_each(|(p, dp)| *p += dt * dp);
+    }
+
+    // Free rotor propagation by symmetric splitting into rotations about the
+    // principal axes.
+    fn rotate(&mut self, dt: Real) {
+        for (k, h) in [(3, 0.5), (2, 0.5), (1, 1.0), (2, 0.5), (3, 0.5)] {
+            let inv = self.inv_inertia(k - 1);
+            if inv == 0.0 {
+                continue;
+            }
+            let pq = permute(k, &self.q);
+            let pp = permute(k, &self.p);
+            let zeta = 0.25 * qdot(&self.p, &pq) * inv * h * dt;
+            let (s, c) = zeta.sin_cos();
+            for i in 0..4 {
+                self.q[i] = c * self.q[i] + s * pq[i];
+                self.p[i] = c * self.p[i] + s * pp[i];
+            }
+        }
+        self.q = normalize(&self.q);
+    }
+
+    // Writes the atomic positions implied by the center of mass and orientation.
+    pub fn update_positions(&self, coords: &mut [Rvec]) {
+        let at = transpose(&rotation_matrix(&self.q));
+        for (&i, b) in self.atoms.iter().zip(self.body_coords.iter()) {
+            coords[i] = rvadd(&self.com, &mat_vec(&at, b));
+        }
+    }
+
+    // Writes the atomic velocities implied by the rigid body motion.
+    pub fn update_velocities(&self, coords: &[Rvec], velocities: &mut [Rvec]) {
+        let at = transpose(&rotation_matrix(&self.q));
+        let w = mat_vec(&at, &self.omega());
+        for &i in &self.atoms {
+            velocities[i] = rvadd(&self.vcom, &cross(&w, &rvsub(&coords[i], &self.com)));
+        }
+    }
+}
+
+// Velocity Verlet for systems made of rigid bodies and flexible atoms.
+// Rigid bodies are translated with velocity Verlet on their center of mass
+// and rotated with NO_SQUISH; the atomic positions and velocities of their
+// members are kept in sync so that the rest of the engine (force field,
+// output, temperature) is unaware of them. Interactions within a rigid body
+// exert no net force or torque and can be omitted from the force field.
+pub struct RigidVerlet {
+    pub dt: Real,
+    pub time: Real,
+    pub step: i32,
+    pub bodies: Vec<RigidBody>,
+    pub comm: Option<ComMotionRemoval>,
+    // Potential energy of each force group at the end of the last step.
+    pub terms: [Real; NGROUPS],
+    flexible: Vec<usize>,
+}
+
+impl RigidVerlet {
+    pub fn new(
+        dt: Real,
+        groups: Vec<Vec<usize>>,
+        coords: &[Rvec],
+        velocities: &[Rvec],
+        masses: &[Real],
+    ) -> RigidVerlet {
+        let mut rigid = vec![false; coords.len()];
+        for &i in groups.iter().flatten() {
+            if rigid[i] {
+                panic!("Atom {} belongs to more than one rigid body", i + 1);
+            }
+            rigid[i] = true;
+        }
+        RigidVerlet {
+            dt,
+            time: 0.0,
+            step: 0,
+            bodies: groups
+                .into_iter()
+                .map(|atoms| RigidBody::new(atoms, coords, velocities, masses))
+                .collect(),
+            comm: None,
+            terms: [0.0; NGROUPS],
+            flexible: (0..coords.len()).filter(|&i| !rigid[i]).collect(),
+        }
+    }
+
+    // Degrees of freedom, before removal of the center of mass motion.
+    pub fn ndof(&self) -> usize {
+        DIM * self.flexible.len() + self.bodies.iter().map(|b| b.ndof()).sum::<usize>()
+    }
+
+    // Replaces the atomic velocities of the bodies by their rigid motion, as
+    // needed for velocities drawn atom by atom, and scales all velocities to
+    // `temp` over `ndof` degrees of freedom.
+    pub fn set_temperature(
+        &mut self,
+        coords: &[Rvec],
+        velocities: &mut [Rvec],
+        masses: &[Real],
+        temp: Real,
+        ndof: usize,
+    ) {
+        for body in self.bodies.iter_mut() {
+            body.sync_momenta(coords, velocities, masses);
+            body.update_velocities(coords, velocities);
+        }
+        scale_to_temperature(velocities, masses, temp, ndof);
+        for body in self.bodies.iter_mut() {
+            body.sync_momenta(coords, velocities, masses);
+        }
+    }
+
+    pub fn step(
+        &mut self,
+        ffield: &Forces,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        velocities: &mut [Rvec],
+        masses: &[Real],
+    ) -> Real {
+        self.time += self.dt;
+        self.step += 1;
+        let dt = self.dt;
+
+        self.kick(coords, forces, velocities, masses);
+        for &i in &self.flexible {
+            for d in 0..DIM {
+                coords[i][d] += velocities[i][d] * dt;
+            }
+        }
+        for body in self.bodies.iter_mut() {
+            for d in 0..DIM {
+                body.com[d] += body.vcom[d] * dt;
+            }
+            body.rotate(dt);
+            body.update_positions(coords);
+        }
+
+        forces.fill([0.0; DIM]);
+        self.terms = ffield.calc_terms(coords, forces);
+        let u = self.terms.iter().sum();
+        self.kick(coords, forces, velocities, masses);
+
+        if let Some(comm) = &self.comm {
+            comm.apply(self.step, coords, velocities, masses);
+            for body in self.bodies.iter_mut() {
+                body.sync_momenta(coords, velocities, masses);
+            }
+        }
+        for body in &self.bodies {
+            body.update_velocities(coords, velocities);
+        }
+        u
+    }
+
+    fn kick(&mut self, coords: &[Rvec], forces: &[Rvec], velocities: &mut [Rvec], masses: &[Real]) {
+        let dt = self.dt;
+        for &i in &self.flexible {
+            for d in 0..DIM {
+                velocities[i][d] += 0.5 * forces[i][d] / masses[i] * dt;
+            }
+        }
+        for body in self.bodies.iter_mut() {
+            body.kick(dt, coords, forces);
+        }
+    }
+}
+
+// Per body: center of mass position and velocity, orientation, its conjugate
+// momentum, and the principal moments and body frame coordinates, which
+// would otherwise be rebuilt from rounded positions on restart.
+impl Checkpointable for RigidVerlet {
+    fn save_state(&self, cpt: &mut Checkpoint) {
+        cpt.step = self.step;
+        cpt.time = self.time;
+        let values = self
+            .bodies
+            .iter()
+            .flat_map(|b| {
+                b.com
+                    .iter()
+                    .chain(&b.vcom)
+                    .chain(&b.q)
+                    .chain(&b.p)
+                    .chain(&b.inertia)
+                    .chain(b.body_coords.iter().flatten())
+            })
+            .copied()
+            .map(to_f64)
+            .collect();
+        cpt.set_state("rigid", values);
+    }
+
+    fn restore_state(&mut self, cpt: &Checkpoint) {
+        let values = cpt.get_state("rigid");
+        let size = |b: &RigidBody| 17 + DIM * b.atoms.len();
+        if values.len() != self.bodies.iter().map(size).sum::<usize>() {
+            panic!(
+                "Checkpoint does not match the {} rigid bodies of the system",
+                self.bodies.len()
+            );
+        }
+        self.step = cpt.step;
+        self.time = cpt.time;
+        let mut values = values.iter().map(|&x| x as Real);
+        for body in self.bodies.iter_mut() {
+            body.com
+                .iter_mut()
+                .chain(&mut body.vcom)
+                .chain(&mut body.q)
+                .chain(&mut body.p)
+                .chain(&mut body.inertia)
+                .chain(body.body_coords.iter_mut().flatten())
+                .for_each(|x| *x = values.next().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::velocities::{kinetic_energy, temperature};
+    use crate::random::Rng;
+    use crate::topology::Topology;
+
+    #[test]
+    fn it_converts_quaternions() {
+        let mut rng = Rng::new(11);
+        for _ in 0..100 {
+            let q = normalize(&[0; 4].map(|_| rng.normal() as Real));
+            let q2 = quat_from_matrix(&rotation_matrix(&q));
+            // q and -q are the same rotation.
+            let sign = qdot(&q, &q2).signum();
+            for i in 0..4 {
+                assert!((q[i] - sign * q2[i]).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn it_conserves_energy() {
+        // A rigid triangle carrying a flexible pendulum atom.
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("frag".to_string(), 1, 3);
+        for name in ["c1", "c2", "c3", "c4"] {
+            top.add_atom(0, "c", name, 1, "FRG", 0.0);
+        }
+        top.add_bonded_interaction(0, "bond_harm 3 4 0.15 250000.0");
+        top.add_bonded_interaction(0, "angle_harm 1 3 4 109.5 500.0");
+        top.add_bonded_interaction(0, "angle_harm 2 3 4 109.5 500.0");
+        let ffield = Forces::new(&top);
+
+        let mut coords = vec![
+            [0.0, 0.0, 0.0],
+            [0.15, 0.0, 0.0],
+            [0.07, 0.13, 0.0],
+            [0.05, 0.2, 0.12],
+        ];
+        let masses = vec![12.0, 14.0, 16.0, 12.0];
+        let mut rng = Rng::new(5);
+        let mut velocities = (0..4)
+            .map(|_| [0; DIM].map(|_| rng.normal() as Real))
+            .collect::<Vec<Rvec>>();
+        let mut forces = vec![[0.0; DIM]; 4];
+
+        let mut integrator =
+            RigidVerlet::new(0.0002, vec![vec![0, 1, 2]], &coords, &velocities, &masses);
+        integrator.bodies[0].update_velocities(&coords, &mut velocities);
+        let u0 = ffield.calc(&coords, &mut forces);
+        let e0 = u0 + kinetic_energy(&velocities, &masses);
+        let d01 = norm2(&rvsub(&coords[0], &coords[1]));
+
+        for _ in 0..5000 {
+            let u = integrator.step(&ffield, &mut coords, &mut forces, &mut velocities, &masses);
+            let e = u + kinetic_energy(&velocities, &masses);
+            assert!((e - e0).abs() < 0.001 * e0, "{} vs {}", e, e0);
+        }
+        assert!((norm2(&rvsub(&coords[0], &coords[1])) - d01).abs() < 1e-5);
+        assert_eq!(integrator.ndof(), 9);
+    }
+
+    #[test]
+    fn it_sets_the_temperature_of_the_rigid_motion() {
+        let coords = vec![
+            [0.0, 0.0, 0.0],
+            [0.15, 0.0, 0.0],
+            [0.07, 0.13, 0.0],
+            [0.3; DIM],
+        ];
+        let masses = vec![12.0, 14.0, 16.0, 12.0];
+        let mut rng = Rng::new(8);
+        let mut velocities = (0..4)
+            .map(|_| [0; DIM].map(|_| rng.normal() as Real))
+            .collect::<Vec<Rvec>>();
+        let mut integrator =
+            RigidVerlet::new(0.001, vec![vec![0, 1, 2]], &coords, &velocities, &masses);
+        integrator.set_temperature(&coords, &mut velocities, &masses, 300.0, 9);
+
+        let temp = temperature(&velocities, &masses, 9);
+        assert!((temp - 300.0).abs() < 1e-3 * 300.0);
+        // No velocity along the bonds of the body, and the body moves with
+        // the atoms.
+        for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+            let dv = rvsub(&velocities[i], &velocities[j]);
+            let dr = rvsub(&coords[i], &coords[j]);
+            assert!(dot(&dv, &dr).abs() < 1e-4 * norm2(&dv).sqrt());
+        }
+        let mut rigid = velocities.clone();
+        integrator.bodies[0].update_velocities(&coords, &mut rigid);
+        for (a, b) in rigid.iter().flatten().zip(velocities.iter().flatten()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::linalg::*;
+use crate::random::Rng;
+use crate::{to_f64, Matrix, Real, Rvec, BOLTZ, DIM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComMotion {
+    None,
+    // Net linear momentum only, as appropriate for periodic systems.
+    Linear,
+    // Linear and angular momentum, for non-periodic systems.
+    Angular,
+}
+
+impl ComMotion {
+    // Degrees of freedom removed from each group.
+    pub fn ndof(&self) -> usize {
+        match self {
+            ComMotion::None => 0,
+            ComMotion::Linear => DIM,
+            ComMotion::Angular => 2 * DIM,
+        }
+    }
+}
+
+// Periodic removal of the center of mass motion of each group of atoms.
+pub struct ComMotionRemoval {
+    pub interval: i32,
+    pub mode: ComMotion,
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl ComMotionRemoval {
+    pub fn new(interval: i32, mode: ComMotion, groups: Vec<Vec<usize>>) -> ComMotionRemoval {
+        ComMotionRemoval {
+            interval,
+            mode,
+            groups,
+        }
+    }
+
+    pub fn apply(&self, step: i32, coords: &[Rvec], velocities: &mut [Rvec], masses: &[Real]) {
+        if self.interval <= 0 || step % self.interval != 0 {
+            return;
+        }
+        for group in &self.groups {
+            match self.mode {
+                ComMotion::None => (),
+                ComMotion::Linear => remove_linear_momentum(group, velocities, masses),
+                ComMotion::Angular => {
+                    remove_linear_momentum(group, velocities, masses);
+                    remove_angular_momentum(group, coords, velocities, masses);
+                }
+            }
+        }
+    }
+}
+
+// Draws velocities from the Maxwell-Boltzmann distribution at `temp`.
+// Massless particles (virtual sites) are left at rest.
+pub fn maxwell_boltzmann(masses: &[Real], temp: Real, rng: &mut Rng) -> Vec<Rvec> {
+    masses
+        .iter()
+        .map(|&m| {
+            if m > 0.0 {
+                let sd = to_f64((BOLTZ * temp / m).sqrt());
+                [0; DIM].map(|_| (sd * rng.normal()) as Real)
+            } else {
+                [0.0; DIM]
+            }
+        })
+        .collect()
+}
+
+// Generates velocities at exactly `temp` for the system: draws from
+// Maxwell-Boltzmann with `seed`, removes the center of mass motion of the
+// whole system according to `comm` and rescales given `ndof`.
+pub fn generate_velocities(
+    coords: &[Rvec],
+    masses: &[Real],
+    temp: Real,
+    seed: u64,
+    comm: ComMotion,
+    ndof: usize,
+) -> Vec<Rvec> {
+    let mut rng = Rng::new(seed);
+    let mut velocities = maxwell_boltzmann(masses, temp, &mut rng);
+    let all = (0..masses.len()).collect::<Vec<usize>>();
+    ComMotionRemoval::new(1, comm, vec![all]).apply(0, coords, &mut velocities, masses);
+    scale_to_temperature(&mut velocities, masses, temp, ndof);
+    velocities
+}
+
+// Number of degrees of freedom of `natoms` atoms subject to `nconstraints`
+// constraints, whose center of mass motion is removed from `ngroups` groups.
+pub fn degrees_of_freedom(
+    natoms: usize,
+    nconstraints: usize,
+    comm: ComMotion,
+    ngroups: usize,
+) -> usize {
+    (DIM * natoms).saturating_sub(nconstraints + comm.ndof() * ngroups)
+}
+
+pub fn kinetic_energy(velocities: &[Rvec], masses: &[Real]) -> Real {
+    velocities
+        .iter()
+        .zip(masses.iter())
+        .map(|(v, m)| to_f64(0.5 * m * norm2(v)))
+        .sum::<f64>() as Real
+}
+
+pub fn temperature(velocities: &[Rvec], masses: &[Real], ndof: usize) -> Real {
+    2.0 * kinetic_energy(velocities, masses) / (ndof as Real * BOLTZ)
+}
+
+pub fn scale_to_temperature(velocities: &mut [Rvec], masses: &[Real], temp: Real, ndof: usize) {
+    let current = temperature(velocities, masses, ndof);
+    if current > 0.0 {
+        let scale = (temp / current).sqrt();
+        velocities.iter_mut().flatten().for_each(|v| *v *= scale);
+    }
+}
+
+pub fn center_of_mass(group: &[usize], coords: &[Rvec], masses: &[Real]) -> Rvec {
+    let mut com = [0.0; DIM];
+    let mut mtot = 0.0;
+    for &i in group {
+        for d in 0..DIM {
+            com[d] += masses[i] * coords[i][d];
+        }
+        mtot += masses[i];
+    }
+    rdiv(&com, mtot)
+}
+
+pub fn remove_linear_momentum(group: &[usize], velocities: &mut [Rvec], masses: &[Real]) {
+    let vcom = center_of_mass(group, velocities, masses);
+    for &i in group {
+        velocities[i] = rvsub(&velocities[i], &vcom);
+    }
+}
+
+pub fn remove_angular_momentum(
+    group: &[usize],
+    coords: &[Rvec],
+    velocities: &mut [Rvec],
+    masses: &[Real],
+) {
+    let com = center_of_mass(group, coords, masses);
+    let mut angmom = [0.0; DIM];
+    let mut inertia: Matrix = [[0.0; DIM]; DIM];
+    for &i in group {
+        let r = rvsub(&coords[i], &com);
+        angmom = rvadd(&angmom, &rmul(&cross(&r, &velocities[i]), masses[i]));
+        let r2 = norm2(&r);
+        for a in 0..DIM {
+            for b in 0..DIM {
+                let delta = if a == b { r2 } else { 0.0 };
+                inertia[a][b] += masses[i] * (delta - r[a] * r[b]);
+            }
+        }
+    }
+    // Linear or single-atom groups have a singular inertia tensor.
+    let trace = inertia[0][0] + inertia[1][1] + inertia[2][2];
+    if det(&inertia).abs() <= 1e-6 * (trace / 3.0).powi(3) {
+        return;
+    }
+    let omega = mat_vec(&mat_inv(&inertia), &angmom);
+    for &i in group {
+        let r = rvsub(&coords[i], &com);
+        velocities[i] = rvsub(&velocities[i], &cross(&omega, &r));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_generates_velocities() {
+        let mut rng = Rng::new(7);
+        let n = 300;
+        let coords = (0..n)
+            .map(|_| [0; DIM].map(|_| rng.uniform() as Real))
+            .collect::<Vec<Rvec>>();
+        let masses = (0..n)
+            .map(|i| [1.008, 12.01, 16.0][i % 3])
+            .collect::<Vec<Real>>();
+        let ndof = degrees_of_freedom(n, 0, ComMotion::Angular, 1);
+        let velocities =
+            generate_velocities(&coords, &masses, 300.0, 1234, ComMotion::Angular, ndof);
+
+        assert!((temperature(&velocities, &masses, ndof) - 300.0).abs() < 1e-2);
+        let all = (0..n).collect::<Vec<usize>>();
+        let vcom = center_of_mass(&all, &velocities, &masses);
+        assert!(norm2(&vcom) < 1e-10);
+        let com = center_of_mass(&all, &coords, &masses);
+        let mut angmom = [0.0; DIM];
+        for i in 0..n {
+            let r = rvsub(&coords[i], &com);
+            angmom = rvadd(&angmom, &rmul(&cross(&r, &velocities[i]), masses[i]));
+        }
+        assert!(norm2(&angmom).sqrt() < 1e-3);
+
+        let again = generate_velocities(&coords, &masses, 300.0, 1234, ComMotion::Angular, ndof);
+        assert_eq!(velocities, again);
+    }
+}
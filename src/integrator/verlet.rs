@@ -1,20 +1,27 @@
 // use rayon::prelude::*;
 
-use crate::{ffield::Forces, system::System, topology::atom::Atom, Rvec, DIM};
+use super::velocities::ComMotionRemoval;
+use crate::checkpoint::{Checkpoint, Checkpointable};
+use crate::{ffield::{Forces, NGROUPS}, Real, Rvec, DIM};
 
 pub struct VelocityVerlet {
-    pub dt: f32,
-    pub time: f32,
+    pub dt: Real,
+    pub time: Real,
     pub step: i32,
+    pub comm: Option<ComMotionRemoval>,
+    // Potential energy of each force group at the end of the last step.
+    pub terms: [Real; NGROUPS],
     cache: Vec<Rvec>,
 }
 
 impl VelocityVerlet {
-    pub fn new(dt: f32, n: usize) -> VelocityVerlet {
+    pub fn new(dt: Real, n: usize) -> VelocityVerlet {
         VelocityVerlet {
             dt,
             time: 0.0,
             step: 0,
+            comm: None,
+            terms: [0.0; NGROUPS],
             cache: vec![[0.0; DIM]; n],
         }
     }
@@ -24,8 +31,8 @@ impl VelocityVerlet {
         coords: &mut [Rvec],
         forces: &mut [Rvec],
         velocities: &mut [Rvec],
-        masses: &[f32],
-    ) -> f32 {
+        masses: &[Real],
+    ) -> Real {
         self.time += self.dt;
         self.step += 1;
 
@@ -38,7 +45,8 @@ impl VelocityVerlet {
 
         self.cache.copy_from_slice(forces);
         forces.fill([0.0; DIM]);
-        let u = ffield.calc(coords, forces);
+        self.terms = ffield.calc_terms(coords, forces);
+        let u = self.terms.iter().sum();
 
         velocities
             .iter_mut()
@@ -48,20 +56,35 @@ impl VelocityVerlet {
             .for_each(|(((vel, frc), frcp), mass)| {
                 Self::update_vel(&self.dt, vel, frc, frcp, mass)
             });
+        if let Some(comm) = &self.comm {
+            comm.apply(self.step, coords, velocities, masses);
+        }
         u
     }
 
     #[inline]
-    fn update_pos(dt: &f32, crd: &mut Rvec, vel: &Rvec, frc: &Rvec, mass: &f32) {
+    fn update_pos(dt: &Real, crd: &mut Rvec, vel: &Rvec, frc: &Rvec, mass: &Real) {
         for i in 0..DIM {
             crd[i] += vel[i] * dt + 0.5 * frc[i] / mass * dt * dt;
         }
     }
 
     #[inline]
-    fn update_vel(dt: &f32, vel: &mut Rvec, frc: &Rvec, frcp: &Rvec, mass: &f32) {
+    fn update_vel(dt: &Real, vel: &mut Rvec, frc: &Rvec, frcp: &Rvec, mass: &Real) {
         for i in 0..DIM {
             vel[i] += 0.5 * (frc[i] + frcp[i]) / mass * dt;
         }
     }
 }
+
+impl Checkpointable for VelocityVerlet {
+    fn save_state(&self, cpt: &mut Checkpoint) {
+        cpt.step = self.step;
+        cpt.time = self.time;
+    }
+
+    fn restore_state(&mut self, cpt: &Checkpoint) {
+        self.step = cpt.step;
+        self.time = cpt.time;
+    }
+}
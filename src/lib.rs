@@ -1,15 +1,71 @@
+pub mod checkpoint;
+pub mod constraints;
+pub mod energy;
 pub mod ffield;
 pub mod linalg;
+pub mod minimizer;
+pub mod params;
+pub mod pbc;
+pub mod random;
+pub mod runinput;
+pub mod simbox;
 pub mod system;
 pub mod topology;
 pub mod trajectory {
+    pub mod amber;
+    pub mod convert;
+    pub mod dcd;
+    pub mod frame;
+    pub mod gro;
+    pub mod pdb;
     pub mod reader;
+    pub mod trr;
     pub mod writer;
+    pub mod xdr;
+    pub mod xtc;
+    pub mod xyz;
 }
 pub mod integrator {
+    pub mod brownian;
+    pub mod respa;
+    pub mod rigid;
+    pub mod velocities;
     pub mod verlet;
 }
 pub mod pme;
 
+// Floating point type of the engine: f32, or f64 with the `double` feature.
+#[cfg(not(feature = "double"))]
+pub type Real = f32;
+#[cfg(feature = "double")]
+pub type Real = f64;
+#[cfg(not(feature = "double"))]
+pub use std::f32::consts;
+#[cfg(feature = "double")]
+pub use std::f64::consts;
+
+// Conversions to the fixed precisions of file formats.
+#[allow(clippy::unnecessary_cast)]
+pub fn to_f32(x: Real) -> f32 {
+    x as f32
+}
+
+#[allow(clippy::unnecessary_cast)]
+pub fn to_f64(x: Real) -> f64 {
+    x as f64
+}
+
+// Path in the temporary directory for a file written by a test, unique to
+// the process and to `name`, as tests run in parallel.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("dynamo_{}_{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}
+
 pub const DIM: usize = 3;
-pub type Rvec = [f32; DIM];
+pub type Rvec = [Real; DIM];
+pub type Matrix = [Rvec; DIM];
+
+// Boltzmann constant in kJ/(mol K).
+pub const BOLTZ: Real = 0.008_314_462;
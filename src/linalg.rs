@@ -1,9 +1,10 @@
-use crate::{Rvec, DIM};
+use crate::simbox::SimBox;
+use crate::{Matrix, Real, Rvec, DIM};
 
-pub const DEG2RAD: f32 = std::f32::consts::PI / 180.0;
+pub const DEG2RAD: Real = crate::consts::PI / 180.0;
 
 #[inline]
-pub fn dot(avec: &Rvec, bvec: &Rvec) -> f32 {
+pub fn dot(avec: &Rvec, bvec: &Rvec) -> Real {
     avec[0] * bvec[0] + avec[1] * bvec[1] + avec[2] * bvec[2]
     // return avec.iter().zip(bvec.iter()).map(|(a, b)| a * b).sum();
 }
@@ -18,7 +19,7 @@ pub fn cross(avec: &Rvec, bvec: &Rvec) -> Rvec {
 }
 
 #[inline]
-pub fn rmul(vec: &Rvec, x: f32) -> Rvec {
+pub fn rmul(vec: &Rvec, x: Real) -> Rvec {
     let mut cvec = [0.0; DIM];
     for i in 0..DIM {
         cvec[i] = vec[i] * x;
@@ -27,7 +28,7 @@ pub fn rmul(vec: &Rvec, x: f32) -> Rvec {
 }
 
 #[inline]
-pub fn rdiv(vec: &Rvec, x: f32) -> Rvec {
+pub fn rdiv(vec: &Rvec, x: Real) -> Rvec {
     let mut cvec = [0.0; DIM];
     for i in 0..DIM {
         cvec[i] = vec[i] / x;
@@ -36,7 +37,7 @@ pub fn rdiv(vec: &Rvec, x: f32) -> Rvec {
 }
 
 #[inline]
-pub fn radd(vec: &Rvec, x: f32) -> Rvec {
+pub fn radd(vec: &Rvec, x: Real) -> Rvec {
     let mut cvec = [0.0; DIM];
     for i in 0..DIM {
         cvec[i] = vec[i] + x;
@@ -45,7 +46,7 @@ pub fn radd(vec: &Rvec, x: f32) -> Rvec {
 }
 
 #[inline]
-pub fn rsub(vec: &Rvec, x: f32) -> Rvec {
+pub fn rsub(vec: &Rvec, x: Real) -> Rvec {
     let mut cvec = [0.0; DIM];
     for i in 0..DIM {
         cvec[i] = vec[i] - x;
@@ -71,33 +72,28 @@ pub fn rvadd(avec: &Rvec, bvec: &Rvec) -> Rvec {
     cvec
 }
 
-// pub fn angle(avec: &Rvec, bvec: &Rvec) -> f32 {
+// pub fn angle(avec: &Rvec, bvec: &Rvec) -> Real {
 //     let x = dot(avec, bvec);
 //     let y = norm2(&cross(avec, bvec)).sqrt();
 //     return y.atan2(x);
 // }
 
 #[inline]
-pub fn norm2(vec: &Rvec) -> f32 {
+pub fn norm2(vec: &Rvec) -> Real {
     dot(vec, vec)
 }
 
+#[deprecated(note = "use SimBox::min_image")]
 #[inline]
 pub fn min_image<'a>(vec: &'a mut Rvec, pbc: &Rvec) -> &'a Rvec {
-    vec.iter_mut().zip(pbc.iter()).for_each(|(v, p)| {
-        let half_box = 0.5 * p;
-        if *v > half_box {
-            *v -= p;
-        } else if *v < -half_box {
-            *v += p;
-        }
-    });
+    *vec = SimBox::rectangular(pbc[0], pbc[1], pbc[2]).min_image(vec);
     vec
 }
 
+#[deprecated(note = "use SimBox::wrap")]
 #[inline]
 pub fn wrap<'a>(vec: &'a mut Rvec, pbc: &Rvec) -> &'a Rvec {
-    vec.iter_mut().zip(pbc.iter()).for_each(|(v, p)| *v %= p);
+    *vec = SimBox::rectangular(pbc[0], pbc[1], pbc[2]).wrap(vec);
     vec
 }
 
@@ -114,6 +110,100 @@ pub fn displace_vec(avec: &Rvec, bvec: &Rvec) -> Rvec {
     cvec
 }
 
+#[inline]
+pub fn det(m: &Matrix) -> Real {
+    dot(&m[0], &cross(&m[1], &m[2]))
+}
+
+#[inline]
+pub fn mat_vec(m: &Matrix, v: &Rvec) -> Rvec {
+    [dot(&m[0], v), dot(&m[1], v), dot(&m[2], v)]
+}
+
+#[inline]
+pub fn transpose(m: &Matrix) -> Matrix {
+    let mut t = [[0.0; DIM]; DIM];
+    for i in 0..DIM {
+        for j in 0..DIM {
+            t[i][j] = m[j][i];
+        }
+    }
+    t
+}
+
+#[inline]
+pub fn mat_mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let bt = transpose(b);
+    let mut c = [[0.0; DIM]; DIM];
+    for i in 0..DIM {
+        for j in 0..DIM {
+            c[i][j] = dot(&a[i], &bt[j]);
+        }
+    }
+    c
+}
+
+// Inverse of a 3x3 matrix from its cofactors.
+#[inline]
+pub fn mat_inv(m: &Matrix) -> Matrix {
+    let inv_det = det(m).recip();
+    let c0 = cross(&m[1], &m[2]);
+    let c1 = cross(&m[2], &m[0]);
+    let c2 = cross(&m[0], &m[1]);
+    let mut inv = [[0.0; DIM]; DIM];
+    for i in 0..DIM {
+        inv[i] = [c0[i] * inv_det, c1[i] * inv_det, c2[i] * inv_det];
+    }
+    inv
+}
+
+// Cyclic Jacobi eigendecomposition of a small symmetric matrix. Returns the
+// eigenvalues and the eigenvectors stored as columns.
+pub fn jacobi_eigen(a: &mut [f64], m: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut v = vec![0.0; m * m];
+    for i in 0..m {
+        v[i * m + i] = 1.0;
+    }
+    for _ in 0..100 {
+        let off = (0..m)
+            .flat_map(|i| (0..m).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i * m + j].powi(2))
+            .sum::<f64>();
+        if off < 1e-30 {
+            break;
+        }
+        for p in 0..m {
+            for q in p + 1..m {
+                let apq = a[p * m + q];
+                if apq.abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q * m + q] - a[p * m + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = (t * t + 1.0).sqrt().recip();
+                let s = t * c;
+                for k in 0..m {
+                    let (akp, akq) = (a[k * m + p], a[k * m + q]);
+                    a[k * m + p] = c * akp - s * akq;
+                    a[k * m + q] = s * akp + c * akq;
+                }
+                for k in 0..m {
+                    let (apk, aqk) = (a[p * m + k], a[q * m + k]);
+                    a[p * m + k] = c * apk - s * aqk;
+                    a[q * m + k] = s * apk + c * aqk;
+                }
+                for k in 0..m {
+                    let (vkp, vkq) = (v[k * m + p], v[k * m + q]);
+                    v[k * m + p] = c * vkp - s * vkq;
+                    v[k * m + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    ((0..m).map(|i| a[i * m + i]).collect(), v)
+}
+
 // Iterator that return the indices (i, j) corresponding to elements
 // of a lower-triangular matrix with shape n x n.
 pub fn tril_indices_from(n: usize) -> impl Iterator<Item = (usize, usize)> {
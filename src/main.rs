@@ -1,31 +1,811 @@
-use dynamo::ffield::Forces;
+use std::collections::HashMap;
+use std::process;
+
+use dynamo::checkpoint::{Checkpoint, CheckpointWriter, Checkpointable};
+use dynamo::energy::{Energies, EnergyWriter, Observable};
+use dynamo::ffield::nonbonded::Nonbonded;
+use dynamo::ffield::{ForceGroup, Forces, NGROUPS};
+use dynamo::integrator::brownian::BrownianDynamics;
+use dynamo::integrator::respa::{Respa, RespaSplitting};
+use dynamo::integrator::rigid::RigidVerlet;
+use dynamo::integrator::velocities::{self, ComMotionRemoval};
 use dynamo::integrator::verlet::VelocityVerlet;
-use dynamo::system::System;
+use dynamo::minimizer::{self, MinimizerParams, Potential};
+use dynamo::params::{IntegratorKind, RunParams, Thermostat};
+use dynamo::pbc::WrapMode;
+use dynamo::random::Rng;
+use dynamo::runinput::RunInput;
+use dynamo::simbox::SimBox;
+use dynamo::topology::check::TopologyReport;
+use dynamo::topology::index::IndexGroups;
+use dynamo::topology::selection::Selection;
 use dynamo::topology::Topology;
-use dynamo::trajectory::writer::TrajectoryWriter;
-use dynamo::{trajectory, Rvec};
+use dynamo::trajectory::convert::{self, ConvertOptions};
+use dynamo::trajectory::frame::Frame;
+use dynamo::trajectory::reader::{open_trajectory, read_frame};
+use dynamo::trajectory::trr::TrrWriter;
+use dynamo::trajectory::writer::{self, FrameWriter};
+use dynamo::trajectory::xtc::XtcWriter;
+use dynamo::{Real, Rvec, BOLTZ, DIM};
+
+const USAGE: &str = "\
+Usage: dynamo <command> [options]
+
+Commands:
+  preprocess  check and compile a system into a run input file
+              -p <topology> -c <coordinates> -f <parameters> [-n <index>]
+              [-o <run input>]
+  run         molecular or Brownian dynamics
+              -s <run input> [-o <prefix>] [--cpi <checkpoint>] [-nt <threads>]
+  minimize    energy minimization
+              -s <run input> [-o <prefix>] [-nt <threads>]
+  check       validate a topology, and optionally coordinates and parameters
+              -p <topology> [-c <coordinates>] [-f <parameters>]
+  index       write the default index groups of a topology
+              -p <topology> [-o <index>]
+  convert     convert coordinates or trajectories between formats
+              -i <input> -o <output> [-p <topology>] [-n <index>] [--atoms <atoms>]
+              [--stride <n>] [-b <ps>] [-e <ps>] [--wrap atom|residue|molecule]
+              [--center <atoms>] [--precision <1/nm>]
+
+Atoms are index group names (from -n, or the default groups), selections
+such as \"resname ALA and name CA\" (these need -p), or atom numbers from 1,
+as in 1-22,30. tc-grps are looked up in the same groups. convert evaluates
+within selections on the first input frame.
+run and minimize also take -p, -c and -f instead of -s. They use all the
+cores unless -nt says otherwise; the results do not depend on it.
+
+Outputs are named after the prefix (default: dynamo): <prefix>.trr and
+<prefix>.xtc trajectories, <prefix>.ener.csv energies, <prefix>.cpt
+checkpoints and <prefix>.crd final coordinates.
+";
+
+struct Args {
+    command: String,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Args {
+        let Some(command) = args.first() else {
+            usage_error("missing command");
+        };
+        let mut options = HashMap::new();
+        let mut rest = args[1..].iter();
+        while let Some(flag) = rest.next() {
+            let key = match flag.as_str() {
+                "-p" | "--top" => "top",
+                "-c" | "--coords" => "coords",
+                "-f" | "--params" => "params",
+                "-o" | "--output" => "output",
+                "-i" | "--input" => "input",
+                "-s" | "--run-input" => "runinput",
+                "--cpi" => "cpi",
+                "-nt" | "--nthreads" => "nthreads",
+                "-n" | "--index" => "index",
+                "--atoms" => "atoms",
+                "--stride" => "stride",
+                "-b" | "--begin" => "begin",
+                "-e" | "--end" => "end",
+                "--wrap" => "wrap",
+                "--center" => "center",
+                "--precision" => "precision",
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    process::exit(0)
+                }
+                _ => usage_error(&format!("unknown option {}", flag)),
+            };
+            let Some(value) = rest.next() else {
+                usage_error(&format!("missing value for {}", flag));
+            };
+            options.insert(key.to_string(), value.clone());
+        }
+        Args {
+            command: command.clone(),
+            options,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(|s| s.as_str())
+    }
+
+    fn require(&self, key: &str) -> &str {
+        self.get(key)
+            .unwrap_or_else(|| usage_error(&format!("{} needs a {} file", self.command, key)))
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("dynamo: {}\n\n{}", message, USAGE);
+    process::exit(2)
+}
+
+fn error(message: &str) -> ! {
+    eprintln!("dynamo: {}", message);
+    process::exit(1)
+}
+
+fn read_params(filename: &str) -> RunParams {
+    let text = std::fs::read_to_string(filename)
+        .unwrap_or_else(|e| error(&format!("{}: {}", filename, e)));
+    RunParams::parse(&text).unwrap_or_else(|mut e| {
+        e.filename = filename.to_string();
+        error(&e.to_string())
+    })
+}
+
+// The run input given with -s, or compiled on the fly from the topology,
+// coordinates and parameters.
+fn load_input(args: &Args) -> RunInput {
+    match args.get("runinput") {
+        Some(filename) => RunInput::read(filename),
+        None => compile(args),
+    }
+}
+
+fn compile(args: &Args) -> RunInput {
+    let params = read_params(args.require("params"));
+    let top = Topology::read(args.require("top"));
+    let index = index_groups(args, &top);
+    if params.tcoupl != Thermostat::No {
+        group_atoms("tc-grps", &params.tc_grps, &index, &top, true);
+    }
+    let comm_groups = group_atoms("comm-grps", &params.comm_grps, &index, &top, false);
+    RunInput::new(
+        &top,
+        read_frame(args.require("coords")),
+        params,
+        comm_groups,
+    )
+}
+
+// The groups of the index file given with -n, or the default groups.
+fn index_groups(args: &Args, top: &Topology) -> IndexGroups {
+    match args.get("index") {
+        Some(filename) => {
+            let index = IndexGroups::read(filename);
+            index.validate(top.hierarchy().natoms());
+            index
+        }
+        None => IndexGroups::defaults(top),
+    }
+}
+
+// The atoms of the groups named by the parameter `key`, which must exist and
+// contain each atom at most once, or exactly once with `cover`.
+fn group_atoms(
+    key: &str,
+    names: &[String],
+    index: &IndexGroups,
+    top: &Topology,
+    cover: bool,
+) -> Vec<Vec<usize>> {
+    let natoms = top.hierarchy().natoms();
+    let mut count = vec![0; natoms];
+    let mut groups = Vec::new();
+    for name in names {
+        let Some(atoms) = index.get(name) else {
+            error(&format!(
+                "unknown {} group {} (groups: {})",
+                key,
+                name,
+                index.names().join(", ")
+            ));
+        };
+        for &i in atoms {
+            if i >= natoms {
+                error(&format!(
+                    "{} group {} has atom {}, the topology has {} atoms",
+                    key,
+                    name,
+                    i + 1,
+                    natoms
+                ));
+            }
+            count[i] += 1;
+        }
+        groups.push(atoms.to_vec());
+    }
+    let wrong = |&c: &usize| if cover { c != 1 } else { c > 1 };
+    if let Some(i) = count.iter().position(wrong) {
+        error(&format!(
+            "{} must contain every atom {}, atom {} is in {} groups",
+            key,
+            if cover { "once" } else { "at most once" },
+            i + 1,
+            count[i]
+        ));
+    }
+    groups
+}
+
+fn index(args: &Args) {
+    let top = Topology::read(args.require("top"));
+    let output = args.get("output").unwrap_or("index.ndx");
+    let index = IndexGroups::defaults(&top);
+    index.write(output);
+    for (name, atoms) in &index.groups {
+        println!("  {:<16} {:>8} atoms", name, atoms.len());
+    }
+    println!("Wrote {} groups to {}", index.groups.len(), output);
+}
 
 fn main() {
-    //rayon::ThreadPoolBuilder::new().num_threads(1).build_global().unwrap();
-    //let mut sys = System::read("tests/diala.top");
-    let top = Topology::read("tests/diala.top");
-    let (mut pbc, mut coords) = trajectory::reader::read_coords("tests/diala.crd");
-    let mut forces = vec![[0.0; 3]; coords.len()];
-    let mut velocities = vec![[0.0; 3]; coords.len()];
-
-    let atoms = top.get_atoms();
-    let masses = atoms.iter().map(|a| a.mass).collect::<Vec<f32>>();
-    let charges = atoms.iter().map(|a| a.charge).collect::<Vec<f32>>();
-
-    let ffield = Forces::new(&top);
-    let mut vv = VelocityVerlet::new(0.0000001, coords.len());
-    let mut traj = TrajectoryWriter::new("tests/simple.traj", 1, false);
-    let mut u = 0.0;
-    (0..1000).for_each(|_| {
-        u = vv.step(&ffield, &mut coords, &mut forces, &mut velocities, &masses);
-        if vv.step % traj.interval == 0 {
-            traj.write(&coords, &vv.time);
+    let args = Args::parse(&std::env::args().skip(1).collect::<Vec<String>>());
+    match args.command.as_str() {
+        "preprocess" => preprocess(&args),
+        "run" => run(&args),
+        "minimize" => minimize(&args),
+        "check" => check(&args),
+        "index" => index(&args),
+        "convert" => convert(&args),
+        "help" => print!("{}", USAGE),
+        cmd => usage_error(&format!("unknown command {}", cmd)),
+    }
+}
+
+enum Integrator {
+    Verlet(VelocityVerlet),
+    Respa(Respa),
+    Rigid(RigidVerlet),
+    Brownian(BrownianDynamics),
+}
+
+impl Integrator {
+    // Returns the energy terms of the force evaluation of the step, which
+    // are those of the new positions.
+    fn step(
+        &mut self,
+        ffield: &Forces,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        velocities: &mut [Rvec],
+        masses: &[Real],
+    ) -> [Real; NGROUPS] {
+        match self {
+            Integrator::Verlet(vv) => {
+                vv.step(ffield, coords, forces, velocities, masses);
+                vv.terms
+            }
+            Integrator::Respa(respa) => {
+                respa.step(ffield, coords, velocities, masses);
+                forces.fill([0.0; DIM]);
+                respa.forces(forces);
+                respa.terms()
+            }
+            Integrator::Rigid(rigid) => {
+                rigid.step(ffield, coords, forces, velocities, masses);
+                rigid.terms
+            }
+            Integrator::Brownian(bd) => {
+                bd.step(ffield, coords, forces);
+                bd.terms
+            }
+        }
+    }
+
+    fn step_time(&self) -> (i32, Real) {
+        match self {
+            Integrator::Verlet(vv) => (vv.step, vv.time),
+            Integrator::Respa(respa) => (respa.step, respa.time),
+            Integrator::Rigid(rigid) => (rigid.step, rigid.time),
+            Integrator::Brownian(bd) => (bd.step, bd.time),
+        }
+    }
+
+    fn set_time(&mut self, time: Real) {
+        match self {
+            Integrator::Verlet(vv) => vv.time = time,
+            Integrator::Respa(respa) => respa.time = time,
+            Integrator::Rigid(rigid) => rigid.time = time,
+            Integrator::Brownian(bd) => bd.time = time,
+        }
+    }
+
+    fn set_comm(&mut self, comm: ComMotionRemoval) {
+        match self {
+            Integrator::Verlet(vv) => vv.comm = Some(comm),
+            Integrator::Respa(respa) => respa.comm = Some(comm),
+            Integrator::Rigid(rigid) => rigid.comm = Some(comm),
+            Integrator::Brownian(_) => (),
+        }
+    }
+
+    // Whether the next step must report the energy of the actual positions.
+    fn report_energy(&mut self, report: bool) {
+        if let Integrator::Respa(respa) = self {
+            respa.report_energy = report;
+        }
+    }
+
+    fn state(&self) -> &dyn Checkpointable {
+        match self {
+            Integrator::Verlet(vv) => vv,
+            Integrator::Respa(respa) => respa,
+            Integrator::Rigid(rigid) => rigid,
+            Integrator::Brownian(bd) => bd,
+        }
+    }
+
+    fn state_mut(&mut self) -> &mut dyn Checkpointable {
+        match self {
+            Integrator::Verlet(vv) => vv,
+            Integrator::Respa(respa) => respa,
+            Integrator::Rigid(rigid) => rigid,
+            Integrator::Brownian(bd) => bd,
+        }
+    }
+}
+
+fn preprocess(args: &Args) {
+    let input = compile(args);
+    let output = args.get("output").unwrap_or("dynamo.rin");
+    input.write(output);
+    println!(
+        "Wrote {} atoms in {} molecule types to {}",
+        input.frame.natoms(),
+        input.topology.molecules().len(),
+        output
+    );
+}
+
+fn run(args: &Args) {
+    let RunInput {
+        params,
+        topology: top,
+        frame,
+        comm_groups,
+    } = load_input(args);
+    if params.integrator.is_minimizer() {
+        error("the integrator is a minimizer, use `dynamo minimize`");
+    }
+    let ffield = force_field(args, &top, &params, frame.simbox.as_ref());
+    let masses = top.atoms().map(|a| a.mass).collect::<Vec<Real>>();
+    let prefix = args.get("output").unwrap_or("dynamo");
+    let natoms = frame.natoms();
+    let simbox = frame.simbox;
+    let mut coords = frame.positions;
+    let mut forces = vec![[0.0; DIM]; natoms];
+
+    let comm = ComMotionRemoval::new(params.nstcomm, params.comm_mode, comm_groups);
+    let ndof_comm = |ndof: usize| ndof.saturating_sub(params.comm_mode.ndof() * comm.groups.len());
+    // Generated velocities are scaled to gen-temp once the integrator tells
+    // the degrees of freedom.
+    let mut velocities = match (&frame.velocities, params.gen_vel) {
+        (Some(v), false) => v.clone(),
+        (None, false) => vec![[0.0; DIM]; natoms],
+        (_, true) => {
+            let mut rng = Rng::new(params.gen_seed);
+            let mut v = velocities::maxwell_boltzmann(&masses, params.gen_temp, &mut rng);
+            let groups = comm.groups.clone();
+            ComMotionRemoval::new(1, params.comm_mode, groups).apply(0, &coords, &mut v, &masses);
+            v
+        }
+    };
+
+    let mut integrator = match params.integrator {
+        IntegratorKind::Md => Integrator::Verlet(VelocityVerlet::new(params.dt, natoms)),
+        IntegratorKind::Respa => {
+            let levels = group_levels(&params.respa_periods);
+            Integrator::Respa(Respa::new(
+                params.dt,
+                natoms,
+                &levels,
+                RespaSplitting::Impulse,
+            ))
+        }
+        IntegratorKind::Rigid => Integrator::Rigid(RigidVerlet::new(
+            params.dt,
+            top.rigid_groups(),
+            &coords,
+            &velocities,
+            &masses,
+        )),
+        IntegratorKind::Brownian => {
+            let temp = params.ref_t[0];
+            let fric = vec![params.bd_fric; natoms];
+            Integrator::Brownian(BrownianDynamics::from_friction(
+                params.dt,
+                temp,
+                &fric,
+                &masses,
+                params.gen_seed,
+            ))
+        }
+        _ => unreachable!(),
+    };
+    let ndof = ndof_comm(match &integrator {
+        Integrator::Rigid(rigid) => rigid.ndof(),
+        _ => DIM * natoms,
+    });
+    if params.gen_vel {
+        match &mut integrator {
+            Integrator::Rigid(rigid) => {
+                rigid.set_temperature(&coords, &mut velocities, &masses, params.gen_temp, ndof)
+            }
+            _ => velocities::scale_to_temperature(&mut velocities, &masses, params.gen_temp, ndof),
+        }
+    }
+    if params.comm_mode.ndof() > 0 && params.nstcomm > 0 {
+        integrator.set_comm(comm);
+    }
+
+    let mut terms = [0.0; NGROUPS];
+    let restart = match args.get("cpi") {
+        Some(cpi) => {
+            let cpt = Checkpoint::read(cpi);
+            cpt.restore(
+                integrator.state_mut(),
+                &mut coords,
+                &mut velocities,
+                &mut forces,
+            );
+            println!("Continuing from step {} of {}", cpt.step, cpi);
+            Some(cpt.step)
+        }
+        None => {
+            integrator.set_time(params.tinit);
+            terms = ffield.calc_terms(&coords, &mut forces);
+            None
+        }
+    };
+
+    // On restart the outputs continue after what was written up to the
+    // checkpoint.
+    let mut trr = (params.nstxout > 0 || params.nstvout > 0 || params.nstfout > 0).then(|| {
+        let filename = &format!("{}.trr", prefix);
+        match restart {
+            Some(step) => TrrWriter::append(filename, step),
+            None => TrrWriter::new(filename),
+        }
+    });
+    let mut xtc = (params.nstxout_compressed > 0).then(|| {
+        let (filename, precision) = (&format!("{}.xtc", prefix), params.compressed_x_precision);
+        match restart {
+            Some(step) => XtcWriter::append(filename, step, precision),
+            None => XtcWriter::new(filename, precision),
         }
     });
-    println!("done!")
+    let mut ener = (params.nstenergy > 0).then(|| {
+        let (filename, columns) = (&format!("{}.ener.csv", prefix), Observable::ALL.to_vec());
+        match restart {
+            Some(step) => EnergyWriter::append(filename, columns, params.nstenergy, step),
+            None => EnergyWriter::new(filename, columns, params.nstenergy),
+        }
+    });
+    let cpt = CheckpointWriter::new(&format!("{}.cpt", prefix), params.nstcheckpoint);
+
+    let every = |step: i32, interval: i32| interval > 0 && step % interval == 0;
+    let (start, _) = integrator.step_time();
+    let last = if params.nsteps < 0 {
+        i32::MAX
+    } else {
+        params.nsteps
+    };
+    let mut step = start;
+    loop {
+        let (_, time) = integrator.step_time();
+        let new = step > start || restart.is_none();
+        if new {
+            let (x, v, f) = (
+                every(step, params.nstxout),
+                every(step, params.nstvout),
+                every(step, params.nstfout),
+            );
+            if let (Some(trr), true) = (&mut trr, x || v || f) {
+                trr.write_frame(&Frame {
+                    step,
+                    time,
+                    simbox,
+                    positions: if x { coords.clone() } else { Vec::new() },
+                    velocities: v.then(|| velocities.clone()),
+                    forces: f.then(|| forces.clone()),
+                    ..Default::default()
+                });
+            }
+            if let (Some(xtc), true) = (&mut xtc, every(step, params.nstxout_compressed)) {
+                xtc.write_frame(&Frame {
+                    step,
+                    time,
+                    simbox,
+                    ..Frame::new(coords.clone())
+                });
+            }
+            let log = every(step, params.nstlog);
+            if every(step, params.nstenergy) || log {
+                let energies = Energies::new(
+                    terms,
+                    &coords,
+                    &forces,
+                    &velocities,
+                    &masses,
+                    ndof,
+                    simbox.as_ref(),
+                );
+                if let (Some(ener), true) = (&mut ener, every(step, params.nstenergy)) {
+                    ener.write(step, time, &energies);
+                }
+                if log {
+                    println!(
+                        "step {:>10} time {:>12.4} ps  potential {:>14.4}  kinetic {:>14.4}  T {:>9.2} K",
+                        step,
+                        time,
+                        energies.potential(),
+                        energies.kinetic,
+                        2.0 * energies.kinetic / (ndof.max(1) as Real * BOLTZ),
+                    );
+                }
+            }
+            if step > start {
+                cpt.write(step, || {
+                    Checkpoint::capture(
+                        integrator.state(),
+                        simbox.as_ref(),
+                        &coords,
+                        &velocities,
+                        &forces,
+                    )
+                });
+            }
+        }
+        if step >= last {
+            break;
+        }
+        let next = step + 1;
+        integrator.report_energy(every(next, params.nstenergy) || every(next, params.nstlog));
+        terms = integrator.step(&ffield, &mut coords, &mut forces, &mut velocities, &masses);
+        step = integrator.step_time().0;
+    }
+
+    // Final state, for continuation and further processing.
+    Checkpoint::capture(
+        integrator.state(),
+        simbox.as_ref(),
+        &coords,
+        &velocities,
+        &forces,
+    )
+    .write(&format!("{}.cpt", prefix));
+    write_final(prefix, simbox.as_ref(), &coords);
+    for writer in [
+        trr.as_mut().map(|w| w as &mut dyn FrameWriter),
+        xtc.as_mut().map(|w| w as &mut dyn FrameWriter),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        writer.flush();
+    }
+    if let Some(ener) = &mut ener {
+        ener.flush();
+    }
+}
+
+// The force field of a run, with the nonbonded interactions of the
+// parameters.
+fn force_field(args: &Args, top: &Topology, params: &RunParams, simbox: Option<&SimBox>) -> Forces {
+    let mut ffield = Forces::new(top);
+    ffield.fixed_point = params.fixed_point_forces;
+    ffield.set_threads(parse_option(args, "nthreads").unwrap_or(0));
+    if let Some(simbox) = simbox {
+        if params.rlist > simbox.max_cutoff() {
+            error(&format!(
+                "rlist {} is longer than half the shortest box vector, {}",
+                params.rlist,
+                simbox.max_cutoff()
+            ));
+        }
+    }
+    let nb = Nonbonded::new(top, params.rcoulomb, 0.0);
+    ffield.set_nonbonded(nb, simbox.copied(), params.rlist);
+    ffield
+}
+
+// RESPA levels from the periods of the force groups, in the order of
+// `ForceGroup::ALL`, the nonbonded interactions with the pairs when they
+// have no period of their own.
+fn group_levels(periods: &[usize]) -> Vec<(Vec<ForceGroup>, usize)> {
+    let mut periods = periods.to_vec();
+    if periods.len() == NGROUPS - 1 {
+        periods.push(periods[NGROUPS - 2]);
+    }
+    let mut levels: Vec<(Vec<ForceGroup>, usize)> = Vec::new();
+    for (&group, &period) in ForceGroup::ALL.iter().zip(periods.iter()) {
+        match levels.iter_mut().find(|(_, p)| *p == period) {
+            Some((groups, _)) => groups.push(group),
+            None => levels.push((vec![group], period)),
+        }
+    }
+    levels
+}
+
+fn write_final(prefix: &str, simbox: Option<&SimBox>, coords: &[Rvec]) {
+    match simbox {
+        Some(simbox) => writer::write_coords(&format!("{}.crd", prefix), simbox, coords),
+        None => println!("No box: final coordinates are only in the checkpoint"),
+    }
+}
+
+fn minimize(args: &Args) {
+    let input = load_input(args);
+    let params = &input.params;
+    if !params.integrator.is_minimizer() {
+        error("the integrator is not a minimizer, use `dynamo run`");
+    }
+    let ffield = force_field(args, &input.topology, params, input.frame.simbox.as_ref());
+    let prefix = args.get("output").unwrap_or("dynamo");
+    let mut coords = input.frame.positions.clone();
+    let n = coords.len();
+
+    let mut minimizer: Box<dyn minimizer::Minimizer> = match params.integrator {
+        IntegratorKind::Steep => Box::new(minimizer::SteepestDescent::new(params.emstep, n)),
+        IntegratorKind::Cg => Box::new(minimizer::ConjugateGradient::new(params.emstep, n)),
+        IntegratorKind::Lbfgs => Box::new(minimizer::Lbfgs::new(params.emstep, 10, n)),
+        IntegratorKind::Fire => Box::new(minimizer::Fire::new(params.dt, params.emstep, n)),
+        _ => unreachable!(),
+    };
+    let nsteps = if params.nsteps < 0 {
+        usize::MAX
+    } else {
+        params.nsteps as usize
+    };
+    let pot = Potential::new(&ffield, None);
+    let log = minimizer::minimize(
+        minimizer.as_mut(),
+        &pot,
+        &mut coords,
+        &MinimizerParams::new(nsteps, params.emtol, params.etol),
+    );
+    log.write(&format!("{}.min.log", prefix));
+    let last = log.last();
+    println!(
+        "{} after {} steps: potential {} kJ/mol, largest force {} kJ/mol/nm",
+        if log.converged {
+            "Converged"
+        } else {
+            "Did not converge"
+        },
+        last.step,
+        last.energy,
+        last.fmax
+    );
+    write_final(prefix, input.frame.simbox.as_ref(), &coords);
+}
+
+fn check(args: &Args) {
+    let top = Topology::read(args.require("top"));
+    let hierarchy = top.hierarchy();
+    println!("Topology {}", args.require("top"));
+    for mol in top.molecules() {
+        println!(
+            "  {:<16} {:>6} copies of {:>5} atoms, {} interactions",
+            mol.name,
+            mol.nmols,
+            mol.atoms.len(),
+            mol.bonded_interactions.len()
+        );
+    }
+    println!(
+        "  {} atoms, total mass {} amu",
+        hierarchy.natoms(),
+        top.atoms().map(|a| a.mass).sum::<Real>()
+    );
+    println!(
+        "  {} chains, {} molecules, {} residues",
+        hierarchy.chains.len(),
+        hierarchy.molecules.len(),
+        hierarchy.residues.len()
+    );
+    let report = TopologyReport::new(&top);
+    println!("{}", report);
+    if report.has_errors() {
+        error("the topology has errors");
+    }
+    if let Some(coords) = args.get("coords") {
+        let frame = read_frame(coords);
+        frame.validate(&top);
+        println!("Coordinates {}: {} atoms match", coords, frame.natoms());
+    }
+    if let Some(params) = args.get("params") {
+        read_params(params);
+        println!("Parameters {}: ok", params);
+    }
+}
+
+// Atoms given as an index group name, a selection, or as 1-based atom
+// numbers and ranges such as "1-22,25" when there is no topology.
+fn select_atoms(
+    text: &str,
+    args: &Args,
+    top: Option<&Topology>,
+    frame: &dyn Fn() -> Frame,
+) -> Vec<usize> {
+    if text
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ',' || c == '-')
+    {
+        return parse_atoms(text);
+    }
+    let Some(top) = top else {
+        usage_error("groups and selections need a topology (-p)");
+    };
+    if let Some(atoms) = index_groups(args, top).get(text) {
+        return atoms.to_vec();
+    }
+    let sel =
+        Selection::parse(text).unwrap_or_else(|e| error(&format!("invalid selection: {}", e)));
+    if !sel.needs_positions() {
+        return sel.evaluate(top, None, None);
+    }
+    let frame = frame();
+    if frame.natoms() != top.hierarchy().natoms() {
+        error(&format!(
+            "the selection needs the positions of the {} atoms of the topology, got {}",
+            top.hierarchy().natoms(),
+            frame.natoms()
+        ));
+    }
+    sel.evaluate(top, Some(&frame.positions), frame.simbox.as_ref())
+}
+
+fn parse_atoms(text: &str) -> Vec<usize> {
+    let number = |x: &str| match x.trim().parse::<usize>() {
+        Ok(i) if i > 0 => i - 1,
+        _ => usage_error(&format!("invalid atom number {}", x)),
+    };
+    let mut atoms = Vec::new();
+    for part in text.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => atoms.extend(number(first)..=number(last)),
+            None => atoms.push(number(part)),
+        }
+    }
+    atoms
+}
+
+fn parse_option<T: std::str::FromStr>(args: &Args, key: &str) -> Option<T> {
+    args.get(key).map(|value| {
+        value
+            .parse::<T>()
+            .unwrap_or_else(|_| usage_error(&format!("invalid value for {}: {}", key, value)))
+    })
+}
+
+fn convert(args: &Args) {
+    let input = args.require("input");
+    let output = args.require("output");
+    let top = args.get("top").map(Topology::read);
+    // Distance based selections are evaluated once, on the first frame.
+    let first_frame = || {
+        if convert::TRAJECTORY_FORMATS.contains(&convert::extension(input).as_str()) {
+            open_trajectory(input)
+                .read_frame()
+                .unwrap_or_else(|| error(&format!("no frames in {}", input)))
+        } else {
+            read_frame(input)
+        }
+    };
+    let d = ConvertOptions::new();
+    let options = ConvertOptions {
+        atoms: args
+            .get("atoms")
+            .map(|sel| select_atoms(sel, args, top.as_ref(), &first_frame)),
+        stride: parse_option(args, "stride").unwrap_or(d.stride),
+        begin: parse_option(args, "begin").unwrap_or(d.begin),
+        end: parse_option(args, "end").unwrap_or(d.end),
+        wrap: args.get("wrap").map(|mode| match mode {
+            "atom" => WrapMode::Atom,
+            "residue" => WrapMode::Residue,
+            "molecule" => WrapMode::Molecule,
+            _ => usage_error(&format!("invalid wrap mode {}", mode)),
+        }),
+        center: args
+            .get("center")
+            .map(|sel| select_atoms(sel, args, top.as_ref(), &first_frame)),
+        precision: parse_option(args, "precision").unwrap_or(d.precision),
+    };
+    let nframes = convert::convert(input, output, top.as_ref(), &options);
+    println!("Wrote {} frames to {}", nframes, output);
 }
@@ -0,0 +1,262 @@
+// Energy minimization of the potential defined by `Forces::calc`.
+//
+// Every algorithm implements `Minimizer::step`, which moves the system from
+// the current configuration to a new one and is driven to convergence by
+// `minimize`. Convergence is reached when the largest atomic force drops below
+// `MinimizerParams::fmax` or when an accepted step changes the energy by less
+// than `MinimizerParams::etol`. The default etol of 0 turns the energy test
+// off, so that only fmax decides: a small energy change also comes from a
+// step that had to be cut short, far from the minimum.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::constraints::Constraints;
+use crate::ffield::Forces;
+use crate::linalg::norm2;
+use crate::{Real, Rvec, DIM};
+
+pub mod cg;
+pub mod fire;
+pub mod lbfgs;
+pub mod steep;
+
+pub use cg::ConjugateGradient;
+pub use fire::Fire;
+pub use lbfgs::Lbfgs;
+pub use steep::SteepestDescent;
+
+// Potential energy surface seen by the minimizers: the force field plus the
+// (optional) constraints that every trial configuration must satisfy.
+pub struct Potential<'a> {
+    pub ffield: &'a Forces,
+    pub constraints: Option<&'a dyn Constraints>,
+}
+
+impl<'a> Potential<'a> {
+    pub fn new(ffield: &'a Forces, constraints: Option<&'a dyn Constraints>) -> Potential<'a> {
+        Potential {
+            ffield,
+            constraints,
+        }
+    }
+
+    // Returns the potential energy at `coords` and stores the (constrained) forces.
+    pub fn eval(&self, coords: &[Rvec], forces: &mut [Rvec]) -> Real {
+        forces.fill([0.0; DIM]);
+        let u = self.ffield.calc(coords, forces);
+        if let Some(constraints) = self.constraints {
+            constraints.project(coords, forces);
+        }
+        u
+    }
+
+    // Restores the constraints on `coords` after a move from `reference`.
+    pub fn constrain(&self, reference: &[Rvec], coords: &mut [Rvec]) {
+        if let Some(constraints) = self.constraints {
+            constraints.apply(reference, coords);
+        }
+    }
+
+    // Removes the constrained components from a vector field at `coords`.
+    pub fn project(&self, coords: &[Rvec], vectors: &mut [Rvec]) {
+        if let Some(constraints) = self.constraints {
+            constraints.project(coords, vectors);
+        }
+    }
+}
+
+pub trait Minimizer {
+    // Takes one iteration starting from `coords`, where `forces` and `energy`
+    // are up to date. On return `coords` and `forces` describe the new
+    // configuration, whose energy is returned. A rejected step leaves
+    // everything untouched and returns `energy`.
+    fn step(
+        &mut self,
+        pot: &Potential,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        energy: Real,
+    ) -> Real;
+}
+
+pub struct MinimizerParams {
+    pub nsteps: usize,
+    pub fmax: Real,
+    // 0 for convergence on fmax only.
+    pub etol: Real,
+}
+
+impl MinimizerParams {
+    pub fn new(nsteps: usize, fmax: Real, etol: Real) -> MinimizerParams {
+        MinimizerParams { nsteps, fmax, etol }
+    }
+}
+
+impl Default for MinimizerParams {
+    fn default() -> Self {
+        Self::new(50000, 10.0, 0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MinimizationStep {
+    pub step: usize,
+    pub energy: Real,
+    pub fmax: Real,
+}
+
+#[derive(Debug, Default)]
+pub struct MinimizationLog {
+    pub steps: Vec<MinimizationStep>,
+    pub converged: bool,
+}
+
+impl MinimizationLog {
+    pub fn last(&self) -> &MinimizationStep {
+        self.steps.last().unwrap()
+    }
+
+    // Writes energy and maximum force vs iteration, one line per step.
+    pub fn write(&self, filename: &str) {
+        let mut file = BufWriter::new(File::create(filename).unwrap());
+        writeln!(file, "# step energy fmax").unwrap();
+        for s in &self.steps {
+            writeln!(file, "{} {} {}", s.step, s.energy, s.fmax).unwrap();
+        }
+    }
+}
+
+pub fn minimize(
+    minimizer: &mut dyn Minimizer,
+    pot: &Potential,
+    coords: &mut [Rvec],
+    params: &MinimizerParams,
+) -> MinimizationLog {
+    let mut log = MinimizationLog::default();
+    let mut forces = vec![[0.0; DIM]; coords.len()];
+
+    let reference = coords.to_vec();
+    pot.constrain(&reference, coords);
+    let mut u = pot.eval(coords, &mut forces);
+    let mut fmax = max_force(&forces);
+    log.steps.push(MinimizationStep {
+        step: 0,
+        energy: u,
+        fmax,
+    });
+
+    for step in 1..=params.nsteps {
+        if fmax < params.fmax {
+            log.converged = true;
+            break;
+        }
+        let unew = minimizer.step(pot, coords, &mut forces, u);
+        let de = (unew - u).abs();
+        let moved = unew != u;
+        u = unew;
+        fmax = max_force(&forces);
+        log.steps.push(MinimizationStep {
+            step,
+            energy: u,
+            fmax,
+        });
+        if moved && de < params.etol {
+            log.converged = true;
+            break;
+        }
+    }
+    log.converged |= fmax < params.fmax;
+    log
+}
+
+// Largest force magnitude on a single atom.
+pub fn max_force(forces: &[Rvec]) -> Real {
+    forces.iter().map(norm2).fold(0.0, Real::max).sqrt()
+}
+
+#[inline]
+fn vdot(a: &[Rvec], b: &[Rvec]) -> Real {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x[0] * y[0] + x[1] * y[1] + x[2] * y[2])
+        .sum()
+}
+
+// out = x + alpha * d
+#[inline]
+fn displace(out: &mut [Rvec], x: &[Rvec], alpha: Real, d: &[Rvec]) {
+    out.iter_mut()
+        .zip(x.iter())
+        .zip(d.iter())
+        .for_each(|((o, x), d)| {
+            for i in 0..DIM {
+                o[i] = x[i] + alpha * d[i];
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::FrozenAtoms;
+    use crate::topology::Topology;
+
+    // A bent, stretched triatomic chain of three molecules.
+    fn setup() -> (Forces, Vec<Rvec>) {
+        let mut top = Topology::new();
+        top.defaults.comb_rule = "geom".to_string();
+        top.add_atomtype("a".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("tri".to_string(), 3, 3);
+        top.add_atom(0, "a", "a1", 1, "TRI", 0.0);
+        top.add_atom(0, "a", "a2", 1, "TRI", 0.0);
+        top.add_atom(0, "a", "a3", 1, "TRI", 0.0);
+        top.add_bonded_interaction(0, "bond_harm 1 2 0.15 250000.0");
+        top.add_bonded_interaction(0, "bond_harm 2 3 0.15 250000.0");
+        top.add_bonded_interaction(0, "angle_harm 1 2 3 109.5 500.0");
+        let ffield = Forces::new(&top);
+        let coords = (0..3)
+            .flat_map(|m| {
+                let x = m as Real;
+                [[x, 0.0, 0.0], [x + 0.2, 0.01, 0.0], [x + 0.35, 0.1, 0.02]]
+            })
+            .collect();
+        (ffield, coords)
+    }
+
+    fn check(minimizer: &mut dyn Minimizer) {
+        let (ffield, mut coords) = setup();
+        let pot = Potential::new(&ffield, None);
+        let params = MinimizerParams::new(5000, 1.0, 0.0);
+        let log = minimize(minimizer, &pot, &mut coords, &params);
+        assert!(log.converged);
+        assert!(log.last().energy < 1e-3);
+        assert!(log.last().energy < log.steps[0].energy);
+    }
+
+    #[test]
+    fn it_minimizes() {
+        check(&mut SteepestDescent::new(0.01, 9));
+        check(&mut ConjugateGradient::new(0.01, 9));
+        check(&mut Lbfgs::new(0.01, 5, 9));
+        check(&mut Fire::new(0.002, 0.01, 9));
+    }
+
+    #[test]
+    fn it_keeps_frozen_atoms() {
+        let (ffield, mut coords) = setup();
+        let frozen = FrozenAtoms::new(vec![0, 1]);
+        let pot = Potential::new(&ffield, Some(&frozen));
+        let start = coords.clone();
+        let log = minimize(
+            &mut Lbfgs::new(0.01, 5, 9),
+            &pot,
+            &mut coords,
+            &MinimizerParams::new(5000, 1.0, 0.0),
+        );
+        assert!(log.converged);
+        assert_eq!(coords[0], start[0]);
+        assert_eq!(coords[1], start[1]);
+        assert_ne!(coords[2], start[2]);
+    }
+}
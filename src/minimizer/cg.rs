@@ -0,0 +1,118 @@
+use super::{displace, vdot, Minimizer, Potential};
+use crate::linalg::norm2;
+use crate::{Real, Rvec, DIM};
+
+// Polak-Ribière conjugate gradient. The line minimum along the search
+// direction is located by a secant step on the directional derivative,
+// starting from a trial move of at most `max_step` per atom. The direction is
+// reset to steepest descent whenever beta < 0 or the line search fails.
+pub struct ConjugateGradient {
+    pub max_step: Real,
+    dir: Vec<Rvec>,
+    prev_forces: Vec<Rvec>,
+    restart: bool,
+    trial: Vec<Rvec>,
+    trial_forces: Vec<Rvec>,
+    best: Vec<Rvec>,
+    best_forces: Vec<Rvec>,
+}
+
+impl ConjugateGradient {
+    pub fn new(max_step: Real, n: usize) -> ConjugateGradient {
+        ConjugateGradient {
+            max_step,
+            dir: vec![[0.0; DIM]; n],
+            prev_forces: vec![[0.0; DIM]; n],
+            restart: true,
+            trial: vec![[0.0; DIM]; n],
+            trial_forces: vec![[0.0; DIM]; n],
+            best: vec![[0.0; DIM]; n],
+            best_forces: vec![[0.0; DIM]; n],
+        }
+    }
+
+    fn update_direction(&mut self, forces: &[Rvec]) {
+        let beta = if self.restart {
+            0.0
+        } else {
+            let num = forces
+                .iter()
+                .zip(self.prev_forces.iter())
+                .map(|(f, fp)| (0..DIM).map(|d| f[d] * (f[d] - fp[d])).sum::<Real>())
+                .sum::<Real>();
+            (num / vdot(&self.prev_forces, &self.prev_forces)).max(0.0)
+        };
+        self.dir.iter_mut().zip(forces.iter()).for_each(|(d, f)| {
+            for i in 0..DIM {
+                d[i] = f[i] + beta * d[i];
+            }
+        });
+        self.prev_forces.copy_from_slice(forces);
+        self.restart = false;
+    }
+}
+
+impl Minimizer for ConjugateGradient {
+    fn step(
+        &mut self,
+        pot: &Potential,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        energy: Real,
+    ) -> Real {
+        self.update_direction(forces);
+        // Slopes are dU/dalpha along the search direction.
+        let mut slope0 = -vdot(forces, &self.dir);
+        if slope0 >= 0.0 {
+            self.dir.copy_from_slice(forces);
+            slope0 = -vdot(forces, forces);
+        }
+        let dmax = self.dir.iter().map(norm2).fold(0.0, Real::max).sqrt();
+        if dmax == 0.0 {
+            return energy;
+        }
+
+        let alpha = self.max_step / dmax;
+        displace(&mut self.trial, coords, alpha, &self.dir);
+        pot.constrain(coords, &mut self.trial);
+        let u1 = pot.eval(&self.trial, &mut self.trial_forces);
+        let slope1 = -vdot(&self.trial_forces, &self.dir);
+
+        let mut best_u = energy;
+        if u1 < energy {
+            best_u = u1;
+            self.best.copy_from_slice(&self.trial);
+            self.best_forces.copy_from_slice(&self.trial_forces);
+        }
+
+        if slope1 > 0.0 || u1 >= energy {
+            // The minimum is bracketed: interpolate between 0 and alpha.
+            let a = if slope1 > slope0 {
+                alpha * slope0 / (slope0 - slope1)
+            } else {
+                0.5 * alpha
+            };
+            displace(&mut self.trial, coords, a, &self.dir);
+            pot.constrain(coords, &mut self.trial);
+            let u2 = pot.eval(&self.trial, &mut self.trial_forces);
+            if u2 < best_u {
+                best_u = u2;
+                self.best.copy_from_slice(&self.trial);
+                self.best_forces.copy_from_slice(&self.trial_forces);
+            }
+            self.max_step *= (a / alpha).clamp(0.1, 1.0);
+        } else {
+            self.max_step *= 1.2;
+        }
+
+        if best_u < energy {
+            coords.copy_from_slice(&self.best);
+            forces.copy_from_slice(&self.best_forces);
+            best_u
+        } else {
+            self.max_step *= 0.2;
+            self.restart = true;
+            energy
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use super::{vdot, Minimizer, Potential};
+use crate::linalg::norm2;
+use crate::{Real, Rvec, DIM};
+
+const N_MIN: usize = 5;
+const F_INC: Real = 1.1;
+const F_DEC: Real = 0.5;
+const ALPHA_START: Real = 0.1;
+const F_ALPHA: Real = 0.99;
+
+// Fast Inertial Relaxation Engine (Bitzek et al., PRL 97, 170201, 2006).
+// Damped dynamics with unit masses whose velocity is steered towards the
+// force; the time step grows while the power F.v stays positive and the
+// motion is quenched as soon as it turns negative. Every move is accepted.
+pub struct Fire {
+    pub dt: Real,
+    pub dt_max: Real,
+    pub max_step: Real,
+    alpha: Real,
+    npos: usize,
+    velocities: Vec<Rvec>,
+}
+
+impl Fire {
+    pub fn new(dt: Real, max_step: Real, n: usize) -> Fire {
+        Fire {
+            dt,
+            dt_max: 10.0 * dt,
+            max_step,
+            alpha: ALPHA_START,
+            npos: 0,
+            velocities: vec![[0.0; DIM]; n],
+        }
+    }
+}
+
+impl Minimizer for Fire {
+    fn step(
+        &mut self,
+        pot: &Potential,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        _energy: Real,
+    ) -> Real {
+        let power = vdot(forces, &self.velocities);
+        if power > 0.0 {
+            let vnorm = vdot(&self.velocities, &self.velocities).sqrt();
+            let fnorm = vdot(forces, forces).sqrt();
+            let mix = self.alpha * vnorm / fnorm;
+            self.velocities
+                .iter_mut()
+                .zip(forces.iter())
+                .for_each(|(v, f)| {
+                    for i in 0..DIM {
+                        v[i] = (1.0 - self.alpha) * v[i] + mix * f[i];
+                    }
+                });
+            if self.npos > N_MIN {
+                self.dt = (self.dt * F_INC).min(self.dt_max);
+                self.alpha *= F_ALPHA;
+            }
+            self.npos += 1;
+        } else {
+            self.velocities.fill([0.0; DIM]);
+            self.dt *= F_DEC;
+            self.alpha = ALPHA_START;
+            self.npos = 0;
+        }
+
+        self.velocities
+            .iter_mut()
+            .zip(forces.iter())
+            .for_each(|(v, f)| {
+                for i in 0..DIM {
+                    v[i] += f[i] * self.dt;
+                }
+            });
+        pot.project(coords, &mut self.velocities);
+
+        let vmax = self
+            .velocities
+            .iter()
+            .map(norm2)
+            .fold(0.0, Real::max)
+            .sqrt();
+        let dt = if vmax * self.dt > self.max_step {
+            self.max_step / vmax
+        } else {
+            self.dt
+        };
+        let reference = coords.to_vec();
+        coords
+            .iter_mut()
+            .zip(self.velocities.iter())
+            .for_each(|(x, v)| {
+                for i in 0..DIM {
+                    x[i] += v[i] * dt;
+                }
+            });
+        pot.constrain(&reference, coords);
+        pot.eval(coords, forces)
+    }
+}
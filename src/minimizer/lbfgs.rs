@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use super::{displace, max_force, vdot, Minimizer, Potential};
+use crate::linalg::norm2;
+use crate::{Real, Rvec, DIM};
+
+// Limited-memory BFGS with the two-loop recursion over the last `m`
+// position/gradient differences and a backtracking (Armijo) line search.
+// No atom moves more than `max_step` in a single iteration.
+pub struct Lbfgs {
+    pub max_step: Real,
+    m: usize,
+    s: VecDeque<Vec<Rvec>>,
+    y: VecDeque<Vec<Rvec>>,
+    rho: VecDeque<Real>,
+    dir: Vec<Rvec>,
+    alpha: Vec<Real>,
+    trial: Vec<Rvec>,
+    trial_forces: Vec<Rvec>,
+}
+
+impl Lbfgs {
+    pub fn new(max_step: Real, m: usize, n: usize) -> Lbfgs {
+        Lbfgs {
+            max_step,
+            m,
+            s: VecDeque::with_capacity(m),
+            y: VecDeque::with_capacity(m),
+            rho: VecDeque::with_capacity(m),
+            dir: vec![[0.0; DIM]; n],
+            alpha: vec![0.0; m],
+            trial: vec![[0.0; DIM]; n],
+            trial_forces: vec![[0.0; DIM]; n],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.s.clear();
+        self.y.clear();
+        self.rho.clear();
+    }
+
+    // dir = -H * g = H * forces
+    fn update_direction(&mut self, forces: &[Rvec]) {
+        self.dir.copy_from_slice(forces);
+        let k = self.s.len();
+        for i in (0..k).rev() {
+            let a = self.rho[i] * vdot(&self.s[i], &self.dir);
+            self.alpha[i] = a;
+            axpy(&mut self.dir, -a, &self.y[i]);
+        }
+        if k > 0 {
+            let gamma = vdot(&self.s[k - 1], &self.y[k - 1]) / vdot(&self.y[k - 1], &self.y[k - 1]);
+            self.dir.iter_mut().flatten().for_each(|x| *x *= gamma);
+        }
+        for i in 0..k {
+            let b = self.rho[i] * vdot(&self.y[i], &self.dir);
+            axpy(&mut self.dir, self.alpha[i] - b, &self.s[i]);
+        }
+    }
+}
+
+impl Minimizer for Lbfgs {
+    fn step(
+        &mut self,
+        pot: &Potential,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        energy: Real,
+    ) -> Real {
+        if max_force(forces) == 0.0 {
+            return energy;
+        }
+        self.update_direction(forces);
+        pot.project(coords, &mut self.dir);
+        let mut slope = -vdot(forces, &self.dir);
+        if slope >= 0.0 {
+            self.reset();
+            self.dir.copy_from_slice(forces);
+            slope = -vdot(forces, forces);
+        }
+
+        let dmax = self.dir.iter().map(norm2).fold(0.0, Real::max).sqrt();
+        let mut alpha = (self.max_step / dmax).min(1.0);
+        for _ in 0..20 {
+            displace(&mut self.trial, coords, alpha, &self.dir);
+            pot.constrain(coords, &mut self.trial);
+            let u = pot.eval(&self.trial, &mut self.trial_forces);
+            if u < energy && u <= energy + 1e-4 * alpha * slope {
+                if self.s.len() == self.m {
+                    self.s.pop_front();
+                    self.y.pop_front();
+                    self.rho.pop_front();
+                }
+                let s = self
+                    .trial
+                    .iter()
+                    .zip(coords.iter())
+                    .map(|(t, c)| [t[0] - c[0], t[1] - c[1], t[2] - c[2]])
+                    .collect::<Vec<Rvec>>();
+                // y is the gradient difference, i.e. the negated force difference.
+                let y = forces
+                    .iter()
+                    .zip(self.trial_forces.iter())
+                    .map(|(f, t)| [f[0] - t[0], f[1] - t[1], f[2] - t[2]])
+                    .collect::<Vec<Rvec>>();
+                let sy = vdot(&s, &y);
+                if sy > 0.0 {
+                    self.rho.push_back(sy.recip());
+                    self.s.push_back(s);
+                    self.y.push_back(y);
+                }
+                coords.copy_from_slice(&self.trial);
+                forces.copy_from_slice(&self.trial_forces);
+                return u;
+            }
+            alpha *= 0.5;
+        }
+        self.reset();
+        energy
+    }
+}
+
+#[inline]
+fn axpy(y: &mut [Rvec], a: Real, x: &[Rvec]) {
+    y.iter_mut().zip(x.iter()).for_each(|(y, x)| {
+        for i in 0..DIM {
+            y[i] += a * x[i];
+        }
+    });
+}
@@ -0,0 +1,49 @@
+use super::{displace, max_force, Minimizer, Potential};
+use crate::{Real, Rvec, DIM};
+
+// Steepest descent with an adaptive step: every move displaces the atom
+// under the largest force by `max_step`, which grows after an accepted move
+// and shrinks after a rejected one.
+pub struct SteepestDescent {
+    pub max_step: Real,
+    trial: Vec<Rvec>,
+    trial_forces: Vec<Rvec>,
+}
+
+impl SteepestDescent {
+    pub fn new(max_step: Real, n: usize) -> SteepestDescent {
+        SteepestDescent {
+            max_step,
+            trial: vec![[0.0; DIM]; n],
+            trial_forces: vec![[0.0; DIM]; n],
+        }
+    }
+}
+
+impl Minimizer for SteepestDescent {
+    fn step(
+        &mut self,
+        pot: &Potential,
+        coords: &mut [Rvec],
+        forces: &mut [Rvec],
+        energy: Real,
+    ) -> Real {
+        let fmax = max_force(forces);
+        if fmax == 0.0 {
+            return energy;
+        }
+        displace(&mut self.trial, coords, self.max_step / fmax, forces);
+        pot.constrain(coords, &mut self.trial);
+        let u = pot.eval(&self.trial, &mut self.trial_forces);
+
+        if u < energy {
+            coords.copy_from_slice(&self.trial);
+            forces.copy_from_slice(&self.trial_forces);
+            self.max_step *= 1.2;
+            u
+        } else {
+            self.max_step *= 0.2;
+            energy
+        }
+    }
+}
@@ -0,0 +1,837 @@
+// Run input parameters, read from a keyword file in the spirit of GROMACS
+// .mdp files:
+//
+//     ; comment
+//     integrator = md
+//     dt         = 0.002
+//     ref-t      = 300 300
+//
+// Keys are case insensitive and '-' and '_' are interchangeable. Keys that
+// are not given take their default values. Every problem found in the file
+// (unknown keys, malformed values, inconsistent settings) is collected and
+// reported at once.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::integrator::velocities::ComMotion;
+use crate::Real;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegratorKind {
+    // Velocity Verlet.
+    Md,
+    Respa,
+    Rigid,
+    Brownian,
+    Steep,
+    Cg,
+    Lbfgs,
+    Fire,
+}
+
+impl IntegratorKind {
+    pub fn is_minimizer(&self) -> bool {
+        matches!(
+            self,
+            IntegratorKind::Steep
+                | IntegratorKind::Cg
+                | IntegratorKind::Lbfgs
+                | IntegratorKind::Fire
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoulombType {
+    Cutoff,
+    ReactionField,
+    Pme,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Thermostat {
+    No,
+    Berendsen,
+    VRescale,
+    NoseHoover,
+    Langevin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Barostat {
+    No,
+    Berendsen,
+    CRescale,
+    ParrinelloRahman,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintKind {
+    None,
+    HBonds,
+    AllBonds,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunParams {
+    pub integrator: IntegratorKind,
+    // Time step in ps and number of steps.
+    pub dt: Real,
+    pub nsteps: i32,
+    pub tinit: Real,
+    // RESPA periods of the bonds, angles, torsions, pairs and nonbonded
+    // interactions, in steps. The nonbonded interactions take the period of
+    // the pairs when only four are given.
+    pub respa_periods: Vec<usize>,
+    // Friction coefficient of Brownian dynamics, in 1/ps.
+    pub bd_fric: Real,
+
+    // Minimization: convergence on the largest force (kJ/mol/nm) and on the
+    // energy change (off with the default 0), and the initial step size (nm).
+    pub emtol: Real,
+    pub etol: Real,
+    pub emstep: Real,
+
+    // Cutoffs in nm.
+    pub rlist: Real,
+    pub rcoulomb: Real,
+    pub rvdw: Real,
+    pub nstlist: i32,
+    pub coulombtype: CoulombType,
+    pub epsilon_r: Real,
+    pub epsilon_rf: Real,
+    pub fourier_spacing: Real,
+    pub pme_order: usize,
+    pub ewald_rtol: Real,
+    // Sums the forces in fixed point, reproducible whatever the order of
+    // the interactions and the number of threads.
+    pub fixed_point_forces: bool,
+
+    pub tcoupl: Thermostat,
+    pub tc_grps: Vec<String>,
+    pub tau_t: Vec<Real>,
+    pub ref_t: Vec<Real>,
+
+    pub pcoupl: Barostat,
+    pub tau_p: Real,
+    pub ref_p: Real,
+    pub compressibility: Real,
+
+    pub constraints: ConstraintKind,
+    pub shake_tol: Real,
+
+    pub comm_mode: ComMotion,
+    pub nstcomm: i32,
+    // Index groups whose center of mass motion is removed separately.
+    pub comm_grps: Vec<String>,
+
+    pub gen_vel: bool,
+    pub gen_temp: Real,
+    pub gen_seed: u64,
+
+    // Output intervals in steps, 0 disables the output.
+    pub nstxout: i32,
+    pub nstvout: i32,
+    pub nstfout: i32,
+    pub nstxout_compressed: i32,
+    pub compressed_x_precision: Real,
+    pub nstenergy: i32,
+    pub nstlog: i32,
+    pub nstcheckpoint: i32,
+}
+
+impl Default for RunParams {
+    fn default() -> Self {
+        RunParams {
+            integrator: IntegratorKind::Md,
+            dt: 0.001,
+            nsteps: 0,
+            tinit: 0.0,
+            respa_periods: vec![1, 1, 1, 1, 1],
+            bd_fric: 1.0,
+            emtol: 10.0,
+            etol: 0.0,
+            emstep: 0.01,
+            rlist: 1.0,
+            rcoulomb: 1.0,
+            rvdw: 1.0,
+            nstlist: 10,
+            coulombtype: CoulombType::Cutoff,
+            epsilon_r: 1.0,
+            epsilon_rf: 0.0,
+            fourier_spacing: 0.12,
+            pme_order: 4,
+            ewald_rtol: 1e-5,
+            fixed_point_forces: false,
+            tcoupl: Thermostat::No,
+            tc_grps: vec!["System".to_string()],
+            tau_t: Vec::new(),
+            ref_t: Vec::new(),
+            pcoupl: Barostat::No,
+            tau_p: 1.0,
+            ref_p: 1.0,
+            compressibility: 4.5e-5,
+            constraints: ConstraintKind::None,
+            shake_tol: 1e-4,
+            comm_mode: ComMotion::Linear,
+            nstcomm: 100,
+            comm_grps: vec!["System".to_string()],
+            gen_vel: false,
+            gen_temp: 300.0,
+            gen_seed: 1,
+            nstxout: 0,
+            nstvout: 0,
+            nstfout: 0,
+            nstxout_compressed: 0,
+            compressed_x_precision: 1000.0,
+            nstenergy: 1000,
+            nstlog: 1000,
+            nstcheckpoint: 0,
+        }
+    }
+}
+
+// Everything wrong with a parameter file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamsError {
+    pub filename: String,
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} errors in {}:", self.errors.len(), self.filename)?;
+        for error in &self.errors {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+// Names of the values of the choice parameters; the first name is the one
+// written out.
+type Choices<T, const N: usize> = [(&'static [&'static str], T); N];
+
+const INTEGRATORS: Choices<IntegratorKind, 8> = [
+    (&["md", "md-vv"], IntegratorKind::Md),
+    (&["respa"], IntegratorKind::Respa),
+    (&["rigid"], IntegratorKind::Rigid),
+    (&["bd"], IntegratorKind::Brownian),
+    (&["steep"], IntegratorKind::Steep),
+    (&["cg"], IntegratorKind::Cg),
+    (&["l-bfgs", "lbfgs"], IntegratorKind::Lbfgs),
+    (&["fire"], IntegratorKind::Fire),
+];
+
+const COULOMB_TYPES: Choices<CoulombType, 3> = [
+    (&["cut-off", "cutoff"], CoulombType::Cutoff),
+    (&["reaction-field"], CoulombType::ReactionField),
+    (&["pme"], CoulombType::Pme),
+];
+
+const THERMOSTATS: Choices<Thermostat, 5> = [
+    (&["no"], Thermostat::No),
+    (&["berendsen"], Thermostat::Berendsen),
+    (&["v-rescale"], Thermostat::VRescale),
+    (&["nose-hoover"], Thermostat::NoseHoover),
+    (&["langevin"], Thermostat::Langevin),
+];
+
+const BAROSTATS: Choices<Barostat, 4> = [
+    (&["no"], Barostat::No),
+    (&["berendsen"], Barostat::Berendsen),
+    (&["c-rescale"], Barostat::CRescale),
+    (&["parrinello-rahman"], Barostat::ParrinelloRahman),
+];
+
+const CONSTRAINTS: Choices<ConstraintKind, 3> = [
+    (&["none"], ConstraintKind::None),
+    (&["h-bonds"], ConstraintKind::HBonds),
+    (&["all-bonds"], ConstraintKind::AllBonds),
+];
+
+const COMM_MODES: Choices<ComMotion, 3> = [
+    (&["linear"], ComMotion::Linear),
+    (&["angular"], ComMotion::Angular),
+    (&["none", "no"], ComMotion::None),
+];
+
+const BOOLEANS: Choices<bool, 2> = [(&["yes"], true), (&["no"], false)];
+
+const KEYS: [&str; 48] = [
+    "integrator",
+    "dt",
+    "nsteps",
+    "tinit",
+    "respa-periods",
+    "bd-fric",
+    "emtol",
+    "etol",
+    "emstep",
+    "rlist",
+    "rcoulomb",
+    "rvdw",
+    "nstlist",
+    "coulombtype",
+    "epsilon-r",
+    "epsilon-rf",
+    "fourierspacing",
+    "pme-order",
+    "ewald-rtol",
+    "fixed-point-forces",
+    "tcoupl",
+    "tc-grps",
+    "tau-t",
+    "ref-t",
+    "pcoupl",
+    "tau-p",
+    "ref-p",
+    "compressibility",
+    "constraints",
+    "shake-tol",
+    "comm-mode",
+    "nstcomm",
+    "comm-grps",
+    "gen-vel",
+    "gen-temp",
+    "gen-seed",
+    "nstxout",
+    "nstvout",
+    "nstfout",
+    "nstxout-compressed",
+    "compressed-x-precision",
+    "nstenergy",
+    "nstlog",
+    "nstcheckpoint",
+    // Accepted and ignored GROMACS keys.
+    "title",
+    "define",
+    "include",
+    "cutoff-scheme",
+];
+
+struct Entries {
+    values: HashMap<String, (usize, String)>,
+    errors: Vec<String>,
+}
+
+impl Entries {
+    fn take<T, F: Fn(&str) -> Option<T>>(&mut self, key: &str, default: T, parse: F) -> T {
+        match self.values.remove(key) {
+            None => default,
+            Some((lineno, value)) => parse(&value).unwrap_or_else(|| {
+                self.errors.push(format!(
+                    "line {}: invalid value for {}: {}",
+                    lineno, key, value
+                ));
+                default
+            }),
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, key: &str, default: T) -> T {
+        self.take(key, default, |v| v.parse::<T>().ok())
+    }
+
+    fn list<T: FromStr>(&mut self, key: &str, default: Vec<T>) -> Vec<T> {
+        self.take(key, default, |v| {
+            v.split_whitespace()
+                .map(|x| x.parse::<T>().ok())
+                .collect::<Option<Vec<T>>>()
+        })
+    }
+
+    // One of `choices`, given as (names, value) pairs.
+    fn choice<T: Copy>(&mut self, key: &str, default: T, choices: &[(&[&str], T)]) -> T {
+        let Some((lineno, value)) = self.values.remove(key) else {
+            return default;
+        };
+        let value = value.to_lowercase();
+        match choices
+            .iter()
+            .find(|(names, _)| names.contains(&value.as_str()))
+        {
+            Some(&(_, choice)) => choice,
+            None => {
+                let names = choices
+                    .iter()
+                    .map(|(names, _)| names[0])
+                    .collect::<Vec<&str>>();
+                self.errors.push(format!(
+                    "line {}: invalid value for {}: {} (expected one of {})",
+                    lineno,
+                    key,
+                    value,
+                    names.join(", ")
+                ));
+                default
+            }
+        }
+    }
+}
+
+impl RunParams {
+    pub fn new() -> RunParams {
+        RunParams::default()
+    }
+
+    pub fn read(filename: &str) -> RunParams {
+        let text =
+            std::fs::read_to_string(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        Self::parse(&text).unwrap_or_else(|mut e| {
+            e.filename = filename.to_string();
+            panic!("{}", e)
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<RunParams, ParamsError> {
+        let mut entries = Entries {
+            values: HashMap::new(),
+            errors: Vec::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                entries
+                    .errors
+                    .push(format!("line {}: expected key = value: {}", i + 1, line));
+                continue;
+            };
+            let key = key.trim().to_lowercase().replace('_', "-");
+            let value = value.trim().to_string();
+            if !KEYS.contains(&key.as_str()) {
+                let hint = suggest(&key)
+                    .map(|k| format!(", did you mean {}?", k))
+                    .unwrap_or_default();
+                entries
+                    .errors
+                    .push(format!("line {}: unknown parameter {}{}", i + 1, key, hint));
+            } else if let Some((first, _)) = entries.values.get(&key) {
+                entries.errors.push(format!(
+                    "line {}: {} was already set on line {}",
+                    i + 1,
+                    key,
+                    first
+                ));
+            } else {
+                entries.values.insert(key, (i + 1, value));
+            }
+        }
+
+        let d = RunParams::default();
+        let e = &mut entries;
+        let params = RunParams {
+            integrator: e.choice("integrator", d.integrator, &INTEGRATORS),
+            dt: e.number("dt", d.dt),
+            nsteps: e.number("nsteps", d.nsteps),
+            tinit: e.number("tinit", d.tinit),
+            respa_periods: e.list("respa-periods", d.respa_periods),
+            bd_fric: e.number("bd-fric", d.bd_fric),
+            emtol: e.number("emtol", d.emtol),
+            etol: e.number("etol", d.etol),
+            emstep: e.number("emstep", d.emstep),
+            rlist: e.number("rlist", d.rlist),
+            rcoulomb: e.number("rcoulomb", d.rcoulomb),
+            rvdw: e.number("rvdw", d.rvdw),
+            nstlist: e.number("nstlist", d.nstlist),
+            coulombtype: e.choice("coulombtype", d.coulombtype, &COULOMB_TYPES),
+            epsilon_r: e.number("epsilon-r", d.epsilon_r),
+            epsilon_rf: e.number("epsilon-rf", d.epsilon_rf),
+            fourier_spacing: e.number("fourierspacing", d.fourier_spacing),
+            pme_order: e.number("pme-order", d.pme_order),
+            ewald_rtol: e.number("ewald-rtol", d.ewald_rtol),
+            fixed_point_forces: e.choice("fixed-point-forces", d.fixed_point_forces, &BOOLEANS),
+            tcoupl: e.choice("tcoupl", d.tcoupl, &THERMOSTATS),
+            tc_grps: e.list("tc-grps", d.tc_grps),
+            tau_t: e.list("tau-t", d.tau_t),
+            ref_t: e.list("ref-t", d.ref_t),
+            pcoupl: e.choice("pcoupl", d.pcoupl, &BAROSTATS),
+            tau_p: e.number("tau-p", d.tau_p),
+            ref_p: e.number("ref-p", d.ref_p),
+            compressibility: e.number("compressibility", d.compressibility),
+            constraints: e.choice("constraints", d.constraints, &CONSTRAINTS),
+            shake_tol: e.number("shake-tol", d.shake_tol),
+            comm_mode: e.choice("comm-mode", d.comm_mode, &COMM_MODES),
+            nstcomm: e.number("nstcomm", d.nstcomm),
+            comm_grps: e.list("comm-grps", d.comm_grps),
+            gen_vel: e.choice("gen-vel", d.gen_vel, &BOOLEANS),
+            gen_temp: e.number("gen-temp", d.gen_temp),
+            gen_seed: e.number("gen-seed", d.gen_seed),
+            nstxout: e.number("nstxout", d.nstxout),
+            nstvout: e.number("nstvout", d.nstvout),
+            nstfout: e.number("nstfout", d.nstfout),
+            nstxout_compressed: e.number("nstxout-compressed", d.nstxout_compressed),
+            compressed_x_precision: e.number("compressed-x-precision", d.compressed_x_precision),
+            nstenergy: e.number("nstenergy", d.nstenergy),
+            nstlog: e.number("nstlog", d.nstlog),
+            nstcheckpoint: e.number("nstcheckpoint", d.nstcheckpoint),
+        };
+
+        let mut errors = entries.errors;
+        errors.extend(params.check());
+        if errors.is_empty() {
+            Ok(params)
+        } else {
+            Err(ParamsError {
+                filename: String::new(),
+                errors,
+            })
+        }
+    }
+
+    // Consistency checks between parameters.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut require = |ok: bool, message: String| {
+            if !ok {
+                errors.push(message)
+            }
+        };
+        let dynamics = !self.integrator.is_minimizer();
+
+        require(
+            self.nsteps >= -1,
+            "nsteps must be -1 (no limit) or more".into(),
+        );
+        if dynamics {
+            require(
+                self.dt > 0.0,
+                format!("dt must be positive, got {}", self.dt),
+            );
+        } else {
+            require(self.emstep > 0.0, "emstep must be positive".into());
+            require(self.emtol > 0.0, "emtol must be positive".into());
+        }
+        if self.integrator == IntegratorKind::Respa {
+            let p = &self.respa_periods;
+            require(
+                (p.len() == 4 || p.len() == 5) && p.iter().all(|&x| x > 0),
+                "respa-periods needs four or five positive periods: bonds, angles, torsions, \
+                 pairs and nonbonded"
+                    .into(),
+            );
+        }
+        if self.integrator == IntegratorKind::Brownian {
+            require(self.bd_fric > 0.0, "bd-fric must be positive".into());
+            require(
+                !self.ref_t.is_empty(),
+                "Brownian dynamics needs the temperature in ref-t".into(),
+            );
+        }
+
+        require(
+            self.rcoulomb > 0.0 && self.rvdw > 0.0,
+            "cutoffs must be positive".into(),
+        );
+        require(
+            self.rlist >= self.rcoulomb.max(self.rvdw),
+            format!(
+                "rlist ({}) must not be shorter than rcoulomb ({}) and rvdw ({})",
+                self.rlist, self.rcoulomb, self.rvdw
+            ),
+        );
+        require(self.nstlist > 0, "nstlist must be positive".into());
+        require(self.epsilon_r > 0.0, "epsilon-r must be positive".into());
+        if self.coulombtype == CoulombType::Pme {
+            require(
+                self.fourier_spacing > 0.0,
+                "fourierspacing must be positive".into(),
+            );
+            require(
+                (3..=12).contains(&self.pme_order),
+                format!("pme-order must be between 3 and 12, got {}", self.pme_order),
+            );
+            require(
+                self.ewald_rtol > 0.0 && self.ewald_rtol < 1.0,
+                "ewald-rtol must be between 0 and 1".into(),
+            );
+        }
+
+        if self.tcoupl != Thermostat::No {
+            require(
+                dynamics,
+                "temperature coupling needs a dynamical integrator".into(),
+            );
+            let n = self.tc_grps.len();
+            require(
+                self.tau_t.len() == n && self.ref_t.len() == n,
+                format!(
+                    "tau-t and ref-t need one value for each of the {} tc-grps",
+                    n
+                ),
+            );
+            require(
+                self.tau_t.iter().all(|&t| t > 0.0),
+                "tau-t must be positive".into(),
+            );
+            require(
+                self.ref_t.iter().all(|&t| t >= 0.0),
+                "ref-t must not be negative".into(),
+            );
+        }
+        if self.pcoupl != Barostat::No {
+            require(
+                self.tcoupl != Thermostat::No,
+                "pressure coupling needs temperature coupling".into(),
+            );
+            require(
+                self.tau_p > 0.0 && self.compressibility > 0.0,
+                "tau-p and compressibility must be positive".into(),
+            );
+        }
+        if self.constraints != ConstraintKind::None {
+            require(self.shake_tol > 0.0, "shake-tol must be positive".into());
+        }
+        require(
+            !self.comm_grps.is_empty(),
+            "comm-grps needs at least one group".into(),
+        );
+        if self.gen_vel {
+            require(self.gen_temp >= 0.0, "gen-temp must not be negative".into());
+        }
+        let intervals = [
+            ("nstxout", self.nstxout),
+            ("nstvout", self.nstvout),
+            ("nstfout", self.nstfout),
+            ("nstxout-compressed", self.nstxout_compressed),
+            ("nstenergy", self.nstenergy),
+            ("nstlog", self.nstlog),
+            ("nstcheckpoint", self.nstcheckpoint),
+            ("nstcomm", self.nstcomm),
+        ];
+        for (key, value) in intervals {
+            require(value >= 0, format!("{} must not be negative", key));
+        }
+        require(
+            self.compressed_x_precision > 0.0,
+            "compressed-x-precision must be positive".into(),
+        );
+        let missing = self.unsupported();
+        require(
+            missing.is_empty(),
+            format!("not supported yet: {}", missing.join(", ")),
+        );
+        errors
+    }
+
+    // Settings that are accepted by the parameter file but not yet
+    // implemented by the engine.
+    fn unsupported(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if self.tcoupl != Thermostat::No {
+            missing.push("temperature coupling (tcoupl)");
+        }
+        if self.pcoupl != Barostat::No {
+            missing.push("pressure coupling (pcoupl)");
+        }
+        if self.constraints != ConstraintKind::None {
+            missing.push("bond constraints (constraints)");
+        }
+        missing.extend(self.unsupported_nonbonded());
+        missing
+    }
+
+    // The nonbonded interactions are plain cut-off Coulomb and LJ with a
+    // single cutoff, under a list that is rebuilt once an atom has moved by
+    // half the buffer rlist - rcoulomb, so the settings of the other
+    // electrostatics and nstlist would be ignored.
+    fn unsupported_nonbonded(&self) -> Vec<&'static str> {
+        let d = RunParams::default();
+        [
+            (self.coulombtype != d.coulombtype, "coulombtype"),
+            (self.rvdw != self.rcoulomb, "rvdw other than rcoulomb"),
+            (self.epsilon_r != d.epsilon_r, "epsilon-r"),
+            (self.epsilon_rf != d.epsilon_rf, "epsilon-rf"),
+            (self.fourier_spacing != d.fourier_spacing, "fourierspacing"),
+            (self.pme_order != d.pme_order, "pme-order"),
+            (self.ewald_rtol != d.ewald_rtol, "ewald-rtol"),
+            (self.nstlist != d.nstlist, "nstlist"),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, key)| key)
+        .collect()
+    }
+}
+
+fn name<T: PartialEq, const N: usize>(choices: &Choices<T, N>, value: T) -> &'static str {
+    choices.iter().find(|(_, v)| *v == value).unwrap().0[0]
+}
+
+fn join<T: fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Writes every parameter as a complete parameter file that parses back to
+// the same values.
+impl fmt::Display for RunParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: [(&str, String); 44] = [
+            ("integrator", name(&INTEGRATORS, self.integrator).into()),
+            ("dt", self.dt.to_string()),
+            ("nsteps", self.nsteps.to_string()),
+            ("tinit", self.tinit.to_string()),
+            ("respa-periods", join(&self.respa_periods)),
+            ("bd-fric", self.bd_fric.to_string()),
+            ("emtol", self.emtol.to_string()),
+            ("etol", self.etol.to_string()),
+            ("emstep", self.emstep.to_string()),
+            ("rlist", self.rlist.to_string()),
+            ("rcoulomb", self.rcoulomb.to_string()),
+            ("rvdw", self.rvdw.to_string()),
+            ("nstlist", self.nstlist.to_string()),
+            ("coulombtype", name(&COULOMB_TYPES, self.coulombtype).into()),
+            ("epsilon-r", self.epsilon_r.to_string()),
+            ("epsilon-rf", self.epsilon_rf.to_string()),
+            ("fourierspacing", self.fourier_spacing.to_string()),
+            ("pme-order", self.pme_order.to_string()),
+            ("ewald-rtol", self.ewald_rtol.to_string()),
+            (
+                "fixed-point-forces",
+                name(&BOOLEANS, self.fixed_point_forces).into(),
+            ),
+            ("tcoupl", name(&THERMOSTATS, self.tcoupl).into()),
+            ("tc-grps", self.tc_grps.join(" ")),
+            ("tau-t", join(&self.tau_t)),
+            ("ref-t", join(&self.ref_t)),
+            ("pcoupl", name(&BAROSTATS, self.pcoupl).into()),
+            ("tau-p", self.tau_p.to_string()),
+            ("ref-p", self.ref_p.to_string()),
+            ("compressibility", self.compressibility.to_string()),
+            ("constraints", name(&CONSTRAINTS, self.constraints).into()),
+            ("shake-tol", self.shake_tol.to_string()),
+            ("comm-mode", name(&COMM_MODES, self.comm_mode).into()),
+            ("nstcomm", self.nstcomm.to_string()),
+            ("comm-grps", self.comm_grps.join(" ")),
+            ("gen-vel", name(&BOOLEANS, self.gen_vel).into()),
+            ("gen-temp", self.gen_temp.to_string()),
+            ("gen-seed", self.gen_seed.to_string()),
+            ("nstxout", self.nstxout.to_string()),
+            ("nstvout", self.nstvout.to_string()),
+            ("nstfout", self.nstfout.to_string()),
+            ("nstxout-compressed", self.nstxout_compressed.to_string()),
+            (
+                "compressed-x-precision",
+                self.compressed_x_precision.to_string(),
+            ),
+            ("nstenergy", self.nstenergy.to_string()),
+            ("nstlog", self.nstlog.to_string()),
+            ("nstcheckpoint", self.nstcheckpoint.to_string()),
+        ];
+        for (key, value) in lines {
+            // Empty lists are left to their defaults.
+            if !value.is_empty() {
+                writeln!(f, "{:<24} = {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Closest known key within an edit distance of 3.
+fn suggest(key: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .map(|k| (edit_distance(key, k), *k))
+        .filter(|&(d, _)| d <= 3)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, k)| k)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let cost = if ca == b[j] { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_run_params() {
+        let params = RunParams::parse(
+            "; NVT run
+integrator = md
+dt         = 0.002
+nsteps     = 5000   ; 10 ps
+tc_grps    = Protein Water
+comm-grps  = Protein Water
+tau-t      = 0.1 0.1
+ref-t      = 300 310
+",
+        )
+        .unwrap();
+        assert_eq!(params.integrator, IntegratorKind::Md);
+        assert_eq!(params.nsteps, 5000);
+        assert_eq!(params.tc_grps, ["Protein", "Water"]);
+        assert_eq!(params.comm_grps, params.tc_grps);
+        assert_eq!(params.ref_t, [300.0, 310.0]);
+        assert_eq!(params.rlist, RunParams::default().rlist);
+        assert_eq!(RunParams::parse(&params.to_string()).unwrap(), params);
+
+        let err = RunParams::parse(
+            "integrator = md
+dt = -1
+nstep = 100
+tcoupl = nose-hover
+rlist = 0.8
+dt = 0.002
+",
+        )
+        .unwrap_err();
+        let expected = [
+            "unknown parameter nstep, did you mean nsteps?",
+            "line 6: dt was already set on line 2",
+            "invalid value for tcoupl: nose-hover",
+            "dt must be positive",
+            "rlist (0.8) must not be shorter",
+        ];
+        for message in expected {
+            assert!(
+                err.errors.iter().any(|e| e.contains(message)),
+                "{} not in {:?}",
+                message,
+                err.errors
+            );
+        }
+
+        // Settings the engine would ignore are rejected with the others.
+        let err = RunParams::parse(
+            "tcoupl = V-rescale
+tc-grps = System
+tau-t = 0.1
+ref-t = 300
+coulombtype = PME
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.errors,
+            ["not supported yet: temperature coupling (tcoupl), coulombtype"]
+        );
+
+        // Plain cut-off takes a single cutoff, and a list buffer.
+        let params = RunParams::parse("rlist = 1.1\nrcoulomb = 0.9\nrvdw = 0.9\n").unwrap();
+        assert_eq!((params.rlist, params.rcoulomb), (1.1, 0.9));
+        let err = RunParams::parse("rvdw = 0.9\n").unwrap_err();
+        assert_eq!(err.errors, ["not supported yet: rvdw other than rcoulomb"]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use crate::integrator::velocities::center_of_mass;
+use crate::linalg::*;
+use crate::simbox::SimBox;
+use crate::topology::Topology;
+use crate::{Real, Rvec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    Atom,
+    // Residues and molecules are made whole and wrapped by center of mass.
+    Residue,
+    Molecule,
+}
+
+// Puts the system in the unit cell of `simbox`, keeping residues or molecules
+// together according to `mode`.
+pub fn wrap(simbox: &SimBox, top: &Topology, coords: &mut [Rvec], mode: WrapMode) {
+    let masses = masses(top);
+    match mode {
+        WrapMode::Atom => coords.iter_mut().for_each(|r| *r = simbox.wrap(r)),
+        WrapMode::Residue => {
+            make_whole(simbox, top, coords);
+            wrap_groups(&top.residue_groups(), coords, &masses, |c| simbox.wrap(c));
+        }
+        WrapMode::Molecule => {
+            make_whole(simbox, top, coords);
+            wrap_groups(&top.molecule_groups(), coords, &masses, |c| simbox.wrap(c));
+        }
+    }
+}
+
+// Reassembles molecules split across the periodic boundaries by walking the
+// bond graph: every atom is put at the minimum image of the atom it is bonded
+// to. Fragments with no bond between them are placed next to the first atom
+// of the molecule.
+pub fn make_whole(simbox: &SimBox, top: &Topology, coords: &mut [Rvec]) {
+    let mut off = 0;
+    for mol in top.molecules() {
+        let natoms = mol.atoms.len();
+        let mut neighbors = vec![Vec::new(); natoms];
+        for [i, j] in mol.bonds() {
+            neighbors[i].push(j);
+            neighbors[j].push(i);
+        }
+        for _ in 0..mol.nmols {
+            let mut visited = vec![false; natoms];
+            let mut queue = VecDeque::new();
+            for root in 0..natoms {
+                if visited[root] {
+                    continue;
+                }
+                if root > 0 {
+                    let r = simbox.displace(&coords[off], &coords[off + root]);
+                    coords[off + root] = rvadd(&coords[off], &r);
+                }
+                visited[root] = true;
+                queue.push_back(root);
+                while let Some(i) = queue.pop_front() {
+                    for &j in &neighbors[i] {
+                        if !visited[j] {
+                            let r = simbox.displace(&coords[off + i], &coords[off + j]);
+                            coords[off + j] = rvadd(&coords[off + i], &r);
+                            visited[j] = true;
+                            queue.push_back(j);
+                        }
+                    }
+                }
+            }
+            off += natoms;
+        }
+    }
+}
+
+// Centers the cluster of atoms `cluster` (e.g. an aggregate of several
+// molecules) in the box and puts every other molecule in the compact unit
+// cell around it, as is customary for trajectory output.
+pub fn center_cluster(simbox: &SimBox, top: &Topology, coords: &mut [Rvec], cluster: &[usize]) {
+    let masses = masses(top);
+    make_whole(simbox, top, coords);
+    let molecules = top.molecule_groups();
+    let mut in_cluster = vec![false; coords.len()];
+    cluster.iter().for_each(|&i| in_cluster[i] = true);
+    let fragments = molecules
+        .iter()
+        .map(|mol| {
+            mol.iter()
+                .cloned()
+                .filter(|&i| in_cluster[i])
+                .collect::<Vec<usize>>()
+        })
+        .filter(|frag| !frag.is_empty())
+        .collect::<Vec<Vec<usize>>>();
+    if fragments.is_empty() {
+        return;
+    }
+
+    // Grow the cluster one fragment at a time, moving each fragment to the
+    // periodic image closest to the center of mass of those already placed,
+    // then refine against the center of mass of the whole cluster.
+    let mut placed = fragments[0].clone();
+    for frag in &fragments[1..] {
+        let com = center_of_mass(&placed, coords, &masses);
+        shift_to_image(simbox, frag, coords, &masses, &com);
+        placed.extend_from_slice(frag);
+    }
+    for _ in 0..10 {
+        let com = center_of_mass(&placed, coords, &masses);
+        let moved = fragments
+            .iter()
+            .filter(|frag| shift_to_image(simbox, frag, coords, &masses, &com))
+            .count();
+        if moved == 0 {
+            break;
+        }
+    }
+
+    let com = center_of_mass(&placed, coords, &masses);
+    let shift = rvsub(&simbox.center(), &com);
+    coords.iter_mut().for_each(|r| *r = rvadd(r, &shift));
+    let center = simbox.center();
+    wrap_groups(&molecules, coords, &masses, |c| {
+        simbox.wrap_compact(c, &center)
+    });
+}
+
+// Moves `group` rigidly to the periodic image whose center of mass is
+// closest to `target`. Returns whether the group was moved.
+fn shift_to_image(
+    simbox: &SimBox,
+    group: &[usize],
+    coords: &mut [Rvec],
+    masses: &[Real],
+    target: &Rvec,
+) -> bool {
+    let com = center_of_mass(group, coords, masses);
+    let image = rvadd(target, &simbox.displace(target, &com));
+    let shift = rvsub(&image, &com);
+    if norm2(&shift) < 1e-12 {
+        return false;
+    }
+    for &i in group {
+        coords[i] = rvadd(&coords[i], &shift);
+    }
+    true
+}
+
+// Translates each group so that `wrap` holds for its center of mass.
+fn wrap_groups<F>(groups: &[Vec<usize>], coords: &mut [Rvec], masses: &[Real], wrap: F)
+where
+    F: Fn(&Rvec) -> Rvec,
+{
+    for group in groups {
+        let com = center_of_mass(group, coords, masses);
+        let shift = rvsub(&wrap(&com), &com);
+        for &i in group {
+            coords[i] = rvadd(&coords[i], &shift);
+        }
+    }
+}
+
+// Atoms with no mass (virtual sites) would make the center of mass
+// undefined for groups made only of them, so fall back to unit weights.
+fn masses(top: &Topology) -> Vec<Real> {
+    let masses = top.atoms().map(|a| a.mass).collect::<Vec<Real>>();
+    if masses.iter().all(|&m| m > 0.0) {
+        masses
+    } else {
+        masses
+            .iter()
+            .map(|&m| if m > 0.0 { m } else { 1.0 })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chains(nmols: usize) -> Topology {
+        let mut top = Topology::new();
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.0, 0.0);
+        top.add_molecule("chain".to_string(), nmols, 3);
+        for (i, name) in ["c1", "c2", "c3", "c4"].iter().enumerate() {
+            top.add_atom(0, "c", name, 1 + i / 2, "CHN", 0.0);
+        }
+        for (i, j) in [(1, 2), (2, 3), (3, 4)] {
+            top.add_bonded_interaction(0, &format!("bond_harm {} {} 0.4 1000.0", i, j));
+        }
+        top
+    }
+
+    #[test]
+    fn it_makes_molecules_whole() {
+        let simbox = SimBox::truncated_octahedron(2.0);
+        let top = chains(2);
+        assert_eq!(top.residue_groups(), [[0, 1], [2, 3], [4, 5], [6, 7]]);
+        let whole = (0..8)
+            .map(|i| [0.4 * (i % 4) as Real + 0.3, 0.5 * (i / 4) as Real, 1.0])
+            .collect::<Vec<Rvec>>();
+
+        let mut coords = whole.clone();
+        wrap(&simbox, &top, &mut coords, WrapMode::Atom);
+        for r in &coords {
+            let s = simbox.to_fractional(r);
+            assert!(s.iter().all(|&x| (-1e-5..1.0 + 1e-5).contains(&x)));
+        }
+        // Scramble with lattice translations, then reassemble.
+        coords[2] = rvadd(&coords[2], &simbox.vectors[2]);
+        coords[5] = rvsub(&coords[5], &simbox.vectors[1]);
+        make_whole(&simbox, &top, &mut coords);
+        for [i, j] in top.bonds() {
+            let d = norm2(&displace_vec(&coords[i], &coords[j])).sqrt();
+            assert!((d - 0.4).abs() < 1e-4);
+        }
+
+        wrap(&simbox, &top, &mut coords, WrapMode::Molecule);
+        let masses = vec![12.0; 8];
+        for group in top.molecule_groups() {
+            let s = simbox.to_fractional(&center_of_mass(&group, &coords, &masses));
+            assert!(s.iter().all(|&x| (-1e-5..1.0 + 1e-5).contains(&x)));
+        }
+
+        center_cluster(&simbox, &top, &mut coords, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        let com = center_of_mass(&(0..8).collect::<Vec<usize>>(), &coords, &masses);
+        assert!(norm2(&rvsub(&com, &simbox.center())) < 1e-8);
+        // Both chains end up at the same relative position as in `whole`.
+        let shift = rvsub(&coords[0], &whole[0]);
+        for (r, w) in coords.iter().zip(whole.iter()) {
+            assert!(norm2(&rvsub(&rvsub(r, w), &shift)) < 1e-8);
+        }
+    }
+}
@@ -1,7 +1,4 @@
 // Write optimized code to run smooth particle mesh ewald (SPME) method using
 // fast fourier transform (FFT) to calculate the electrostatic potential
 
-
 pub struct PME {}
-
-
@@ -0,0 +1,297 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use crate::checkpoint::{
+    checksum, put_i32, put_real, put_simbox, put_string, put_u32, put_u64, Cursor,
+};
+use crate::ffield::Forces;
+use crate::params::RunParams;
+use crate::topology::Topology;
+use crate::trajectory::frame::Frame;
+use crate::{Real, Rvec};
+
+pub const RUN_INPUT_MAGIC: &[u8; 8] = b"DYNAMRIN";
+// Version 1: parameters, defaults, atom types, molecules, starting frame and
+// a checksum of everything before it.
+// Version 2: reals as f64, whatever the precision of the build.
+// Version 3: the atoms of each comm-grps group, after the frame.
+pub const RUN_INPUT_VERSION: u32 = 3;
+
+// Everything a run needs, checked and resolved ahead of time: the parameters,
+// the expanded topology (see `Topology::expand`), the starting frame and the
+// atoms of the index groups named by the parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunInput {
+    pub params: RunParams,
+    pub topology: Topology,
+    pub frame: Frame,
+    // One for each of the comm-grps, in their order.
+    pub comm_groups: Vec<Vec<usize>>,
+}
+
+impl RunInput {
+    // Checks the frame against the topology (atom count and names) and the
+    // interactions against the force field, and expands the topology.
+    pub fn new(
+        top: &Topology,
+        frame: Frame,
+        params: RunParams,
+        comm_groups: Vec<Vec<usize>>,
+    ) -> RunInput {
+        frame.validate(top);
+        let natoms = frame.natoms();
+        if let Some(&i) = comm_groups.iter().flatten().find(|&&i| i >= natoms) {
+            panic!("Atom {} of the comm-grps is not in the system", i + 1);
+        }
+        let topology = top.expand();
+        Forces::new(&topology);
+        RunInput {
+            params,
+            topology,
+            frame,
+            comm_groups,
+        }
+    }
+
+    pub fn masses(&self) -> Vec<Real> {
+        self.topology.atoms().map(|a| a.mass).collect()
+    }
+
+    // Little endian, so that run input files can be moved between machines.
+    pub fn write(&self, filename: &str) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(RUN_INPUT_MAGIC);
+        put_u32(&mut buf, RUN_INPUT_VERSION);
+        put_string(&mut buf, &self.params.to_string());
+
+        let top = &self.topology;
+        let defaults = &top.defaults;
+        put_string(&mut buf, &defaults.nb_func);
+        put_string(&mut buf, &defaults.comb_rule);
+        for scale in [defaults.ljscale, defaults.qqscale] {
+            put_u32(&mut buf, scale.is_some() as u32);
+            put_real(&mut buf, scale.unwrap_or(0.0));
+        }
+
+        let mut atomtypes = top.atomtypes.iter().collect::<Vec<_>>();
+        atomtypes.sort_by(|a, b| a.0.cmp(b.0));
+        put_u32(&mut buf, atomtypes.len() as u32);
+        for (name, params) in atomtypes {
+            put_string(&mut buf, name);
+            put_u32(&mut buf, params.element);
+            put_real(&mut buf, params.mass);
+            put_real(&mut buf, params.v);
+            put_real(&mut buf, params.w);
+        }
+
+        put_u32(&mut buf, top.molecules().len() as u32);
+        for mol in top.molecules() {
+            put_string(&mut buf, &mol.name);
+            put_u32(&mut buf, mol.nmols as u32);
+            put_u32(&mut buf, mol.nbexc as u32);
+            put_u32(&mut buf, mol.rigid as u32);
+            put_u32(&mut buf, mol.atoms.len() as u32);
+            for atom in &mol.atoms {
+                put_string(&mut buf, &atom.atomtype);
+                put_string(&mut buf, &atom.name);
+                put_u32(&mut buf, atom.resnum as u32);
+                put_string(&mut buf, &atom.resname);
+                put_real(&mut buf, atom.charge);
+                put_u32(&mut buf, atom.excluded.len() as u32);
+                atom.excluded
+                    .iter()
+                    .for_each(|&j| put_u32(&mut buf, j as u32));
+            }
+            put_u32(&mut buf, mol.bonded_interactions.len() as u32);
+            for interaction in &mol.bonded_interactions {
+                put_string(&mut buf, interaction);
+            }
+        }
+
+        let frame = &self.frame;
+        put_string(&mut buf, &frame.title);
+        put_i32(&mut buf, frame.step);
+        put_real(&mut buf, frame.time);
+        put_simbox(&mut buf, frame.simbox.as_ref());
+        put_u32(&mut buf, frame.natoms() as u32);
+        frame
+            .positions
+            .iter()
+            .flatten()
+            .for_each(|&x| put_real(&mut buf, x));
+        put_u32(&mut buf, frame.velocities.is_some() as u32);
+        if let Some(velocities) = &frame.velocities {
+            velocities
+                .iter()
+                .flatten()
+                .for_each(|&x| put_real(&mut buf, x));
+        }
+        put_u32(&mut buf, self.comm_groups.len() as u32);
+        for group in &self.comm_groups {
+            put_u32(&mut buf, group.len() as u32);
+            group.iter().for_each(|&i| put_u32(&mut buf, i as u32));
+        }
+        let sum = checksum(&buf);
+        put_u64(&mut buf, sum);
+
+        let mut file = BufWriter::new(
+            File::create(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e)),
+        );
+        file.write_all(&buf).unwrap();
+    }
+
+    pub fn read(filename: &str) -> RunInput {
+        let mut data = Vec::new();
+        File::open(filename)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        if data.len() < 20 || &data[..8] != RUN_INPUT_MAGIC {
+            panic!("{}: not a run input file", filename);
+        }
+        let (body, sum) = data.split_at(data.len() - 8);
+        if checksum(body) != u64::from_le_bytes(sum.try_into().unwrap()) {
+            panic!("{}: run input is corrupted or truncated", filename);
+        }
+
+        let mut r = Cursor::new(body, 8);
+        let version = r.u32();
+        if version != RUN_INPUT_VERSION {
+            panic!(
+                "{}: unsupported run input version {} (this build reads {})",
+                filename, version, RUN_INPUT_VERSION
+            );
+        }
+        let params = RunParams::parse(&r.string()).unwrap_or_else(|mut e| {
+            e.filename = filename.to_string();
+            panic!("{}", e)
+        });
+
+        let mut top = Topology::new();
+        let nb_func = r.string();
+        let comb_rule = r.string();
+        let mut scale = || {
+            let set = r.u32() != 0;
+            let value = r.real();
+            set.then_some(value)
+        };
+        let (ljscale, qqscale) = (scale(), scale());
+        top.set_defaults(&nb_func, &comb_rule, ljscale, qqscale);
+
+        for _ in 0..r.u32() {
+            let name = r.string();
+            let element = r.u32();
+            let (mass, v, w) = (r.real(), r.real(), r.real());
+            top.add_atomtype(name, element, mass, v, w);
+        }
+
+        for moli in 0..r.u32() as usize {
+            let name = r.string();
+            let nmols = r.u32() as usize;
+            top.add_molecule(name, nmols, r.u32() as usize);
+            top.set_rigid(moli, r.u32() != 0);
+            for _ in 0..r.u32() {
+                let atomtype = r.string();
+                let name = r.string();
+                let resnum = r.u32() as usize;
+                let resname = r.string();
+                top.add_atom(moli, &atomtype, &name, resnum, &resname, r.real());
+                let excluded = (0..r.u32()).map(|_| r.u32() as usize).collect();
+                top.set_excluded(moli, excluded);
+            }
+            for _ in 0..r.u32() {
+                top.add_bonded_interaction(moli, &r.string());
+            }
+        }
+
+        let title = r.string();
+        let step = r.i32();
+        let time = r.real();
+        let simbox = r.simbox();
+        let natoms = r.u32() as usize;
+        let vectors = |r: &mut Cursor| {
+            (0..natoms)
+                .map(|_| [r.real(), r.real(), r.real()])
+                .collect::<Vec<Rvec>>()
+        };
+        let positions = vectors(&mut r);
+        let velocities = (r.u32() != 0).then(|| vectors(&mut r));
+        let frame = Frame {
+            title,
+            step,
+            time,
+            simbox,
+            velocities,
+            ..Frame::new(positions)
+        };
+
+        let comm_groups = (0..r.u32())
+            .map(|_| (0..r.u32()).map(|_| r.u32() as usize).collect())
+            .collect();
+
+        RunInput {
+            params,
+            topology: top,
+            frame,
+            comm_groups,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffield::ForceGroup, DIM};
+
+    #[test]
+    fn it_compiles_run_input() {
+        let mut top = Topology::new();
+        top.set_defaults("lj", "geom", Some(0.5), Some(0.8333));
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.3, 0.4);
+        top.add_molecule("tri".to_string(), 2, 1);
+        for name in ["c1", "c2", "c3"] {
+            top.add_atom(0, "c", name, 1, "TRI", -0.1);
+        }
+        top.add_bonded_interaction(0, "bond_harm 1 2 0.15 250000.0");
+        top.add_bonded_interaction(0, "bond_harm 2 3 0.15 250000.0");
+        top.add_bonded_interaction(0, "lj_pair 1 3");
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [0.16, 0.01, 0.0],
+            [0.2, 0.15, 0.02],
+            [1.0, 0.0, 0.0],
+            [1.14, 0.02, 0.0],
+            [1.2, 0.16, 0.01],
+        ];
+        let frame = Frame {
+            velocities: Some(vec![[0.1, -0.2, 0.3]; 6]),
+            ..Frame::new(positions.clone())
+        };
+        let params = RunParams::parse("integrator = md\nnsteps = 10\n").unwrap();
+        let input = RunInput::new(&top, frame, params, vec![vec![0, 1, 2], vec![3, 4, 5]]);
+
+        let expanded = &input.topology;
+        // The molecule type is kept with its number of copies.
+        assert_eq!(expanded.molecules().len(), 1);
+        assert_eq!(expanded.molecules()[0].nmols, 2);
+        assert_eq!(expanded.hierarchy().natoms(), 6);
+        let atoms = expanded.get_atoms();
+        assert_eq!(atoms[0].excluded, [1]);
+        assert_eq!(atoms[1].excluded, [0, 2]);
+        assert_eq!(atoms[4].excluded, [0, 2]);
+        let pair = &expanded.molecules()[0].bonded_interactions[2];
+        assert_eq!(pair.split_whitespace().count(), 5);
+
+        // The expanded system has the same energy and forces.
+        let energy = |top: &Topology| {
+            let mut forces = vec![[0.0; DIM]; 6];
+            let u =
+                ForceGroup::ALL.map(|g| Forces::new(top).calc_group(g, &positions, &mut forces));
+            (u, forces)
+        };
+        assert_eq!(energy(&top), energy(expanded));
+
+        let filename = &crate::temp_path("compile.rin");
+        input.write(filename);
+        assert_eq!(RunInput::read(filename), input);
+    }
+}
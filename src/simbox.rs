@@ -0,0 +1,269 @@
+use crate::linalg::*;
+use crate::{Matrix, Real, Rvec, DIM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxShape {
+    Rectangular,
+    Triclinic,
+    TruncatedOctahedron,
+    RhombicDodecahedron,
+}
+
+// Periodic simulation cell. The box vectors are the rows of `vectors` and
+// are stored in lower triangular form, i.e. a = (ax, 0, 0),
+// b = (bx, by, 0) and c = (cx, cy, cz), with ax, by, cz > 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimBox {
+    pub vectors: Matrix,
+    pub shape: BoxShape,
+    inv: Matrix,
+}
+
+impl SimBox {
+    pub fn new(vectors: Matrix, shape: BoxShape) -> SimBox {
+        if vectors[0][1] != 0.0 || vectors[0][2] != 0.0 || vectors[1][2] != 0.0 {
+            panic!(
+                "Box vectors must be lower triangular (a along x, b in the xy plane): {:?}",
+                vectors
+            );
+        }
+        if vectors[0][0] <= 0.0 || vectors[1][1] <= 0.0 || vectors[2][2] <= 0.0 {
+            panic!("Box vectors must be right-handed: {:?}", vectors);
+        }
+        SimBox {
+            vectors,
+            shape,
+            inv: mat_inv(&vectors),
+        }
+    }
+
+    pub fn rectangular(a: Real, b: Real, c: Real) -> SimBox {
+        Self::new(
+            [[a, 0.0, 0.0], [0.0, b, 0.0], [0.0, 0.0, c]],
+            BoxShape::Rectangular,
+        )
+    }
+
+    // General box, classified as rectangular if all off-diagonal elements vanish.
+    pub fn triclinic(vectors: Matrix) -> SimBox {
+        let rect = vectors[1][0] == 0.0 && vectors[2][0] == 0.0 && vectors[2][1] == 0.0;
+        let shape = if rect {
+            BoxShape::Rectangular
+        } else {
+            BoxShape::Triclinic
+        };
+        Self::new(vectors, shape)
+    }
+
+    // Box from edge lengths and angles in degrees (alpha between b and c,
+    // beta between a and c, gamma between a and b).
+    pub fn from_lengths_angles(lengths: Rvec, angles: Rvec) -> SimBox {
+        let [a, b, c] = lengths;
+        let [alpha, beta, gamma] = angles.map(|x| x * DEG2RAD);
+        if angles.iter().all(|&x| (x - 90.0).abs() < 1e-4) {
+            return Self::rectangular(a, b, c);
+        }
+        let bx = b * gamma.cos();
+        let by = b * gamma.sin();
+        let cx = c * beta.cos();
+        let cy = c * (alpha.cos() - beta.cos() * gamma.cos()) / gamma.sin();
+        let cz = (c * c - cx * cx - cy * cy).sqrt();
+        Self::new(
+            [[a, 0.0, 0.0], [bx, by, 0.0], [cx, cy, cz]],
+            BoxShape::Triclinic,
+        )
+    }
+
+    // Truncated octahedron with image distance `d`.
+    pub fn truncated_octahedron(d: Real) -> SimBox {
+        let s2 = crate::consts::SQRT_2;
+        let s6 = Real::sqrt(6.0);
+        Self::new(
+            [
+                [d, 0.0, 0.0],
+                [d / 3.0, 2.0 * s2 * d / 3.0, 0.0],
+                [-d / 3.0, s2 * d / 3.0, s6 * d / 3.0],
+            ],
+            BoxShape::TruncatedOctahedron,
+        )
+    }
+
+    // Rhombic dodecahedron with image distance `d` (square xy-plane variant).
+    pub fn rhombic_dodecahedron(d: Real) -> SimBox {
+        let s2 = crate::consts::SQRT_2;
+        Self::new(
+            [
+                [d, 0.0, 0.0],
+                [0.0, d, 0.0],
+                [0.5 * d, 0.5 * d, 0.5 * s2 * d],
+            ],
+            BoxShape::RhombicDodecahedron,
+        )
+    }
+
+    pub fn volume(&self) -> Real {
+        self.vectors[0][0] * self.vectors[1][1] * self.vectors[2][2]
+    }
+
+    // Diagonal of the box, i.e. the edge lengths of a rectangular box.
+    pub fn diagonal(&self) -> Rvec {
+        [self.vectors[0][0], self.vectors[1][1], self.vectors[2][2]]
+    }
+
+    pub fn lengths(&self) -> Rvec {
+        self.vectors.map(|v| norm2(&v).sqrt())
+    }
+
+    // Angles in degrees: alpha between b and c, beta between a and c, gamma
+    // between a and b.
+    pub fn angles(&self) -> Rvec {
+        let [a, b, c] = &self.vectors;
+        let angle =
+            |u: &Rvec, v: &Rvec| (dot(u, v) / (norm2(u) * norm2(v)).sqrt()).acos() / DEG2RAD;
+        [angle(b, c), angle(a, c), angle(a, b)]
+    }
+
+    // Reciprocal vectors (rows) a*, b*, c*, such that a.a* = 1 and a.b* = 0.
+    pub fn reciprocal(&self) -> Matrix {
+        transpose(&self.inv)
+    }
+
+    // Fractional coordinates of `r` in units of the box vectors.
+    pub fn to_fractional(&self, r: &Rvec) -> Rvec {
+        mat_vec(&transpose(&self.inv), r)
+    }
+
+    pub fn from_fractional(&self, s: &Rvec) -> Rvec {
+        mat_vec(&transpose(&self.vectors), s)
+    }
+
+    // Half the shortest lattice vector: any distance shorter than this is
+    // its own minimum image.
+    pub fn max_cutoff(&self) -> Real {
+        let mut shortest = Real::MAX;
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    if i == 0 && j == 0 && k == 0 {
+                        continue;
+                    }
+                    let t = self.from_fractional(&[i as Real, j as Real, k as Real]);
+                    shortest = shortest.min(norm2(&t));
+                }
+            }
+        }
+        0.5 * shortest.sqrt()
+    }
+
+    // Shortest periodic image of the distance vector `r`.
+    pub fn min_image(&self, r: &Rvec) -> Rvec {
+        let mut r = *r;
+        // Shift into the triclinic brick centered at the origin, starting
+        // from c since it is the only vector with a z component.
+        for d in (0..DIM).rev() {
+            let shift = (r[d] / self.vectors[d][d]).round();
+            if shift != 0.0 {
+                for (x, v) in r.iter_mut().zip(self.vectors[d].iter()) {
+                    *x -= shift * v;
+                }
+            }
+        }
+        if self.shape == BoxShape::Rectangular {
+            return r;
+        }
+        // In skewed cells the brick image is not always the closest one.
+        let mut best = r;
+        let mut best_d2 = norm2(&r);
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    if i == 0 && j == 0 && k == 0 {
+                        continue;
+                    }
+                    let shift = self.from_fractional(&[i as Real, j as Real, k as Real]);
+                    let image = rvadd(&r, &shift);
+                    let d2 = norm2(&image);
+                    if d2 < best_d2 {
+                        best = image;
+                        best_d2 = d2;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    // Minimum image distance vector from `ri` to `rj`.
+    pub fn displace(&self, ri: &Rvec, rj: &Rvec) -> Rvec {
+        self.min_image(&displace_vec(ri, rj))
+    }
+
+    // Puts `r` in the triclinic unit cell, i.e. with fractional coordinates in [0, 1).
+    pub fn wrap(&self, r: &Rvec) -> Rvec {
+        if self.shape == BoxShape::Rectangular {
+            let diag = self.diagonal();
+            return [0, 1, 2].map(|d| r[d] - diag[d] * (r[d] / diag[d]).floor());
+        }
+        let s = self.to_fractional(r).map(|s| s - s.floor());
+        self.from_fractional(&s)
+    }
+
+    // Puts `r` in the Wigner-Seitz cell around `center`, which is the natural
+    // (compact) unit cell of truncated octahedra and rhombic dodecahedra.
+    pub fn wrap_compact(&self, r: &Rvec, center: &Rvec) -> Rvec {
+        rvadd(center, &self.min_image(&rvsub(r, center)))
+    }
+
+    // Geometric center of the triclinic unit cell.
+    pub fn center(&self) -> Rvec {
+        self.from_fractional(&[0.5; DIM])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+
+    #[test]
+    fn it_finds_min_images() {
+        let mut rng = Rng::new(31);
+        let boxes = [
+            SimBox::rectangular(3.0, 4.0, 5.0),
+            SimBox::truncated_octahedron(4.0),
+            SimBox::rhombic_dodecahedron(4.0),
+            SimBox::from_lengths_angles([4.0, 4.5, 5.0], [70.0, 80.0, 110.0]),
+        ];
+        for simbox in boxes {
+            assert!((simbox.volume() - det(&simbox.vectors)).abs() < 1e-3);
+            let recip = simbox.reciprocal();
+            for (i, v) in simbox.vectors.iter().enumerate() {
+                for (j, w) in recip.iter().enumerate() {
+                    let expected = if i == j { 1.0 } else { 0.0 };
+                    assert!((dot(v, w) - expected).abs() < 1e-5);
+                }
+            }
+            for _ in 0..200 {
+                let r = [0; DIM].map(|_| 20.0 * (rng.uniform() as Real - 0.5));
+                let image = simbox.min_image(&r);
+                // Brute force over a large neighbourhood of images.
+                let mut best = Real::MAX;
+                for i in -6..=6 {
+                    for j in -6..=6 {
+                        for k in -6..=6 {
+                            let shift = simbox.from_fractional(&[i as Real, j as Real, k as Real]);
+                            best = best.min(norm2(&rvadd(&r, &shift)));
+                        }
+                    }
+                }
+                assert!((norm2(&image) - best).abs() < 1e-3);
+
+                let w = simbox.to_fractional(&simbox.wrap(&r));
+                assert!(w.iter().all(|&s| (-1e-5..1.0 + 1e-5).contains(&s)));
+            }
+        }
+        let rd = SimBox::rhombic_dodecahedron(4.0);
+        assert!((rd.volume() - 0.5 * crate::consts::SQRT_2 * 64.0).abs() < 1e-3);
+        assert!((rd.max_cutoff() - 2.0).abs() < 1e-4);
+    }
+}
@@ -1,11 +1,11 @@
-use crate::{ffield::Forces, topology::Topology, Rvec};
+use crate::{ffield::Forces, topology::Topology, Real};
 
 pub struct System {
     pub topology: Topology,
     pub forces: Forces,
-    pub potential: f32,
-    pub press: f32,
-    pub temp: f32,
+    pub potential: Real,
+    pub press: Real,
+    pub temp: Real,
 }
 
 impl System {
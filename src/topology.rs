@@ -1,29 +1,43 @@
 pub mod atom;
+pub mod check;
+pub mod hierarchy;
+pub mod index;
 pub mod molecule;
 mod reader;
+pub mod selection;
+use crate::ffield::functions;
+use crate::simbox::SimBox;
+use crate::{Real, Rvec};
 use atom::*;
+use hierarchy::{Hierarchy, HierarchyCache, Residue};
 use molecule::*;
+use selection::{Selection, SelectionError};
 use std::collections::HashMap;
 
-use crate::ffield::Forces;
-
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Defaults {
     pub nb_func: String,
     pub comb_rule: String,
-    pub ljscale: Option<f32>,
-    pub qqscale: Option<f32>,
+    pub ljscale: Option<Real>,
+    pub qqscale: Option<Real>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Topology {
     pub atomtypes: HashMap<String, AtomTypeParams>,
+    // Read through `molecules()` and changed through the methods below,
+    // which keep `hierarchy` in step with the molecules.
+    #[deprecated(note = "read with molecules(); changes made here are not seen by hierarchy()")]
     pub molecules: Vec<Molecule>,
     natoms: usize,
     nmols: usize,
     pub defaults: Defaults,
+    // Built on first use and reset by the methods that change the molecules.
+    hierarchy: HierarchyCache,
 }
 
+// The methods are the only users of the deprecated field.
+#[allow(deprecated)]
 impl Topology {
     pub fn new() -> Self {
         Self::default()
@@ -33,8 +47,8 @@ impl Topology {
         &mut self,
         nb_func: &str,
         comb_rule: &str,
-        ljscale: Option<f32>,
-        qqscale: Option<f32>,
+        ljscale: Option<Real>,
+        qqscale: Option<Real>,
     ) {
         self.defaults.nb_func = nb_func.to_string();
         self.defaults.comb_rule = comb_rule.to_string();
@@ -42,7 +56,7 @@ impl Topology {
         self.defaults.qqscale = qqscale;
     }
 
-    pub fn add_atomtype(&mut self, atomtype: String, element: u32, mass: f32, v: f32, w: f32) {
+    pub fn add_atomtype(&mut self, atomtype: String, element: u32, mass: Real, v: Real, w: Real) {
         let params = AtomTypeParams {
             element,
             mass,
@@ -60,6 +74,16 @@ impl Topology {
         let molecule = Molecule::new(self.nmols, name, nmols, nbexc);
         self.molecules.push(molecule);
         self.nmols += 1;
+        self.hierarchy.reset();
+    }
+
+    pub fn set_nmols(&mut self, moli: usize, nmols: usize) {
+        self.molecules[moli].nmols = nmols;
+        self.hierarchy.reset();
+    }
+
+    pub fn set_rigid(&mut self, moli: usize, rigid: bool) {
+        self.molecules[moli].rigid = rigid;
     }
 
     pub fn add_exclpairs(&mut self, i: usize, excl: Vec<usize>) {
@@ -70,6 +94,11 @@ impl Topology {
             .extend_from_slice(&excl);
     }
 
+    // Replaces the exclusions of the last atom added to molecule `moli`.
+    pub fn set_excluded(&mut self, moli: usize, excluded: Vec<usize>) {
+        self.molecules[moli].atoms.last_mut().unwrap().excluded = excluded;
+    }
+
     pub fn add_atom(
         &mut self,
         moli: usize,
@@ -77,7 +106,7 @@ impl Topology {
         name: &str,
         resnum: usize,
         resname: &str,
-        charge: f32,
+        charge: Real,
     ) {
         if !self.atomtypes.contains_key(atomtype) {
             panic!("Undefined atom type: {}", atomtype);
@@ -96,6 +125,33 @@ impl Topology {
         );
         self.molecules[moli].atoms.push(atom);
         self.natoms += 1;
+        self.hierarchy.reset();
+    }
+
+    pub fn molecules(&self) -> &[Molecule] {
+        &self.molecules
+    }
+
+    // Chains, molecule copies and residues of the system.
+    pub fn hierarchy(&self) -> &Hierarchy {
+        self.hierarchy.get(self)
+    }
+
+    // Atom `i` of the system, i.e. counting every copy of every molecule.
+    pub fn atom(&self, i: usize) -> &Atom {
+        let location = self.hierarchy().locate(i);
+        &self.molecules[location.moltype].atoms[location.local]
+    }
+
+    // The atoms of the system, without copying them as `get_atoms` does.
+    pub fn atoms(&self) -> impl Iterator<Item = &Atom> {
+        self.molecules
+            .iter()
+            .flat_map(|mol| (0..mol.nmols).flat_map(|_| mol.atoms.iter()))
+    }
+
+    pub fn residue_name(&self, residue: &Residue) -> &str {
+        &self.atom(residue.atoms.start).resname
     }
 
     pub fn get_atoms(&self) -> Vec<Atom> {
@@ -110,6 +166,108 @@ impl Topology {
         atoms
     }
 
+    // Global atom indices of every copy of the molecules declared rigid.
+    pub fn rigid_groups(&self) -> Vec<Vec<usize>> {
+        self.hierarchy()
+            .molecules
+            .iter()
+            .filter(|mol| self.molecules[mol.moltype].rigid)
+            .map(|mol| mol.atoms.clone().collect())
+            .collect()
+    }
+
+    // Global atom indices of every copy of every molecule.
+    pub fn molecule_groups(&self) -> Vec<Vec<usize>> {
+        let molecules = &self.hierarchy().molecules;
+        molecules
+            .iter()
+            .map(|mol| mol.atoms.clone().collect())
+            .collect()
+    }
+
+    // Global atom indices of every residue, i.e. runs of atoms of a molecule
+    // sharing the same residue number.
+    pub fn residue_groups(&self) -> Vec<Vec<usize>> {
+        let residues = &self.hierarchy().residues;
+        residues
+            .iter()
+            .map(|res| res.atoms.clone().collect())
+            .collect()
+    }
+
+    // Global atom indices of the chemical bonds of every molecule copy.
+    pub fn bonds(&self) -> Vec<[usize; 2]> {
+        let mut bonds = Vec::new();
+        let mut off = 0;
+        for mol in &self.molecules {
+            let mol_bonds = mol.bonds();
+            for _ in 0..mol.nmols {
+                bonds.extend(mol_bonds.iter().map(|b| b.map(|i| i + off)));
+                off += mol.atoms.len();
+            }
+        }
+        bonds
+    }
+
+    // The same system with the nonbonded exclusions of each atom resolved
+    // (see `Molecule::exclusions`) and the parameters of the LJ pairs
+    // combined from the atom types, once for each molecule type.
+    pub fn expand(&self) -> Topology {
+        let mut top = Topology::new();
+        top.defaults = self.defaults.clone();
+        top.atomtypes = self
+            .atomtypes
+            .iter()
+            .map(|(name, params)| (name.clone(), params.clone()))
+            .collect();
+        let comb_rule = functions::comb_rule(&self.defaults.comb_rule);
+        for mol in &self.molecules {
+            let exclusions = mol.exclusions();
+            let interactions = mol
+                .bonded_interactions
+                .iter()
+                .map(|interaction| {
+                    let fields = interaction.split_whitespace().collect::<Vec<&str>>();
+                    if fields[0] != "lj_pair" || fields.len() > 3 {
+                        return interaction.clone();
+                    }
+                    let [ai, aj] =
+                        [1, 2].map(|i| &mol.atoms[fields[i].parse::<usize>().unwrap() - 1]);
+                    let (v, w) = comb_rule(ai.v, ai.w, aj.v, aj.w);
+                    format!("{} {} {}", interaction, v, w)
+                })
+                .collect::<Vec<String>>();
+            let moli = top.nmols;
+            top.add_molecule(mol.name.clone(), mol.nmols, mol.nbexc);
+            top.set_rigid(moli, mol.rigid);
+            for (atom, excluded) in mol.atoms.iter().zip(exclusions) {
+                top.add_atom(
+                    moli,
+                    &atom.atomtype,
+                    &atom.name,
+                    atom.resnum,
+                    &atom.resname,
+                    atom.charge,
+                );
+                top.set_excluded(moli, excluded);
+            }
+            for interaction in &interactions {
+                top.add_bonded_interaction(moli, interaction);
+            }
+        }
+        top
+    }
+
+    // Indices of the atoms matching a selection, see `selection`.
+    pub fn select(
+        &self,
+        text: &str,
+        positions: Option<&[Rvec]>,
+        simbox: Option<&SimBox>,
+    ) -> Result<Vec<usize>, SelectionError> {
+        Ok(Selection::parse(text)?.evaluate(self, positions, simbox))
+    }
+
     pub fn read(filename: &str) -> Self {
         reader::parse(filename)
     }
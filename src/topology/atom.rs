@@ -1,12 +1,14 @@
-#[derive(Debug)]
+use crate::Real;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct AtomTypeParams {
     pub element: u32,
-    pub mass: f32,
-    pub v: f32,
-    pub w: f32,
+    pub mass: Real,
+    pub v: Real,
+    pub w: Real,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 // Stores particle information
 pub struct Atom {
     pub index: usize,
@@ -15,14 +17,15 @@ pub struct Atom {
     pub atomtype: String,
     pub name: String,
     pub element: u32,
-    pub mass: f32,
-    pub charge: f32,
-    pub v: f32,
-    pub w: f32,
+    pub mass: Real,
+    pub charge: Real,
+    pub v: Real,
+    pub w: Real,
     pub excluded: Vec<usize>,
 }
 
 impl Atom {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         index: usize,
         atomtype: String,
@@ -30,10 +33,10 @@ impl Atom {
         resnum: usize,
         resname: String,
         element: u32,
-        mass: f32,
-        charge: f32,
-        v: f32,
-        w: f32,
+        mass: Real,
+        charge: Real,
+        v: Real,
+        w: Real,
     ) -> Atom {
         Atom {
             index,
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::molecule::Molecule;
+use super::Topology;
+use crate::ffield::{check_interaction, ForceGroup, Forces, INTERACTIONS};
+use crate::Real;
+
+// Standard atomic masses (amu) by atomic number, up to Kr.
+const MASSES: [Real; 36] = [
+    1.008, 4.003, 6.94, 9.012, 10.81, 12.011, 14.007, 15.999, 18.998, 20.180, 22.990, 24.305,
+    26.982, 28.085, 30.974, 32.06, 35.45, 39.948, 39.098, 40.078, 44.956, 47.867, 50.942, 51.996,
+    54.938, 55.845, 58.933, 58.693, 63.546, 65.38, 69.723, 72.630, 74.922, 78.971, 79.904, 83.798,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub molecule: String,
+    pub message: String,
+}
+
+// Sanity checks of a topology: the charges, the interactions of every
+// molecule type against its atoms, bonds and exclusions, and the masses and
+// LJ parameters of the atoms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyReport {
+    // Net charge of a single copy of every molecule type.
+    pub molecule_charges: Vec<(String, Real)>,
+    pub system_charge: Real,
+    // Interactions of the whole system, by name.
+    pub interaction_counts: Vec<(String, usize)>,
+    // Interactions of the force field, by group; None when the topology has
+    // errors that keep the force field from being built.
+    pub group_counts: Option<Vec<(ForceGroup, usize)>>,
+    pub issues: Vec<Issue>,
+}
+
+impl TopologyReport {
+    pub fn new(top: &Topology) -> TopologyReport {
+        let mut report = TopologyReport {
+            molecule_charges: Vec::new(),
+            system_charge: 0.0,
+            interaction_counts: INTERACTIONS
+                .iter()
+                .map(|(name, _, _)| (name.to_string(), 0))
+                .collect(),
+            group_counts: None,
+            issues: Vec::new(),
+        };
+        for mol in top.molecules() {
+            let charge = mol.atoms.iter().map(|a| a.charge).sum::<Real>();
+            report.molecule_charges.push((mol.name.clone(), charge));
+            report.system_charge += charge * mol.nmols as Real;
+            report.check_molecule(mol);
+        }
+        let nearest = report.system_charge.round();
+        if (report.system_charge - nearest).abs() > 1e-3 {
+            report.add(
+                Severity::Warning,
+                "",
+                format!("net charge {} is not an integer", report.system_charge),
+            );
+        } else if nearest != 0.0 {
+            report.add(Severity::Note, "", format!("net charge {}", nearest));
+        }
+        if !report.has_errors() {
+            let forces = Forces::new(top);
+            // The nonbonded pairs depend on the positions.
+            let counts = ForceGroup::ALL
+                .into_iter()
+                .filter(|&g| g != ForceGroup::Nonbonded)
+                .map(|g| (g, forces.count(g)));
+            report.group_counts = Some(counts.collect());
+        }
+        report
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == Severity::Error)
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|i| i.severity == severity)
+            .count()
+    }
+
+    fn add(&mut self, severity: Severity, molecule: &str, message: String) {
+        self.issues.push(Issue {
+            severity,
+            molecule: molecule.to_string(),
+            message,
+        });
+    }
+
+    fn check_molecule(&mut self, mol: &Molecule) {
+        let name = mol.name.as_str();
+        let natoms = mol.atoms.len();
+
+        // Interactions, keyed by name and atoms (in either direction) to
+        // find duplicates. Torsions with several terms are only duplicates
+        // when their parameters are the same too.
+        let mut seen = HashMap::new();
+        let mut bonds = Vec::new();
+        let mut angles = Vec::new();
+        let mut pairs = Vec::new();
+        for (i, interaction) in mol.bonded_interactions.iter().enumerate() {
+            let (funct, atoms) = match check_interaction(interaction, natoms) {
+                Ok(checked) => checked,
+                Err(e) => {
+                    let message = format!("interaction {} ({}): {}", i + 1, interaction, e);
+                    self.add(Severity::Error, name, message);
+                    continue;
+                }
+            };
+            let count = self
+                .interaction_counts
+                .iter_mut()
+                .find(|(n, _)| n == funct)
+                .unwrap();
+            count.1 += mol.nmols;
+            let mut key = atoms.clone();
+            if key.last() < key.first() {
+                key.reverse();
+            }
+            let params = interaction.split_whitespace().skip(atoms.len() + 1);
+            let params = match funct {
+                "pdih" => params.collect::<Vec<&str>>().join(" "),
+                _ => String::new(),
+            };
+            if let Some(first) = seen.insert((funct, key, params), i) {
+                self.add(
+                    Severity::Warning,
+                    name,
+                    format!(
+                        "interaction {} ({}) repeats interaction {}",
+                        i + 1,
+                        interaction,
+                        first + 1
+                    ),
+                );
+            }
+            match funct {
+                "bond_harm" => bonds.push([atoms[0], atoms[1]]),
+                "angle_harm" => angles.push([atoms[0], atoms[1], atoms[2]]),
+                "lj_pair" => pairs.push([atoms[0], atoms[1]]),
+                _ => (),
+            }
+        }
+
+        let mut neighbors = vec![Vec::new(); natoms];
+        for &[i, j] in &bonds {
+            neighbors[i].push(j);
+            neighbors[j].push(i);
+        }
+        for n in neighbors.iter_mut() {
+            n.sort();
+            n.dedup();
+        }
+        let atom = |i: usize| format!("{} ({})", i + 1, mol.atoms[i].name);
+
+        if natoms > 1 && !mol.rigid {
+            let unbonded = (0..natoms)
+                .filter(|&i| neighbors[i].is_empty())
+                .map(atom)
+                .collect::<Vec<String>>();
+            if !unbonded.is_empty() {
+                self.add(
+                    Severity::Warning,
+                    name,
+                    format!("atoms without bonds: {}", unbonded.join(", ")),
+                );
+            }
+        }
+
+        // Every two bonds sharing an atom should make an angle, unless their
+        // ends are bonded too, as in rigid water models.
+        for j in 0..natoms {
+            for (a, &i) in neighbors[j].iter().enumerate() {
+                for &k in &neighbors[j][a + 1..] {
+                    let has_angle = angles
+                        .iter()
+                        .any(|&[ai, aj, ak]| aj == j && ((ai, ak) == (i, k) || (ai, ak) == (k, i)));
+                    if !has_angle && !neighbors[i].contains(&k) {
+                        self.add(
+                            Severity::Warning,
+                            name,
+                            format!("no angle for bonds {}-{}-{}", i + 1, j + 1, k + 1),
+                        );
+                    }
+                }
+            }
+        }
+
+        // Bond distances of the atoms up to nbexc bonds apart.
+        let distance = |i: usize, j: usize| {
+            let mut shell = vec![i];
+            let mut found = vec![i];
+            for d in 1..=mol.nbexc {
+                shell = shell
+                    .iter()
+                    .flat_map(|&a| neighbors[a].iter().copied())
+                    .filter(|a| !found.contains(a))
+                    .collect();
+                if shell.contains(&j) {
+                    return Some(d);
+                }
+                found.extend_from_slice(&shell);
+            }
+            None
+        };
+
+        for (i, a) in mol.atoms.iter().enumerate() {
+            for &j in &a.excluded {
+                if j >= natoms || j == i {
+                    let message = format!("atom {} excludes atom {}", i + 1, j + 1);
+                    self.add(Severity::Error, name, message);
+                } else if distance(i, j).is_some_and(|d| d <= mol.nbexc) {
+                    self.add(
+                        Severity::Note,
+                        name,
+                        format!(
+                            "exclusion {}-{} is implied by nbexc {}",
+                            i + 1,
+                            j + 1,
+                            mol.nbexc
+                        ),
+                    );
+                }
+            }
+        }
+        // LJ pairs stand in for the nonbonded interactions of excluded
+        // atoms: they are counted twice between atoms that are not excluded.
+        for [i, j] in pairs {
+            let excluded = distance(i, j).is_some_and(|d| d <= mol.nbexc)
+                || mol.atoms[i].excluded.contains(&j)
+                || mol.atoms[j].excluded.contains(&i);
+            if !excluded {
+                self.add(
+                    Severity::Warning,
+                    name,
+                    format!(
+                        "LJ pair {}-{} is not excluded with nbexc {}",
+                        i + 1,
+                        j + 1,
+                        mol.nbexc
+                    ),
+                );
+            }
+        }
+
+        for (i, a) in mol.atoms.iter().enumerate() {
+            let expected = (a.element as usize)
+                .checked_sub(1)
+                .and_then(|z| MASSES.get(z));
+            if a.mass <= 0.0 {
+                let message = format!("atom {} has mass {}", atom(i), a.mass);
+                self.add(Severity::Error, name, message);
+            } else if let Some(&m) = expected.filter(|&&m| a.mass < 0.5 * m || a.mass > 4.0 * m) {
+                self.add(
+                    Severity::Warning,
+                    name,
+                    format!(
+                        "atom {} has mass {} for an element of mass {}",
+                        atom(i),
+                        a.mass,
+                        m
+                    ),
+                );
+            }
+        }
+
+        // Fine for the hydrogens of water models, fatal when the charged
+        // atoms are allowed to come close.
+        let bare = (0..natoms)
+            .filter(|&i| {
+                let a = &mol.atoms[i];
+                a.charge != 0.0 && (a.v == 0.0 || a.w == 0.0)
+            })
+            .map(atom)
+            .collect::<Vec<String>>();
+        if !bare.is_empty() {
+            self.add(
+                Severity::Warning,
+                name,
+                format!("charged atoms without LJ: {}", bare.join(", ")),
+            );
+        }
+    }
+}
+
+impl fmt::Display for TopologyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, charge) in &self.molecule_charges {
+            writeln!(f, "  {:<16} net charge {:>8.4}", name, charge)?;
+        }
+        writeln!(
+            f,
+            "  {:<16} net charge {:>8.4}",
+            "System", self.system_charge
+        )?;
+        let counts = self
+            .interaction_counts
+            .iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(name, n)| format!("{} {}", n, name))
+            .collect::<Vec<String>>();
+        writeln!(f, "  Interactions: {}", counts.join(", "))?;
+        if let Some(groups) = &self.group_counts {
+            let groups = groups
+                .iter()
+                .map(|(g, n)| format!("{} {}", n, g.name()))
+                .collect::<Vec<String>>();
+            writeln!(f, "  Force field: {}", groups.join(", "))?;
+        }
+        for issue in &self.issues {
+            let severity = match issue.severity {
+                Severity::Note => "note",
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            match issue.molecule.as_str() {
+                "" => writeln!(f, "  {}: {}", severity, issue.message)?,
+                mol => writeln!(f, "  {}: {}: {}", severity, mol, issue.message)?,
+            }
+        }
+        write!(
+            f,
+            "  {} errors, {} warnings, {} notes",
+            self.count(Severity::Error),
+            self.count(Severity::Warning),
+            self.count(Severity::Note)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_topologies() {
+        let top = Topology::read("tests/diala.top");
+        let report = TopologyReport::new(&top);
+        assert!(!report.has_errors());
+        assert!(report.system_charge.abs() < 1e-3);
+        assert_eq!(
+            report.interaction_counts[0],
+            ("bond_harm".to_string(), 21 + 3 * 1683)
+        );
+        let groups = report.group_counts.as_ref().unwrap();
+        assert_eq!(groups[0], (ForceGroup::Bonds, 21 + 3 * 1683));
+
+        let mut top = Topology::new();
+        top.set_defaults("lj", "geom", None, None);
+        top.add_atomtype("c".to_string(), 6, 12.0, 0.3, 0.4);
+        top.add_atomtype("h".to_string(), 1, 0.0, 0.0, 0.0);
+        top.add_molecule("bad".to_string(), 2, 3);
+        for name in ["c1", "c2", "c3", "h4"] {
+            top.add_atom(
+                0,
+                if name == "h4" { "h" } else { "c" },
+                name,
+                1,
+                "BAD",
+                0.25,
+            );
+        }
+        top.add_exclpairs(0, vec![1]);
+        for interaction in [
+            "bond_harm 1 2 0.15 1000.0",
+            "bond_harm 2 1 0.15 1000.0",
+            "bond_harm 2 3 0.15 1000.0",
+            "bond_harm 3 5 0.15 1000.0",
+            "angle_harm 1 2",
+            "lj_pair 1 3",
+        ] {
+            top.add_bonded_interaction(0, interaction);
+        }
+        let report = TopologyReport::new(&top);
+        assert!(report.has_errors());
+        assert_eq!(report.group_counts, None);
+        assert_eq!(report.molecule_charges[0].1, 1.0);
+        assert_eq!(report.system_charge, 2.0);
+        let messages = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                (
+                    Severity::Warning,
+                    "interaction 2 (bond_harm 2 1 0.15 1000.0) repeats interaction 1"
+                ),
+                (
+                    Severity::Error,
+                    "interaction 4 (bond_harm 3 5 0.15 1000.0): \
+                     atom 5 is out of range, the molecule has 4 atoms"
+                ),
+                (
+                    Severity::Error,
+                    "interaction 5 (angle_harm 1 2): angle_harm takes 3 atoms and 2 parameters"
+                ),
+                (Severity::Warning, "atoms without bonds: 4 (h4)"),
+                (Severity::Warning, "no angle for bonds 1-2-3"),
+                (Severity::Note, "exclusion 1-2 is implied by nbexc 3"),
+                (Severity::Error, "atom 4 (h4) has mass 0"),
+                (Severity::Warning, "charged atoms without LJ: 4 (h4)"),
+                (Severity::Note, "net charge 2"),
+            ]
+        );
+    }
+}
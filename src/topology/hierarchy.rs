@@ -0,0 +1,243 @@
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use super::Topology;
+
+// Where a global atom index points to in the molecule templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtomLocation {
+    // Index into `Topology::molecules`.
+    pub moltype: usize,
+    pub copy: usize,
+    // Index into the atoms of the molecule type.
+    pub local: usize,
+}
+
+// One copy of a molecule type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoleculeCopy {
+    pub moltype: usize,
+    pub copy: usize,
+    pub atoms: Range<usize>,
+    pub residues: Range<usize>,
+}
+
+// A run of atoms of a molecule copy sharing the same residue number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Residue {
+    // Index into `Hierarchy::molecules`.
+    pub molecule: usize,
+    pub resnum: usize,
+    pub atoms: Range<usize>,
+}
+
+// A molecule copy with several residues (a polymer chain), or a run of
+// consecutive copies of the same single residue molecule type (the solvent,
+// ions), as chains are used in PDB files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chain {
+    pub molecules: Range<usize>,
+    pub residues: Range<usize>,
+    pub atoms: Range<usize>,
+}
+
+// The system spelled out as chains, molecule copies, residues and atoms, by
+// global index ranges, with constant time lookups both ways between global
+// atom indices and molecule templates. It holds no atom data: the atoms are
+// looked up in the templates of the topology it was built from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hierarchy {
+    pub chains: Vec<Chain>,
+    pub molecules: Vec<MoleculeCopy>,
+    pub residues: Vec<Residue>,
+    // Global index of the first atom of the first copy of every molecule type.
+    moltype_offsets: Vec<usize>,
+    // Molecule copy and residue of every atom.
+    atom_molecule: Vec<u32>,
+    atom_residue: Vec<u32>,
+}
+
+impl Hierarchy {
+    pub fn new(top: &Topology) -> Hierarchy {
+        let mut h = Hierarchy::default();
+        let mut off = 0;
+        for (moltype, mol) in top.molecules().iter().enumerate() {
+            h.moltype_offsets.push(off);
+            for copy in 0..mol.nmols {
+                let moli = h.molecules.len();
+                let first_residue = h.residues.len();
+                let mut resnum = None;
+                for (i, atom) in mol.atoms.iter().enumerate() {
+                    if resnum != Some(atom.resnum) {
+                        h.residues.push(Residue {
+                            molecule: moli,
+                            resnum: atom.resnum,
+                            atoms: off + i..off + i,
+                        });
+                        resnum = Some(atom.resnum);
+                    }
+                    h.residues.last_mut().unwrap().atoms.end += 1;
+                    h.atom_molecule.push(moli as u32);
+                    h.atom_residue.push(h.residues.len() as u32 - 1);
+                }
+                let atoms = off..off + mol.atoms.len();
+                let residues = first_residue..h.residues.len();
+                // Single residue copies go in the chain of the previous copy.
+                let extends_chain = copy > 0 && residues.len() == 1 && {
+                    let previous = &h.molecules[moli - 1];
+                    previous.residues.len() == 1
+                };
+                h.molecules.push(MoleculeCopy {
+                    moltype,
+                    copy,
+                    atoms: atoms.clone(),
+                    residues: residues.clone(),
+                });
+                if extends_chain {
+                    let chain = h.chains.last_mut().unwrap();
+                    chain.molecules.end = moli + 1;
+                    chain.residues.end = residues.end;
+                    chain.atoms.end = atoms.end;
+                } else {
+                    h.chains.push(Chain {
+                        molecules: moli..moli + 1,
+                        residues,
+                        atoms,
+                    });
+                }
+                off += mol.atoms.len();
+            }
+        }
+        h
+    }
+
+    pub fn natoms(&self) -> usize {
+        self.atom_molecule.len()
+    }
+
+    pub fn locate(&self, i: usize) -> AtomLocation {
+        let mol = &self.molecules[self.atom_molecule[i] as usize];
+        AtomLocation {
+            moltype: mol.moltype,
+            copy: mol.copy,
+            local: i - mol.atoms.start,
+        }
+    }
+
+    pub fn global_index(&self, location: AtomLocation, top: &Topology) -> usize {
+        let AtomLocation {
+            moltype,
+            copy,
+            local,
+        } = location;
+        let mol = &top.molecules()[moltype];
+        if copy >= mol.nmols || local >= mol.atoms.len() {
+            panic!(
+                "Molecule {} has {} copies of {} atoms, there is no atom {} of copy {}",
+                mol.name,
+                mol.nmols,
+                mol.atoms.len(),
+                local + 1,
+                copy + 1
+            );
+        }
+        self.moltype_offsets[moltype] + copy * mol.atoms.len() + local
+    }
+
+    pub fn molecule_of(&self, i: usize) -> &MoleculeCopy {
+        &self.molecules[self.atom_molecule[i] as usize]
+    }
+
+    pub fn residue_of(&self, i: usize) -> &Residue {
+        &self.residues[self.atom_residue[i] as usize]
+    }
+
+    // The copies of molecule type `moltype`.
+    pub fn copies(&self, moltype: usize) -> &[MoleculeCopy] {
+        let start = self.molecules.partition_point(|m| m.moltype < moltype);
+        let end = self.molecules.partition_point(|m| m.moltype <= moltype);
+        &self.molecules[start..end]
+    }
+
+    pub fn chain_residues(&self, chain: &Chain) -> &[Residue] {
+        &self.residues[chain.residues.clone()]
+    }
+
+    pub fn molecule_residues(&self, mol: &MoleculeCopy) -> &[Residue] {
+        &self.residues[mol.residues.clone()]
+    }
+}
+
+// Lazily built hierarchy of a topology. It takes no part in comparisons and
+// clones, so that it never outlives the topology it was built for.
+#[derive(Debug, Default)]
+pub(crate) struct HierarchyCache(OnceLock<Hierarchy>);
+
+impl HierarchyCache {
+    pub(crate) fn get(&self, top: &Topology) -> &Hierarchy {
+        self.0.get_or_init(|| Hierarchy::new(top))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.0.take();
+    }
+}
+
+impl Clone for HierarchyCache {
+    fn clone(&self) -> Self {
+        HierarchyCache::default()
+    }
+}
+
+impl PartialEq for HierarchyCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_the_hierarchy() {
+        let mut top = Topology::read("tests/diala.top");
+        let h = top.hierarchy();
+        let nwater = 1683;
+        assert_eq!(h.natoms(), 22 + 3 * nwater);
+        assert_eq!(h.molecules.len(), 1 + nwater);
+        assert_eq!(h.residues.len(), 3 + nwater);
+        // The peptide, and the water.
+        assert_eq!(h.chains.len(), 2);
+        assert_eq!(h.chains[0].atoms, 0..22);
+        assert_eq!(h.chains[1].molecules, 1..1 + nwater);
+        assert_eq!(h.chains[1].residues, 3..3 + nwater);
+        let names = h
+            .chain_residues(&h.chains[0])
+            .iter()
+            .map(|res| top.residue_name(res))
+            .collect::<Vec<&str>>();
+        assert_eq!(names, ["ACE", "ALA", "NME"]);
+
+        let i = 22 + 3 * 10 + 2;
+        let location = h.locate(i);
+        assert_eq!(
+            location,
+            AtomLocation {
+                moltype: 1,
+                copy: 10,
+                local: 2
+            }
+        );
+        assert_eq!(h.global_index(location, &top), i);
+        assert_eq!(h.molecule_of(i).atoms, 52..55);
+        assert_eq!(h.residue_of(i).resnum, h.residue_of(i - 2).resnum);
+        assert_eq!(h.copies(1).len(), nwater);
+        assert_eq!(top.atom(i), &top.molecules()[1].atoms[2]);
+        assert!(top.atoms().eq(top.get_atoms().iter()));
+
+        // Changing the topology rebuilds it.
+        top.set_nmols(1, 10);
+        assert_eq!(top.hierarchy().natoms(), 22 + 3 * 10);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use super::Topology;
+
+// Residue names of amino acids, including the usual protonation variants
+// and terminal caps.
+const PROTEIN_RESIDUES: [&str; 33] = [
+    "ALA", "ARG", "ASN", "ASP", "ASH", "CYS", "CYX", "CYM", "GLN", "GLU", "GLH", "GLY", "HIS",
+    "HID", "HIE", "HIP", "HSD", "HSE", "ILE", "LEU", "LYS", "LYN", "MET", "PHE", "PRO", "SER",
+    "THR", "TRP", "TYR", "VAL", "ACE", "NME", "NH2",
+];
+
+const WATER_RESIDUES: [&str; 8] = ["SOL", "WAT", "HOH", "TIP3", "TIP4", "TIP5", "SPC", "T3P"];
+
+// Named groups of atoms, as in GROMACS index (.ndx) files. Atom indices are
+// 0-based in memory and 1-based in the files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexGroups {
+    pub groups: Vec<(String, Vec<usize>)>,
+}
+
+impl IndexGroups {
+    pub fn new() -> IndexGroups {
+        IndexGroups::default()
+    }
+
+    // The groups every system gets: System, one group per molecule type and,
+    // when they are not empty, Protein, Backbone, C-alpha, Water, non-Water,
+    // Hydrogens and non-Hydrogens.
+    pub fn defaults(top: &Topology) -> IndexGroups {
+        let mut index = IndexGroups::new();
+        let natoms = top.hierarchy().natoms();
+        index.add("System", (0..natoms).collect());
+        for mol in top.molecules() {
+            let name = &mol.name;
+            if index.get(name).is_none() {
+                index.add(name, select(top, &format!("molecule {}", name)));
+            }
+        }
+        let protein = format!("resname {}", PROTEIN_RESIDUES.join(" "));
+        let water = format!("resname {}", WATER_RESIDUES.join(" "));
+        let selections = [
+            ("Protein", protein.clone()),
+            ("Backbone", format!("({}) and name N CA C", protein)),
+            ("C-alpha", format!("({}) and name CA", protein)),
+            ("Water", water.clone()),
+            ("non-Water", format!("not {}", water)),
+            ("Hydrogens", "element 1".to_string()),
+            ("non-Hydrogens", "not element 1".to_string()),
+        ];
+        for (name, sel) in selections {
+            let atoms = select(top, &sel);
+            if !atoms.is_empty() && index.get(name).is_none() {
+                index.add(name, atoms);
+            }
+        }
+        index
+    }
+
+    // Adds a group, replacing any group of the same name.
+    pub fn add(&mut self, name: &str, atoms: Vec<usize>) {
+        match self
+            .groups
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, a)) => *a = atoms,
+            None => self.groups.push((name.to_string(), atoms)),
+        }
+    }
+
+    // Group names are case insensitive, as in GROMACS.
+    pub fn get(&self, name: &str) -> Option<&[usize]> {
+        self.groups
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, atoms)| atoms.as_slice())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.groups.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    pub fn read(filename: &str) -> IndexGroups {
+        let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        Self::parse(BufReader::new(file), filename)
+    }
+
+    pub fn parse<R: BufRead>(reader: R, filename: &str) -> IndexGroups {
+        let mut index = IndexGroups::new();
+        for (lineno, line) in reader.lines().map_while(Result::ok).enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                index.groups.push((name.trim().to_string(), Vec::new()));
+                continue;
+            }
+            let Some((_, atoms)) = index.groups.last_mut() else {
+                panic!("{}:{}: atoms before the first group", filename, lineno + 1);
+            };
+            for field in line.split_whitespace() {
+                match field.parse::<usize>() {
+                    Ok(i) if i > 0 => atoms.push(i - 1),
+                    _ => panic!("{}:{}: invalid atom number {}", filename, lineno + 1, field),
+                }
+            }
+        }
+        index
+    }
+
+    pub fn write(&self, filename: &str) {
+        let file = File::create(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let mut file = BufWriter::new(file);
+        for (name, atoms) in &self.groups {
+            writeln!(file, "[ {} ]", name).unwrap();
+            // 15 numbers per line, like GROMACS.
+            for line in atoms.chunks(15) {
+                let numbers = line.iter().map(|i| format!("{:>4}", i + 1));
+                writeln!(file, "{}", numbers.collect::<Vec<String>>().join(" ")).unwrap();
+            }
+            writeln!(file).unwrap();
+        }
+    }
+
+    // Checks that every group refers to atoms of a system of `natoms` atoms.
+    pub fn validate(&self, natoms: usize) {
+        for (name, atoms) in &self.groups {
+            if let Some(&i) = atoms.iter().find(|&&i| i >= natoms) {
+                panic!(
+                    "Group {} has atom {} but the system has {} atoms",
+                    name,
+                    i + 1,
+                    natoms
+                );
+            }
+        }
+    }
+}
+
+fn select(top: &Topology, sel: &str) -> Vec<usize> {
+    top.select(sel, None, None).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_makes_index_groups() {
+        let top = Topology::read("tests/diala.top");
+        let index = IndexGroups::defaults(&top);
+        let natoms = 22 + 3 * 1683;
+        assert_eq!(index.get("system").unwrap().len(), natoms);
+        assert_eq!(
+            index.get("Solute").unwrap(),
+            (0..22).collect::<Vec<usize>>()
+        );
+        assert_eq!(index.get("Protein"), index.get("Solute"));
+        assert_eq!(index.get("C-alpha").unwrap().len(), 1);
+        assert_eq!(index.get("Backbone").unwrap().len(), 6);
+        assert_eq!(index.get("Water"), index.get("Solvent"));
+        assert_eq!(index.get("non-Water"), index.get("Solute"));
+        assert_eq!(index.get("Hydrogens").unwrap().len(), 12 + 2 * 1683);
+
+        let filename = crate::temp_path("groups.ndx");
+        index.write(&filename);
+        assert_eq!(IndexGroups::read(&filename), index);
+    }
+}
@@ -1,11 +1,12 @@
 use super::atom::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Molecule {
     pub index: usize,
     pub name: String,
     pub nmols: usize,
     pub nbexc: usize,
+    pub rigid: bool,
     pub bonded_interactions: Vec<String>,
     pub atoms: Vec<Atom>,
 }
@@ -17,11 +18,73 @@ impl Molecule {
             name,
             nmols,
             nbexc,
+            rigid: false,
             atoms: Vec::new(),
             bonded_interactions: Vec::new(),
         }
     }
-    pub fn add_bonded_interaction(&mut self, interaction: &str){
+    pub fn add_bonded_interaction(&mut self, interaction: &str) {
         self.bonded_interactions.push(interaction.to_string());
     }
+
+    // Chemical bonds as pairs of (0-based) atom indices within the molecule.
+    pub fn bonds(&self) -> Vec<[usize; 2]> {
+        self.bonded_interactions
+            .iter()
+            .filter_map(|interaction| {
+                let fields = interaction.split_whitespace().collect::<Vec<&str>>();
+                match fields[0] {
+                    "bond_harm" => Some([1, 2].map(|i| fields[i].parse::<usize>().unwrap() - 1)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    // Atoms excluded from nonbonded interactions with each atom: those up to
+    // `nbexc` bonds away plus the explicit exclusions, as sorted (0-based)
+    // indices within the molecule.
+    pub fn exclusions(&self) -> Vec<Vec<usize>> {
+        let n = self.atoms.len();
+        let mut neighbors = vec![Vec::new(); n];
+        for [i, j] in self.bonds() {
+            neighbors[i].push(j);
+            neighbors[j].push(i);
+        }
+        let mut excluded: Vec<Vec<usize>> = (0..n)
+            .map(|i| {
+                let mut found = vec![i];
+                let mut shell = vec![i];
+                for _ in 0..self.nbexc {
+                    shell = shell
+                        .iter()
+                        .flat_map(|&a| neighbors[a].iter().copied())
+                        .filter(|a| !found.contains(a))
+                        .collect();
+                    shell.sort();
+                    shell.dedup();
+                    found.extend_from_slice(&shell);
+                }
+                found.retain(|&a| a != i);
+                found
+            })
+            .collect();
+        for (i, atom) in self.atoms.iter().enumerate() {
+            for &j in &atom.excluded {
+                if j >= n {
+                    panic!(
+                        "Exclusion {} of atom {} is out of range in molecule {}",
+                        j, i, self.name
+                    );
+                }
+                excluded[i].push(j);
+                excluded[j].push(i);
+            }
+        }
+        for excl in excluded.iter_mut() {
+            excl.sort();
+            excl.dedup();
+        }
+        excluded
+    }
 }
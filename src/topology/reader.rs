@@ -1,131 +1,269 @@
 use super::Topology;
+use crate::Real;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::str::FromStr;
 
-// Specification of the topology file format:
+// Specification of the topology file format. Section names may be followed
+// by column labels on the same line, which are ignored.
 //  * Section MAIN
 //      nb_func comb_rule [ljscale qqscale]
+//    nb_func and comb_rule are either names (lj, geom, LB) or GROMACS
+//    codes (nbfunc 1 = lj; comb-rule 1 and 3 = geom, 2 = LB).
 //  * Section ATOMTYPES
 //      type element mass charge c0 c1
+//    or, in GROMACS column order, type atnum mass charge ptype c0 c1. With
+//    comb-rule 1 and this column order, as in GROMACS, the c0 and c1 columns
+//    of atom types and pairs are C6 and C12, converted to sigma and epsilon
+//    on reading.
 //  * Section MOL
-//      name nbexc
+//      name [nmols] nbexc
 //  * Section ATOMS
 //      index type name resnum resname [charge c0 c1]
-//  * Section BONDED
+//  * Section BONDS
 //      funct ...params
-//  * Section
+//    or, in GROMACS column order, ai aj funct r0 k
+//  * Sections ANGLES, DIHEDRALS and PAIRS (GROMACS column order)
+//      ai aj ak funct t0 k
+//      ai aj ak al funct p0 k [n]
+//      ai aj funct [c0 c1]
+//  * Section RIGID (no data, marks the current molecule as a rigid body)
+//  * Section EXCLPAIRS
 //      a b c
+//  * Section SYSTEM
+//      name nmols
+//  * Section BOX (ignored, the box comes with the coordinates)
 
+#[derive(Default, Clone, Copy)]
 enum Section {
+    #[default]
     Main,
     AtomTypes,
     Molecule,
     Atoms,
     Bonds,
+    Angles,
+    Dihedrals,
+    Pairs,
     ExclPairs,
+    Rigid,
     System,
+    Box,
 }
 
-impl Default for Section {
-    fn default() -> Self {
-        Self::Main
-    }
-}
+const SECTIONS: [(&str, Section); 12] = [
+    ("MAIN", Section::Main),
+    ("ATOMTYPES", Section::AtomTypes),
+    ("MOL", Section::Molecule),
+    ("ATOMS", Section::Atoms),
+    ("BONDS", Section::Bonds),
+    ("ANGLES", Section::Angles),
+    ("DIHEDRALS", Section::Dihedrals),
+    ("PAIRS", Section::Pairs),
+    ("EXCLPAIRS", Section::ExclPairs),
+    ("RIGID", Section::Rigid),
+    ("SYSTEM", Section::System),
+    ("BOX", Section::Box),
+];
 
 pub fn parse(filename: &str) -> Topology {
-    let mut top = Topology::new();
+    let f = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    parse_from(BufReader::new(f), filename)
+}
 
-    let f = File::open(filename).unwrap();
+pub fn parse_from<R: BufRead>(reader: R, filename: &str) -> Topology {
+    let mut top = Topology::new();
 
     let mut section: Section = Default::default();
-    let mut section_counter = 0;
+    let mut comb_rule_1 = false;
+    let mut c6c12 = false;
 
-    for line in BufReader::new(f).lines().map_while(Result::ok) {
-        if line.is_empty() {
+    for (lineno, line) in reader.lines().map_while(Result::ok).enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
             continue;
         }
-        if line.starts_with("MAIN") {
-            section = Section::Main;
-            section_counter = 0;
-        } else if line.starts_with("ATOMTYPES") {
-            section = Section::AtomTypes;
-            section_counter = 0;
-        } else if line.starts_with("MOL") {
-            section = Section::Molecule;
-            section_counter = 0;
-        } else if line.starts_with("ATOMS") {
-            section = Section::Atoms;
-            section_counter = 0;
-        } else if line.starts_with("BONDS") {
-            section = Section::Bonds;
-            section_counter = 0;
-        } else if line.starts_with("EXCLPAIRS") {
-            section = Section::ExclPairs;
-            section_counter = 0;
-        } else if line.starts_with("SYSTEM") {
-            section = Section::System;
-            section_counter = 0;
-        } else {
-            section_counter += 1;
+        if let Some(&(_, s)) = SECTIONS.iter().find(|(name, _)| *name == fields[0]) {
+            section = s;
+            if let Section::Rigid = section {
+                top.set_rigid(top.nmols - 1, true);
+            }
+            continue;
         }
 
-        if section_counter > 0 {
-            let fields: Vec<&str> = line.split_whitespace().collect();
-
-            match section {
-                Section::Main => top.set_defaults(
-                    fields[0],
-                    fields[1],
-                    fields[2].parse::<f32>().ok(),
-                    fields[2].parse::<f32>().ok(),
-                ),
-
-                Section::AtomTypes => top.add_atomtype(
-                    fields[0].to_owned(),
-                    fields[1].parse::<u32>().unwrap(),
-                    fields[2].parse::<f32>().unwrap(),
-                    fields[3].parse::<f32>().unwrap(),
-                    fields[4].parse::<f32>().unwrap(),
-                ),
-
-                Section::Molecule => {
-                    top.add_molecule(fields[0].to_owned(), 0, fields[1].parse::<usize>().unwrap())
-                }
+        let at = |i: usize| -> &str {
+            fields.get(i).copied().unwrap_or_else(|| {
+                panic!(
+                    "{}:{}: missing column {} in line: {}",
+                    filename,
+                    lineno + 1,
+                    i + 1,
+                    line
+                )
+            })
+        };
+        let num = |i: usize| parse_field::<Real>(at(i), filename, lineno, &line);
+        let int = |i: usize| parse_field::<usize>(at(i), filename, lineno, &line);
 
-                Section::Atoms => top.add_atom(
-                    top.nmols - 1,
-                    fields[1],
-                    fields[2],
-                    fields[3].parse::<usize>().unwrap(),
-                    fields[4],
-                    fields[5].parse::<f32>().unwrap(),
-                ),
-
-                Section::ExclPairs => top.add_exclpairs(
-                    fields[0].parse::<usize>().unwrap(),
-                    fields[1..]
-                        .iter()
-                        .map(|a| a.parse::<usize>().unwrap())
-                        .collect(),
-                ),
-
-                Section::System => {
-                    let molname = fields[0].parse::<String>().unwrap();
-                    for mol in top.molecules.iter_mut() {
-                        if mol.name == molname {
-                            mol.nmols = fields[1].parse::<usize>().unwrap();
-                            break;
-                        }
+        match section {
+            Section::Main => top.set_defaults(
+                match at(0) {
+                    "1" => "lj",
+                    "2" => "buckingham",
+                    name => name,
+                },
+                match at(1) {
+                    "1" => {
+                        comb_rule_1 = true;
+                        "geom"
                     }
+                    "3" => "geom",
+                    "2" => "LB",
+                    name => name,
+                },
+                fields.get(2).and_then(|x| x.parse::<Real>().ok()),
+                fields.get(3).and_then(|x| x.parse::<Real>().ok()),
+            ),
+
+            Section::AtomTypes => {
+                // The ptype column of the GROMACS order.
+                let gromacs = fields.len() > 6;
+                c6c12 |= comb_rule_1 && gromacs;
+                let (mut c0, mut c1) = if gromacs {
+                    (num(5), num(6))
+                } else {
+                    (num(4), num(5))
+                };
+                if comb_rule_1 && gromacs {
+                    (c0, c1) = sigma_epsilon(c0, c1, filename, lineno);
+                }
+                top.add_atomtype(
+                    at(0).to_owned(),
+                    parse_field::<u32>(at(1), filename, lineno, &line),
+                    num(2),
+                    c0,
+                    c1,
+                )
+            }
+
+            Section::Molecule => {
+                let name = at(0).to_owned();
+                if fields.len() > 2 {
+                    top.add_molecule(name, int(1), int(2))
+                } else {
+                    top.add_molecule(name, 0, int(1))
+                }
+            }
+
+            Section::Atoms => top.add_atom(top.nmols - 1, at(1), at(2), int(3), at(4), num(5)),
+
+            Section::ExclPairs => top.add_exclpairs(int(0), (1..fields.len()).map(int).collect()),
+
+            Section::System => {
+                let molname = at(0);
+                match top.molecules().iter().position(|mol| mol.name == molname) {
+                    Some(moli) => top.set_nmols(moli, int(1)),
+                    None => panic!(
+                        "{}:{}: undefined molecule {} in SYSTEM",
+                        filename,
+                        lineno + 1,
+                        molname
+                    ),
                 }
+            }
+
+            Section::Rigid => panic!("Unexpected data in RIGID section: {}", line),
+
+            Section::Box => (),
 
-                Section::Bonds => top.add_bonded_interaction(top.nmols - 1, &fields.join(" ")),
+            Section::Bonds | Section::Angles | Section::Dihedrals | Section::Pairs => {
+                if top.nmols == 0 {
+                    panic!(
+                        "{}:{}: interaction outside of a molecule",
+                        filename,
+                        lineno + 1
+                    );
+                }
+                let interaction = if fields[0].parse::<usize>().is_ok() {
+                    let mut columns = fields.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+                    if c6c12 && matches!(section, Section::Pairs) && columns.len() == 5 {
+                        let (sigma, epsilon) = sigma_epsilon(num(3), num(4), filename, lineno);
+                        columns[3] = sigma.to_string();
+                        columns[4] = epsilon.to_string();
+                    }
+                    convert_interaction(section, &columns).unwrap_or_else(|| {
+                        panic!(
+                            "{}:{}: unsupported interaction: {}",
+                            filename,
+                            lineno + 1,
+                            line
+                        )
+                    })
+                } else {
+                    fields.join(" ")
+                };
+                top.add_bonded_interaction(top.nmols - 1, &interaction)
             }
         }
     }
     top
 }
 
+fn parse_field<T: FromStr>(field: &str, filename: &str, lineno: usize, line: &str) -> T {
+    field.parse::<T>().unwrap_or_else(|_| {
+        panic!(
+            "{}:{}: invalid value {} in line: {}",
+            filename,
+            lineno + 1,
+            field,
+            line
+        )
+    })
+}
+
+// Lennard-Jones sigma and epsilon from C6 and C12. Geometric means of C6
+// and C12 are geometric means of sigma and epsilon, so comb-rule 1 becomes
+// the geometric rule.
+fn sigma_epsilon(c6: Real, c12: Real, filename: &str, lineno: usize) -> (Real, Real) {
+    if c6 == 0.0 && c12 == 0.0 {
+        return (0.0, 0.0);
+    }
+    if c6 <= 0.0 || c12 <= 0.0 {
+        panic!(
+            "{}:{}: C6 {} and C12 {} have no sigma and epsilon",
+            filename,
+            lineno + 1,
+            c6,
+            c12
+        );
+    }
+    ((c12 / c6).powf(1.0 / 6.0), c6 * c6 / (4.0 * c12))
+}
+
+// Rewrites an interaction given in GROMACS column order as the equivalent
+// keyword form understood by `Forces`.
+fn convert_interaction(section: Section, fields: &[String]) -> Option<String> {
+    let natoms = match section {
+        Section::Bonds | Section::Pairs => 2,
+        Section::Angles => 3,
+        Section::Dihedrals => 4,
+        _ => return None,
+    };
+    let name = match (section, fields.get(natoms)?.as_str()) {
+        (Section::Bonds, "1") => "bond_harm",
+        (Section::Angles, "1") => "angle_harm",
+        // Proper (1, 9) and periodic improper (4) dihedrals.
+        (Section::Dihedrals, "1" | "4" | "9") => "pdih",
+        (Section::Dihedrals, "2") => "idih_harm",
+        (Section::Pairs, "1") => "lj_pair",
+        _ => return None,
+    };
+    let mut interaction = vec![name.to_string()];
+    interaction.extend_from_slice(&fields[..natoms]);
+    interaction.extend_from_slice(&fields[natoms + 1..]);
+    Some(interaction.join(" "))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -133,6 +271,44 @@ mod tests {
     #[test]
     fn it_reads() {
         let sys = parse("tests/diala.top");
-        println!("{:#?}", sys);
+        assert_eq!(sys.defaults.comb_rule, "geom");
+        // The sigma and epsilon of the repo column order are kept.
+        let hc = &sys.get_atoms()[0];
+        assert_eq!(hc.v, "0.26495328".parse::<Real>().unwrap());
+        assert_eq!(hc.w, "0.0656888".parse::<Real>().unwrap());
+        assert_eq!(sys.molecules().len(), 2);
+        assert_eq!(sys.molecules()[1].nmols, 1683);
+        assert_eq!(sys.molecules()[0].nbexc, 3);
+        assert_eq!(sys.get_atoms().len(), 22 + 3 * 1683);
+        assert_eq!(
+            sys.molecules()[0].bonded_interactions[0],
+            "bond_harm 5 6 0.12290 476976.000000"
+        );
+        let interactions = &sys.molecules()[0].bonded_interactions;
+        assert!(interactions.contains(&"pdih 5 9 7 8 180.0000771 4.6024000 2".to_string()));
+        assert!(interactions.contains(&"lj_pair 6 9".to_string()));
+    }
+
+    #[test]
+    fn it_converts_c6_c12() {
+        let top = std::fs::read_to_string("tests/c6c12.top").unwrap();
+        let sys = parse_from(top.as_bytes(), "c6c12.top");
+        assert_eq!(sys.defaults.comb_rule, "geom");
+        let sigma = |c6: Real, c12: Real| (c12 / c6).powf(1.0 / 6.0);
+        let epsilon = |c6: Real, c12: Real| c6 * c6 / (4.0 * c12);
+        let atom = &sys.get_atoms()[0];
+        assert!((atom.v - sigma(0.0023406, 4.937e-6)).abs() < 1e-5);
+        assert!((atom.w - epsilon(0.0023406, 4.937e-6)).abs() < 1e-5);
+        let pair = &sys.molecules()[0].bonded_interactions[0];
+        let params = pair.split_whitespace().collect::<Vec<&str>>();
+        assert_eq!(params[..3], ["lj_pair", "1", "2"]);
+        let [s, e] = [3, 4].map(|i| params[i].parse::<Real>().unwrap());
+        assert!((s - sigma(0.0046812, 9.874e-6)).abs() < 1e-5);
+        assert!((e - epsilon(0.0046812, 9.874e-6)).abs() < 1e-5);
+
+        let repulsive = top.replace("0.0023406  4.937e-6", "0.0  4.937e-6");
+        assert!(
+            std::panic::catch_unwind(|| parse_from(repulsive.as_bytes(), "c6c12.top")).is_err()
+        );
     }
 }
@@ -0,0 +1,498 @@
+// Atom selections, e.g.
+//
+//     resname ALA and name CA
+//     within 0.5 of resnum 2
+//     element O and not molecule Solvent
+//     index 1 to 22 or (charge < -0.5)
+//
+// Keywords select on the fields of `Atom`: name, resname, atomtype (or
+// type), element (atomic number or symbol), resnum (or resid), charge, the
+// 1-based atom index and the molecule name. Names may use `*` as a
+// wildcard; numbers may be given as lists, ranges (`a to b`) and
+// comparisons (`< x`, `<= x`, `> x`, `>= x`). `within d of sel` selects
+// the atoms closer than d nm to any atom of sel. Selections combine with
+// and, or, not and parentheses; `all` and `none` do what they say.
+
+use std::fmt;
+
+use super::atom::Atom;
+use super::Topology;
+use crate::linalg::{norm2, rvsub};
+use crate::simbox::SimBox;
+use crate::{Real, Rvec};
+
+// Symbols of the elements up to krypton, by atomic number.
+const ELEMENTS: [&str; 36] = [
+    "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al", "Si", "P", "S", "Cl",
+    "Ar", "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As",
+    "Se", "Br", "Kr",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    Resname,
+    Atomtype,
+    Molecule,
+    Element,
+    Resnum,
+    Index,
+    Charge,
+}
+
+impl Field {
+    fn from_keyword(keyword: &str) -> Option<Field> {
+        Some(match keyword {
+            "name" => Field::Name,
+            "resname" => Field::Resname,
+            "atomtype" | "type" => Field::Atomtype,
+            "molecule" | "mol" => Field::Molecule,
+            "element" => Field::Element,
+            "resnum" | "resid" => Field::Resnum,
+            "index" => Field::Index,
+            "charge" => Field::Charge,
+            _ => return None,
+        })
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Field::Element | Field::Resnum | Field::Index | Field::Charge
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Pattern(String),
+    Number(Real),
+    // Inclusive range.
+    Range(Real, Real),
+    Less(Real),
+    LessEqual(Real),
+    Greater(Real),
+    GreaterEqual(Real),
+}
+
+impl Value {
+    fn matches_str(&self, s: &str) -> bool {
+        match self {
+            Value::Pattern(pattern) => glob(pattern, s),
+            _ => false,
+        }
+    }
+
+    fn matches_number(&self, x: Real) -> bool {
+        match *self {
+            Value::Number(y) => x == y,
+            Value::Range(a, b) => a <= x && x <= b,
+            Value::Less(y) => x < y,
+            Value::LessEqual(y) => x <= y,
+            Value::Greater(y) => x > y,
+            Value::GreaterEqual(y) => x >= y,
+            Value::Pattern(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    All,
+    None,
+    Not(Box<Selection>),
+    And(Box<Selection>, Box<Selection>),
+    Or(Box<Selection>, Box<Selection>),
+    Keyword(Field, Vec<Value>),
+    Within(Real, Box<Selection>),
+}
+
+// A selection that could not be parsed, with the position of the offending
+// word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionError {
+    pub selection: String,
+    pub word: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (word {} of \"{}\")",
+            self.message,
+            self.word + 1,
+            self.selection
+        )
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '<' || c == '>' {
+            chars.next();
+            if chars.peek() == Some(&'=') {
+                chars.next();
+                tokens.push(format!("{}=", c));
+            } else {
+                tokens.push(c.to_string());
+            }
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "()<>".contains(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        }
+    }
+    tokens
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, message: String) -> Result<T, SelectionError> {
+        Err(SelectionError {
+            selection: self.text.to_string(),
+            word: self.pos.min(self.tokens.len()),
+            message,
+        })
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn number(&mut self) -> Result<Real, SelectionError> {
+        match self.peek().map(|t| t.parse::<Real>()) {
+            Some(Ok(x)) => {
+                self.pos += 1;
+                Ok(x)
+            }
+            Some(Err(_)) => self.error(format!("expected a number, got {}", self.peek().unwrap())),
+            None => self.error("expected a number".into()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Selection, SelectionError> {
+        let mut sel = self.and()?;
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            sel = Selection::Or(Box::new(sel), Box::new(self.and()?));
+        }
+        Ok(sel)
+    }
+
+    fn and(&mut self) -> Result<Selection, SelectionError> {
+        let mut sel = self.not()?;
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            sel = Selection::And(Box::new(sel), Box::new(self.not()?));
+        }
+        Ok(sel)
+    }
+
+    fn not(&mut self) -> Result<Selection, SelectionError> {
+        if self.peek() == Some("not") {
+            self.pos += 1;
+            return Ok(Selection::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Selection, SelectionError> {
+        let Some(token) = self.next() else {
+            return self.error("unexpected end of selection".into());
+        };
+        match token.as_str() {
+            "(" => {
+                let sel = self.or()?;
+                if self.next().as_deref() != Some(")") {
+                    self.pos -= 1;
+                    return self.error("missing )".into());
+                }
+                Ok(sel)
+            }
+            "all" => Ok(Selection::All),
+            "none" => Ok(Selection::None),
+            "within" => {
+                let cutoff = self.number()?;
+                if self.next().as_deref() != Some("of") {
+                    self.pos -= 1;
+                    return self.error("expected of after the within distance".into());
+                }
+                Ok(Selection::Within(cutoff, Box::new(self.not()?)))
+            }
+            keyword => {
+                let Some(field) = Field::from_keyword(keyword) else {
+                    self.pos -= 1;
+                    return self.error(format!("unknown keyword {}", keyword));
+                };
+                let values = self.values(field)?;
+                if values.is_empty() {
+                    return self.error(format!("expected values after {}", keyword));
+                }
+                Ok(Selection::Keyword(field, values))
+            }
+        }
+    }
+
+    fn values(&mut self, field: Field) -> Result<Vec<Value>, SelectionError> {
+        let mut values = Vec::new();
+        while let Some(token) = self.peek() {
+            if ["and", "or", "not", "within", "of", "(", ")"].contains(&token)
+                || Field::from_keyword(token).is_some()
+            {
+                break;
+            }
+            if !field.is_numeric() {
+                values.push(Value::Pattern(self.next().unwrap()));
+                continue;
+            }
+            let value = match token {
+                "<" | "<=" | ">" | ">=" => {
+                    let op = self.next().unwrap();
+                    let x = self.number()?;
+                    match op.as_str() {
+                        "<" => Value::Less(x),
+                        "<=" => Value::LessEqual(x),
+                        ">" => Value::Greater(x),
+                        _ => Value::GreaterEqual(x),
+                    }
+                }
+                _ if field == Field::Element && token.parse::<Real>().is_err() => {
+                    let symbol = self.next().unwrap();
+                    match ELEMENTS
+                        .iter()
+                        .position(|e| e.eq_ignore_ascii_case(&symbol))
+                    {
+                        Some(z) => Value::Number(z as Real + 1.0),
+                        None => {
+                            self.pos -= 1;
+                            return self.error(format!("unknown element {}", symbol));
+                        }
+                    }
+                }
+                _ => {
+                    let x = self.number()?;
+                    if self.peek() == Some("to") {
+                        self.pos += 1;
+                        Value::Range(x, self.number()?)
+                    } else {
+                        Value::Number(x)
+                    }
+                }
+            };
+            values.push(value);
+        }
+        Ok(values)
+    }
+}
+
+impl Selection {
+    pub fn parse(text: &str) -> Result<Selection, SelectionError> {
+        let mut parser = Parser {
+            text,
+            tokens: tokenize(text),
+            pos: 0,
+        };
+        let sel = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return parser.error(format!("unexpected {}", token));
+        }
+        Ok(sel)
+    }
+
+    // Whether evaluating the selection needs the atom positions.
+    pub fn needs_positions(&self) -> bool {
+        match self {
+            Selection::All | Selection::None | Selection::Keyword(..) => false,
+            Selection::Not(sel) => sel.needs_positions(),
+            Selection::And(a, b) | Selection::Or(a, b) => {
+                a.needs_positions() || b.needs_positions()
+            }
+            Selection::Within(..) => true,
+        }
+    }
+
+    // Indices (0-based, sorted) of the atoms of `top` in the selection.
+    // Distance based selections need the positions, and the box for
+    // periodic systems.
+    pub fn evaluate(
+        &self,
+        top: &Topology,
+        positions: Option<&[Rvec]>,
+        simbox: Option<&SimBox>,
+    ) -> Vec<usize> {
+        let molecules = top
+            .molecules()
+            .iter()
+            .flat_map(|mol| (0..mol.nmols * mol.atoms.len()).map(|_| mol.name.as_str()))
+            .collect::<Vec<&str>>();
+        let context = Context {
+            atoms: top.atoms().collect(),
+            molecules,
+            positions,
+            simbox,
+        };
+        let mask = self.mask(&context);
+        (0..mask.len()).filter(|&i| mask[i]).collect()
+    }
+
+    fn mask(&self, context: &Context) -> Vec<bool> {
+        let n = context.atoms.len();
+        match self {
+            Selection::All => vec![true; n],
+            Selection::None => vec![false; n],
+            Selection::Not(sel) => sel.mask(context).iter().map(|x| !x).collect(),
+            Selection::And(a, b) => {
+                let (a, b) = (a.mask(context), b.mask(context));
+                a.iter().zip(b).map(|(&a, b)| a && b).collect()
+            }
+            Selection::Or(a, b) => {
+                let (a, b) = (a.mask(context), b.mask(context));
+                a.iter().zip(b).map(|(&a, b)| a || b).collect()
+            }
+            Selection::Keyword(field, values) => (0..n)
+                .map(|i| {
+                    let atom = &context.atoms[i];
+                    let string = match field {
+                        Field::Name => &atom.name,
+                        Field::Resname => &atom.resname,
+                        Field::Atomtype => &atom.atomtype,
+                        Field::Molecule => context.molecules[i],
+                        _ => "",
+                    };
+                    let number = match field {
+                        Field::Element => atom.element as Real,
+                        Field::Resnum => atom.resnum as Real,
+                        Field::Index => (i + 1) as Real,
+                        Field::Charge => atom.charge,
+                        _ => Real::NAN,
+                    };
+                    values.iter().any(|v| {
+                        if field.is_numeric() {
+                            v.matches_number(number)
+                        } else {
+                            v.matches_str(string)
+                        }
+                    })
+                })
+                .collect(),
+            Selection::Within(cutoff, sel) => {
+                let Some(positions) = context.positions else {
+                    panic!("Selections with within need the atom positions");
+                };
+                if positions.len() != n {
+                    panic!(
+                        "Got {} positions for the {} atoms of the topology",
+                        positions.len(),
+                        n
+                    );
+                }
+                let centers = sel.mask(context);
+                let centers = (0..n).filter(|&j| centers[j]).collect::<Vec<usize>>();
+                let cutoff2 = cutoff * cutoff;
+                let distance2 = |i: usize, j: usize| match context.simbox {
+                    Some(simbox) => norm2(&simbox.displace(&positions[i], &positions[j])),
+                    None => norm2(&rvsub(&positions[j], &positions[i])),
+                };
+                (0..n)
+                    .map(|i| centers.iter().any(|&j| distance2(i, j) <= cutoff2))
+                    .collect()
+            }
+        }
+    }
+}
+
+struct Context<'a> {
+    atoms: Vec<&'a Atom>,
+    molecules: Vec<&'a str>,
+    positions: Option<&'a [Rvec]>,
+    simbox: Option<&'a SimBox>,
+}
+
+// Matches `s` against `pattern`, where `*` stands for any (possibly empty)
+// sequence of characters.
+fn glob(pattern: &str, s: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == s,
+        Some((head, tail)) => {
+            let Some(rest) = s.strip_prefix(head) else {
+                return false;
+            };
+            (0..=rest.len())
+                .filter(|&k| rest.is_char_boundary(k))
+                .any(|k| glob(tail, &rest[k..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_selects() {
+        let top = Topology::read("tests/diala.top");
+        let select = |text: &str| Selection::parse(text).unwrap().evaluate(&top, None, None);
+        assert_eq!(select("index 1 to 22"), (0..22).collect::<Vec<usize>>());
+        assert_eq!(select("molecule Solute"), select("not molecule Solvent"));
+        assert_eq!(select("resname ALA and name CA").len(), 1);
+        assert_eq!(select("element O and molecule Solvent").len(), 1683);
+        assert_eq!(select("element 8"), select("element o"));
+        assert_eq!(select("name H* and resname ACE").len(), 3);
+        assert_eq!(select("index 2 or (resnum 1 and charge < 0)")[0], 1);
+        assert!(select("none or not all").is_empty());
+
+        let mut positions = vec![[10.0, 10.0, 10.0]; top.hierarchy().natoms()];
+        positions[0] = [0.0, 0.0, 0.0];
+        positions[5] = [0.3, 0.0, 0.0];
+        positions[7] = [0.0, 0.0, 3.9];
+        let simbox = SimBox::rectangular(4.0, 4.0, 4.0);
+        let sel = Selection::parse("within 0.5 of index 1").unwrap();
+        assert!(sel.needs_positions());
+        assert!(!Selection::parse("not (name CA or all)")
+            .unwrap()
+            .needs_positions());
+        assert_eq!(sel.evaluate(&top, Some(&positions), None), [0, 5]);
+        assert_eq!(
+            sel.evaluate(&top, Some(&positions), Some(&simbox)),
+            [0, 5, 7]
+        );
+
+        for (text, word) in [
+            ("name", 1),
+            ("resname ALA and", 3),
+            ("(index 1", 3),
+            ("resnum x", 1),
+            ("color red", 0),
+            ("within 0.5 index 1", 2),
+        ] {
+            let err = Selection::parse(text).unwrap_err();
+            assert_eq!(err.word, word, "{}", err);
+        }
+    }
+}
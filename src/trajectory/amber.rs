@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use super::frame::Frame;
+use super::reader::column;
+use crate::simbox::SimBox;
+use crate::{Real, Rvec};
+
+// AMBER velocities are in Angstrom per 1/20.455 ps.
+const AMBER_VELOCITY: Real = 20.455;
+
+// Reads an ASCII AMBER coordinate/restart file (inpcrd, rst7), with
+// velocities and box if present. NetCDF restarts are not supported.
+pub fn read(filename: &str) -> Frame {
+    let mut file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    let mut magic = [0; 3];
+    if file.read_exact(&mut magic).is_ok() && (&magic == b"CDF" || &magic == b"\x89HD") {
+        panic!("{}: NetCDF AMBER restarts are not supported", filename);
+    }
+    let file = File::open(filename).unwrap();
+    parse(BufReader::new(file))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Frame {
+    let mut lines = reader.lines().map_while(Result::ok);
+    let mut frame = Frame {
+        title: lines.next().expect("Empty AMBER file").trim().to_string(),
+        ..Default::default()
+    };
+    let header = lines.next().expect("Missing number of atoms in AMBER file");
+    let mut header = header.split_whitespace();
+    let natoms = header
+        .next()
+        .and_then(|x| x.parse::<usize>().ok())
+        .expect("Missing number of atoms in AMBER file");
+    if let Some(time) = header.next().and_then(|x| x.parse::<Real>().ok()) {
+        frame.time = time;
+    }
+
+    // Fixed format 6F12.7: fields may touch, so split by column.
+    let mut records = Vec::new();
+    for line in lines {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let mut values = Vec::new();
+        let mut start = 0;
+        while start < line.len() {
+            let field = column(line, start, start + 12).trim();
+            values.push(
+                field
+                    .parse::<Real>()
+                    .unwrap_or_else(|_| panic!("Invalid number in AMBER file: {}", field)),
+            );
+            start += 12;
+        }
+        records.push(values);
+    }
+
+    // Coordinates and velocities take 3 * natoms values, six per line, and
+    // the box a last line of 3 or 6 values. With up to two atoms a single
+    // line after the coordinates could hold either.
+    let n = 3 * natoms;
+    let nlines = n.div_ceil(6);
+    if records.len() < nlines {
+        panic!(
+            "AMBER file has {} lines of coordinates for {} atoms",
+            records.len(),
+            natoms
+        );
+    }
+    let is_box = |line: &[Real]| line.len() == 3 || line.len() == 6;
+    let rest = &records[nlines..];
+    let (has_velocities, has_box) = match rest.len() {
+        0 => (false, false),
+        1 if nlines == 1 && rest[0].len() == n => {
+            if is_box(&rest[0]) {
+                panic!(
+                    "AMBER file with {} atoms: cannot tell velocities from a box",
+                    natoms
+                );
+            }
+            (true, false)
+        }
+        1 => (false, true),
+        x if x == nlines => (true, false),
+        x if x == nlines + 1 => (true, true),
+        x => panic!(
+            "AMBER file has {} lines after the coordinates of {} atoms",
+            x, natoms
+        ),
+    };
+    let read_vectors = |lines: &[Vec<Real>], what: &str| {
+        for (k, line) in lines.iter().enumerate() {
+            let expected = (n - 6 * k).min(6);
+            if line.len() != expected {
+                panic!(
+                    "AMBER file: line {} of the {} has {} values instead of {}",
+                    k + 1,
+                    what,
+                    line.len(),
+                    expected
+                );
+            }
+        }
+        lines.concat()
+    };
+    let mut values = read_vectors(&records[..nlines], "coordinates");
+    if has_velocities {
+        values.extend(read_vectors(&rest[..nlines], "velocities"));
+    }
+    if has_box {
+        let line = rest.last().unwrap();
+        if !is_box(line) {
+            panic!(
+                "AMBER file: box line has {} values instead of 3 or 6",
+                line.len()
+            );
+        }
+        values.extend_from_slice(line);
+    }
+
+    let vectors = |values: &[Real], scale: Real| {
+        values
+            .chunks(3)
+            .map(|v| [v[0], v[1], v[2]].map(|x| scale * x))
+            .collect::<Vec<Rvec>>()
+    };
+    frame.positions = vectors(&values[..n], 0.1);
+    if has_velocities {
+        frame.velocities = Some(vectors(&values[n..2 * n], 0.1 * AMBER_VELOCITY));
+    }
+    if has_box {
+        let off = if has_velocities { 2 * n } else { n };
+        let b = &values[off..];
+        let lengths = [b[0], b[1], b[2]].map(|x| 0.1 * x);
+        let angles = if b.len() == 6 {
+            [b[3], b[4], b[5]]
+        } else {
+            [90.0; 3]
+        };
+        frame.simbox = Some(SimBox::from_lengths_angles(lengths, angles));
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_rst7() {
+        let rst7 = "\
+water restart
+    2  0.1000000E+02
+  10.0000000  20.0000000  30.0000000 -11.0000000-100.0000000  30.0000000
+   0.2045500   0.0000000   0.0000000   0.0000000   0.0000000   0.0000000
+  40.0000000  40.0000000  40.0000000  90.0000000  90.0000000  90.0000000
+";
+        let frame = parse(rst7.as_bytes());
+        assert_eq!(frame.title, "water restart");
+        assert_eq!(frame.time, 10.0);
+        assert_eq!(frame.positions, [[1.0, 2.0, 3.0], [-1.1, -10.0, 3.0]]);
+        let v = frame.velocities.unwrap();
+        assert!((v[0][0] - 0.418407).abs() < 1e-5);
+        assert_eq!(frame.simbox, Some(SimBox::rectangular(4.0, 4.0, 4.0)));
+    }
+
+    #[test]
+    fn it_tells_velocities_from_a_box() {
+        let inpcrd = "\
+two atoms
+    2
+   1.0000000   2.0000000   3.0000000   4.0000000   5.0000000   6.0000000
+  30.0000000  30.0000000  30.0000000  90.0000000  90.0000000  90.0000000
+";
+        assert!(std::panic::catch_unwind(|| parse(inpcrd.as_bytes())).is_err());
+
+        let inpcrd = "\
+three atoms
+    3
+   1.0000000   2.0000000   3.0000000   4.0000000   5.0000000   6.0000000
+   7.0000000   8.0000000   9.0000000
+  30.0000000  30.0000000  30.0000000  90.0000000  90.0000000  90.0000000
+";
+        let frame = parse(inpcrd.as_bytes());
+        assert_eq!(frame.velocities, None);
+        assert_eq!(frame.simbox, Some(SimBox::rectangular(3.0, 3.0, 3.0)));
+
+        let truncated = inpcrd.replace("   7.0000000   8.0000000   9.0000000\n", "   7.0000000\n");
+        assert!(std::panic::catch_unwind(|| parse(truncated.as_bytes())).is_err());
+    }
+}
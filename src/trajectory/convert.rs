@@ -0,0 +1,306 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use super::dcd::DcdWriter;
+use super::frame::{AtomInfo, Frame};
+use super::reader::{open_trajectory, read_frame, Frames};
+use super::trr::TrrWriter;
+use super::writer::{write_crd, FrameWriter, TrajectoryWriter};
+use super::xtc::XtcWriter;
+use super::{gro, pdb, xyz};
+use crate::pbc::{self, WrapMode};
+use crate::topology::Topology;
+use crate::Real;
+
+// Trajectory formats; everything else is read as a single structure.
+pub const TRAJECTORY_FORMATS: [&str; 4] = ["xtc", "trr", "dcd", "traj"];
+
+// What happens to the frames on their way from the input to the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertOptions {
+    // Atoms to keep, in this order; all of them when None.
+    pub atoms: Option<Vec<usize>>,
+    pub stride: usize,
+    // Time range in ps.
+    pub begin: Real,
+    pub end: Real,
+    pub wrap: Option<WrapMode>,
+    // Atoms to put at the center of the box, with everything else wrapped
+    // around them. Takes the place of `wrap`.
+    pub center: Option<Vec<usize>>,
+    // Precision of XTC output, in 1/nm.
+    pub precision: Real,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            atoms: None,
+            stride: 1,
+            begin: Real::NEG_INFINITY,
+            end: Real::INFINITY,
+            wrap: None,
+            center: None,
+            precision: 1000.0,
+        }
+    }
+}
+
+impl ConvertOptions {
+    pub fn new() -> ConvertOptions {
+        ConvertOptions::default()
+    }
+}
+
+pub fn extension(filename: &str) -> String {
+    std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+// Reads `input` and writes the selected frames to `output`, in the formats
+// given by the file extensions. The topology provides atom names to formats
+// that do not store them, and the molecules for wrapping and centering.
+// Returns the number of frames written.
+pub fn convert(
+    input: &str,
+    output: &str,
+    top: Option<&Topology>,
+    options: &ConvertOptions,
+) -> usize {
+    let mut reader = None;
+    let frames: Box<dyn Iterator<Item = Frame>> =
+        if TRAJECTORY_FORMATS.contains(&extension(input).as_str()) {
+            let reader = reader.insert(open_trajectory(input));
+            Box::new(
+                Frames::new(reader.as_mut())
+                    .stride(options.stride)
+                    .time_window(options.begin, options.end),
+            )
+        } else {
+            let frame = read_frame(input);
+            let keep = frame.time >= options.begin && frame.time <= options.end;
+            Box::new(keep.then_some(frame).into_iter())
+        };
+    let mut frames = frames.map(|mut frame| {
+        transform(&mut frame, top, options);
+        frame
+    });
+
+    let Some(first) = frames.next() else {
+        return 0;
+    };
+    // DCD frames are evenly spaced: the spacing comes from the first two.
+    let second = frames.next();
+    let mut writer = open_writer(output, &first, second.as_ref(), options);
+    let mut nframes = 0;
+    for frame in [Some(first), second].into_iter().flatten().chain(frames) {
+        writer.write_frame(&frame);
+        nframes += 1;
+    }
+    writer.flush();
+    nframes
+}
+
+// Wraps or centers the frame, then keeps only the selected atoms.
+pub fn transform(frame: &mut Frame, top: Option<&Topology>, options: &ConvertOptions) {
+    if let Some(top) = top {
+        if frame.atoms.is_empty() {
+            frame.validate(top);
+            frame.atoms = top
+                .atoms()
+                .map(|atom| AtomInfo {
+                    name: atom.name.clone(),
+                    resname: atom.resname.clone(),
+                    resnum: atom.resnum,
+                    element: String::new(),
+                })
+                .collect();
+        }
+    }
+
+    if options.center.is_some() || options.wrap.is_some() {
+        let Some(simbox) = frame.simbox else {
+            panic!("Frame at {} ps has no box to wrap into", frame.time);
+        };
+        let needs_top =
+            || top.unwrap_or_else(|| panic!("Wrapping molecules and centering need a topology"));
+        match (&options.center, options.wrap) {
+            (Some(cluster), _) => {
+                pbc::center_cluster(&simbox, needs_top(), &mut frame.positions, cluster)
+            }
+            (None, Some(WrapMode::Atom)) => {
+                frame.positions.iter_mut().for_each(|r| *r = simbox.wrap(r))
+            }
+            (None, Some(mode)) => pbc::wrap(&simbox, needs_top(), &mut frame.positions, mode),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    if let Some(atoms) = &options.atoms {
+        let natoms = frame.natoms();
+        if let Some(&i) = atoms.iter().find(|&&i| i >= natoms) {
+            panic!(
+                "Atom {} is out of range, the frame has {} atoms",
+                i + 1,
+                natoms
+            );
+        }
+        frame.positions = atoms.iter().map(|&i| frame.positions[i]).collect();
+        for vectors in [&mut frame.velocities, &mut frame.forces]
+            .into_iter()
+            .flatten()
+        {
+            *vectors = atoms.iter().map(|&i| vectors[i]).collect();
+        }
+        if !frame.atoms.is_empty() {
+            frame.atoms = atoms.iter().map(|&i| frame.atoms[i].clone()).collect();
+        }
+    }
+}
+
+// Writer for the format of `filename`. `first` and `second` are the first
+// two frames that will be written.
+pub fn open_writer(
+    filename: &str,
+    first: &Frame,
+    second: Option<&Frame>,
+    options: &ConvertOptions,
+) -> Box<dyn FrameWriter> {
+    match extension(filename).as_str() {
+        "xtc" => Box::new(XtcWriter::new(filename, options.precision)),
+        "trr" => Box::new(TrrWriter::new(filename)),
+        "dcd" => {
+            let (nsavc, dt) = match second {
+                Some(s) if s.step > first.step => (
+                    s.step - first.step,
+                    (s.time - first.time) / (s.step - first.step) as Real,
+                ),
+                _ => (1, 0.0),
+            };
+            Box::new(DcdWriter::new(
+                filename,
+                first.natoms(),
+                first.step,
+                nsavc,
+                dt,
+            ))
+        }
+        "traj" => Box::new(TrajectoryWriter::new(filename, 1, false)),
+        "crd" | "gro" | "pdb" | "xyz" => Box::new(StructureWriter::new(filename)),
+        ext => panic!("Cannot write .{} files: {}", ext, filename),
+    }
+}
+
+// Writes frames to a structure file. GRO, PDB (as models) and XYZ files take
+// any number of frames, crd files only one.
+pub struct StructureWriter {
+    filename: String,
+    format: String,
+    file: BufWriter<File>,
+    nframes: usize,
+}
+
+impl StructureWriter {
+    pub fn new(filename: &str) -> StructureWriter {
+        let file = File::create(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        StructureWriter {
+            filename: filename.to_string(),
+            format: extension(filename),
+            file: BufWriter::new(file),
+            nframes: 0,
+        }
+    }
+}
+
+impl FrameWriter for StructureWriter {
+    fn write_frame(&mut self, frame: &Frame) {
+        self.nframes += 1;
+        match self.format.as_str() {
+            "gro" => gro::write(&mut self.file, frame),
+            "pdb" => pdb::write(&mut self.file, frame, self.nframes),
+            "xyz" => xyz::write(&mut self.file, frame),
+            _ => {
+                if self.nframes > 1 {
+                    panic!("{} can hold a single frame", self.filename);
+                }
+                let Some(simbox) = &frame.simbox else {
+                    panic!("{} needs a box", self.filename);
+                };
+                write_crd(&mut self.file, simbox, &frame.positions);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simbox::SimBox;
+    use crate::trajectory::reader::open_trajectory;
+
+    #[test]
+    fn it_converts_trajectories() {
+        let input = &crate::temp_path("convert.trr");
+        let mut writer = TrrWriter::new(input);
+        for step in 0..10 {
+            let x = 0.4 * step as Real;
+            writer.write_frame(&Frame {
+                step,
+                time: 0.5 * step as Real,
+                simbox: Some(SimBox::rectangular(2.0, 2.0, 2.0)),
+                velocities: Some(vec![[1.0, 0.0, 0.0]; 3]),
+                ..Frame::new(vec![[x, 0.1, 0.2], [0.5, x, 0.5], [1.0, 1.0, 1.0]])
+            });
+        }
+        writer.flush();
+
+        let options = ConvertOptions {
+            atoms: Some(vec![1, 0]),
+            stride: 2,
+            begin: 1.0,
+            end: 3.5,
+            wrap: Some(WrapMode::Atom),
+            ..ConvertOptions::new()
+        };
+        let output = &crate::temp_path("convert.xtc");
+        assert_eq!(convert(input, output, None, &options), 3);
+        let mut reader = open_trajectory(output);
+        let frames = Frames::new(reader.as_mut()).collect::<Vec<Frame>>();
+        assert_eq!(
+            frames.iter().map(|f| f.step).collect::<Vec<i32>>(),
+            [2, 4, 6]
+        );
+        // Input atom 0 of step 6, at x = 2.4, is wrapped back into the box.
+        let r = frames[2].positions[1];
+        assert!((r[0] - 0.4).abs() < 1e-3 && (r[1] - 0.1).abs() < 1e-3);
+        // Input atom 1 of step 4, inside the box, stays.
+        let r = frames[1].positions[0];
+        assert!((r[1] - 1.6).abs() < 1e-3);
+
+        let gro = &crate::temp_path("convert.gro");
+        assert_eq!(convert(input, gro, None, &options), 3);
+        let frame = read_frame(gro);
+        assert_eq!(frame.step, 2);
+        assert_eq!(frame.natoms(), 2);
+        assert_eq!(frame.velocities, Some(vec![[1.0, 0.0, 0.0]; 2]));
+
+        // A single frame to crd.
+        let crd = &crate::temp_path("convert.crd");
+        let last = ConvertOptions {
+            begin: 3.0,
+            end: 3.0,
+            ..options
+        };
+        assert_eq!(convert(input, crd, None, &last), 1);
+        let frame = read_frame(crd);
+        assert_eq!(frame.natoms(), 2);
+        assert!((frame.positions[1][0] - 0.4).abs() < 1e-3);
+    }
+}
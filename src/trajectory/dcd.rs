@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use super::frame::Frame;
+use super::reader::{read_or_eof, TrajectoryReader};
+use super::writer::FrameWriter;
+use crate::linalg::DEG2RAD;
+use crate::simbox::SimBox;
+use crate::{to_f32, to_f64, Real};
+
+// CHARMM time unit (AKMA) in ps.
+pub const AKMA_TIME: Real = 0.048_888_21;
+
+// Offsets of the frame count and last step in the header.
+const NSET_POS: u64 = 8;
+const NSTEP_POS: u64 = 20;
+
+// CHARMM/NAMD DCD trajectory (little endian, CHARMM 24 flavour with unit
+// cell), storing positions in Angstrom. The step and time of the frames are
+// implicit: frames are `nsavc` steps of `dt` ps apart starting at `istart`.
+pub struct DcdWriter {
+    file: BufWriter<File>,
+    natoms: usize,
+    nframes: i32,
+    istart: i32,
+    nsavc: i32,
+}
+
+impl DcdWriter {
+    pub fn new(filename: &str, natoms: usize, istart: i32, nsavc: i32, dt: Real) -> DcdWriter {
+        let mut file = BufWriter::new(File::create(filename).unwrap());
+        let mut buf = Vec::new();
+        record(&mut buf, |b| {
+            b.extend_from_slice(b"CORD");
+            let mut icntrl = [0i32; 20];
+            icntrl[1] = istart;
+            icntrl[2] = nsavc;
+            icntrl[9] = to_f32(dt / AKMA_TIME).to_bits() as i32;
+            icntrl[10] = 1;
+            icntrl[19] = 24;
+            icntrl
+                .iter()
+                .for_each(|x| b.extend_from_slice(&x.to_le_bytes()));
+        });
+        record(&mut buf, |b| {
+            b.extend_from_slice(&2i32.to_le_bytes());
+            let titles = [
+                format!("REMARKS FILENAME={}", filename),
+                "REMARKS CREATED BY DYNAMO".to_string(),
+            ];
+            for title in titles {
+                let mut line = title.into_bytes();
+                line.resize(80, b' ');
+                b.extend_from_slice(&line);
+            }
+        });
+        record(&mut buf, |b| {
+            b.extend_from_slice(&(natoms as i32).to_le_bytes())
+        });
+        file.write_all(&buf).unwrap();
+        DcdWriter {
+            file,
+            natoms,
+            nframes: 0,
+            istart,
+            nsavc,
+        }
+    }
+}
+
+impl FrameWriter for DcdWriter {
+    fn write_frame(&mut self, frame: &Frame) {
+        if frame.natoms() != self.natoms {
+            panic!(
+                "DCD file has {} atoms but the frame has {}",
+                self.natoms,
+                frame.natoms()
+            );
+        }
+        let mut buf = Vec::new();
+        // a, cos(gamma), b, cos(beta), cos(alpha), c
+        let cell = match &frame.simbox {
+            Some(simbox) => {
+                let [a, b, c] = simbox.lengths().map(|x| 10.0 * to_f64(x));
+                let [alpha, beta, gamma] = simbox.angles().map(|x| to_f64((x * DEG2RAD).cos()));
+                [a, gamma, b, beta, alpha, c]
+            }
+            None => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        };
+        record(&mut buf, |b| {
+            cell.iter()
+                .for_each(|x| b.extend_from_slice(&x.to_le_bytes()))
+        });
+        for d in 0..3 {
+            record(&mut buf, |b| {
+                frame
+                    .positions
+                    .iter()
+                    .for_each(|r| b.extend_from_slice(&to_f32(10.0 * r[d]).to_le_bytes()))
+            });
+        }
+        self.file.write_all(&buf).unwrap();
+
+        // Keep the header consistent so the file is readable at any time.
+        self.nframes += 1;
+        let nstep = self.istart + (self.nframes - 1) * self.nsavc;
+        self.file.seek(SeekFrom::Start(NSET_POS)).unwrap();
+        self.file.write_all(&self.nframes.to_le_bytes()).unwrap();
+        self.file.seek(SeekFrom::Start(NSTEP_POS)).unwrap();
+        self.file.write_all(&nstep.to_le_bytes()).unwrap();
+        self.file.seek(SeekFrom::End(0)).unwrap();
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+}
+
+// Reads DCD files of either byte order. Frames have a fixed size, so the
+// frame index follows from the file length and the step and time of a frame
+// from its position in the file.
+pub struct DcdReader {
+    file: BufReader<File>,
+    big_endian: bool,
+    natoms: usize,
+    istart: i32,
+    nsavc: i32,
+    // Time step in ps.
+    delta: Real,
+    has_cell: bool,
+    start: u64,
+    frame_size: u64,
+    offsets: Vec<u64>,
+}
+
+impl DcdReader {
+    pub fn new(filename: &str) -> DcdReader {
+        let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let len = file.metadata().unwrap().len();
+        let mut file = BufReader::new(file);
+        let mut marker = [0; 4];
+        file.read_exact(&mut marker)
+            .unwrap_or_else(|_| panic!("{}: not a DCD file", filename));
+        let big_endian = match (i32::from_le_bytes(marker), i32::from_be_bytes(marker)) {
+            (84, _) => false,
+            (_, 84) => true,
+            _ => panic!("{}: not a DCD file", filename),
+        };
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut dcd = DcdReader {
+            file,
+            big_endian,
+            natoms: 0,
+            istart: 0,
+            nsavc: 0,
+            delta: 0.0,
+            has_cell: false,
+            start: 0,
+            frame_size: 0,
+            offsets: Vec::new(),
+        };
+
+        let header = dcd.record();
+        if &header[..4] != b"CORD" {
+            panic!("{}: not a DCD file", filename);
+        }
+        let icntrl = (0..20)
+            .map(|i| dcd.int(&header[4 + 4 * i..8 + 4 * i]))
+            .collect::<Vec<i32>>();
+        if icntrl[8] != 0 {
+            panic!("{}: DCD files with fixed atoms are not supported", filename);
+        }
+        let charmm = icntrl[19] != 0;
+        dcd.istart = icntrl[1];
+        dcd.nsavc = icntrl[2];
+        dcd.delta = if charmm {
+            dcd.float(&header[40..44]) * AKMA_TIME
+        } else {
+            // X-PLOR files store the time step as a double.
+            f64::from_bits(dcd.long(&header[40..48])) as Real * AKMA_TIME
+        };
+        dcd.has_cell = charmm && icntrl[10] != 0;
+        dcd.record();
+        let natoms = dcd.record();
+        dcd.natoms = dcd.int(&natoms) as usize;
+
+        dcd.start = dcd.file.stream_position().unwrap();
+        let coords = 3 * (8 + 4 * dcd.natoms as u64);
+        dcd.frame_size = coords + if dcd.has_cell { 56 } else { 0 };
+        let nframes = (len - dcd.start) / dcd.frame_size;
+        dcd.offsets = (0..nframes)
+            .map(|i| dcd.start + i * dcd.frame_size)
+            .collect();
+        dcd
+    }
+
+    fn int(&self, bytes: &[u8]) -> i32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        if self.big_endian {
+            i32::from_be_bytes(bytes)
+        } else {
+            i32::from_le_bytes(bytes)
+        }
+    }
+
+    fn long(&self, bytes: &[u8]) -> u64 {
+        let bytes = bytes[..8].try_into().unwrap();
+        if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+
+    fn float(&self, bytes: &[u8]) -> Real {
+        f32::from_bits(self.int(bytes) as u32) as Real
+    }
+
+    fn record(&mut self) -> Vec<u8> {
+        let mut marker = [0; 4];
+        if !read_or_eof(&mut self.file, &mut marker) {
+            panic!("Unexpected end of DCD file");
+        }
+        let len = self.int(&marker) as usize;
+        let mut buf = vec![0; len + 4];
+        if !read_or_eof(&mut self.file, &mut buf) {
+            panic!("Unexpected end of DCD file");
+        }
+        if self.int(&buf[len..]) != len as i32 {
+            panic!("Corrupted DCD record");
+        }
+        buf.truncate(len);
+        buf
+    }
+
+    // Index of the frame starting at the current position.
+    fn index(&mut self) -> Option<u64> {
+        let i = (self.tell() - self.start) / self.frame_size;
+        (i < self.offsets.len() as u64).then_some(i)
+    }
+
+    fn time(&self, index: u64) -> (i32, Real) {
+        let step = self.istart + index as i32 * self.nsavc;
+        (step, step as Real * self.delta)
+    }
+}
+
+impl TrajectoryReader for DcdReader {
+    fn read_frame(&mut self) -> Option<Frame> {
+        let index = self.index()?;
+        let (step, time) = self.time(index);
+        let mut frame = Frame {
+            step,
+            time,
+            ..Default::default()
+        };
+        if self.has_cell {
+            let cell = self.record();
+            let x = (0..6)
+                .map(|i| f64::from_bits(self.long(&cell[8 * i..])))
+                .collect::<Vec<f64>>();
+            let lengths = [x[0], x[2], x[5]].map(|l| l as Real / 10.0);
+            // Newer CHARMM and NAMD files store the cosines of the angles.
+            let angles = [x[4], x[3], x[1]].map(|a| {
+                if a.abs() <= 1.0 {
+                    (a.acos() as Real).to_degrees()
+                } else {
+                    a as Real
+                }
+            });
+            if lengths.iter().any(|&l| l > 0.0) {
+                frame.simbox = Some(SimBox::from_lengths_angles(lengths, angles));
+            }
+        }
+        let coords = [self.record(), self.record(), self.record()];
+        frame.positions = (0..self.natoms)
+            .map(|i| [0, 1, 2].map(|d| 0.1 * self.float(&coords[d][4 * i..])))
+            .collect();
+        Some(frame)
+    }
+
+    fn skip_frame(&mut self) -> Option<Real> {
+        let index = self.index()?;
+        self.file.seek_relative(self.frame_size as i64).unwrap();
+        Some(self.time(index).1)
+    }
+
+    fn tell(&mut self) -> u64 {
+        self.file.stream_position().unwrap()
+    }
+
+    fn seek_offset(&mut self, offset: u64) {
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+    }
+
+    fn offsets(&mut self) -> &[u64] {
+        &self.offsets
+    }
+}
+
+// Fortran unformatted record: the data framed by its length in bytes.
+fn record<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, fill: F) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    fill(buf);
+    let len = (buf.len() - start - 4) as i32;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simbox::SimBox;
+
+    #[test]
+    fn it_writes_dcd() {
+        let filename = &crate::temp_path("writes.dcd");
+        let frame = Frame {
+            simbox: Some(SimBox::rectangular(3.0, 3.0, 3.0)),
+            ..Frame::new(vec![[0.1, 0.2, 0.3]; 5])
+        };
+        let mut dcd = DcdWriter::new(filename, 5, 0, 10, 0.002);
+        dcd.write_frame(&frame);
+        dcd.write_frame(&frame);
+        dcd.flush();
+
+        let data = std::fs::read(filename).unwrap();
+        let int = |pos: usize| i32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let header = 92 + 172 + 12;
+        let frame_size = 56 + 3 * (8 + 4 * 5);
+        assert_eq!(data.len(), header + 2 * frame_size);
+        assert_eq!(&data[4..8], b"CORD");
+        assert_eq!(int(NSET_POS as usize), 2);
+        assert_eq!(int(NSTEP_POS as usize), 10);
+        assert_eq!(int(header - 8), 5);
+        let x = f32::from_le_bytes(data[header + 60..header + 64].try_into().unwrap());
+        assert!((x - 1.0).abs() < 1e-6);
+        std::fs::remove_file(filename).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use crate::simbox::SimBox;
+use crate::topology::Topology;
+use crate::{Real, Rvec};
+
+// Per-atom metadata carried by structure files. Empty strings mean the
+// format does not provide the field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AtomInfo {
+    pub name: String,
+    pub resname: String,
+    pub resnum: usize,
+    pub element: String,
+}
+
+// A single configuration of the system, in nm and ps.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    pub title: String,
+    pub step: i32,
+    pub time: Real,
+    pub simbox: Option<SimBox>,
+    pub positions: Vec<Rvec>,
+    pub velocities: Option<Vec<Rvec>>,
+    pub forces: Option<Vec<Rvec>>,
+    pub atoms: Vec<AtomInfo>,
+}
+
+impl Frame {
+    pub fn new(positions: Vec<Rvec>) -> Frame {
+        Frame {
+            positions,
+            ..Default::default()
+        }
+    }
+
+    pub fn natoms(&self) -> usize {
+        self.positions.len()
+    }
+
+    // Checks that the frame describes the atoms of `top` in the same order.
+    // Atom and residue names are only compared when the format provides them.
+    pub fn validate(&self, top: &Topology) {
+        let natoms = top.hierarchy().natoms();
+        if natoms != self.natoms() {
+            panic!(
+                "Frame has {} atoms but the topology has {}",
+                self.natoms(),
+                natoms
+            );
+        }
+        if let Some(velocities) = &self.velocities {
+            if velocities.len() != self.natoms() {
+                panic!(
+                    "Frame has {} velocities for {} atoms",
+                    velocities.len(),
+                    self.natoms()
+                );
+            }
+        }
+        for (i, (info, atom)) in self.atoms.iter().zip(top.atoms()).enumerate() {
+            let name_ok = info.name.is_empty() || info.name == atom.name;
+            let resname_ok = info.resname.is_empty() || info.resname == atom.resname;
+            if !name_ok || !resname_ok {
+                panic!(
+                    "Atom {} is {} {} in the frame but {} {} in the topology",
+                    i + 1,
+                    info.resname,
+                    info.name,
+                    atom.resname,
+                    atom.name
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use super::frame::{AtomInfo, Frame};
+use super::reader::column;
+use crate::simbox::{BoxShape, SimBox};
+use crate::{Real, Rvec};
+
+// Reads a GROMACS structure file, with velocities if present.
+pub fn read(filename: &str) -> Frame {
+    let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    parse(BufReader::new(file))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Frame {
+    let mut lines = reader.lines().map_while(Result::ok);
+    let mut frame = Frame {
+        title: lines.next().expect("Empty GRO file").trim().to_string(),
+        ..Default::default()
+    };
+    // GROMACS writes "t= <time> step= <step>" in the title of trajectory frames.
+    let words = frame.title.split_whitespace().collect::<Vec<&str>>();
+    for pair in words.windows(2) {
+        match pair[0] {
+            "t=" => frame.time = pair[1].parse::<Real>().unwrap_or(0.0),
+            "step=" => frame.step = pair[1].parse::<i32>().unwrap_or(0),
+            _ => (),
+        }
+    }
+    let natoms = lines
+        .next()
+        .and_then(|l| l.trim().parse::<usize>().ok())
+        .expect("Missing number of atoms in GRO file");
+
+    let mut velocities = Vec::new();
+    for i in 0..natoms {
+        let line = lines
+            .next()
+            .unwrap_or_else(|| panic!("GRO file ends after {} of {} atoms", i, natoms));
+        let resnum = column(&line, 0, 5).trim().parse::<usize>().unwrap_or(0);
+        frame.atoms.push(AtomInfo {
+            name: column(&line, 10, 15).trim().to_string(),
+            resname: column(&line, 5, 10).trim().to_string(),
+            resnum,
+            element: String::new(),
+        });
+        // The precision is free, so the field width is the distance
+        // between consecutive decimal points.
+        let data = column(&line, 20, line.len());
+        let dots = data
+            .match_indices('.')
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        if dots.len() < 3 {
+            panic!("Invalid GRO atom line: {}", line);
+        }
+        let width = dots[1] - dots[0];
+        let values = (0..dots.len())
+            .map(|k| column(data, k * width, (k + 1) * width).trim())
+            .take_while(|x| !x.is_empty())
+            .map(|x| {
+                x.parse::<Real>()
+                    .unwrap_or_else(|_| panic!("Invalid GRO atom line: {}", line))
+            })
+            .collect::<Vec<Real>>();
+        frame.positions.push([values[0], values[1], values[2]]);
+        if values.len() >= 6 {
+            velocities.push([values[3], values[4], values[5]]);
+        }
+    }
+    if !velocities.is_empty() {
+        if velocities.len() != natoms {
+            panic!("GRO file has velocities for only some atoms");
+        }
+        frame.velocities = Some(velocities);
+    }
+
+    let line = lines.next().expect("Missing box line in GRO file");
+    let v = line
+        .split_whitespace()
+        .map(|x| {
+            x.parse::<Real>()
+                .unwrap_or_else(|_| panic!("Invalid GRO box line: {}", line))
+        })
+        .collect::<Vec<Real>>();
+    frame.simbox = match v[..] {
+        _ if v.iter().all(|&x| x == 0.0) => None,
+        [a, b, c] => Some(SimBox::rectangular(a, b, c)),
+        // v1(x) v2(y) v3(z) v1(y) v1(z) v2(x) v2(z) v3(x) v3(y)
+        [ax, by, cz, ay, az, bx, bz, cx, cy] => {
+            let vectors: [Rvec; 3] = [[ax, ay, az], [bx, by, bz], [cx, cy, cz]];
+            Some(SimBox::triclinic(vectors))
+        }
+        _ => panic!("Invalid GRO box line: {}", line),
+    };
+    frame
+}
+
+// Writes a frame in GRO format, with velocities if the frame has them. Atom
+// and residue names come from `frame.atoms`; frames without them get X and
+// UNK.
+pub fn write<W: Write>(out: &mut W, frame: &Frame) {
+    // The time and step of titles read from GRO files are replaced.
+    let title = match frame.title.split(" t= ").next().unwrap().trim() {
+        "" => "Generated by dynamo",
+        title => title,
+    };
+    writeln!(out, "{} t= {:.5} step= {}", title, frame.time, frame.step).unwrap();
+    writeln!(out, "{:5}", frame.natoms()).unwrap();
+    for (i, r) in frame.positions.iter().enumerate() {
+        let (name, resname, resnum) = match frame.atoms.get(i) {
+            Some(info) => (info.name.as_str(), info.resname.as_str(), info.resnum),
+            None => ("X", "UNK", 1),
+        };
+        write!(
+            out,
+            "{:>5}{:<5}{:>5}{:>5}{:8.3}{:8.3}{:8.3}",
+            resnum % 100_000,
+            resname,
+            name,
+            (i + 1) % 100_000,
+            r[0],
+            r[1],
+            r[2]
+        )
+        .unwrap();
+        if let Some(v) = frame.velocities.as_ref().map(|v| v[i]) {
+            write!(out, "{:8.4}{:8.4}{:8.4}", v[0], v[1], v[2]).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    let v = frame.simbox.map(|b| b.vectors).unwrap_or_default();
+    let mut line = format!("{:10.5}{:10.5}{:10.5}", v[0][0], v[1][1], v[2][2]);
+    if frame
+        .simbox
+        .is_some_and(|b| b.shape != BoxShape::Rectangular)
+    {
+        for x in [v[0][1], v[0][2], v[1][0], v[1][2], v[2][0], v[2][1]] {
+            line.push_str(&format!("{:10.5}", x));
+        }
+    }
+    writeln!(out, "{}", line).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_gro() {
+        let gro = "\
+Water t= 2.50000 step= 1250
+    3
+    1SOL     OW    1   0.126   1.624   1.679  0.1227 -0.0580  0.0434
+    1SOL    HW1    2   0.190   1.661   1.747  0.8085  0.3191 -0.7791
+    1SOL    HW2    3   0.177   1.568   1.613 -0.9045 -2.6469  1.3180
+   1.86206   1.86206   1.31668   0.00000   0.00000   0.62069   0.00000   0.62069   0.62069
+";
+        let frame = parse(gro.as_bytes());
+        assert_eq!(frame.time, 2.5);
+        assert_eq!(frame.step, 1250);
+        assert_eq!(frame.natoms(), 3);
+        assert_eq!(frame.positions[1], [0.190, 1.661, 1.747]);
+        assert_eq!(
+            frame.velocities.as_ref().unwrap()[2],
+            [-0.9045, -2.6469, 1.3180]
+        );
+        assert_eq!(frame.atoms[2].name, "HW2");
+        assert_eq!(frame.atoms[2].resname, "SOL");
+        let simbox = frame.simbox.unwrap();
+        assert_eq!(simbox.vectors[2], [0.62069, 0.62069, 1.31668]);
+
+        let mut out = Vec::new();
+        write(&mut out, &frame);
+        assert_eq!(parse(out.as_slice()), frame);
+    }
+}
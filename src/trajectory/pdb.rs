@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use super::frame::{AtomInfo, Frame};
+use super::reader::column;
+use crate::simbox::SimBox;
+use crate::Real;
+
+// Reads the first model of a PDB file. Coordinates are converted from
+// Angstrom to nm; of atoms with alternate locations only the first one
+// listed is kept.
+pub fn read(filename: &str) -> Frame {
+    let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    parse(BufReader::new(file))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Frame {
+    let mut frame = Frame::default();
+    let mut seen = HashSet::new();
+    for line in reader.lines().map_while(Result::ok) {
+        let record = column(&line, 0, 6).trim_end();
+        match record {
+            "TITLE" => frame.title.push_str(column(&line, 10, 80).trim()),
+            "CRYST1" => {
+                let x = |start, end| parse_f32(&line, start, end);
+                let lengths = [x(6, 15), x(15, 24), x(24, 33)].map(|l| 0.1 * l);
+                let angles = [x(33, 40), x(40, 47), x(47, 54)];
+                // Structures without a unit cell carry a 1 Angstrom cube.
+                if lengths.iter().any(|&l| l > 0.1) {
+                    frame.simbox = Some(SimBox::from_lengths_angles(lengths, angles));
+                }
+            }
+            "ATOM" | "HETATM" => {
+                let name = column(&line, 12, 16).trim();
+                let altloc = column(&line, 16, 17);
+                let resname = column(&line, 17, 21).trim();
+                let chain = column(&line, 21, 22);
+                let resseq = column(&line, 22, 27);
+                if altloc.trim() != "" && !seen.insert(format!("{}{}{}", chain, resseq, name)) {
+                    continue;
+                }
+                let resnum = column(&line, 22, 26)
+                    .trim()
+                    .parse::<i64>()
+                    .unwrap_or_else(|_| panic!("Invalid residue number in PDB line: {}", line));
+                let pos = [(30, 38), (38, 46), (46, 54)].map(|(s, e)| 0.1 * parse_f32(&line, s, e));
+                frame.positions.push(pos);
+                frame.atoms.push(AtomInfo {
+                    name: name.to_string(),
+                    resname: resname.to_string(),
+                    resnum: resnum.max(0) as usize,
+                    element: column(&line, 76, 78).trim().to_string(),
+                });
+            }
+            "ENDMDL" | "END" => break,
+            _ => (),
+        }
+    }
+    frame
+}
+
+// Writes a frame as model `model` of a PDB file, converting to Angstrom.
+// Frames without atom names get X and UNK.
+pub fn write<W: Write>(out: &mut W, frame: &Frame, model: usize) {
+    if model == 1 && !frame.title.is_empty() {
+        writeln!(out, "TITLE     {}", frame.title).unwrap();
+    }
+    if let Some(simbox) = &frame.simbox {
+        let [a, b, c] = simbox.lengths().map(|l| 10.0 * l);
+        let [alpha, beta, gamma] = simbox.angles();
+        writeln!(
+            out,
+            "CRYST1{:9.3}{:9.3}{:9.3}{:7.2}{:7.2}{:7.2} P 1           1",
+            a, b, c, alpha, beta, gamma
+        )
+        .unwrap();
+    }
+    writeln!(out, "MODEL     {:>4}", model).unwrap();
+    for (i, r) in frame.positions.iter().enumerate() {
+        let default = AtomInfo {
+            name: "X".to_string(),
+            resname: "UNK".to_string(),
+            resnum: 1,
+            element: String::new(),
+        };
+        let info = frame.atoms.get(i).unwrap_or(&default);
+        // Names shorter than four characters start in the second column.
+        let name = if info.name.len() < 4 {
+            format!(" {:<3}", info.name)
+        } else {
+            info.name.clone()
+        };
+        let [x, y, z] = r.map(|x| 10.0 * x);
+        writeln!(
+            out,
+            "ATOM  {:>5} {:<4} {:<4} {:>4}    {:8.3}{:8.3}{:8.3}  1.00  0.00          {:>2}",
+            (i + 1) % 100_000,
+            name,
+            format!("{:>3}", info.resname),
+            info.resnum % 10_000,
+            x,
+            y,
+            z,
+            info.element
+        )
+        .unwrap();
+    }
+    writeln!(out, "ENDMDL").unwrap();
+}
+
+fn parse_f32(line: &str, start: usize, end: usize) -> Real {
+    column(line, start, end)
+        .trim()
+        .parse::<Real>()
+        .unwrap_or_else(|_| panic!("Invalid number in PDB line: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linalg::{norm2, rvsub};
+
+    #[test]
+    fn it_reads_pdb() {
+        let pdb = "\
+TITLE     WATER DIMER
+CRYST1   30.000   30.000   30.000  90.00  90.00  90.00 P 1           1
+ATOM      1  OW  SOL     1       1.000   2.000   3.000  1.00  0.00           O
+ATOM      2  HW1 SOL     1       1.957   2.000   3.000  1.00  0.00           H
+ATOM      3  HW2ASOL     1       0.760   2.927   3.000  0.60  0.00           H
+ATOM      4  HW2BSOL     1       0.760   1.073   3.000  0.40  0.00           H
+HETATM    5  OW  SOL     2      -4.000   2.000   3.000  1.00  0.00           O
+END
+";
+        let frame = parse(pdb.as_bytes());
+        assert_eq!(frame.title, "WATER DIMER");
+        assert_eq!(frame.simbox, Some(SimBox::rectangular(3.0, 3.0, 3.0)));
+        assert_eq!(frame.natoms(), 4);
+        assert!(norm2(&rvsub(&frame.positions[2], &[0.076, 0.2927, 0.3])) < 1e-12);
+        assert!(norm2(&rvsub(&frame.positions[3], &[-0.4, 0.2, 0.3])) < 1e-12);
+        assert_eq!(frame.atoms[3].resnum, 2);
+        assert_eq!(frame.atoms[1].name, "HW1");
+        assert_eq!(frame.atoms[1].element, "H");
+
+        let mut out = Vec::new();
+        write(&mut out, &frame, 1);
+        let copy = parse(out.as_slice());
+        assert_eq!(copy.atoms, frame.atoms);
+        assert_eq!(copy.title, frame.title);
+        for (a, b) in copy.positions.iter().zip(&frame.positions) {
+            assert!(norm2(&rvsub(a, b)) < 1e-8);
+        }
+    }
+}
@@ -1,28 +1,423 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 
-use crate::Rvec;
+use super::dcd::DcdReader;
+use super::frame::Frame;
+use super::trr::TrrReader;
+use super::xtc::XtcReader;
+use super::{amber, gro, pdb, xyz};
+use crate::simbox::SimBox;
+use crate::{Real, Rvec};
 
-pub fn read_coords(file: &str) -> (Rvec, Vec<Rvec>) {
+// Reads a structure file, choosing the format from the file extension.
+pub fn read_frame(filename: &str) -> Frame {
+    let ext = std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match ext.as_str() {
+        "crd" => {
+            let (simbox, positions) = read_coords(filename);
+            Frame {
+                simbox: Some(simbox),
+                ..Frame::new(positions)
+            }
+        }
+        "pdb" | "ent" => pdb::read(filename),
+        "gro" => gro::read(filename),
+        "xyz" | "extxyz" => xyz::read(filename),
+        "inpcrd" | "rst7" | "restrt" | "ncrst" => amber::read(filename),
+        _ => panic!("Unknown structure file format: {}", filename),
+    }
+}
+
+// Byte range [start, end) of a fixed-format line, clipped to its length.
+pub(crate) fn column(line: &str, start: usize, end: usize) -> &str {
+    let end = end.min(line.len());
+    line.get(start.min(end)..end).unwrap_or("")
+}
+
+// Sequential access to the frames of a trajectory file, with random access
+// through an index of frame offsets built on first use.
+pub trait TrajectoryReader {
+    // Reads and decodes the next frame, or returns None at the end of file.
+    fn read_frame(&mut self) -> Option<Frame>;
+    // Moves past the next frame reading only what is needed to find its end;
+    // returns its time.
+    fn skip_frame(&mut self) -> Option<Real>;
+    fn tell(&mut self) -> u64;
+    fn seek_offset(&mut self, offset: u64);
+    // Byte offset of every frame in the file.
+    fn offsets(&mut self) -> &[u64];
+
+    fn nframes(&mut self) -> usize {
+        self.offsets().len()
+    }
+
+    // Positions the reader so that the next frame read is `frame`.
+    fn seek(&mut self, frame: usize) {
+        let offsets = self.offsets();
+        if frame >= offsets.len() {
+            panic!(
+                "Frame {} out of range, the trajectory has {} frames",
+                frame,
+                offsets.len()
+            );
+        }
+        let offset = offsets[frame];
+        self.seek_offset(offset);
+    }
+}
+
+// Builds the frame index by skipping through the file from `start`, leaving
+// the reader where it was.
+pub(crate) fn scan_offsets<T: TrajectoryReader + ?Sized>(reader: &mut T, start: u64) -> Vec<u64> {
+    let pos = reader.tell();
+    reader.seek_offset(start);
+    let mut offsets = Vec::new();
+    loop {
+        let offset = reader.tell();
+        if reader.skip_frame().is_none() {
+            break;
+        }
+        offsets.push(offset);
+    }
+    reader.seek_offset(pos);
+    offsets
+}
+
+// Fills `buf`, returning false on a clean end of file. Running out of data
+// in the middle of `buf` means the file is truncated.
+pub(crate) fn read_or_eof<R: Read>(file: &mut R, buf: &mut [u8]) -> bool {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return false,
+            Ok(0) => panic!("Truncated trajectory file"),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => panic!("Couldn't read trajectory: {}", e),
+        }
+    }
+    true
+}
+
+// Opens a trajectory, choosing the format from the file extension.
+pub fn open_trajectory(filename: &str) -> Box<dyn TrajectoryReader> {
+    let ext = std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match ext.as_str() {
+        "xtc" => Box::new(XtcReader::new(filename)),
+        "trr" => Box::new(TrrReader::new(filename)),
+        "dcd" => Box::new(DcdReader::new(filename)),
+        "traj" => Box::new(TextTrajectoryReader::new(filename)),
+        _ => panic!("Unknown trajectory file format: {}", filename),
+    }
+}
+
+// Iterator over the frames of a trajectory, optionally restricted to a time
+// window and taking every `stride`-th frame. Frames that are not returned
+// are skipped without decoding.
+pub struct Frames<'a> {
+    reader: &'a mut dyn TrajectoryReader,
+    stride: usize,
+    begin: Real,
+    end: Real,
+    started: bool,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(reader: &'a mut dyn TrajectoryReader) -> Frames<'a> {
+        Frames {
+            reader,
+            stride: 1,
+            begin: Real::NEG_INFINITY,
+            end: Real::INFINITY,
+            started: false,
+        }
+    }
+
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.stride = stride.max(1);
+        self
+    }
+
+    // Frames with begin <= time <= end (ps).
+    pub fn time_window(mut self, begin: Real, end: Real) -> Self {
+        self.begin = begin;
+        self.end = end;
+        self
+    }
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if !self.started {
+            self.started = true;
+            loop {
+                let offset = self.reader.tell();
+                if self.reader.skip_frame()? >= self.begin {
+                    self.reader.seek_offset(offset);
+                    break;
+                }
+            }
+        }
+        let frame = self.reader.read_frame()?;
+        if frame.time > self.end {
+            return None;
+        }
+        for _ in 1..self.stride {
+            if self.reader.skip_frame().is_none() {
+                break;
+            }
+        }
+        Some(frame)
+    }
+}
+
+// Reader for the text trajectories of `writer::TrajectoryWriter`. The step
+// is not stored, so frames are numbered from zero.
+pub struct TextTrajectoryReader {
+    file: BufReader<File>,
+    offsets: Option<Vec<u64>>,
+    pos: u64,
+    index: i32,
+}
+
+impl TextTrajectoryReader {
+    pub fn new(filename: &str) -> TextTrajectoryReader {
+        let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        TextTrajectoryReader {
+            file: BufReader::new(file),
+            offsets: None,
+            pos: 0,
+            index: 0,
+        }
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = self.file.read_line(&mut line).unwrap();
+            if n == 0 {
+                return None;
+            }
+            self.pos += n as u64;
+            if !line.trim().is_empty() {
+                return Some(line);
+            }
+        }
+    }
+}
+
+impl TrajectoryReader for TextTrajectoryReader {
+    fn read_frame(&mut self) -> Option<Frame> {
+        let line = self.next_line()?;
+        let values = line
+            .split_whitespace()
+            .map(|x| {
+                x.parse::<Real>()
+                    .unwrap_or_else(|_| panic!("Invalid number in trajectory: {}", x))
+            })
+            .collect::<Vec<Real>>();
+        if values.len() % 3 != 1 {
+            panic!("Trajectory line does not hold a time and 3D coordinates");
+        }
+        let positions = values[1..].chunks(3).map(|v| [v[0], v[1], v[2]]).collect();
+        let frame = Frame {
+            step: self.index,
+            time: values[0],
+            ..Frame::new(positions)
+        };
+        self.index += 1;
+        Some(frame)
+    }
+
+    fn skip_frame(&mut self) -> Option<Real> {
+        let line = self.next_line()?;
+        self.index += 1;
+        let time = line.split_whitespace().next().unwrap();
+        Some(time.parse::<Real>().unwrap())
+    }
+
+    fn tell(&mut self) -> u64 {
+        self.pos
+    }
+
+    fn seek_offset(&mut self, offset: u64) {
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+        self.pos = offset;
+        self.index = match &self.offsets {
+            Some(offsets) => offsets.partition_point(|&o| o < offset) as i32,
+            None => 0,
+        };
+    }
+
+    fn offsets(&mut self) -> &[u64] {
+        if self.offsets.is_none() {
+            let offsets = scan_offsets(self, 0);
+            self.index = offsets.partition_point(|&o| o < self.pos) as i32;
+            self.offsets = Some(offsets);
+        }
+        self.offsets.as_ref().unwrap()
+    }
+}
+
+// Reads a coordinate file: the box on the first line, either as the three
+// edges of a rectangular box or as the nine components of the box vectors
+// (a, b, c), followed by one line per atom.
+pub fn read_coords(file: &str) -> (SimBox, Vec<Rvec>) {
     let mut coords = Vec::new();
     let file = File::open(file).unwrap();
-    for line in BufReader::new(file).lines().map_while(Result::ok) {
+    let mut lines = BufReader::new(file).lines().map_while(Result::ok);
+    let header = lines.next().expect("Empty coordinate file");
+    let dims = header
+        .split_whitespace()
+        .map(|x| x.parse::<Real>().unwrap())
+        .collect::<Vec<Real>>();
+    let simbox = match dims[..] {
+        [a, b, c] => SimBox::rectangular(a, b, c),
+        [ax, ay, az, bx, by, bz, cx, cy, cz] => {
+            SimBox::triclinic([[ax, ay, az], [bx, by, bz], [cx, cy, cz]])
+        }
+        _ => panic!("Invalid box line: {}", header),
+    };
+    for line in lines {
         let mut xyz = [0.0; 3];
         line.split_whitespace()
             .enumerate()
-            .for_each(|(i, x)| xyz[i] = x.parse::<f32>().unwrap());
+            .for_each(|(i, x)| xyz[i] = x.parse::<Real>().unwrap());
         coords.push([xyz[0], xyz[1], xyz[2]])
     }
-    (coords[0], coords[1..].to_vec())
+    (simbox, coords)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::topology::Topology;
+    use crate::trajectory::dcd::DcdWriter;
+    use crate::trajectory::trr::TrrWriter;
+    use crate::trajectory::writer::{FrameWriter, TrajectoryWriter};
+    use crate::trajectory::xtc::XtcWriter;
 
     #[test]
     fn test_read_coords() {
-        let (ref pbc, ref coords) = read_coords("tests/diala.crd");
+        let (ref _simbox, ref coords) = read_coords("tests/diala.crd");
         println!("{:?}", coords);
     }
+
+    #[test]
+    fn it_validates_frames() {
+        let mut top = Topology::new();
+        top.add_atomtype("OW".to_string(), 8, 16.0, 0.0, 0.0);
+        top.add_atomtype("HW".to_string(), 1, 1.008, 0.0, 0.0);
+        top.add_molecule("SOL".to_string(), 1683, 2);
+        for (atomtype, name) in [("OW", "OW"), ("HW", "HW1"), ("HW", "HW2")] {
+            top.add_atom(0, atomtype, name, 1, "SOL", 0.0);
+        }
+        let frame = read_frame("tests/diala.crd");
+        assert_eq!(frame.natoms(), 22 + 3 * 1683);
+        assert!(std::panic::catch_unwind(|| frame.validate(&top)).is_err());
+
+        let mut water = Frame::new(frame.positions[22..].to_vec());
+        water.validate(&top);
+        water.atoms = vec![Default::default(); water.natoms()];
+        water.atoms[1].name = "HW2".to_string();
+        assert!(std::panic::catch_unwind(|| water.validate(&top)).is_err());
+    }
+
+    #[test]
+    fn it_reads_trajectories() {
+        let frames = (0..10)
+            .map(|i| Frame {
+                step: 10 * i,
+                time: 0.02 * i as Real,
+                simbox: Some(SimBox::rectangular(3.0, 3.0, 3.0)),
+                velocities: Some(vec![[0.5, -0.5, i as Real]; 4]),
+                ..Frame::new(vec![[0.1 * i as Real, 0.2, 0.3]; 4])
+            })
+            .collect::<Vec<Frame>>();
+        for ext in ["xtc", "trr", "dcd", "traj"] {
+            let filename = &crate::temp_path(&format!("read.{}", ext));
+            let mut writer: Box<dyn FrameWriter> = match ext {
+                "xtc" => Box::new(XtcWriter::new(filename, 1000.0)),
+                "trr" => Box::new(TrrWriter::new(filename)),
+                "dcd" => Box::new(DcdWriter::new(filename, 4, 0, 10, 0.002)),
+                _ => Box::new(TrajectoryWriter::new(filename, 1, false)),
+            };
+            frames.iter().for_each(|f| writer.write_frame(f));
+            writer.flush();
+
+            let mut reader = open_trajectory(filename);
+            assert_eq!(reader.nframes(), 10, "{}", ext);
+            reader.seek(7);
+            let frame = reader.read_frame().unwrap();
+            assert!((frame.positions[0][0] - 0.7).abs() < 1e-3, "{}", ext);
+            assert!((frame.time - 0.14).abs() < 1e-4, "{}", ext);
+            assert_eq!(frame.step, if ext == "traj" { 7 } else { 70 });
+            if ext != "traj" {
+                assert_eq!(frame.simbox, frames[7].simbox, "{}", ext);
+            }
+            if ext == "trr" {
+                assert_eq!(frame, frames[7]);
+            }
+
+            reader.seek(0);
+            let times = Frames::new(&mut *reader)
+                .stride(3)
+                .map(|f| (f.time * 100.0).round() as i32)
+                .collect::<Vec<i32>>();
+            assert_eq!(times, [0, 6, 12, 18], "{}", ext);
+            reader.seek(0);
+            let times = Frames::new(&mut *reader)
+                .time_window(0.05, 0.12)
+                .stride(2)
+                .map(|f| (f.time * 100.0).round() as i32)
+                .collect::<Vec<i32>>();
+            assert_eq!(times, [6, 10], "{}", ext);
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_continues_trajectories_after_a_checkpoint() {
+        let frame = |step: i32| Frame {
+            step,
+            time: 0.002 * step as Real,
+            ..Frame::new(vec![[0.1 * step as Real, 0.2, 0.3]; 4])
+        };
+        for ext in ["xtc", "trr"] {
+            let filename = &crate::temp_path(&format!("restart.{}", ext));
+            let open = |step: Option<i32>| -> Box<dyn FrameWriter> {
+                match (ext, step) {
+                    ("xtc", None) => Box::new(XtcWriter::new(filename, 1000.0)),
+                    ("xtc", Some(step)) => Box::new(XtcWriter::append(filename, step, 1000.0)),
+                    (_, None) => Box::new(TrrWriter::new(filename)),
+                    (_, Some(step)) => Box::new(TrrWriter::append(filename, step)),
+                }
+            };
+            // The first run wrote up to step 8, with a checkpoint at step 5.
+            let mut writer = open(None);
+            (0..=8).for_each(|step| writer.write_frame(&frame(step)));
+            writer.flush();
+            let mut writer = open(Some(5));
+            (6..=10).for_each(|step| writer.write_frame(&frame(step)));
+            writer.flush();
+
+            let mut reader = open_trajectory(filename);
+            let frames = Frames::new(&mut *reader).collect::<Vec<Frame>>();
+            let steps = frames.iter().map(|f| f.step).collect::<Vec<i32>>();
+            assert_eq!(steps, (0..=10).collect::<Vec<i32>>(), "{}", ext);
+            assert!((frames[3].positions[0][0] - 0.3).abs() < 1e-3, "{}", ext);
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
 }
@@ -0,0 +1,251 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use super::frame::Frame;
+use super::reader::{read_or_eof, scan_offsets, TrajectoryReader};
+use super::writer::{open_after_step, FrameWriter};
+use super::xdr::*;
+use super::xtc::read_box;
+use crate::{to_f32, to_f64, Matrix, Real, Rvec};
+
+pub const TRR_MAGIC: i32 = 1993;
+pub const TRR_VERSION: &str = "GMX_trn_file";
+
+// Full precision GROMACS trajectory with box, positions, velocities and
+// forces, each stored only when present in the frame. Reals are written in
+// the precision of the build, as GROMACS does.
+pub struct TrrWriter {
+    file: BufWriter<File>,
+    pub lambda: Real,
+}
+
+impl TrrWriter {
+    pub fn new(filename: &str) -> TrrWriter {
+        TrrWriter {
+            file: BufWriter::new(File::create(filename).unwrap()),
+            lambda: 0.0,
+        }
+    }
+
+    // Continues `filename` after its last frame at or before `step`, as on a
+    // restart from a checkpoint at `step`: the later frames are dropped.
+    pub fn append(filename: &str, step: i32) -> TrrWriter {
+        TrrWriter {
+            file: BufWriter::new(open_after_step(filename, step)),
+            lambda: 0.0,
+        }
+    }
+}
+
+impl FrameWriter for TrrWriter {
+    fn write_frame(&mut self, frame: &Frame) {
+        let natoms = frame.natoms();
+        let real = std::mem::size_of::<Real>();
+        let put_real = |buf: &mut Vec<u8>, x: Real| match real {
+            4 => put_f32(buf, to_f32(x)),
+            _ => put_f64(buf, to_f64(x)),
+        };
+        let vec_size = |v: Option<&Vec<_>>| v.map_or(0, |_| (3 * real * natoms) as i32);
+        let mut buf = Vec::new();
+        put_i32(&mut buf, TRR_MAGIC);
+        put_i32(&mut buf, TRR_VERSION.len() as i32 + 1);
+        put_string(&mut buf, TRR_VERSION);
+        // ir, e, box, vir, pres, top, sym, x, v and f sizes in bytes.
+        let sizes = [
+            0,
+            0,
+            frame.simbox.map_or(0, |_| 9 * real as i32),
+            0,
+            0,
+            0,
+            0,
+            vec_size(Some(&frame.positions)),
+            vec_size(frame.velocities.as_ref()),
+            vec_size(frame.forces.as_ref()),
+        ];
+        sizes.iter().for_each(|&s| put_i32(&mut buf, s));
+        put_i32(&mut buf, natoms as i32);
+        put_i32(&mut buf, frame.step);
+        put_i32(&mut buf, 0);
+        put_real(&mut buf, frame.time);
+        put_real(&mut buf, self.lambda);
+
+        if let Some(simbox) = &frame.simbox {
+            simbox
+                .vectors
+                .iter()
+                .flatten()
+                .for_each(|&x| put_real(&mut buf, x));
+        }
+        let vectors = [
+            Some(&frame.positions),
+            frame.velocities.as_ref(),
+            frame.forces.as_ref(),
+        ];
+        for v in vectors.into_iter().flatten() {
+            v.iter().flatten().for_each(|&x| put_real(&mut buf, x));
+        }
+        self.file.write_all(&buf).unwrap();
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+}
+
+struct TrrHeader {
+    box_size: usize,
+    vir_size: usize,
+    pres_size: usize,
+    x_size: usize,
+    v_size: usize,
+    f_size: usize,
+    natoms: usize,
+    step: i32,
+    time: Real,
+    // Size of reals, 4 or 8 bytes.
+    real: usize,
+}
+
+impl TrrHeader {
+    fn data_size(&self) -> usize {
+        self.box_size + self.vir_size + self.pres_size + self.x_size + self.v_size + self.f_size
+    }
+}
+
+// Reads single and double precision TRR files.
+pub struct TrrReader {
+    file: BufReader<File>,
+    offsets: Option<Vec<u64>>,
+}
+
+impl TrrReader {
+    pub fn new(filename: &str) -> TrrReader {
+        let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        TrrReader {
+            file: BufReader::new(file),
+            offsets: None,
+        }
+    }
+
+    fn read_exact(&mut self, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        if !read_or_eof(&mut self.file, &mut buf) {
+            panic!("Truncated TRR frame");
+        }
+        buf
+    }
+
+    fn read_header(&mut self) -> Option<TrrHeader> {
+        let mut buf = vec![0; 12];
+        if !read_or_eof(&mut self.file, &mut buf) {
+            return None;
+        }
+        let mut xdr = XdrReader::new(&buf);
+        let magic = xdr.i32();
+        if magic != TRR_MAGIC {
+            panic!("Not a TRR frame (magic number {})", magic);
+        }
+        xdr.i32();
+        let len = xdr.i32() as usize;
+        self.read_exact(len + (4 - len % 4) % 4);
+
+        let buf = self.read_exact(13 * 4);
+        let mut xdr = XdrReader::new(&buf);
+        let ints = [0; 13].map(|_| xdr.i32() as usize);
+        let [_ir, _e, box_size, vir_size, pres_size, _top, _sym, x_size, v_size, f_size, natoms, step, _nre] =
+            ints;
+        let real = if box_size > 0 {
+            box_size / 9
+        } else if natoms > 0 {
+            [x_size, v_size, f_size]
+                .into_iter()
+                .find(|&s| s > 0)
+                .map_or(4, |s| s / (3 * natoms))
+        } else {
+            4
+        };
+        if real != 4 && real != 8 {
+            panic!("Invalid TRR frame: {} bytes per real", real);
+        }
+        let buf = self.read_exact(2 * real);
+        let mut xdr = XdrReader::new(&buf);
+        let time = if real == 4 {
+            xdr.f32() as Real
+        } else {
+            xdr.f64() as Real
+        };
+        Some(TrrHeader {
+            box_size,
+            vir_size,
+            pres_size,
+            x_size,
+            v_size,
+            f_size,
+            natoms,
+            step: step as i32,
+            time,
+            real,
+        })
+    }
+}
+
+impl TrajectoryReader for TrrReader {
+    fn read_frame(&mut self) -> Option<Frame> {
+        let header = self.read_header()?;
+        let buf = self.read_exact(header.data_size());
+        let mut xdr = XdrReader::new(&buf);
+        let mut real = || {
+            if header.real == 4 {
+                xdr.f32() as Real
+            } else {
+                xdr.f64() as Real
+            }
+        };
+        let mut frame = Frame {
+            step: header.step,
+            time: header.time,
+            ..Default::default()
+        };
+        if header.box_size > 0 {
+            let mut vectors: Matrix = [[0.0; 3]; 3];
+            vectors.iter_mut().flatten().for_each(|x| *x = real());
+            frame.simbox = read_box(vectors);
+        }
+        for _ in 0..(header.vir_size + header.pres_size) / header.real {
+            real();
+        }
+        let mut vectors = |size: usize| {
+            (size > 0).then(|| {
+                (0..header.natoms)
+                    .map(|_| [real(), real(), real()])
+                    .collect::<Vec<Rvec>>()
+            })
+        };
+        frame.positions = vectors(header.x_size).unwrap_or_default();
+        frame.velocities = vectors(header.v_size);
+        frame.forces = vectors(header.f_size);
+        Some(frame)
+    }
+
+    fn skip_frame(&mut self) -> Option<Real> {
+        let header = self.read_header()?;
+        self.file.seek_relative(header.data_size() as i64).unwrap();
+        Some(header.time)
+    }
+
+    fn tell(&mut self) -> u64 {
+        self.file.stream_position().unwrap()
+    }
+
+    fn seek_offset(&mut self, offset: u64) {
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+    }
+
+    fn offsets(&mut self) -> &[u64] {
+        if self.offsets.is_none() {
+            self.offsets = Some(scan_offsets(self, 0));
+        }
+        self.offsets.as_ref().unwrap()
+    }
+}
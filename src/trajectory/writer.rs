@@ -1,38 +1,113 @@
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::io::BufWriter;
 
-use crate::system::System;
-use crate::Rvec;
+use super::frame::Frame;
+use super::reader::open_trajectory;
+use crate::simbox::{BoxShape, SimBox};
+use crate::{Real, Rvec};
 
+// Opens `filename` to continue it after its first `len` bytes, dropping the
+// rest, or creates it when it does not exist.
+pub fn open_truncated(filename: &str, len: u64) -> File {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(filename)
+        .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    file.set_len(len)
+        .and_then(|_| file.seek(std::io::SeekFrom::End(0)))
+        .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    file
+}
+
+// Opens the trajectory `filename` to continue it after its last frame at or
+// before `step`, as on a restart from a checkpoint at `step`.
+pub fn open_after_step(filename: &str, step: i32) -> File {
+    let len = if std::path::Path::new(filename).exists() {
+        let mut reader = open_trajectory(filename);
+        loop {
+            let offset = reader.tell();
+            match reader.read_frame() {
+                Some(frame) if frame.step <= step => (),
+                _ => break offset,
+            }
+        }
+    } else {
+        0
+    };
+    open_truncated(filename, len)
+}
+
+// Sink for trajectory frames. Output is buffered: `flush` (or dropping the
+// writer) makes sure everything reaches the file.
+pub trait FrameWriter {
+    fn write_frame(&mut self, frame: &Frame);
+    fn flush(&mut self);
+}
+
+// Plain text trajectory: one line per frame with the time followed by the
+// coordinates of every atom.
 pub struct TrajectoryWriter {
-    file: File,
+    file: BufWriter<File>,
     pub interval: i32,
 }
 
 impl TrajectoryWriter {
     pub fn new(filename: &str, interval: i32, append: bool) -> Self {
         Self {
-            file: OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(append)
-                .open(filename)
-                .unwrap(),
+            file: BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(filename)
+                    .unwrap(),
+            ),
             interval,
         }
     }
-    pub fn write(&mut self, positions: &[Rvec], time: &f32) {
-
-        let blob = positions
-            .iter()
-            .flat_map(|xyz| xyz.map(|p| p.to_string()))
-            .collect::<Vec<String>>()
-            .join(" ");
-
-        if let Err(e) = writeln!(self.file, "{} {}", time, blob) {
+    pub fn write(&mut self, positions: &[Rvec], time: &Real) {
+        let mut line = time.to_string();
+        for p in positions.iter().flatten() {
+            line.push(' ');
+            line.push_str(&p.to_string());
+        }
+        if let Err(e) = writeln!(self.file, "{}", line) {
             eprintln!("Couldn't write to file: {}", e);
         }
+    }
+}
+
+impl FrameWriter for TrajectoryWriter {
+    fn write_frame(&mut self, frame: &Frame) {
+        self.write(&frame.positions, &frame.time);
+    }
+
+    fn flush(&mut self) {
         self.file.flush().unwrap();
     }
 }
+
+// Writes a coordinate file in the format read by `reader::read_coords`:
+// the box on the first line followed by one line per atom.
+pub fn write_coords(filename: &str, simbox: &SimBox, coords: &[Rvec]) {
+    let file = File::create(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    write_crd(&mut BufWriter::new(file), simbox, coords);
+}
+
+pub fn write_crd<W: Write>(file: &mut W, simbox: &SimBox, coords: &[Rvec]) {
+    if simbox.shape == BoxShape::Rectangular {
+        let [a, b, c] = simbox.diagonal();
+        writeln!(file, "{} {} {}", a, b, c).unwrap();
+    } else {
+        let v = simbox.vectors.iter().flatten().map(|x| x.to_string());
+        writeln!(file, "{}", v.collect::<Vec<String>>().join(" ")).unwrap();
+    }
+    for xyz in coords.iter() {
+        writeln!(file, "{} {} {}", xyz[0], xyz[1], xyz[2]).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+// Big-endian XDR primitives shared by the GROMACS trajectory formats.
+
+pub fn put_i32(buf: &mut Vec<u8>, x: i32) {
+    buf.extend_from_slice(&x.to_be_bytes());
+}
+
+pub fn put_f32(buf: &mut Vec<u8>, x: f32) {
+    buf.extend_from_slice(&x.to_be_bytes());
+}
+
+pub fn put_f64(buf: &mut Vec<u8>, x: f64) {
+    buf.extend_from_slice(&x.to_be_bytes());
+}
+
+// Opaque data is padded with zeros to a multiple of four bytes.
+pub fn put_opaque(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+// Strings are stored as their length followed by the padded characters.
+pub fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_i32(buf, s.len() as i32);
+    put_opaque(buf, s.as_bytes());
+}
+
+// Cursor over an XDR encoded buffer.
+pub struct XdrReader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> XdrReader<'a> {
+    pub fn new(data: &'a [u8]) -> XdrReader<'a> {
+        XdrReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        if self.pos + n > self.data.len() {
+            panic!("Unexpected end of XDR data");
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        bytes
+    }
+
+    pub fn i32(&mut self) -> i32 {
+        i32::from_be_bytes(self.take(4).try_into().unwrap())
+    }
+
+    pub fn f32(&mut self) -> f32 {
+        f32::from_be_bytes(self.take(4).try_into().unwrap())
+    }
+
+    pub fn f64(&mut self) -> f64 {
+        f64::from_be_bytes(self.take(8).try_into().unwrap())
+    }
+
+    pub fn opaque(&mut self, n: usize) -> &'a [u8] {
+        let bytes = self.take(n);
+        self.take((4 - n % 4) % 4);
+        bytes
+    }
+
+    pub fn string(&mut self) -> String {
+        let n = self.i32() as usize;
+        String::from_utf8_lossy(self.opaque(n)).into_owned()
+    }
+}
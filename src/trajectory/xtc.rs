@@ -0,0 +1,558 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use super::frame::Frame;
+use super::reader::{read_or_eof, scan_offsets, TrajectoryReader};
+use super::writer::{open_after_step, FrameWriter};
+use super::xdr::*;
+use crate::simbox::SimBox;
+use crate::{to_f32, Matrix, Real, Rvec};
+
+pub const XTC_MAGIC: i32 = 1995;
+
+// Lossy compressed GROMACS trajectory, storing positions only, rounded to
+// 1/precision nm.
+pub struct XtcWriter {
+    file: BufWriter<File>,
+    pub precision: Real,
+}
+
+impl XtcWriter {
+    pub fn new(filename: &str, precision: Real) -> XtcWriter {
+        XtcWriter {
+            file: BufWriter::new(File::create(filename).unwrap()),
+            precision,
+        }
+    }
+
+    // Continues `filename` after its last frame at or before `step`, as on a
+    // restart from a checkpoint at `step`: the later frames are dropped.
+    pub fn append(filename: &str, step: i32, precision: Real) -> XtcWriter {
+        XtcWriter {
+            file: BufWriter::new(open_after_step(filename, step)),
+            precision,
+        }
+    }
+}
+
+impl FrameWriter for XtcWriter {
+    fn write_frame(&mut self, frame: &Frame) {
+        let mut buf = Vec::new();
+        put_i32(&mut buf, XTC_MAGIC);
+        put_i32(&mut buf, frame.natoms() as i32);
+        put_i32(&mut buf, frame.step);
+        put_f32(&mut buf, to_f32(frame.time));
+        let vectors = frame.simbox.map(|b| b.vectors).unwrap_or_default();
+        vectors
+            .iter()
+            .flatten()
+            .for_each(|&x| put_f32(&mut buf, to_f32(x)));
+        compress_coords(&mut buf, &frame.positions, self.precision);
+        self.file.write_all(&buf).unwrap();
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+}
+
+// Size of the frame header up to the repeated number of atoms.
+const HEADER_SIZE: usize = 56;
+// Size of the compression parameters up to the length of the packed data.
+const PARAMS_SIZE: usize = 36;
+
+pub struct XtcReader {
+    file: BufReader<File>,
+    offsets: Option<Vec<u64>>,
+}
+
+impl XtcReader {
+    pub fn new(filename: &str) -> XtcReader {
+        let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        XtcReader {
+            file: BufReader::new(file),
+            offsets: None,
+        }
+    }
+
+    // Reads the header and the compression parameters of the next frame,
+    // returning them with the size of the data that follows.
+    fn read_head(&mut self) -> Option<(Vec<u8>, usize)> {
+        let mut buf = vec![0; HEADER_SIZE];
+        if !read_or_eof(&mut self.file, &mut buf) {
+            return None;
+        }
+        let mut xdr = XdrReader::new(&buf);
+        let magic = xdr.i32();
+        if magic != XTC_MAGIC {
+            panic!("Not an XTC frame (magic number {})", magic);
+        }
+        let natoms = xdr.i32() as usize;
+        if natoms <= 9 {
+            return Some((buf, 12 * natoms));
+        }
+        buf.resize(HEADER_SIZE + PARAMS_SIZE, 0);
+        if !read_or_eof(&mut self.file, &mut buf[HEADER_SIZE..]) {
+            panic!("Truncated XTC frame");
+        }
+        let mut xdr = XdrReader::new(&buf[HEADER_SIZE + PARAMS_SIZE - 4..]);
+        let nbytes = xdr.i32() as usize;
+        Some((buf, nbytes + (4 - nbytes % 4) % 4))
+    }
+}
+
+impl TrajectoryReader for XtcReader {
+    fn read_frame(&mut self) -> Option<Frame> {
+        let (mut buf, rest) = self.read_head()?;
+        let len = buf.len();
+        buf.resize(len + rest, 0);
+        if !read_or_eof(&mut self.file, &mut buf[len..]) {
+            panic!("Truncated XTC frame");
+        }
+        let mut xdr = XdrReader::new(&buf);
+        xdr.i32();
+        xdr.i32();
+        let step = xdr.i32();
+        let time = xdr.f32() as Real;
+        let mut vectors: Matrix = [[0.0; 3]; 3];
+        vectors
+            .iter_mut()
+            .flatten()
+            .for_each(|x| *x = xdr.f32() as Real);
+        let (positions, _) = decompress_coords(&mut xdr);
+        Some(Frame {
+            step,
+            time,
+            simbox: read_box(vectors),
+            ..Frame::new(positions)
+        })
+    }
+
+    fn skip_frame(&mut self) -> Option<Real> {
+        let (buf, rest) = self.read_head()?;
+        self.file.seek_relative(rest as i64).unwrap();
+        Some(XdrReader::new(&buf[12..16]).f32() as Real)
+    }
+
+    fn tell(&mut self) -> u64 {
+        self.file.stream_position().unwrap()
+    }
+
+    fn seek_offset(&mut self, offset: u64) {
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+    }
+
+    fn offsets(&mut self) -> &[u64] {
+        if self.offsets.is_none() {
+            self.offsets = Some(scan_offsets(self, 0));
+        }
+        self.offsets.as_ref().unwrap()
+    }
+}
+
+// GROMACS files store an all zero box for non-periodic systems.
+pub(crate) fn read_box(vectors: Matrix) -> Option<SimBox> {
+    if vectors.iter().flatten().all(|&x| x == 0.0) {
+        None
+    } else {
+        Some(SimBox::triclinic(vectors))
+    }
+}
+
+// Compression scheme of xdrfile's xdr3dfcoord: coordinates are turned into
+// integers and packed with just enough bits for the range spanned by the
+// system; runs of atoms close to their predecessor (as in water) are stored
+// as small differences, with an adaptive size.
+const MAGICINTS: [i32; 73] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 10, 12, 16, 20, 25, 32, 40, 50, 64, 80, 101, 128, 161, 203, 256,
+    322, 406, 512, 645, 812, 1024, 1290, 1625, 2048, 2580, 3250, 4096, 5060, 6501, 8192, 10321,
+    13003, 16384, 20642, 26007, 32768, 41285, 52015, 65536, 82570, 104031, 131072, 165140, 208063,
+    262144, 330280, 416127, 524287, 660561, 832255, 1048576, 1321122, 1664510, 2097152, 2642245,
+    3329021, 4194304, 5284491, 6658042, 8388607, 10568983, 13316085, 16777216,
+];
+const FIRSTIDX: usize = 9;
+const LASTIDX: usize = MAGICINTS.len();
+
+// Number of bits needed to store integers in [0, size).
+fn sizeofint(size: i32) -> u32 {
+    let mut bits = 0;
+    let mut num: i64 = 1;
+    while size as i64 >= num && bits < 32 {
+        bits += 1;
+        num <<= 1;
+    }
+    bits
+}
+
+// Number of bits needed to store three integers in [0, sizes) as one number.
+fn sizeofints(sizes: &[i32; 3]) -> u32 {
+    let product = sizes.iter().fold(1u128, |p, &s| p * s as u128);
+    128 - product.leading_zeros()
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    nacc: u32,
+}
+
+impl BitWriter {
+    fn send_bits(&mut self, nbits: u32, value: u32) {
+        if nbits == 0 {
+            return;
+        }
+        self.acc = (self.acc << nbits) | (value as u64 & ((1u64 << nbits) - 1));
+        self.nacc += nbits;
+        while self.nacc >= 8 {
+            self.nacc -= 8;
+            self.bytes.push((self.acc >> self.nacc) as u8);
+        }
+        self.acc &= (1u64 << self.nacc) - 1;
+    }
+
+    // The combined number is sent least significant byte first.
+    fn send_ints(&mut self, nbits: u32, sizes: &[i32; 3], nums: &[i32]) {
+        let mut value = nums[0] as u128;
+        for i in 1..3 {
+            value = value * sizes[i] as u128 + nums[i] as u128;
+        }
+        let mut left = nbits;
+        while left > 8 {
+            self.send_bits(8, (value & 0xff) as u32);
+            value >>= 8;
+            left -= 8;
+        }
+        self.send_bits(left, value as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nacc > 0 {
+            self.bytes.push((self.acc << (8 - self.nacc)) as u8);
+        }
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u64,
+    nacc: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            acc: 0,
+            nacc: 0,
+        }
+    }
+
+    fn receive_bits(&mut self, nbits: u32) -> u32 {
+        while self.nacc < nbits {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .expect("Truncated compressed coordinates");
+            self.acc = (self.acc << 8) | byte as u64;
+            self.pos += 1;
+            self.nacc += 8;
+        }
+        self.nacc -= nbits;
+        let value = (self.acc >> self.nacc) & ((1u64 << nbits) - 1);
+        self.acc &= (1u64 << self.nacc) - 1;
+        value as u32
+    }
+
+    fn receive_ints(&mut self, nbits: u32, sizes: &[i32; 3]) -> [i32; 3] {
+        let mut value = 0u128;
+        let mut shift = 0;
+        let mut left = nbits;
+        while left > 8 {
+            value |= (self.receive_bits(8) as u128) << shift;
+            shift += 8;
+            left -= 8;
+        }
+        value |= (self.receive_bits(left) as u128) << shift;
+        let mut nums = [0; 3];
+        for i in (1..3).rev() {
+            nums[i] = (value % sizes[i] as u128) as i32;
+            value /= sizes[i] as u128;
+        }
+        nums[0] = value as i32;
+        nums
+    }
+}
+
+pub fn compress_coords(buf: &mut Vec<u8>, coords: &[Rvec], precision: Real) {
+    let natoms = coords.len();
+    put_i32(buf, natoms as i32);
+    if natoms <= 9 {
+        coords
+            .iter()
+            .flatten()
+            .for_each(|&x| put_f32(buf, to_f32(x)));
+        return;
+    }
+    put_f32(buf, to_f32(precision));
+
+    let maxabs = (i32::MAX - 2) as Real;
+    let mut ints = Vec::with_capacity(3 * natoms);
+    let mut minint = [i32::MAX; 3];
+    let mut maxint = [i32::MIN; 3];
+    let mut mindiff = i32::MAX;
+    let mut old = [0i32; 3];
+    for (i, r) in coords.iter().enumerate() {
+        let mut lint = [0; 3];
+        for d in 0..3 {
+            let lf = if r[d] >= 0.0 {
+                r[d] * precision + 0.5
+            } else {
+                r[d] * precision - 0.5
+            };
+            if lf.abs() > maxabs {
+                panic!(
+                    "Coordinate {} too large for XTC precision {}",
+                    r[d], precision
+                );
+            }
+            lint[d] = lf as i32;
+            minint[d] = minint[d].min(lint[d]);
+            maxint[d] = maxint[d].max(lint[d]);
+        }
+        ints.extend_from_slice(&lint);
+        let diff = (0..3).map(|d| (old[d] - lint[d]).abs()).sum::<i32>();
+        if i > 0 && diff < mindiff {
+            mindiff = diff;
+        }
+        old = lint;
+    }
+    minint.iter().for_each(|&x| put_i32(buf, x));
+    maxint.iter().for_each(|&x| put_i32(buf, x));
+    let sizeint = [0, 1, 2].map(|d| maxint[d] - minint[d] + 1);
+    let (bitsize, bitsizeint) = if (sizeint[0] | sizeint[1] | sizeint[2]) > 0xffffff {
+        (0, sizeint.map(sizeofint))
+    } else {
+        (sizeofints(&sizeint), [0; 3])
+    };
+
+    let mut smallidx = FIRSTIDX;
+    while smallidx < LASTIDX - 1 && MAGICINTS[smallidx] < mindiff {
+        smallidx += 1;
+    }
+    put_i32(buf, smallidx as i32);
+    let maxidx = (LASTIDX - 1).min(smallidx + 8);
+    let minidx = maxidx - 8;
+    let mut smaller = MAGICINTS[FIRSTIDX.max(smallidx - 1)] / 2;
+    let mut smallnum = MAGICINTS[smallidx] / 2;
+    let mut sizesmall = [MAGICINTS[smallidx]; 3];
+    let larger = MAGICINTS[maxidx] / 2;
+
+    let mut bits = BitWriter {
+        bytes: Vec::new(),
+        acc: 0,
+        nacc: 0,
+    };
+    let mut prevcoord = [0i32; 3];
+    let mut prevrun = -1;
+    let mut tmpcoord = [0i32; 24];
+    let mut i = 0;
+    while i < natoms {
+        let mut is_small = false;
+        let this = 3 * i;
+        let mut is_smaller = if smallidx < maxidx
+            && i >= 1
+            && (0..3).all(|d| (ints[this + d] - prevcoord[d]).abs() < larger)
+        {
+            1
+        } else if smallidx > minidx {
+            -1
+        } else {
+            0
+        };
+        if i + 1 < natoms && (0..3).all(|d| (ints[this + d] - ints[this + 3 + d]).abs() < smallnum)
+        {
+            // Swap the first two atoms of a run, which compresses water better.
+            for d in 0..3 {
+                ints.swap(this + d, this + 3 + d);
+            }
+            is_small = true;
+        }
+        let coord = [0, 1, 2].map(|d| ints[this + d] - minint[d]);
+        if bitsize == 0 {
+            for d in 0..3 {
+                bits.send_bits(bitsizeint[d], coord[d] as u32);
+            }
+        } else {
+            bits.send_ints(bitsize, &sizeint, &coord);
+        }
+        prevcoord = [ints[this], ints[this + 1], ints[this + 2]];
+        i += 1;
+
+        let mut run = 0;
+        if !is_small && is_smaller == -1 {
+            is_smaller = 0;
+        }
+        while is_small && run < 8 * 3 {
+            let this = 3 * i;
+            let dist2 = (0..3)
+                .map(|d| {
+                    let x = (ints[this + d] - prevcoord[d]) as i64;
+                    x * x
+                })
+                .sum::<i64>();
+            if is_smaller == -1 && dist2 >= smaller as i64 * smaller as i64 {
+                is_smaller = 0;
+            }
+            for d in 0..3 {
+                tmpcoord[run] = ints[this + d] - prevcoord[d] + smallnum;
+                run += 1;
+            }
+            prevcoord = [ints[this], ints[this + 1], ints[this + 2]];
+            i += 1;
+            let next = 3 * i;
+            is_small =
+                i < natoms && (0..3).all(|d| (ints[next + d] - prevcoord[d]).abs() < smallnum);
+        }
+        if run as i32 != prevrun || is_smaller != 0 {
+            prevrun = run as i32;
+            bits.send_bits(1, 1);
+            bits.send_bits(5, (run as i32 + is_smaller + 1) as u32);
+        } else {
+            bits.send_bits(1, 0);
+        }
+        for k in (0..run).step_by(3) {
+            bits.send_ints(smallidx as u32, &sizesmall, &tmpcoord[k..k + 3]);
+        }
+        if is_smaller != 0 {
+            smallidx = (smallidx as i32 + is_smaller) as usize;
+            if is_smaller < 0 {
+                smallnum = smaller;
+                smaller = if smallidx > FIRSTIDX {
+                    MAGICINTS[smallidx - 1] / 2
+                } else {
+                    0
+                };
+            } else {
+                smaller = smallnum;
+                smallnum = MAGICINTS[smallidx] / 2;
+            }
+            sizesmall = [MAGICINTS[smallidx]; 3];
+        }
+    }
+    let bytes = bits.finish();
+    put_i32(buf, bytes.len() as i32);
+    put_opaque(buf, &bytes);
+}
+
+// Inverse of `compress_coords`; returns the coordinates and their precision.
+pub fn decompress_coords(xdr: &mut XdrReader) -> (Vec<Rvec>, Real) {
+    let natoms = xdr.i32() as usize;
+    if natoms <= 9 {
+        let coords = (0..natoms)
+            .map(|_| [0; 3].map(|_| xdr.f32() as Real))
+            .collect();
+        return (coords, 0.0);
+    }
+    let precision = xdr.f32() as Real;
+    let minint = [xdr.i32(), xdr.i32(), xdr.i32()];
+    let maxint = [xdr.i32(), xdr.i32(), xdr.i32()];
+    let sizeint = [0, 1, 2].map(|d| maxint[d] - minint[d] + 1);
+    let (bitsize, bitsizeint) = if (sizeint[0] | sizeint[1] | sizeint[2]) > 0xffffff {
+        (0, sizeint.map(sizeofint))
+    } else {
+        (sizeofints(&sizeint), [0; 3])
+    };
+    let mut smallidx = xdr.i32() as usize;
+    if !(FIRSTIDX..LASTIDX).contains(&smallidx) {
+        panic!("Invalid XTC compression index {}", smallidx);
+    }
+    let mut smaller = MAGICINTS[FIRSTIDX.max(smallidx - 1)] / 2;
+    let mut smallnum = MAGICINTS[smallidx] / 2;
+    let mut sizesmall = [MAGICINTS[smallidx]; 3];
+    let nbytes = xdr.i32() as usize;
+    let mut bits = BitReader::new(xdr.opaque(nbytes));
+
+    let inv_precision = 1.0 / precision;
+    let to_real = |c: &[i32; 3]| c.map(|x| x as Real * inv_precision);
+    let mut coords = Vec::with_capacity(natoms);
+    let mut run = 0;
+    while coords.len() < natoms {
+        let mut this = if bitsize == 0 {
+            [0, 1, 2].map(|d| bits.receive_bits(bitsizeint[d]) as i32)
+        } else {
+            bits.receive_ints(bitsize, &sizeint)
+        };
+        for d in 0..3 {
+            this[d] += minint[d];
+        }
+        let mut prev = this;
+        let mut is_smaller = 0;
+        if bits.receive_bits(1) == 1 {
+            run = bits.receive_bits(5) as i32;
+            is_smaller = run % 3;
+            run -= is_smaller;
+            is_smaller -= 1;
+        }
+        if run > 0 {
+            for k in (0..run).step_by(3) {
+                let small = bits.receive_ints(smallidx as u32, &sizesmall);
+                let mut next = [0, 1, 2].map(|d| small[d] + prev[d] - smallnum);
+                if k == 0 {
+                    // Undo the swap of the first two atoms.
+                    std::mem::swap(&mut next, &mut prev);
+                    coords.push(to_real(&prev));
+                } else {
+                    prev = next;
+                }
+                coords.push(to_real(&next));
+            }
+        } else {
+            coords.push(to_real(&this));
+        }
+        smallidx = (smallidx as i32 + is_smaller) as usize;
+        if is_smaller < 0 {
+            smallnum = smaller;
+            smaller = if smallidx > FIRSTIDX {
+                MAGICINTS[smallidx - 1] / 2
+            } else {
+                0
+            };
+        } else if is_smaller > 0 {
+            smaller = smallnum;
+            smallnum = MAGICINTS[smallidx] / 2;
+        }
+        sizesmall = [MAGICINTS[smallidx]; 3];
+    }
+    if coords.len() != natoms {
+        panic!("Corrupt XTC coordinates");
+    }
+    (coords, precision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trajectory::reader::read_coords;
+
+    #[test]
+    fn it_compresses_coords() {
+        let (_, coords) = read_coords("tests/diala.crd");
+        for precision in [1000.0, 100.0] {
+            let mut buf = Vec::new();
+            compress_coords(&mut buf, &coords, precision);
+            // Much smaller than 12 bytes per atom.
+            assert!(buf.len() < 6 * coords.len());
+            let (decoded, prec) = decompress_coords(&mut XdrReader::new(&buf));
+            assert_eq!(prec, precision);
+            assert_eq!(decoded.len(), coords.len());
+            for (a, b) in decoded.iter().zip(coords.iter()) {
+                for d in 0..3 {
+                    assert!((a[d] - b[d]).abs() <= 0.5 / precision + 1e-6);
+                }
+            }
+        }
+    }
+}
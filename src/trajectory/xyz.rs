@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use super::frame::{AtomInfo, Frame};
+use crate::linalg::*;
+use crate::simbox::SimBox;
+use crate::{Matrix, Real};
+
+// Reads the first frame of an XYZ file. Extended XYZ comment lines are
+// understood: `Lattice` gives the box vectors and `Properties` the columns,
+// where `pos` is in Angstrom and `velo` in Angstrom/fs. Everything is
+// converted to nm and ps.
+pub fn read(filename: &str) -> Frame {
+    let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    parse(BufReader::new(file))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Frame {
+    let mut lines = reader.lines().map_while(Result::ok);
+    let natoms = lines
+        .next()
+        .and_then(|l| l.trim().parse::<usize>().ok())
+        .expect("Missing number of atoms in XYZ file");
+    let comment = lines.next().unwrap_or_default();
+    let keys = parse_comment(&comment);
+    let get = |key: &str| {
+        keys.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    };
+
+    // Column offsets of species, positions and velocities.
+    let (mut species, mut pos, mut velo) = (Some(0), Some(1), None);
+    if let Some(properties) = get("Properties") {
+        (species, pos) = (None, None);
+        let fields = properties.split(':').collect::<Vec<&str>>();
+        let mut col = 0;
+        for prop in fields.chunks(3) {
+            if prop.len() != 3 {
+                panic!("Invalid extended XYZ properties: {}", properties);
+            }
+            let ncols = prop[2]
+                .parse::<usize>()
+                .unwrap_or_else(|_| panic!("Invalid extended XYZ properties: {}", properties));
+            match prop[0] {
+                "species" => species = Some(col),
+                "pos" => pos = Some(col),
+                "velo" | "vel" | "velocities" => velo = Some(col),
+                _ => (),
+            }
+            col += ncols;
+        }
+    }
+    let pos = pos.expect("Extended XYZ file without positions");
+
+    let mut frame = Frame::default();
+    let mut velocities = Vec::new();
+    for i in 0..natoms {
+        let line = lines
+            .next()
+            .unwrap_or_else(|| panic!("XYZ file ends after {} of {} atoms", i, natoms));
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let vec = |col: usize| {
+            [0, 1, 2].map(|d| {
+                fields
+                    .get(col + d)
+                    .and_then(|x| x.parse::<Real>().ok())
+                    .unwrap_or_else(|| panic!("Invalid XYZ atom line: {}", line))
+            })
+        };
+        frame.positions.push(vec(pos).map(|x| x / 10.0));
+        if let Some(velo) = velo {
+            velocities.push(vec(velo).map(|x| 100.0 * x));
+        }
+        frame.atoms.push(AtomInfo {
+            element: species.map(|s| fields[s].to_string()).unwrap_or_default(),
+            ..Default::default()
+        });
+    }
+    if velo.is_some() {
+        frame.velocities = Some(velocities);
+    }
+    if let Some(time) = get("Time").and_then(|t| t.parse::<Real>().ok()) {
+        frame.time = time;
+    }
+    if let Some(lattice) = get("Lattice") {
+        let v = lattice
+            .split_whitespace()
+            .map(|x| x.parse::<Real>().unwrap() / 10.0)
+            .collect::<Vec<Real>>();
+        if v.len() != 9 {
+            panic!("Invalid extended XYZ lattice: {}", lattice);
+        }
+        let vectors: Matrix = [[v[0], v[1], v[2]], [v[3], v[4], v[5]], [v[6], v[7], v[8]]];
+        set_box(&mut frame, vectors);
+    }
+    if keys.is_empty() || get("Properties").is_none() {
+        frame.title = comment.trim().to_string();
+    }
+    frame
+}
+
+// Writes a frame in extended XYZ format, in Angstrom and Angstrom/fs. The
+// species are the elements of `frame.atoms`, or the atom names when the
+// elements are not known.
+pub fn write<W: Write>(out: &mut W, frame: &Frame) {
+    writeln!(out, "{}", frame.natoms()).unwrap();
+    let mut comment = String::new();
+    if let Some(simbox) = &frame.simbox {
+        let v = simbox
+            .vectors
+            .iter()
+            .flatten()
+            .map(|x| (10.0 * x).to_string());
+        comment.push_str(&format!(
+            "Lattice=\"{}\" ",
+            v.collect::<Vec<String>>().join(" ")
+        ));
+    }
+    comment.push_str("Properties=species:S:1:pos:R:3");
+    if frame.velocities.is_some() {
+        comment.push_str(":velo:R:3");
+    }
+    writeln!(out, "{} Time={}", comment, frame.time).unwrap();
+    for (i, r) in frame.positions.iter().enumerate() {
+        let species = match frame.atoms.get(i) {
+            Some(info) if !info.element.is_empty() => info.element.as_str(),
+            Some(info) if !info.name.is_empty() => info.name.as_str(),
+            _ => "X",
+        };
+        let [x, y, z] = r.map(|x| 10.0 * x);
+        write!(out, "{} {} {} {}", species, x, y, z).unwrap();
+        if let Some(v) = frame.velocities.as_ref().map(|v| v[i].map(|x| 0.01 * x)) {
+            write!(out, " {} {} {}", v[0], v[1], v[2]).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+}
+
+// The box of an extended XYZ file may have any orientation: rotate the
+// system so that the box vectors are in the lower triangular form.
+fn set_box(frame: &mut Frame, vectors: Matrix) {
+    let lengths = vectors.map(|v| norm2(&v).sqrt());
+    let angle = |u, v| (dot(u, v) / (norm2(u) * norm2(v)).sqrt()).acos() / DEG2RAD;
+    let angles = [
+        angle(&vectors[1], &vectors[2]),
+        angle(&vectors[0], &vectors[2]),
+        angle(&vectors[0], &vectors[1]),
+    ];
+    let simbox = SimBox::from_lengths_angles(lengths, angles);
+    if simbox.vectors != vectors {
+        let to_fractional = transpose(&mat_inv(&vectors));
+        let rotate = |r: &[Real; 3]| simbox.from_fractional(&mat_vec(&to_fractional, r));
+        frame.positions.iter_mut().for_each(|r| *r = rotate(r));
+        if let Some(velocities) = &mut frame.velocities {
+            velocities.iter_mut().for_each(|v| *v = rotate(v));
+        }
+    }
+    frame.simbox = Some(simbox);
+}
+
+// Splits an extended XYZ comment line into key=value pairs, with values
+// optionally enclosed in double quotes.
+fn parse_comment(comment: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = comment.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let key = chars
+            .by_ref()
+            .skip_while(|c| c.is_whitespace())
+            .take_while(|&c| c != '=')
+            .collect::<String>();
+        if key.contains(char::is_whitespace) {
+            // Plain XYZ comment.
+            return Vec::new();
+        }
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            chars.by_ref().take_while(|&c| c != '"').collect::<String>()
+        } else {
+            chars
+                .by_ref()
+                .take_while(|c| !c.is_whitespace())
+                .collect::<String>()
+        };
+        if !key.is_empty() {
+            pairs.push((key, value));
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_extended_xyz() {
+        let xyz = "\
+3
+Lattice=\"10.0 0.0 0.0 0.0 12.0 0.0 0.0 0.0 14.0\" Properties=species:S:1:pos:R:3:velo:R:3 Time=0.5
+O 1.0 2.0 3.0 0.001 0.0 0.0
+H 1.957 2.0 3.0 0.0 0.002 0.0
+H 0.76 2.927 3.0 0.0 0.0 0.003
+";
+        let frame = parse(xyz.as_bytes());
+        assert_eq!(frame.natoms(), 3);
+        assert_eq!(frame.time, 0.5);
+        assert_eq!(frame.simbox, Some(SimBox::rectangular(1.0, 1.2, 1.4)));
+        assert!(norm2(&rvsub(&frame.positions[1], &[0.1957, 0.2, 0.3])) < 1e-12);
+        let velocities = frame.velocities.clone().unwrap();
+        assert!(norm2(&rvsub(&velocities[2], &[0.0, 0.0, 0.3])) < 1e-12);
+        assert_eq!(frame.atoms[1].element, "H");
+
+        let mut out = Vec::new();
+        write(&mut out, &frame);
+        let copy = parse(out.as_slice());
+        assert_eq!(copy.simbox, frame.simbox);
+        assert_eq!(copy.atoms, frame.atoms);
+        assert!(norm2(&rvsub(&copy.positions[1], &frame.positions[1])) < 1e-12);
+
+        let plain = parse("1\nwater oxygen\nO 1.0 2.0 3.0\n".as_bytes());
+        assert_eq!(plain.title, "water oxygen");
+        assert_eq!(plain.simbox, None);
+        assert_eq!(plain.atoms[0].element, "O");
+    }
+}